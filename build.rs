@@ -1,9 +1,15 @@
+use std::env;
+use std::path;
+
 use tonic_build;
 
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("proto/orderbook.proto")?;
+    // descriptors of all services, served by gRPC server reflection
+    let out_dir = path::PathBuf::from(env::var("OUT_DIR")?);
+    tonic_build::configure()
+        .file_descriptor_set_path(out_dir.join("dragonflybot_descriptor.bin"))
+        .compile(&["proto/orderbook.proto", "proto/arbitrage.proto", "proto/microstructure.proto",
+                   "proto/paper_trading.proto"], &["proto"])?;
     Ok(())
 }
-
-
@@ -1,16 +1,63 @@
 //! Example gRPC client that prints received messages
 
+use clap::Parser;
 use tokio;
 use tokio_stream::StreamExt;
 use tonic::transport;
 
-use dragonflybot::{constants, error, service::grpc::server::orderbook};
+use dragonflybot::{constants, error, service::grpc::server::orderbook, service::grpc::tls};
+use error_stack::{IntoReport, Result, ResultExt};
 
 type GrpcClient = orderbook::orderbook_aggregator_client::OrderbookAggregatorClient<transport::Channel>;
 
 
-async fn print_stream(client: &mut GrpcClient) {
-    let rq = orderbook::Empty{};
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Request the book ranked by fee-inclusive effective prices
+    #[arg(long)]
+    fee_adjusted: bool,
+    /// Additionally request exact decimals: double, string or scaled
+    #[arg(long, default_value = "double", value_parser = parse_price_format)]
+    price_format: orderbook::PriceFormat,
+    /// What the server does when we read too slowly: conflate, disconnect or drop_oldest
+    #[arg(long, default_value = "conflate", value_parser = parse_slow_consumer_policy)]
+    slow_consumer_policy: orderbook::SlowConsumerPolicy,
+    /// PEM CA bundle to verify the server certificate against, enables TLS
+    #[arg(long)]
+    tls_ca: Option<std::path::PathBuf>,
+    /// Name the server certificate is issued for
+    #[arg(long, default_value = "localhost", requires = "tls_ca")]
+    tls_domain: String,
+    /// PEM client certificate, for servers requiring mutual TLS
+    #[arg(long, requires_all = ["tls_ca", "tls_key"])]
+    tls_cert: Option<std::path::PathBuf>,
+    /// PEM private key of the client certificate
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<std::path::PathBuf>,
+    /// Bearer token, for servers requiring authentication
+    #[arg(long)]
+    token: Option<String>,
+}
+
+fn parse_price_format(value: &str) -> std::result::Result<orderbook::PriceFormat, String> {
+    orderbook::PriceFormat::from_str_name(&value.to_uppercase()).ok_or(format!("Unknown price format {}", value))
+}
+
+fn parse_slow_consumer_policy(value: &str) -> std::result::Result<orderbook::SlowConsumerPolicy, String> {
+    orderbook::SlowConsumerPolicy::from_str_name(&value.to_uppercase())
+        .ok_or(format!("Unknown slow-consumer policy {}", value))
+}
+
+async fn print_stream(client: &mut GrpcClient, args: &Args) {
+    let mut rq = tonic::Request::new(orderbook::BookSummaryRequest {
+        fee_adjusted: args.fee_adjusted,
+        price_format: args.price_format.into(),
+        slow_consumer_policy: args.slow_consumer_policy.into()
+    });
+    if let Some(token) = &args.token {
+        rq.metadata_mut().insert("authorization", format!("Bearer {}", token).parse().unwrap());
+    }
     let mut stream = client.book_summary(rq)
         .await
         .unwrap()
@@ -24,9 +71,21 @@ async fn print_stream(client: &mut GrpcClient) {
 
 #[tokio::main]
 async fn main() -> Result<(), error::Error> {
-    let mut client = GrpcClient::connect(
-        format!("http://localhost:{}", constants::service::GRPC_SERVER_PORT)).await.unwrap();
-    print_stream(&mut client).await;
+    let args = Args::parse();
+    let scheme = if args.tls_ca.is_some() {"https"} else {"http"};
+    let mut endpoint = transport::Endpoint::from_shared(
+        format!("{}://localhost:{}", scheme, constants::service::GRPC_SERVER_PORT)).unwrap();
+    if let Some(ca) = &args.tls_ca {
+        let identity = args.tls_cert.as_deref().zip(args.tls_key.as_deref());
+        let tls_config = tls::get_client_tls_config(&args.tls_domain, ca, identity)
+            .change_context(error::Error)?;
+        endpoint = endpoint.tls_config(tls_config)
+            .into_report()
+            .change_context(error::Error)
+            .attach_printable("Invalid TLS configuration")?;
+    }
+    let mut client = GrpcClient::connect(endpoint).await.unwrap();
+    print_stream(&mut client, &args).await;
 
     Ok(())
-}
\ No newline at end of file
+}
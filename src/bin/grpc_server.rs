@@ -3,14 +3,23 @@
 #[global_allocator]
 static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
 
+use std::str::FromStr;
 use std::sync::Arc;
 
 use clap::Parser;
-use dragonflybot::{constants, error, feed, service::grpc::orderbook_aggregator,
-                   service::grpc::server::orderbook::orderbook_aggregator_server, types, util};
-use error_stack::{IntoReport, Result, ResultExt};
-use tokio::sync::{broadcast, mpsc};
+use dragonflybot::{constants, error, execution::paper, execution::risk, feed, service::fix, service::grpc::arbitrage_detector,
+                   service::grpc::microstructure_analytics, service::grpc::orderbook_aggregator,
+                   service::grpc::paper_trading, service::grpc::server::arbitrage::arbitrage_detector_server,
+                   service::grpc::server::microstructure::microstructure_analytics_server,
+                   service::grpc::server::orderbook::orderbook_aggregator_server,
+                   service::grpc::server::paper_trading::paper_trading_server, service::grpc::auth, service::grpc::health,
+                   service::grpc::server::FILE_DESCRIPTOR_SET, service::grpc::tls,
+                   service::metrics, service::rest, service::ws, types, util};
+use error_stack::{IntoReport, Report, Result, ResultExt};
+use strum::EnumCount;
+use tokio::sync::{broadcast, mpsc, watch};
 use tonic;
+use tonic::server::NamedService;
 use tracing;
 use tracing_subscriber;
 
@@ -21,13 +30,200 @@ struct Args {
     /// Instrument name to subscribe to
     #[arg(short, long)]
     instrument_name: String,
+
+    /// Feed fees in basis points as `<feed>:<maker_bps>:<taker_bps>` e.g. `binance:1:7.5`
+    #[arg(long = "fee", value_parser = parse_fee_schedule)]
+    fee_schedules: Vec<(constants::Feed, util::FeeSchedule)>,
+
+    /// Additionally rank the aggregated book by fee-inclusive effective prices
+    #[arg(long)]
+    fee_adjusted: bool,
+
+    /// Minimum time between two reports of the same arbitrage opportunity, in milliseconds
+    #[arg(long, default_value_t = constants::feed_aggregator::arbitrage::DEBOUNCE_INTERVAL_MS)]
+    arbitrage_debounce_ms: u64,
+
+    /// Base instrument of the synthetic book in the common currency e.g. `ethusdt`
+    #[arg(long, requires = "synthetic_quote_instrument")]
+    synthetic_base_instrument: Option<String>,
+
+    /// Quote instrument of the synthetic book in the common currency e.g. `btcusdt`
+    #[arg(long, requires = "synthetic_base_instrument")]
+    synthetic_quote_instrument: Option<String>,
+
+    /// Feed to subscribe to for both instruments of the synthetic book
+    #[arg(long, default_value = "binance", value_parser = parse_listener_feed)]
+    synthetic_feed: constants::Feed,
+
+    /// Time it takes a paper-trading order to reach the venue, in milliseconds
+    #[arg(long, default_value_t = constants::execution::paper::LATENCY_MS)]
+    paper_latency_ms: u64,
+
+    /// Part of the displayed amount queued ahead of a resting paper-trading order, from 0 (front)
+    /// to 1 (back of the queue)
+    #[arg(long, default_value = "1", value_parser = parse_queue_position)]
+    paper_queue_position: rust_decimal::Decimal,
+
+    /// Maximum amount * price of a single order
+    #[arg(long)]
+    max_order_notional: Option<rust_decimal::Decimal>,
+
+    /// Maximum absolute position
+    #[arg(long)]
+    max_position: Option<rust_decimal::Decimal>,
+
+    /// Maximum distance of the limit price from the aggregated mid price, in basis points
+    #[arg(long)]
+    price_collar_bps: Option<rust_decimal::Decimal>,
+
+    /// Maximum number of orders per venue per second
+    #[arg(long)]
+    max_orders_per_second: Option<usize>,
+
+    /// Directory where the FIX server persists sequence numbers of it's sessions
+    #[arg(long, default_value = constants::fix::STORE_DIR)]
+    fix_store_dir: std::path::PathBuf,
+
+    /// Directory to record raw feed messages to, enables recording
+    #[arg(long)]
+    record_dir: Option<std::path::PathBuf>,
+
+    /// Read a feed's market data from a FIX session instead of it's WebSocket stream, as
+    /// `<feed>:<host>:<port>:<target_comp_id>` e.g. `bitstamp:127.0.0.1:9880:VENUE`
+    #[arg(long = "fix-market-data", value_parser = parse_fix_market_data, conflicts_with = "replay")]
+    fix_market_data: Vec<FixMarketData>,
+
+    /// Compress recordings with LZ4
+    #[arg(long, requires = "record_dir")]
+    record_compressed: bool,
+
+    /// Uncompressed size after which recording continues in a new file, in MiB
+    #[arg(long, default_value_t = constants::listener::recorder::MAX_FILE_SIZE_MB)]
+    record_max_file_size_mb: u64,
+
+    /// Age after which recording continues in a new file, in seconds
+    #[arg(long, default_value_t = constants::listener::recorder::MAX_FILE_AGE_S)]
+    record_max_file_age_s: u64,
+
+    /// Recordings to replay instead of connecting to the venues, files or directories of them
+    #[arg(long, num_args = 1.., conflicts_with = "synthetic_base_instrument")]
+    replay: Vec<std::path::PathBuf>,
+
+    /// Replay at the recorded pace multiplied by the speed, without it as fast as possible
+    #[arg(long, requires = "replay", value_parser = parse_replay_speed)]
+    replay_speed: Option<f64>,
+
+    /// SenderCompID of the FIX server
+    #[arg(long, default_value = constants::service::FIX_SENDER_COMP_ID)]
+    fix_sender_comp_id: String,
+
+    /// PEM certificate (chain) of the gRPC server, enables TLS
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<std::path::PathBuf>,
+
+    /// PEM private key of the gRPC server
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<std::path::PathBuf>,
+
+    /// PEM CA bundle to verify gRPC client certificates against, enables mutual TLS
+    #[arg(long, requires = "tls_cert")]
+    tls_client_ca: Option<std::path::PathBuf>,
+
+    /// JSON file mapping bearer tokens to client entitlements, enables authentication of the gRPC
+    /// services
+    #[arg(long, conflicts_with = "auth_jwt_key")]
+    auth_tokens: Option<std::path::PathBuf>,
+
+    /// File with the key of HS256 JWT bearer tokens carrying client entitlements, enables
+    /// authentication of the gRPC services
+    #[arg(long)]
+    auth_jwt_key: Option<std::path::PathBuf>,
+
+    /// Bind the FIX, WebSocket, REST and metrics servers to all interfaces instead of localhost,
+    /// they don't authenticate clients, and the gRPC server if authentication is disabled
+    #[arg(long)]
+    public_unauthenticated_servers: bool,
+}
+
+fn parse_listener_feed(arg: &str) -> std::result::Result<constants::Feed, String> {
+    constants::Feed::from_venue_name(arg).ok_or(format!("no listener for feed `{}`", arg))
+}
+
+/// FIX session a feed's market data is read from
+#[derive(Clone, Debug)]
+struct FixMarketData {
+    feed: constants::Feed,
+    domain: &'static str,
+    port: u16,
+    target_comp_id: String
+}
+
+fn parse_fix_market_data(arg: &str) -> std::result::Result<FixMarketData, String> {
+    let fields: Vec<&str> = arg.splitn(4, ':').collect();
+    let [feed, domain, port, target_comp_id] = fields[..] else {
+        return Err("expected `<feed>:<host>:<port>:<target_comp_id>`".to_owned())
+    };
+    let feed = parse_listener_feed(feed)?;
+    let port = port.parse().map_err(|e: std::num::ParseIntError| e.to_string())?;
+    // parsed once at startup, the client's endpoint lives as long as the process
+    let domain = Box::leak(domain.to_owned().into_boxed_str());
+    Ok(FixMarketData{feed, domain, port, target_comp_id: target_comp_id.to_owned()})
+}
+
+fn parse_fee_schedule(arg: &str) -> std::result::Result<(constants::Feed, util::FeeSchedule), String> {
+    let fields: Vec<&str> = arg.split(':').collect();
+    if fields.len() != 3 {
+        return Err("expected `<feed>:<maker_bps>:<taker_bps>`".to_owned())
+    }
+    let feed = constants::Feed::from_venue_name(fields[0])
+        .ok_or(format!("unknown venue `{}`", fields[0]))?;
+    let maker_bps = rust_decimal::Decimal::from_str(fields[1]).map_err(|e| e.to_string())?;
+    let taker_bps = rust_decimal::Decimal::from_str(fields[2]).map_err(|e| e.to_string())?;
+
+    Ok((feed, util::FeeSchedule{maker_bps, taker_bps}))
+}
+
+fn parse_replay_speed(arg: &str) -> std::result::Result<f64, String> {
+    let speed = f64::from_str(arg).map_err(|e| e.to_string())?;
+    if !speed.is_finite() || speed <= 0.0 {
+        return Err("expected a positive number".to_owned())
+    }
+    Ok(speed)
+}
+
+fn parse_queue_position(arg: &str) -> std::result::Result<rust_decimal::Decimal, String> {
+    let queue_position = rust_decimal::Decimal::from_str(arg).map_err(|e| e.to_string())?;
+    if queue_position < rust_decimal::Decimal::ZERO || queue_position > rust_decimal::Decimal::ONE {
+        return Err("expected a number from 0 to 1".to_owned())
+    }
+    Ok(queue_position)
 }
 
 
 fn main() -> Result<(), error::Error> {
     let args = Args::parse();
-    let addr = format!("0.0.0.0:{}", constants::service::GRPC_SERVER_PORT).parse().unwrap();
+    let unauthenticated_host = if args.public_unauthenticated_servers {"0.0.0.0"} else {"127.0.0.1"};
+    let grpc_host = if args.auth_tokens.is_some() || args.auth_jwt_key.is_some() {"0.0.0.0"} else {unauthenticated_host};
+    let addr = format!("{}:{}", grpc_host, constants::service::GRPC_SERVER_PORT).parse().unwrap();
+    let fix_addr = format!("{}:{}", unauthenticated_host, constants::service::FIX_SERVER_PORT).parse().unwrap();
+    let ws_addr = format!("{}:{}", unauthenticated_host, constants::service::WS_SERVER_PORT).parse().unwrap();
+    let rest_addr = format!("{}:{}", unauthenticated_host, constants::service::REST_SERVER_PORT).parse().unwrap();
+    let metrics_addr = format!("{}:{}", unauthenticated_host, constants::service::METRICS_SERVER_PORT).parse().unwrap();
     let instrument_name = args.instrument_name.to_owned();
+    let mut fee_schedules = [util::FeeSchedule::default(); constants::Feed::COUNT];
+    for (feed, fee_schedule) in args.fee_schedules {
+        fee_schedules[feed as usize] = fee_schedule;
+    }
+    let fee_adjusted = args.fee_adjusted;
+    let arbitrage_debounce_interval = std::time::Duration::from_millis(args.arbitrage_debounce_ms);
+    let paper_latency = std::time::Duration::from_millis(args.paper_latency_ms);
+    let paper_queue_position = args.paper_queue_position;
+    let risk_limits = risk::RiskLimits {
+        max_order_notional: args.max_order_notional,
+        max_position: args.max_position,
+        price_collar_bps: args.price_collar_bps,
+        max_orders_per_second: args.max_orders_per_second
+    };
 
     let logger = tracing_subscriber::fmt()
         .compact()
@@ -42,6 +238,7 @@ fn main() -> Result<(), error::Error> {
 
     let threaded_runtime = tokio::runtime::Builder::new_multi_thread()
         .enable_io()
+        .enable_time()
         .build()
         .into_report()
         .change_context(error::Error)
@@ -50,61 +247,433 @@ fn main() -> Result<(), error::Error> {
     let (queue_feed_listener_tx, queue_aggregator_rx) =
         mpsc::channel::<types::BoxedFeedOrderBook>(constants::QUEUE_BUFFER_SIZE);
     let (broadcast_tx, _) =
-        broadcast::channel::<types::BoxedOrderbookSummary>(1);
+        broadcast::channel::<types::SharedAggregatedBook>(1);
     let broadcast_aggregator_tx = Arc::new(broadcast_tx);
     let broadcast_aggregator_tx_clone = Arc::clone(&broadcast_aggregator_tx);
+    let (orderbooks_tx, orderbooks_rx) = watch::channel(util::get_initialized_orderbooks());
+    let (aggregated_book_tx, aggregated_book_rx) = watch::channel(None);
+    let (queue_arbitrage_tx, queue_arbitrage_rx) =
+        mpsc::channel::<types::BoxedFeedOrderBook>(constants::QUEUE_BUFFER_SIZE);
+    let (broadcast_tx, _) = broadcast::channel::<types::BoxedArbitrageOpportunity>(
+        constants::feed_aggregator::arbitrage::BROADCAST_BUFFER_SIZE);
+    let broadcast_arbitrage_tx = Arc::new(broadcast_tx);
+    let broadcast_arbitrage_tx_clone = Arc::clone(&broadcast_arbitrage_tx);
+    let (queue_microstructure_tx, queue_microstructure_rx) =
+        mpsc::channel::<types::BoxedFeedOrderBook>(constants::QUEUE_BUFFER_SIZE);
+    let (broadcast_tx, _) =
+        broadcast::channel::<types::BoxedMicrostructureSignals>(1);
+    let broadcast_microstructure_tx = Arc::new(broadcast_tx);
+    let broadcast_microstructure_tx_clone = Arc::clone(&broadcast_microstructure_tx);
+    let (queue_paper_tx, queue_paper_rx) =
+        mpsc::channel::<types::BoxedFeedOrderBook>(constants::QUEUE_BUFFER_SIZE);
+    let (command_paper_tx, command_paper_rx) =
+        mpsc::channel::<paper::Command>(constants::execution::paper::COMMAND_BUFFER_SIZE);
+    let (broadcast_tx, _) = broadcast::channel::<types::BoxedPaperOrder>(
+        constants::execution::paper::BROADCAST_BUFFER_SIZE);
+    let broadcast_paper_tx = Arc::new(broadcast_tx);
+    let broadcast_paper_tx_clone = Arc::clone(&broadcast_paper_tx);
+
+    //shut down on SIGINT/SIGTERM
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    threaded_runtime.spawn(
+        async move {
+            match wait_for_shutdown_signal().await {
+                Ok(_) => {
+                    tracing::info!("Shutting down");
+                    shutdown_tx.send_replace(true);
+                }
+                Err(e) => tracing::error!("Cannot listen for shutdown signals: {}", e)
+            }});
+
+    //record raw feed msgs, the writer stops once all listeners dropped their recorder
+    let (recorder, recorder_handle) = match args.record_dir {
+        Some(dir) => {
+            let (records_tx, records_rx) =
+                mpsc::channel::<feed::listener::recorder::Record>(constants::listener::recorder::BUFFER_SIZE);
+            let mut record_writer = feed::listener::recorder::RecordWriter {
+                records_rx,
+                dir,
+                compressed: args.record_compressed,
+                max_file_size: args.record_max_file_size_mb * 1024 * 1024,
+                max_file_age: std::time::Duration::from_secs(args.record_max_file_age_s)
+            };
+            let recorder_handle = threaded_runtime.spawn_blocking(
+                move || {
+                    if let Err(e) = record_writer.run() {
+                        tracing::error!("Recorder: {:?}", e);
+                    }});
+            (Some(feed::listener::recorder::Recorder::new(records_tx)), Some(recorder_handle))
+        }
+        None => (None, None)
+    };
+
+    //replay recordings instead of connecting to the venues
+    let mut replay = match args.replay_speed {
+        _ if args.replay.is_empty() => None,
+        Some(speed) => Some(feed::client::replay::Replay::new(args.replay, feed::client::replay::Pace::RealTime(speed))),
+        None => Some(feed::client::replay::Replay::new(args.replay, feed::client::replay::Pace::AsFastAsPossible))
+    };
 
     //spawn listeners
-    let cloned_queue1 = queue_feed_listener_tx.clone();
-    let cloned_queue2 = queue_feed_listener_tx.clone();
-    let cloned_instrument_name1 = instrument_name.to_owned();
-    let cloned_instrument_name2 = instrument_name.to_owned();
+    let queues_tx = vec![queue_feed_listener_tx.clone(), queue_arbitrage_tx.clone(), queue_microstructure_tx.clone(),
+                         queue_paper_tx.clone()];
+    let mut listeners = vec![];
+    for feed in [constants::Feed::BinanceSpot, constants::Feed::BitstampSpot] {
+        let source = match args.fix_market_data.iter().find(|fix_market_data| fix_market_data.feed == feed) {
+            Some(fix_market_data) => ListenerSource::Fix(fix_market_data.clone(), args.fix_store_dir.clone()),
+            None => match replay.as_mut() {
+                Some(replay) => ListenerSource::Replay(replay.client(feed)),
+                None => ListenerSource::Venue
+            }
+        };
+        listeners.push(spawn_listener(&threaded_runtime, feed, queues_tx.clone(), instrument_name.to_owned(), source,
+                                      recorder.clone(), shutdown_rx.clone()));
+    }
+    if let Some(replay) = replay {
+        threaded_runtime.spawn(
+            async move {
+                if let Err(e) = replay.run().await {
+                    tracing::error!("Replay: {:?}", e);
+                }});
+    }
+
+    //spawn the synthetic book, both legs are subscribed to on the same feed. Legs are not recorded,
+    //recordings are per feed and their msgs would be replayed as the main instrument's book
+    if let (Some(base_instrument), Some(quote_instrument)) =
+        (args.synthetic_base_instrument, args.synthetic_quote_instrument) {
+        let (base_leg_tx, base_leg_rx) =
+            mpsc::channel::<types::BoxedFeedOrderBook>(constants::QUEUE_BUFFER_SIZE);
+        let (quote_leg_tx, quote_leg_rx) =
+            mpsc::channel::<types::BoxedFeedOrderBook>(constants::QUEUE_BUFFER_SIZE);
+        listeners.push(spawn_listener(&threaded_runtime, args.synthetic_feed, vec![base_leg_tx], base_instrument,
+                                      ListenerSource::Venue, None, shutdown_rx.clone()));
+        listeners.push(spawn_listener(&threaded_runtime, args.synthetic_feed, vec![quote_leg_tx], quote_instrument,
+                                      ListenerSource::Venue, None, shutdown_rx.clone()));
 
+        threaded_runtime.spawn(
+            async move {
+                let mut listener_aggregator = feed::listener_aggregator::synthetic::Aggregator {
+                    base_leg_rx,
+                    quote_leg_rx,
+                    queues_tx
+                };
+                listener_aggregator.run().await;});
+    }
+    drop(recorder);
+
+    //spawn the paper-trading engine
     threaded_runtime.spawn(
         async move {
-            let mut listener = feed::listener::orderbook_snap_change_forwarder::Listener::<constants::feed::BinanceSpot>::new(
-                constants::Feed::BinanceSpot,
-                cloned_queue1,
-                constants::listener::orderbook_snap_change_forwarder::msg_offset_orderbook_start::BINANCE,
-                cloned_instrument_name1
-            )
-                .await.expect("Could not create new listener");
-            let _ = listener.run().await;});
-    threaded_runtime.spawn(
+            let mut engine = paper::Engine {
+                queue_rx: queue_paper_rx,
+                command_rx: command_paper_rx,
+                queue_tx: broadcast_paper_tx,
+                simulator: paper::Simulator::new(
+                    paper_latency, paper_queue_position, fee_schedules, risk::RiskManager::new(risk_limits))
+            };
+            engine.run().await;});
+
+    //start the FIX server
+    let fix_server = fix::FixServer {
+        sender_comp_id: args.fix_sender_comp_id,
+        store_dir: args.fix_store_dir,
+        instrument_name: instrument_name.to_owned(),
+        broadcast_aggregator_tx: Arc::clone(&broadcast_aggregator_tx),
+        orderbooks_rx: orderbooks_rx.clone(),
+        shutdown_rx: shutdown_rx.clone()
+    };
+    let fix_server_handle = threaded_runtime.spawn(
+        async move {
+            if let Err(e) = fix_server.run(fix_addr).await {
+                tracing::error!("FIX server: {:?}", e);
+            }});
+
+    //start the WebSocket server
+    let ws_server = ws::WsServer {
+        instrument_name: instrument_name.to_owned(),
+        broadcast_aggregator_tx: Arc::clone(&broadcast_aggregator_tx),
+        fee_adjusted,
+        shutdown_rx: shutdown_rx.clone()
+    };
+    let ws_server_handle = threaded_runtime.spawn(
+        async move {
+            if let Err(e) = ws_server.run(ws_addr).await {
+                tracing::error!("WebSocket server: {:?}", e);
+            }});
+
+    //start the REST server
+    let rest_server = rest::RestServer {
+        instrument_name: instrument_name.to_owned(),
+        orderbooks_rx: orderbooks_rx.clone(),
+        shutdown_rx: shutdown_rx.clone()
+    };
+    let rest_server_handle = threaded_runtime.spawn(
         async move {
-            let mut listener = feed::listener::orderbook_snap_change_forwarder::Listener::<constants::feed::BitstampSpot>::new(
-                constants::Feed::BitstampSpot,
-                cloned_queue2,
-                constants::listener::orderbook_snap_change_forwarder::msg_offset_orderbook_start::BITSTAMP,
-                cloned_instrument_name2
-            )
-                .await.expect("Could not create new listener");
-            let _ = listener.run().await;});
+            if let Err(e) = rest_server.run(rest_addr).await {
+                tracing::error!("REST server: {:?}", e);
+            }});
 
+    //start the metrics server
+    let metrics_server = metrics::MetricsServer{shutdown_rx: shutdown_rx.clone()};
+    let metrics_server_handle = threaded_runtime.spawn(
+        async move {
+            if let Err(e) = metrics_server.run(metrics_addr).await {
+                tracing::error!("Metrics server: {:?}", e);
+            }});
 
     //start the gRPC server
-    threaded_runtime.spawn(
-        tonic::transport::Server::builder()
+    let authenticator = match (&args.auth_tokens, &args.auth_jwt_key) {
+        (Some(path), _) => Some(auth::Authenticator::from_tokens_file(path).change_context(error::Error)?),
+        (_, Some(path)) => Some(auth::Authenticator::from_jwt_key_file(path).change_context(error::Error)?),
+        _ => None
+    };
+    let interceptor = auth::Interceptor{authenticator: authenticator.map(Arc::new)};
+    //the order book service follows readiness of feeds and the aggregator, the others serve once
+    //the server is up
+    let (mut health_reporter, health_service) = tonic_health::server::health_reporter();
+    threaded_runtime.block_on(async {
+        for service_name in [
+            arbitrage_detector_server::ArbitrageDetectorServer::<arbitrage_detector::ArbitrageDetectorService>::NAME,
+            microstructure_analytics_server::MicrostructureAnalyticsServer::<microstructure_analytics::MicrostructureAnalyticsService>::NAME,
+            paper_trading_server::PaperTradingServer::<paper_trading::PaperTradingService>::NAME] {
+            health_reporter.set_service_status(service_name, tonic_health::ServingStatus::Serving).await;
+        }
+    });
+    let health_monitor = health::HealthMonitor {
+        reporter: health_reporter,
+        service_name: orderbook_aggregator_server::OrderbookAggregatorServer::<orderbook_aggregator::OrderbookAggregatorService>::NAME,
+        aggregated_book_rx: aggregated_book_rx.clone(),
+        max_age: std::time::Duration::from_secs(constants::service::grpc::HEALTH_MAX_AGE_S),
+        check_interval: std::time::Duration::from_millis(constants::service::grpc::HEALTH_CHECK_INTERVAL_MS),
+        shutdown_rx: shutdown_rx.clone()
+    };
+    threaded_runtime.spawn(health_monitor.run());
+    let reflection_service = tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(FILE_DESCRIPTOR_SET)
+        .register_encoded_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET)
+        .build()
+        .into_report()
+        .change_context(error::Error)
+        .attach_printable("Cannot build reflection service")?;
+
+    let mut grpc_server = tonic::transport::Server::builder();
+    if let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) {
+        let tls_config = tls::get_server_tls_config(cert, key, args.tls_client_ca.as_deref())
+            .change_context(error::Error)?;
+        grpc_server = grpc_server.tls_config(tls_config)
+            .into_report()
+            .change_context(error::Error)
+            .attach_printable("Invalid TLS configuration")?;
+    }
+    let mut grpc_shutdown_rx = shutdown_rx.clone();
+    let grpc_server_handle = threaded_runtime.spawn(
+        grpc_server
             .add_service(
-                orderbook_aggregator_server::OrderbookAggregatorServer::new(
+                orderbook_aggregator_server::OrderbookAggregatorServer::with_interceptor(
                     orderbook_aggregator::OrderbookAggregatorService{
                         context: {util::GrpcClientContext {
                             instrument_name: instrument_name.to_owned(),
                             broadcast_aggregator_tx: broadcast_aggregator_tx_clone,
+                            orderbooks_rx,
+                            aggregated_book_rx,
+                            fee_adjusted,
+                            shutdown_rx: shutdown_rx.clone()
                         }
-                    }}))
-            .serve(addr)
+                    }},
+                    interceptor.clone()))
+            .add_service(
+                arbitrage_detector_server::ArbitrageDetectorServer::with_interceptor(
+                    arbitrage_detector::ArbitrageDetectorService{
+                        instrument_name: instrument_name.to_owned(),
+                        broadcast_opportunity_tx: broadcast_arbitrage_tx_clone,
+                        shutdown_rx: shutdown_rx.clone()
+                    },
+                    interceptor.clone()))
+            .add_service(
+                microstructure_analytics_server::MicrostructureAnalyticsServer::with_interceptor(
+                    microstructure_analytics::MicrostructureAnalyticsService{
+                        instrument_name: instrument_name.to_owned(),
+                        broadcast_signals_tx: broadcast_microstructure_tx_clone,
+                        shutdown_rx: shutdown_rx.clone()
+                    },
+                    interceptor.clone()))
+            .add_service(
+                paper_trading_server::PaperTradingServer::with_interceptor(
+                    paper_trading::PaperTradingService{
+                        instrument_name: instrument_name.to_owned(),
+                        command_tx: command_paper_tx,
+                        broadcast_order_tx: broadcast_paper_tx_clone,
+                        shutdown_rx: shutdown_rx.clone()
+                    },
+                    interceptor))
+            .add_service(health_service)
+            .add_service(reflection_service)
+            // stops accepting clients, streams end with `UNAVAILABLE` and the server waits for them
+            .serve_with_shutdown(addr, async move {util::wait_for_shutdown(&mut grpc_shutdown_rx).await})
     );
 
+    // the arbitrage detector is not on the latency critical path, it blocks while there's no data
+    std::thread::spawn(move ||{
+        let mut listener_aggregator = feed::listener_aggregator::arbitrage::Aggregator {
+            queue_rx: queue_arbitrage_rx,
+            queue_tx: broadcast_arbitrage_tx,
+            fee_schedules,
+            debounce_interval: arbitrage_debounce_interval
+        };
+        listener_aggregator.run();
+    });
+    std::thread::spawn(move ||{
+        let mut listener_aggregator = feed::listener_aggregator::microstructure::Aggregator {
+            queue_rx: queue_microstructure_rx,
+            queue_tx: broadcast_microstructure_tx
+        };
+        listener_aggregator.run();
+    });
+
     // run the aggregator in it's own thread
-    let handle_thread = std::thread::spawn(||{
+    let handle_thread = std::thread::spawn(move ||{
         let mut listener_aggregator = feed::listener_aggregator::top_bbo::Aggregator {
             queue_rx: queue_aggregator_rx,
-            queue_tx: broadcast_aggregator_tx
+            queue_tx: broadcast_aggregator_tx,
+            orderbooks_tx,
+            aggregated_book_tx,
+            fee_schedules,
+            fee_adjusted,
+            shutdown_rx
         };
         listener_aggregator.run();
     });
     handle_thread.join().unwrap();
 
-    Ok(())
+    //the aggregator stopped, give listeners, the recorder and the servers bounded time to finish
+    let shutdown_timeout = std::time::Duration::from_millis(constants::service::SHUTDOWN_TIMEOUT_MS);
+    let grpc_server_result = threaded_runtime.block_on(async {
+        tokio::time::timeout(shutdown_timeout, async {
+            for listener in listeners {
+                let _ = listener.await;
+            }
+            if let Some(recorder_handle) = recorder_handle {
+                let _ = recorder_handle.await;
+            }
+            for server_handle in [fix_server_handle, ws_server_handle, rest_server_handle, metrics_server_handle] {
+                let _ = server_handle.await;
+            }
+            grpc_server_handle.await
+        }).await
+    });
+    tracing::info!("Stage latencies:\n{}", dragonflybot::metrics::get().latency.dump());
+    //other servers and tasks are dropped with the runtime
+    threaded_runtime.shutdown_background();
+    //write what FIX sessions persisted last
+    dragonflybot::fix::store::flush();
+
+    match grpc_server_result {
+        Ok(Ok(Ok(_))) => {
+            tracing::info!("Shut down");
+            Ok(())
+        }
+        Ok(Ok(Err(e))) => Err(e).into_report().change_context(error::Error).attach_printable("gRPC server failed"),
+        Ok(Err(e)) => Err(e).into_report().change_context(error::Error).attach_printable("gRPC server panicked"),
+        Err(_) => Err(Report::new(error::Error).attach_printable("Graceful shutdown timed out"))
+    }
+}
+
+/// Waits for SIGINT or SIGTERM
+async fn wait_for_shutdown_signal() -> std::io::Result<()> {
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result,
+        _ = terminate.recv() => Ok(())
+    }
+}
+
+/// Where a listener reads it's feed from
+enum ListenerSource {
+    /// The venue's WebSocket stream
+    Venue,
+    Replay(feed::client::replay::ReplayClient),
+    /// FIX session, sequence numbers are persisted in the store directory
+    Fix(FixMarketData, std::path::PathBuf)
+}
+
+/// Spawns an order book listener for the feed, forwarding to all the given queues
+///
+/// The listener's task ends when it closed the connection after shutdown was signalled. Msgs of FIX
+/// sessions are not recorded.
+fn spawn_listener(threaded_runtime: &tokio::runtime::Runtime, feed: constants::Feed,
+                  queues_tx: Vec<mpsc::Sender<types::BoxedFeedOrderBook>>, instrument_name: String,
+                  source: ListenerSource, recorder: Option<feed::listener::recorder::Recorder>,
+                  shutdown_rx: watch::Receiver<bool>) -> tokio::task::JoinHandle<()> {
+    let client = match source {
+        ListenerSource::Venue => None,
+        ListenerSource::Replay(client) => Some(client),
+        ListenerSource::Fix(fix_market_data, store_dir) => return threaded_runtime.spawn(
+            async move {
+                let feed_info = constants::FeedInfo{
+                    domain: fix_market_data.domain, path: "", port: fix_market_data.port, protocol: constants::Protocol::FIX};
+                let config = dragonflybot::fix::session::SessionConfig {
+                    role: dragonflybot::fix::session::Role::Initiator,
+                    sender_comp_id: constants::service::FIX_SENDER_COMP_ID.to_owned(),
+                    target_comp_id: fix_market_data.target_comp_id,
+                    heartbeat_interval: std::time::Duration::from_secs(constants::fix::HEARTBEAT_INTERVAL_S)
+                };
+                let subscriber = feed::subscriber::fix::Subscriber::new(feed, feed_info, config, store_dir)
+                    .await.expect("Could not create new FIX subscriber");
+                let mut listener = feed::listener::fix_market_data_forwarder::Listener::new(
+                    feed, queues_tx, instrument_name, subscriber, shutdown_rx);
+                if let Err(e) = listener.run().await {
+                    tracing::error!("FIX listener of feed {}: {:?}", feed, e);
+                }})
+    };
+    match (feed, client) {
+        (constants::Feed::BinanceSpot, None) => threaded_runtime.spawn(
+            async move {
+                let mut listener = feed::listener::orderbook_snap_change_forwarder::Listener::<constants::feed::BinanceSpot, feed::client::ws::ClientManager>::new(
+                    feed,
+                    queues_tx,
+                    constants::listener::orderbook_snap_change_forwarder::msg_offset_orderbook_start::BINANCE,
+                    instrument_name,
+                    recorder,
+                    shutdown_rx
+                )
+                    .await.expect("Could not create new listener");
+                let _ = listener.run().await;}),
+        (constants::Feed::BinanceSpot, Some(client)) => threaded_runtime.spawn(
+            async move {
+                let mut listener = feed::listener::orderbook_snap_change_forwarder::Listener::<constants::feed::BinanceSpot, _>::with_subscribed_client(
+                    feed,
+                    queues_tx,
+                    constants::listener::orderbook_snap_change_forwarder::msg_offset_orderbook_start::BINANCE,
+                    instrument_name,
+                    client,
+                    recorder,
+                    shutdown_rx
+                );
+                let _ = listener.run().await;}),
+        (constants::Feed::BitstampSpot, None) => threaded_runtime.spawn(
+            async move {
+                let mut listener = feed::listener::orderbook_snap_change_forwarder::Listener::<constants::feed::BitstampSpot, feed::client::ws::ClientManager>::new(
+                    feed,
+                    queues_tx,
+                    constants::listener::orderbook_snap_change_forwarder::msg_offset_orderbook_start::BITSTAMP,
+                    instrument_name,
+                    recorder,
+                    shutdown_rx
+                )
+                    .await.expect("Could not create new listener");
+                let _ = listener.run().await;}),
+        (constants::Feed::BitstampSpot, Some(client)) => threaded_runtime.spawn(
+            async move {
+                let mut listener = feed::listener::orderbook_snap_change_forwarder::Listener::<constants::feed::BitstampSpot, _>::with_subscribed_client(
+                    feed,
+                    queues_tx,
+                    constants::listener::orderbook_snap_change_forwarder::msg_offset_orderbook_start::BITSTAMP,
+                    instrument_name,
+                    client,
+                    recorder,
+                    shutdown_rx
+                );
+                let _ = listener.run().await;}),
+        (constants::Feed::Synthetic, _) => unreachable!("Synthetic feed is built by the `synthetic` listener aggregator")
+    }
 }
\ No newline at end of file
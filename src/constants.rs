@@ -1,13 +1,15 @@
 use strum;
+use strum::IntoEnumIterator;
 
 
 pub const QUEUE_BUFFER_SIZE: usize = 1024 * 1024;
 pub const ORDER_PRICE_INF: i32 = 100_000_000; //a price not reachable by any fin. instrument
+pub const BPS_PER_UNIT: i32 = 10_000;
 
 pub enum Protocol {
     // ARROWHEAD,  //Tokio Stock Exchange
     // BOE,    //Chicago options
-    // FIX,
+    FIX,
     // OUCH,
     // PILLAR, //NYSE
     // REST,
@@ -17,6 +19,14 @@ pub enum Protocol {
     WEBSOCKETS
 }
 
+pub mod execution {
+    pub mod paper {
+        pub const BROADCAST_BUFFER_SIZE: usize = 1024;
+        pub const COMMAND_BUFFER_SIZE: usize = 1024;
+        /// Time it takes an order to reach the venue
+        pub const LATENCY_MS: u64 = 10;
+    }
+}
 pub mod listener {
     pub mod orderbook_snap_change_forwarder {
         pub const INIT_DUMMY_MSG: &str = "{\"data\":{\"timestamp\":\"1686616236\",\"microtimestamp\":\"1686616236740643\",\"bids\":[";
@@ -25,13 +35,70 @@ pub mod listener {
             pub const BINANCE: usize = 76;
             pub const BITSTAMP: usize = 78;
         }
+
+        // how long to wait for the venue to confirm closing the connection on shutdown
+        pub const CLOSE_TIMEOUT_MS: u64 = 1000;
+    }
+    pub mod recorder {
+        pub const BUFFER_SIZE: usize = 1024 * 1024;
+        /// Uncompressed size after which the next record goes to a new file
+        pub const MAX_FILE_SIZE_MB: u64 = 256;
+        pub const MAX_FILE_AGE_S: u64 = 3600;
     }
 }
 pub mod feed_aggregator {
     pub const TOP_N_BBO: usize = 10;
+
+    pub mod synthetic {
+        // synthetic prices and amounts are rounded conservatively to this many decimal places
+        pub const DECIMAL_PLACES: u32 = 8;
+    }
+
+    pub mod microstructure {
+        // order book imbalance is calculated over this many top levels of the aggregated book
+        pub const IMBALANCE_LEVELS: [usize; 3] = [1, 5, 10];
+        // cumulative depth is calculated at these offsets from mid price, in basis points
+        pub const DEPTH_OFFSETS_BPS: [i64; 4] = [5, 10, 25, 50];
+    }
+
+    pub mod arbitrage {
+        // opportunities are events, so slow consumers should not skip them as easily as BBO updates
+        pub const BROADCAST_BUFFER_SIZE: usize = 1024;
+        pub const DEBOUNCE_INTERVAL_MS: u64 = 100;
+    }
+}
+pub mod fix {
+    pub const BEGIN_STRING: &str = "FIX.4.4";
+    pub const HEARTBEAT_INTERVAL_S: u64 = 30;
+    /// Time the counterparty gets to confirm our Logout on shutdown, as acceptor and as initiator
+    pub const LOGOUT_TIMEOUT_MS: u64 = 1000;
+    /// Larger messages are rejected, a peer can't make us buffer more than this, in bytes
+    pub const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+    /// Directory where sequence numbers of sessions are persisted
+    pub const STORE_DIR: &str = "fix_store";
 }
 pub mod service {
     pub const GRPC_SERVER_PORT: usize = 50051;
+    pub const FIX_SERVER_PORT: usize = 9878;
+    pub const FIX_SENDER_COMP_ID: &str = "DRAGONFLYBOT";
+    pub const WS_SERVER_PORT: usize = 8080;
+    pub const REST_SERVER_PORT: usize = 8081;
+    pub const METRICS_SERVER_PORT: usize = 9090;
+    // time listeners and the servers get to finish after the aggregator stopped
+    pub const SHUTDOWN_TIMEOUT_MS: u64 = 5000;
+
+    pub mod grpc {
+        // per client, messages are small but there can be many clients
+        pub const STREAM_BUFFER_SIZE: usize = 1024;
+        // the service is not ready when nothing was read from any feed for this long
+        pub const HEALTH_MAX_AGE_S: u64 = 30;
+        pub const HEALTH_CHECK_INTERVAL_MS: u64 = 1000;
+    }
+
+    pub mod ws {
+        // client requests are few, the reader waits when the connection's task is busy writing
+        pub const FRAME_BUFFER_SIZE: usize = 16;
+    }
 }
 
 pub struct FeedInfo<'a> {
@@ -41,24 +108,48 @@ pub struct FeedInfo<'a> {
     pub protocol: Protocol
 }
 
-#[derive(strum::EnumCount, strum::EnumIter, Clone, Copy, Debug, strum::Display)]
+/// Source of order book levels, a venue feed or a book derived from other feeds
+///
+/// `Feed::iter()` includes derived sources, as their levels are part of the aggregated book, use
+/// `Feed::venues()` where a venue to connect or trade with is needed.
+#[derive(strum::EnumCount, strum::EnumIter, Clone, Copy, Debug, PartialEq, Eq, strum::Display)]
 pub enum Feed {
     BinanceSpot,
-    BitstampSpot
+    BitstampSpot,
+    /// Cross-rate book built from two instruments by the `synthetic` listener aggregator
+    Synthetic
 }
 impl Feed {
-    pub fn feed_info(&self) -> FeedInfo<'static> {
+    /// Endpoint of the venue, `None` for books derived from other feeds
+    pub fn feed_info(&self) -> Option<FeedInfo<'static>> {
         match self {
-            Feed::BinanceSpot => FeedInfo{domain: "stream.binance.com", path: "/stream", port: 9443, protocol: Protocol::WEBSOCKETS},
-            Feed::BitstampSpot => FeedInfo{domain: "ws.bitstamp.net", path: "", port: 443, protocol: Protocol::WEBSOCKETS},
+            Feed::BinanceSpot => Some(FeedInfo{domain: "stream.binance.com", path: "/stream", port: 9443, protocol: Protocol::WEBSOCKETS}),
+            Feed::BitstampSpot => Some(FeedInfo{domain: "ws.bitstamp.net", path: "", port: 443, protocol: Protocol::WEBSOCKETS}),
+            Feed::Synthetic => None
         }
     }
+    pub fn is_venue(&self) -> bool {
+        self.feed_info().is_some()
+    }
+    /// Feeds of venues we can connect to and trade with
+    pub fn venues() -> impl Iterator<Item = Feed> {
+        Feed::iter().filter(Feed::is_venue)
+    }
     pub fn feed_name_for_grpc_service(&self) -> &str {
         match self {
             Feed::BinanceSpot => "binance",
-            Feed::BitstampSpot => "bitstamp"
+            Feed::BitstampSpot => "bitstamp",
+            Feed::Synthetic => "synthetic"
         }
     }
+    /// Looks up the feed by the name used in the gRPC service e.g. `binance`
+    pub fn from_feed_name(name: &str) -> Option<Feed> {
+        Feed::iter().find(|feed| feed.feed_name_for_grpc_service() == name)
+    }
+    /// Looks up the venue by the name used in the gRPC service, derived feeds are not found
+    pub fn from_venue_name(name: &str) -> Option<Feed> {
+        Feed::from_feed_name(name).filter(Feed::is_venue)
+    }
 }
 
 pub mod feed {
@@ -12,17 +12,40 @@ pub enum ClientError {
     EndpointClosedConnection
 }
 #[derive(Debug)]
+pub enum ExecutionError {
+    InvalidOrder,
+    NoVenue
+}
+#[derive(Debug)]
+pub enum FixError {
+    Error,
+    ParsingError,
+    StoreError,
+    EndpointClosedConnection
+}
+#[derive(Debug)]
 pub struct ListenerError;
 #[derive(Debug)]
 pub struct ListenerAggregatorError;
 #[derive(Debug)]
+pub struct RecorderError;
+#[derive(Debug)]
+pub struct ServiceError;
+#[derive(Debug)]
 pub struct SubscriberError;
+#[derive(Debug)]
+pub struct VenueError;
 
 impl Context for Error {}
 impl Context for ClientError {}
+impl Context for ExecutionError {}
+impl Context for FixError {}
 impl Context for ListenerError {}
 impl Context for ListenerAggregatorError {}
+impl Context for RecorderError {}
+impl Context for ServiceError {}
 impl Context for SubscriberError {}
+impl Context for VenueError {}
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -34,6 +57,16 @@ impl fmt::Display for ClientError {
         f.write_str("ClientError")
     }
 }
+impl fmt::Display for ExecutionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ExecutionError")
+    }
+}
+impl fmt::Display for FixError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("FixError")
+    }
+}
 impl fmt::Display for ListenerError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.write_str("MainError")
@@ -44,8 +77,23 @@ impl fmt::Display for ListenerAggregatorError {
         f.write_str("ListenerAggregatorError")
     }
 }
+impl fmt::Display for RecorderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RecorderError")
+    }
+}
+impl fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ServiceError")
+    }
+}
 impl fmt::Display for SubscriberError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.write_str("SubscriberError")
     }
+}
+impl fmt::Display for VenueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("VenueError")
+    }
 }
\ No newline at end of file
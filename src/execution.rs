@@ -0,0 +1,6 @@
+pub mod cost;
+pub mod order;
+pub mod paper;
+pub mod risk;
+pub mod router;
+pub mod venue;
\ No newline at end of file
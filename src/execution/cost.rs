@@ -0,0 +1,118 @@
+//! Cost to execute an order against the aggregated book
+//!
+//! We walk top N of all order books merged together, so the cost is what we'd pay if we took
+//! liquidity on all feeds at once at the current market state.
+use rust_decimal;
+use strum::IntoEnumIterator;
+
+use crate::constants;
+use crate::types;
+use crate::util;
+
+
+/// Walks the aggregated book and allocates the amount to the best priced orders
+///
+/// Returns `None` if there are no bids or no asks to calculate mid price from.
+pub fn get_execution_cost(orderbooks: &types::OrderBooksByFeed, side: util::Side, amount: rust_decimal::Decimal)
+    -> Option<util::ExecutionCost> {
+    let (asks, bids) = util::get_aggregated_orders(orderbooks);
+    let mid_price = (asks.first()?.price + bids.first()?.price) / rust_decimal::Decimal::TWO;
+    let orders = match side {
+        util::Side::Buy => asks,
+        util::Side::Sell => bids
+    };
+
+    let mut allocations: Vec<util::FeedAllocation> = constants::Feed::iter()
+        .map(|feed| util::FeedAllocation{
+            feed, amount: rust_decimal::Decimal::ZERO, notional: rust_decimal::Decimal::ZERO})
+        .collect();
+    let mut filled_amount = rust_decimal::Decimal::ZERO;
+    let mut notional = rust_decimal::Decimal::ZERO;
+    let mut worst_price = orders[0].price;
+
+    for order in orders.iter() {
+        if filled_amount >= amount {break}
+
+        let fill = std::cmp::min(order.amount, amount - filled_amount);
+        let allocation = &mut allocations[order.feed as usize];
+        allocation.amount += fill;
+        allocation.notional += fill * order.price;
+        filled_amount += fill;
+        notional += fill * order.price;
+        worst_price = order.price;
+    }
+    allocations.retain(|allocation| !allocation.amount.is_zero());
+
+    let vwap = notional.checked_div(filled_amount).unwrap_or(mid_price);
+    let slippage = match side {
+        util::Side::Buy => vwap - mid_price,
+        util::Side::Sell => mid_price - vwap
+    };
+    Some(util::ExecutionCost {
+        side,
+        requested_amount: amount,
+        filled_amount,
+        notional,
+        vwap,
+        worst_price,
+        mid_price,
+        slippage_bps: slippage / mid_price * rust_decimal::Decimal::from(constants::BPS_PER_UNIT),
+        allocations
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    fn get_orderbooks() -> types::OrderBooksByFeed {
+        let mut orderbooks = util::get_initialized_orderbooks();
+        for (feed, asks, bids) in [
+            (constants::Feed::BinanceSpot, [(101, 1), (103, 2)], [(99, 1), (97, 2)]),
+            (constants::Feed::BitstampSpot, [(102, 1), (104, 5)], [(98, 1), (96, 5)])] {
+            let orderbook = &mut orderbooks[feed as usize];
+            for (order, (price, amount)) in orderbook.asks.iter_mut().zip(asks) {
+                *order = util::Order{feed, price: rust_decimal::Decimal::from(price), amount: rust_decimal::Decimal::from(amount)};
+            }
+            for (order, (price, amount)) in orderbook.bids.iter_mut().zip(bids) {
+                *order = util::Order{feed, price: rust_decimal::Decimal::from(price), amount: rust_decimal::Decimal::from(amount)};
+            }
+        }
+        orderbooks
+    }
+
+    #[test]
+    fn test_buy() {
+        let cost = get_execution_cost(&get_orderbooks(), util::Side::Buy, rust_decimal::Decimal::from(3))
+            .expect("Expected execution cost");
+
+        // 1@101 binance, 1@102 bitstamp, 1@103 binance
+        assert_eq!(cost.filled_amount, rust_decimal::Decimal::from(3));
+        assert_eq!(cost.vwap, rust_decimal::Decimal::from(102));
+        assert_eq!(cost.worst_price, rust_decimal::Decimal::from(103));
+        assert_eq!(cost.mid_price, rust_decimal::Decimal::from(100));
+        assert_eq!(cost.slippage_bps, rust_decimal::Decimal::from(200));
+        assert_eq!(cost.allocations.len(), 2);
+        assert_eq!(cost.allocations[0].amount, rust_decimal::Decimal::from(2));
+        assert_eq!(cost.allocations[1].notional, rust_decimal::Decimal::from(102));
+    }
+
+    #[test]
+    fn test_sell_not_enough_liquidity() {
+        let cost = get_execution_cost(&get_orderbooks(), util::Side::Sell, rust_decimal::Decimal::from(20))
+            .expect("Expected execution cost");
+
+        assert_eq!(cost.filled_amount, rust_decimal::Decimal::from(9));
+        assert_eq!(cost.worst_price, rust_decimal::Decimal::from(96));
+        assert!(cost.slippage_bps > rust_decimal::Decimal::ZERO);
+    }
+
+    #[test]
+    fn test_empty_orderbooks() {
+        let orderbooks = util::get_initialized_orderbooks();
+
+        assert!(get_execution_cost(&orderbooks, util::Side::Buy, rust_decimal::Decimal::ONE).is_none());
+    }
+}
\ No newline at end of file
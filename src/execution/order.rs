@@ -0,0 +1,98 @@
+//! Orders and fills of the execution layer
+//!
+//! A parent order is what we want to execute, child orders are what we send to venues. Child orders
+//! are immediate-or-cancel, the part that is not filled immediately is cancelled by the venue.
+use std::time;
+
+use rust_decimal;
+
+use crate::constants;
+use crate::util;
+
+
+pub type OrderId = u64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OrderStatus {
+    /// Not at the venue yet
+    PendingNew,
+    New,
+    PartiallyFilled,
+    Filled,
+    /// Nothing was or can be filled anymore
+    Cancelled,
+    Rejected
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ParentOrder {
+    pub id: OrderId,
+    pub side: util::Side,
+    pub amount: rust_decimal::Decimal,
+    /// Worst price we're willing to execute at, `None` for a market order
+    pub limit_price: Option<rust_decimal::Decimal>
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ChildOrder {
+    pub id: OrderId,
+    pub parent_id: OrderId,
+    pub feed: constants::Feed,
+    pub side: util::Side,
+    pub amount: rust_decimal::Decimal,
+    pub limit_price: rust_decimal::Decimal
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Fill {
+    pub child_id: OrderId,
+    pub feed: constants::Feed,
+    pub price: rust_decimal::Decimal,
+    pub amount: rust_decimal::Decimal,
+    pub timestamp: time::SystemTime
+}
+
+/// Venue's response to a child order
+#[derive(Clone, Debug)]
+pub struct ExecutionReport {
+    pub child_id: OrderId,
+    pub status: OrderStatus,
+    pub fills: Vec<Fill>
+}
+impl ExecutionReport {
+    pub fn filled_amount(&self) -> rust_decimal::Decimal {
+        self.fills.iter().map(|fill| fill.amount).sum()
+    }
+}
+
+/// Execution state of a parent order, tracked by the router
+#[derive(Clone, Debug)]
+pub struct ParentOrderState {
+    pub order: ParentOrder,
+    pub status: OrderStatus,
+    pub child_orders: Vec<ChildOrder>,
+    pub reports: Vec<ExecutionReport>
+}
+impl ParentOrderState {
+    pub fn new(order: ParentOrder) -> Self {
+        Self {order, status: OrderStatus::New, child_orders: vec![], reports: vec![]}
+    }
+
+    pub fn fills(&self) -> impl Iterator<Item = &Fill> {
+        self.reports.iter().flat_map(|report| report.fills.iter())
+    }
+
+    pub fn filled_amount(&self) -> rust_decimal::Decimal {
+        self.fills().map(|fill| fill.amount).sum()
+    }
+
+    pub fn remaining_amount(&self) -> rust_decimal::Decimal {
+        self.order.amount - self.filled_amount()
+    }
+
+    /// Volume weighted average price of all fills
+    pub fn average_price(&self) -> Option<rust_decimal::Decimal> {
+        let notional: rust_decimal::Decimal = self.fills().map(|fill| fill.amount * fill.price).sum();
+        notional.checked_div(self.filled_amount())
+    }
+}
\ No newline at end of file
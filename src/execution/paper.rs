@@ -0,0 +1,617 @@
+//! Paper-trading engine filling simulated orders against order books coming from listeners
+//!
+//! Consumes a queue from `orderbook_snap_change_forwarder` listeners, so orders are filled against
+//! exactly the data our other services see.
+//!
+//! Latency: an order reaches the venue `latency` after it was submitted. We only know the market
+//! state from listener updates, so the order is processed on the first update of it's feed after
+//! it reached the venue, against the order book that was valid at that time. Marketable part of the
+//! order takes liquidity, the rest of a limit order rests on the book and the rest of a market
+//! order is cancelled.
+//!
+//! Queue position: a resting order joins the queue of it's price level behind `queue_position`
+//! (0 - front, 1 - back) of the displayed amount. Decreases of the displayed amount are assumed to
+//! be trades, they first consume the queue ahead of us and then fill us. If the opposite side
+//! crosses our price, we're filled against the crossing amount.
+//!
+//! Orders go through pre-trade risk checks when they're submitted. Turning the kill switch on
+//! cancels all working orders.
+use std;
+use std::sync::Arc;
+use std::time;
+
+use rust_decimal;
+use strum::EnumCount;
+use tokio;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tracing;
+
+use crate::constants;
+use crate::execution::order;
+use crate::execution::risk;
+use crate::execution::venue::simulated;
+use crate::types;
+use crate::util;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OrderType {
+    Limit,
+    Market
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct OrderRequest {
+    pub feed: constants::Feed,
+    pub side: util::Side,
+    pub order_type: OrderType,
+    pub amount: rust_decimal::Decimal,
+    /// Required for limit orders, ignored for market orders
+    pub limit_price: Option<rust_decimal::Decimal>
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PaperFill {
+    pub price: rust_decimal::Decimal,
+    pub amount: rust_decimal::Decimal,
+    pub fee: rust_decimal::Decimal,
+    /// Resting order was filled, otherwise we took liquidity
+    pub is_maker: bool,
+    pub timestamp: time::SystemTime
+}
+
+#[derive(Clone, Debug)]
+pub struct PaperOrder {
+    pub id: order::OrderId,
+    pub request: OrderRequest,
+    pub status: order::OrderStatus,
+    pub reject_reason: Option<String>,
+    pub fills: Vec<PaperFill>
+}
+impl PaperOrder {
+    pub fn filled_amount(&self) -> rust_decimal::Decimal {
+        self.fills.iter().map(|fill| fill.amount).sum()
+    }
+
+    pub fn remaining_amount(&self) -> rust_decimal::Decimal {
+        self.request.amount - self.filled_amount()
+    }
+
+    /// Volume weighted average price of all fills
+    pub fn average_price(&self) -> Option<rust_decimal::Decimal> {
+        let notional: rust_decimal::Decimal = self.fills.iter().map(|fill| fill.amount * fill.price).sum();
+        notional.checked_div(self.filled_amount())
+    }
+
+    fn is_working(&self) -> bool {
+        matches!(self.status,
+            order::OrderStatus::PendingNew | order::OrderStatus::New | order::OrderStatus::PartiallyFilled)
+    }
+}
+
+/// Simulated position and PnL over all feeds
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Account {
+    /// Positive for long, negative for short
+    pub position: rust_decimal::Decimal,
+    /// Average entry price of the open position
+    pub average_price: rust_decimal::Decimal,
+    /// Mid price of the aggregated book, `None` while there's no market data
+    pub mark_price: Option<rust_decimal::Decimal>,
+    pub realized_pnl: rust_decimal::Decimal,
+    pub unrealized_pnl: rust_decimal::Decimal,
+    pub fees: rust_decimal::Decimal
+}
+impl Account {
+    fn apply_fill(&mut self, side: util::Side, price: rust_decimal::Decimal, amount: rust_decimal::Decimal,
+                  fee: rust_decimal::Decimal) {
+        let signed_amount = match side {
+            util::Side::Buy => amount,
+            util::Side::Sell => -amount
+        };
+        self.fees += fee;
+
+        if self.position.is_zero() || self.position.is_sign_positive() == signed_amount.is_sign_positive() {
+            let position = self.position.abs();
+            self.average_price = (self.average_price * position + price * amount) / (position + amount);
+            self.position += signed_amount;
+            return
+        }
+
+        let closed_amount = std::cmp::min(amount, self.position.abs());
+        self.realized_pnl += match side {
+            util::Side::Buy => (self.average_price - price) * closed_amount,
+            util::Side::Sell => (price - self.average_price) * closed_amount
+        };
+        self.position += signed_amount;
+        if closed_amount < amount {
+            // position flipped
+            self.average_price = price;
+        } else if self.position.is_zero() {
+            self.average_price = rust_decimal::Decimal::ZERO;
+        }
+    }
+}
+
+/// Our place in the queue of the resting order's price level
+struct Queue {
+    ahead: rust_decimal::Decimal,
+    /// Displayed amount at our price at the last update
+    level_amount: rust_decimal::Decimal
+}
+
+struct WorkingOrder {
+    order: PaperOrder,
+    arrives_at: time::Instant,
+    /// `None` until the order reached the venue
+    queue: Option<Queue>
+}
+
+/// Order matching, positions and PnL, without any I/O
+pub struct Simulator {
+    latency: time::Duration,
+    queue_position: rust_decimal::Decimal,
+    fee_schedules: [util::FeeSchedule; constants::Feed::COUNT],
+    risk_manager: risk::RiskManager,
+    orderbooks: types::OrderBooksByFeed,
+    working_orders: Vec<WorkingOrder>,
+    account: Account,
+    next_order_id: order::OrderId
+}
+
+impl Simulator {
+    pub fn new(latency: time::Duration, queue_position: rust_decimal::Decimal,
+               fee_schedules: [util::FeeSchedule; constants::Feed::COUNT], risk_manager: risk::RiskManager) -> Self {
+        Self {
+            latency,
+            queue_position,
+            fee_schedules,
+            risk_manager,
+            orderbooks: util::get_initialized_orderbooks(),
+            working_orders: vec![],
+            account: Account::default(),
+            next_order_id: 1
+        }
+    }
+
+    pub fn submit_order(&mut self, request: OrderRequest, now: time::Instant) -> PaperOrder {
+        let id = self.next_order_id;
+        self.next_order_id += 1;
+        let is_valid = request.amount > rust_decimal::Decimal::ZERO
+            && request.feed.is_venue()
+            && match request.order_type {
+                OrderType::Limit => request.limit_price.is_some_and(|price| price > rust_decimal::Decimal::ZERO),
+                OrderType::Market => true
+            };
+        let mut paper_order = PaperOrder{
+            id, request, status: order::OrderStatus::Rejected, reject_reason: None, fills: vec![]};
+        if !is_valid {
+            paper_order.reject_reason = Some("Invalid order".to_owned());
+            return paper_order
+        }
+
+        let limit_price = match request.order_type {
+            OrderType::Limit => request.limit_price,
+            OrderType::Market => None
+        };
+        if let Err(rejection) = self.risk_manager.check_order(
+            request.feed, request.side, request.amount, limit_price, util::get_mid_price(&self.orderbooks), now) {
+            paper_order.reject_reason = Some(rejection.to_string());
+            return paper_order
+        }
+
+        paper_order.status = order::OrderStatus::PendingNew;
+        self.working_orders.push(
+            WorkingOrder{order: paper_order.clone(), arrives_at: now + self.latency, queue: None});
+        paper_order
+    }
+
+    /// Returns `None` if the order is not working anymore
+    pub fn cancel_order(&mut self, id: order::OrderId) -> Option<PaperOrder> {
+        let index = self.working_orders.iter().position(|working_order| working_order.order.id == id)?;
+        let mut paper_order = self.working_orders.remove(index).order;
+        paper_order.status = order::OrderStatus::Cancelled;
+        self.risk_manager.on_order_done(paper_order.request.side, paper_order.remaining_amount());
+        Some(paper_order)
+    }
+
+    /// Returns orders cancelled when the kill switch is turned on
+    pub fn set_kill_switch(&mut self, is_on: bool) -> Vec<PaperOrder> {
+        self.risk_manager.set_kill_switch(is_on);
+        if !is_on {return vec![]}
+
+        std::mem::take(&mut self.working_orders).into_iter()
+            .map(|working_order| {
+                self.risk_manager.on_order_done(working_order.order.request.side, working_order.order.remaining_amount());
+                PaperOrder{status: order::OrderStatus::Cancelled, ..working_order.order}
+            })
+            .collect()
+    }
+
+    pub fn get_account(&self) -> Account {
+        let mark_price = util::get_mid_price(&self.orderbooks);
+        let unrealized_pnl = mark_price
+            .map_or(rust_decimal::Decimal::ZERO, |price| (price - self.account.average_price) * self.account.position);
+
+        Account{mark_price, unrealized_pnl, ..self.account}
+    }
+
+    /// Processes working orders of the feed against the new order book
+    ///
+    /// Returns orders which have changed.
+    pub fn on_orderbook(&mut self, feed_orderbook: &util::FeedOrderBook, now: time::Instant) -> Vec<PaperOrder> {
+        let feed = feed_orderbook.feed;
+        // market state until now, orders arriving at the venue in the meantime are matched against it
+        let mut previous_orderbook = std::mem::replace(&mut self.orderbooks[feed as usize], feed_orderbook.orderbook);
+        // liquidity resting orders can still take, shared so two orders don't fill against the same level
+        let mut orderbook = feed_orderbook.orderbook;
+        let timestamp = time::SystemTime::now();
+        let mut updated_orders = vec![];
+
+        for mut working_order in std::mem::take(&mut self.working_orders) {
+            if working_order.order.request.feed as usize != feed as usize {
+                self.working_orders.push(working_order);
+                continue
+            }
+
+            let mut is_updated = false;
+            if working_order.queue.is_none() {
+                if working_order.arrives_at > now {
+                    self.working_orders.push(working_order);
+                    continue
+                }
+                self.arrive(&mut working_order, &mut previous_orderbook, timestamp);
+                is_updated = true;
+            }
+            if working_order.queue.is_some() {
+                is_updated |= self.match_resting(&mut working_order, &feed_orderbook.orderbook, &mut orderbook, timestamp);
+            }
+
+            if is_updated {updated_orders.push(working_order.order.clone())}
+            if working_order.order.is_working() {
+                self.working_orders.push(working_order)
+            } else {
+                // the rest of a market order is cancelled
+                self.risk_manager.on_order_done(working_order.order.request.side, working_order.order.remaining_amount());
+            }
+        }
+        updated_orders
+    }
+
+    /// Order reached the venue, takes liquidity and the rest of a limit order joins the queue
+    fn arrive(&mut self, working_order: &mut WorkingOrder, orderbook: &mut util::OrderBookTopN, timestamp: time::SystemTime) {
+        let request = working_order.order.request;
+        let limit_price = match request.order_type {
+            OrderType::Limit => request.limit_price,
+            OrderType::Market => None
+        };
+        for (price, amount) in simulated::take_liquidity(orderbook, request.side, request.amount, limit_price) {
+            self.fill(&mut working_order.order, price, amount, false, timestamp);
+        }
+
+        working_order.order.status = match (working_order.order.remaining_amount().is_zero(), limit_price) {
+            (true, _) => order::OrderStatus::Filled,
+            (false, None) => order::OrderStatus::Cancelled,
+            (false, Some(limit_price)) => {
+                let level_amount = get_level_amount(orderbook, request.side, limit_price)
+                    .unwrap_or(rust_decimal::Decimal::ZERO);
+                working_order.queue = Some(Queue{ahead: level_amount * self.queue_position, level_amount});
+                if working_order.order.fills.is_empty() {
+                    order::OrderStatus::New
+                } else {
+                    order::OrderStatus::PartiallyFilled
+                }
+            }
+        };
+    }
+
+    /// Fills the resting order if the market crossed it's price or traded through the queue ahead
+    ///
+    /// The queue is tracked on the venue's `orderbook`, crossing liquidity is taken from `liquidity`
+    /// left by other orders. Returns `true` if the order was filled.
+    fn match_resting(&mut self, working_order: &mut WorkingOrder, orderbook: &util::OrderBookTopN,
+                     liquidity: &mut util::OrderBookTopN, timestamp: time::SystemTime) -> bool {
+        let (Some(queue), Some(limit_price)) = (working_order.queue.as_mut(), working_order.order.request.limit_price) else {
+            return false
+        };
+        let side = working_order.order.request.side;
+        let remaining_amount = working_order.order.remaining_amount();
+
+        let crossing_amount = simulated::take_liquidity(liquidity, side, remaining_amount, Some(limit_price))
+            .iter().map(|(_, amount)| *amount).sum::<rust_decimal::Decimal>();
+        let level_amount = get_level_amount(orderbook, side, limit_price);
+        let amount = if !crossing_amount.is_zero() {
+            // the level we're queued at was traded through
+            queue.ahead = rust_decimal::Decimal::ZERO;
+            queue.level_amount = level_amount.unwrap_or(rust_decimal::Decimal::ZERO);
+            crossing_amount
+        } else if let Some(level_amount) = level_amount {
+            let traded_amount = std::cmp::max(queue.level_amount - level_amount, rust_decimal::Decimal::ZERO);
+            let traded_ahead = std::cmp::min(queue.ahead, traded_amount);
+            queue.ahead -= traded_ahead;
+            queue.level_amount = level_amount;
+            std::cmp::min(traded_amount - traded_ahead, remaining_amount)
+        } else {
+            // our level is out of view, nothing we can tell
+            rust_decimal::Decimal::ZERO
+        };
+        if amount.is_zero() {return false}
+
+        self.fill(&mut working_order.order, limit_price, amount, true, timestamp);
+        working_order.order.status = if working_order.order.remaining_amount().is_zero() {
+            order::OrderStatus::Filled
+        } else {
+            order::OrderStatus::PartiallyFilled
+        };
+        true
+    }
+
+    fn fill(&mut self, paper_order: &mut PaperOrder, price: rust_decimal::Decimal, amount: rust_decimal::Decimal,
+            is_maker: bool, timestamp: time::SystemTime) {
+        let fee_schedule = &self.fee_schedules[paper_order.request.feed as usize];
+        let fee_bps = if is_maker {fee_schedule.maker_bps} else {fee_schedule.taker_bps};
+        let fee = price * amount * fee_bps / rust_decimal::Decimal::from(constants::BPS_PER_UNIT);
+
+        paper_order.fills.push(PaperFill{price, amount, fee, is_maker, timestamp});
+        self.risk_manager.on_fill(paper_order.request.side, amount);
+        self.account.apply_fill(paper_order.request.side, price, amount, fee);
+    }
+}
+
+/// Displayed amount at the price on our side of the order book
+///
+/// Returns `None` if the price is out of the top N we see.
+fn get_level_amount(orderbook: &util::OrderBookTopN, side: util::Side, price: rust_decimal::Decimal)
+    -> Option<rust_decimal::Decimal> {
+    let inf = rust_decimal::Decimal::from(constants::ORDER_PRICE_INF);
+    let (orders, is_worse): (_, fn(rust_decimal::Decimal, rust_decimal::Decimal) -> bool) = match side {
+        util::Side::Buy => (&orderbook.bids, |price, other| price < other),
+        util::Side::Sell => (&orderbook.asks, |price, other| price > other)
+    };
+    let worst_order = orders.iter().rfind(|order| order.price.abs() < inf)?;
+    if is_worse(price, worst_order.price) {return None}
+
+    Some(orders.iter().filter(|order| order.price == price).map(|order| order.amount).sum())
+}
+
+pub enum Command {
+    SubmitOrder {request: OrderRequest, reply_tx: oneshot::Sender<PaperOrder>},
+    /// Replies with `None` if the order is not working anymore
+    CancelOrder {id: order::OrderId, reply_tx: oneshot::Sender<Option<PaperOrder>>},
+    GetAccount {reply_tx: oneshot::Sender<Account>},
+    SetKillSwitch {is_on: bool, reply_tx: oneshot::Sender<()>}
+}
+
+pub struct Engine {
+    pub queue_rx: mpsc::Receiver<types::BoxedFeedOrderBook>,
+    pub command_rx: mpsc::Receiver<Command>,
+    /// Every change of an order is published
+    pub queue_tx: Arc<broadcast::Sender<types::BoxedPaperOrder>>,
+    pub simulator: Simulator
+}
+
+impl Engine {
+    /// Entry point for the task - worker
+    pub async fn run(&mut self) {
+        loop {
+            tokio::select! {
+                Some(feed_orderbook) = self.queue_rx.recv() => {
+                    for paper_order in self.simulator.on_orderbook(&feed_orderbook, time::Instant::now()) {
+                        self.publish(paper_order);
+                    }
+                }
+                Some(command) = self.command_rx.recv() => {self.handle_command(command)}
+                else => {
+                    tracing::info!("Paper-trading queues are closed");
+                    break
+                }
+            }
+        }
+    }
+
+    /// Replies are dropped if the requester is gone
+    fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::SubmitOrder {request, reply_tx} => {
+                let paper_order = self.simulator.submit_order(request, time::Instant::now());
+                self.publish(paper_order.clone());
+                let _ = reply_tx.send(paper_order);
+            }
+            Command::CancelOrder {id, reply_tx} => {
+                let paper_order = self.simulator.cancel_order(id);
+                if let Some(paper_order) = &paper_order {self.publish(paper_order.clone())}
+                let _ = reply_tx.send(paper_order);
+            }
+            Command::GetAccount {reply_tx} => {
+                let _ = reply_tx.send(self.simulator.get_account());
+            }
+            Command::SetKillSwitch {is_on, reply_tx} => {
+                tracing::warn!("Kill switch is turned {}", if is_on {"on"} else {"off"});
+                for paper_order in self.simulator.set_kill_switch(is_on) {
+                    self.publish(paper_order);
+                }
+                let _ = reply_tx.send(());
+            }
+        }
+    }
+
+    fn publish(&self, paper_order: PaperOrder) {
+        match self.queue_tx.send(Box::new(paper_order)) {
+            Ok(_) => {
+                //msg is sent
+            }
+            Err(_) => {
+                //nobody subscribed to this broadcast yet
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    fn get_orderbook(asks: &[(i64, i64)], bids: &[(i64, i64)]) -> util::FeedOrderBook {
+        let feed = constants::Feed::BinanceSpot;
+        let mut orderbook = util::OrderBookTopN::default();
+        orderbook.set_unreachable_price();
+        for (order, (price, amount)) in orderbook.asks.iter_mut().zip(asks) {
+            *order = util::Order{feed, price: rust_decimal::Decimal::from(*price), amount: rust_decimal::Decimal::from(*amount)};
+        }
+        for (order, (price, amount)) in orderbook.bids.iter_mut().zip(bids) {
+            *order = util::Order{feed, price: rust_decimal::Decimal::from(*price), amount: rust_decimal::Decimal::from(*amount)};
+        }
+        util::FeedOrderBook::new(feed, orderbook, time::Instant::now())
+    }
+
+    fn get_request(side: util::Side, amount: i64, limit_price: Option<i64>) -> OrderRequest {
+        OrderRequest {
+            feed: constants::Feed::BinanceSpot,
+            side,
+            order_type: if limit_price.is_some() {OrderType::Limit} else {OrderType::Market},
+            amount: rust_decimal::Decimal::from(amount),
+            limit_price: limit_price.map(rust_decimal::Decimal::from)
+        }
+    }
+
+    fn get_simulator() -> Simulator {
+        let mut fee_schedules = [util::FeeSchedule::default(); constants::Feed::COUNT];
+        fee_schedules[constants::Feed::BinanceSpot as usize] = util::FeeSchedule{
+            maker_bps: rust_decimal::Decimal::ZERO, taker_bps: rust_decimal::Decimal::from(10)};
+        Simulator::new(time::Duration::from_millis(10), rust_decimal::Decimal::ONE, fee_schedules,
+                       risk::RiskManager::new(risk::RiskLimits::default()))
+    }
+
+    mod on_orderbook {
+        use super::*;
+
+
+        #[test]
+        fn test_market_order_after_latency() {
+            let mut simulator = get_simulator();
+            let start = time::Instant::now();
+            simulator.on_orderbook(&get_orderbook(&[(100, 1), (101, 1)], &[(99, 1)]), start);
+            let paper_order = simulator.submit_order(get_request(util::Side::Buy, 3, None), start);
+            assert_eq!(paper_order.status, order::OrderStatus::PendingNew);
+
+            // hasn't reached the venue yet
+            let updated_orders = simulator.on_orderbook(
+                &get_orderbook(&[(100, 1), (101, 1)], &[(99, 1)]), start + time::Duration::from_millis(5));
+            assert!(updated_orders.is_empty());
+
+            // matched against the book that was valid when it arrived
+            let updated_orders = simulator.on_orderbook(
+                &get_orderbook(&[(200, 5)], &[(99, 1)]), start + time::Duration::from_millis(20));
+            assert_eq!(updated_orders.len(), 1);
+            assert_eq!(updated_orders[0].status, order::OrderStatus::Cancelled);
+            assert_eq!(updated_orders[0].filled_amount(), rust_decimal::Decimal::TWO);
+            assert_eq!(updated_orders[0].fills[0].fee, rust_decimal::Decimal::new(1, 1));
+            assert!(!updated_orders[0].fills[0].is_maker);
+            assert!(simulator.cancel_order(paper_order.id).is_none());
+        }
+
+        #[test]
+        fn test_limit_order_queue_position() {
+            let mut simulator = get_simulator();
+            let start = time::Instant::now();
+            simulator.on_orderbook(&get_orderbook(&[(101, 1)], &[(100, 3), (99, 1)]), start);
+            simulator.submit_order(get_request(util::Side::Buy, 2, Some(100)), start);
+
+            // joins the queue behind 3
+            let updated_orders = simulator.on_orderbook(
+                &get_orderbook(&[(101, 1)], &[(100, 3), (99, 1)]), start + time::Duration::from_millis(20));
+            assert_eq!(updated_orders[0].status, order::OrderStatus::New);
+
+            // 1 joins behind us, then all 4 are traded of which 1 fills us
+            let updated_orders = simulator.on_orderbook(
+                &get_orderbook(&[(101, 1)], &[(100, 4), (99, 1)]), start + time::Duration::from_millis(30));
+            assert!(updated_orders.is_empty());
+            let updated_orders = simulator.on_orderbook(
+                &get_orderbook(&[(101, 1)], &[(99, 1)]), start + time::Duration::from_millis(40));
+            assert_eq!(updated_orders[0].status, order::OrderStatus::PartiallyFilled);
+            assert_eq!(updated_orders[0].filled_amount(), rust_decimal::Decimal::ONE);
+
+            // asks cross our price
+            let updated_orders = simulator.on_orderbook(
+                &get_orderbook(&[(100, 5)], &[(99, 1)]), start + time::Duration::from_millis(50));
+            assert_eq!(updated_orders[0].status, order::OrderStatus::Filled);
+            assert!(updated_orders[0].fills.iter().all(|fill| fill.is_maker));
+        }
+
+        #[test]
+        fn test_resting_orders_share_crossing_liquidity() {
+            let mut simulator = get_simulator();
+            let start = time::Instant::now();
+            simulator.on_orderbook(&get_orderbook(&[(101, 1)], &[(99, 1)]), start);
+            simulator.submit_order(get_request(util::Side::Buy, 1, Some(100)), start);
+            simulator.submit_order(get_request(util::Side::Buy, 1, Some(100)), start);
+            simulator.on_orderbook(&get_orderbook(&[(101, 1)], &[(99, 1)]), start + time::Duration::from_millis(20));
+
+            // only 1 is offered at our price, the first order in line takes it
+            let updated_orders = simulator.on_orderbook(
+                &get_orderbook(&[(100, 1), (101, 1)], &[(99, 1)]), start + time::Duration::from_millis(30));
+            assert_eq!(updated_orders.len(), 1);
+            assert_eq!(updated_orders[0].id, 1);
+            assert_eq!(updated_orders[0].status, order::OrderStatus::Filled);
+            assert_eq!(simulator.get_account().position, rust_decimal::Decimal::ONE);
+        }
+
+        #[test]
+        fn test_rejected_orders() {
+            let mut simulator = get_simulator();
+            let now = time::Instant::now();
+
+            let paper_order = simulator.submit_order(get_request(util::Side::Buy, 0, None), now);
+            assert_eq!(paper_order.status, order::OrderStatus::Rejected);
+            let request = OrderRequest{order_type: OrderType::Limit, ..get_request(util::Side::Buy, 1, None)};
+            assert_eq!(simulator.submit_order(request, now).status, order::OrderStatus::Rejected);
+            let request = OrderRequest{feed: constants::Feed::Synthetic, ..get_request(util::Side::Buy, 1, None)};
+            assert_eq!(simulator.submit_order(request, now).status, order::OrderStatus::Rejected);
+        }
+
+        #[test]
+        fn test_kill_switch() {
+            let mut simulator = get_simulator();
+            let now = time::Instant::now();
+            simulator.submit_order(get_request(util::Side::Buy, 1, Some(100)), now);
+
+            let cancelled_orders = simulator.set_kill_switch(true);
+            assert_eq!(cancelled_orders.len(), 1);
+            assert_eq!(cancelled_orders[0].status, order::OrderStatus::Cancelled);
+            let paper_order = simulator.submit_order(get_request(util::Side::Buy, 1, Some(100)), now);
+            assert_eq!(paper_order.status, order::OrderStatus::Rejected);
+            assert!(paper_order.reject_reason.is_some());
+
+            simulator.set_kill_switch(false);
+            let paper_order = simulator.submit_order(get_request(util::Side::Buy, 1, Some(100)), now);
+            assert_eq!(paper_order.status, order::OrderStatus::PendingNew);
+        }
+    }
+
+    mod get_account {
+        use super::*;
+
+
+        #[test]
+        fn test_pnl() {
+            let mut simulator = get_simulator();
+            let start = time::Instant::now();
+            simulator.on_orderbook(&get_orderbook(&[(100, 2)], &[(98, 2)]), start);
+            simulator.submit_order(get_request(util::Side::Buy, 2, None), start);
+            simulator.on_orderbook(&get_orderbook(&[(111, 2)], &[(110, 2)]), start + time::Duration::from_millis(20));
+            simulator.submit_order(get_request(util::Side::Sell, 1, None), start);
+            simulator.on_orderbook(&get_orderbook(&[(111, 2)], &[(109, 2)]), start + time::Duration::from_millis(40));
+            let account = simulator.get_account();
+
+            assert_eq!(account.position, rust_decimal::Decimal::ONE);
+            assert_eq!(account.average_price, rust_decimal::Decimal::from(100));
+            assert_eq!(account.realized_pnl, rust_decimal::Decimal::from(10));
+            assert_eq!(account.mark_price, Some(rust_decimal::Decimal::from(110)));
+            assert_eq!(account.unrealized_pnl, rust_decimal::Decimal::from(10));
+            // 10 bps of 200 and 110
+            assert_eq!(account.fees, rust_decimal::Decimal::new(31, 2));
+        }
+    }
+}
\ No newline at end of file
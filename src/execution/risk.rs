@@ -0,0 +1,243 @@
+//! Pre-trade risk checks
+//!
+//! Every order from order-entry services is checked before it's sent to a venue adapter. Checks run
+//! in a fixed order and the first failing one is returned as a structured rejection. Limits which
+//! are not set are not checked.
+use std::collections::VecDeque;
+use std::fmt;
+use std::time;
+
+use rust_decimal;
+use strum::EnumCount;
+
+use crate::constants;
+use crate::util;
+
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RiskLimits {
+    /// Maximum amount * price of a single order, market orders are valued at mid price
+    pub max_order_notional: Option<rust_decimal::Decimal>,
+    /// Maximum absolute position after the order and the open orders of it's side are fully filled
+    pub max_position: Option<rust_decimal::Decimal>,
+    /// Maximum distance of the limit price from mid price, in basis points
+    pub price_collar_bps: Option<rust_decimal::Decimal>,
+    /// Maximum number of orders per venue in any one second window
+    pub max_orders_per_second: Option<usize>
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Rejection {
+    KillSwitch,
+    /// Mid price is needed to check the order but there's no market data
+    NoMarketData,
+    OrderNotional {notional: rust_decimal::Decimal, limit: rust_decimal::Decimal},
+    Position {position: rust_decimal::Decimal, limit: rust_decimal::Decimal},
+    PriceCollar {price: rust_decimal::Decimal, mid_price: rust_decimal::Decimal, limit_bps: rust_decimal::Decimal},
+    RateLimit {feed: constants::Feed, limit: usize}
+}
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::KillSwitch => f.write_str("Kill switch is on"),
+            Rejection::NoMarketData => f.write_str("No market data to check the order against"),
+            Rejection::OrderNotional {notional, limit} =>
+                write!(f, "Order notional {} exceeds the limit {}", notional, limit),
+            Rejection::Position {position, limit} =>
+                write!(f, "Position {} would exceed the limit {}", position, limit),
+            Rejection::PriceCollar {price, mid_price, limit_bps} =>
+                write!(f, "Price {} is more than {} bps away from mid price {}", price, limit_bps, mid_price),
+            Rejection::RateLimit {feed, limit} =>
+                write!(f, "More than {} orders per second sent to {}", limit, feed)
+        }
+    }
+}
+
+pub struct RiskManager {
+    limits: RiskLimits,
+    kill_switch: bool,
+    /// Filled position over all venues
+    position: rust_decimal::Decimal,
+    /// Unfilled amount of accepted orders per side, the position moves by up to this much
+    open_buy_amount: rust_decimal::Decimal,
+    open_sell_amount: rust_decimal::Decimal,
+    /// Times of accepted orders within the last second, per venue
+    order_times: [VecDeque<time::Instant>; constants::Feed::COUNT]
+}
+
+impl RiskManager {
+    pub fn new(limits: RiskLimits) -> Self {
+        Self{limits, kill_switch: false, position: rust_decimal::Decimal::ZERO, open_buy_amount: rust_decimal::Decimal::ZERO,
+             open_sell_amount: rust_decimal::Decimal::ZERO, order_times: Default::default()}
+    }
+
+    pub fn set_kill_switch(&mut self, is_on: bool) {
+        self.kill_switch = is_on;
+    }
+
+    pub fn is_kill_switch_on(&self) -> bool {
+        self.kill_switch
+    }
+
+    /// Checks the order and counts it towards the venue's rate limit and open orders if it's accepted
+    ///
+    /// `limit_price` is `None` for market orders. The unfilled amount of an accepted order must be
+    /// released with `on_order_done` once it stops working.
+    pub fn check_order(&mut self, feed: constants::Feed, side: util::Side, amount: rust_decimal::Decimal,
+                       limit_price: Option<rust_decimal::Decimal>, mid_price: Option<rust_decimal::Decimal>,
+                       now: time::Instant) -> Result<(), Rejection> {
+        if self.kill_switch {
+            return Err(Rejection::KillSwitch)
+        }
+
+        if let Some(limit) = self.limits.max_order_notional {
+            let price = limit_price.or(mid_price).ok_or(Rejection::NoMarketData)?;
+            let notional = amount * price;
+            if notional > limit {
+                return Err(Rejection::OrderNotional{notional, limit})
+            }
+        }
+
+        if let Some(limit) = self.limits.max_position {
+            let position = match side {
+                util::Side::Buy => self.position + self.open_buy_amount + amount,
+                util::Side::Sell => self.position - self.open_sell_amount - amount
+            };
+            if position.abs() > limit {
+                return Err(Rejection::Position{position, limit})
+            }
+        }
+
+        if let (Some(limit_bps), Some(price)) = (self.limits.price_collar_bps, limit_price) {
+            let mid_price = mid_price.ok_or(Rejection::NoMarketData)?;
+            let distance_bps = (price - mid_price).abs() / mid_price * rust_decimal::Decimal::from(constants::BPS_PER_UNIT);
+            if distance_bps > limit_bps {
+                return Err(Rejection::PriceCollar{price, mid_price, limit_bps})
+            }
+        }
+
+        let order_times = &mut self.order_times[feed as usize];
+        if let Some(limit) = self.limits.max_orders_per_second {
+            while order_times.front().is_some_and(|time| now.duration_since(*time) >= time::Duration::from_secs(1)) {
+                order_times.pop_front();
+            }
+            if order_times.len() >= limit {
+                return Err(Rejection::RateLimit{feed, limit})
+            }
+            order_times.push_back(now);
+        }
+        *self.get_open_amount(side) += amount;
+        Ok(())
+    }
+
+    /// Moves the filled amount from open orders to the position
+    pub fn on_fill(&mut self, side: util::Side, amount: rust_decimal::Decimal) {
+        self.release(side, amount);
+        match side {
+            util::Side::Buy => self.position += amount,
+            util::Side::Sell => self.position -= amount
+        }
+    }
+
+    /// Releases the unfilled amount of an accepted order which was cancelled, rejected or expired
+    pub fn on_order_done(&mut self, side: util::Side, remaining_amount: rust_decimal::Decimal) {
+        self.release(side, remaining_amount);
+    }
+
+    fn release(&mut self, side: util::Side, amount: rust_decimal::Decimal) {
+        let open_amount = self.get_open_amount(side);
+        *open_amount -= std::cmp::min(amount, *open_amount);
+    }
+
+    fn get_open_amount(&mut self, side: util::Side) -> &mut rust_decimal::Decimal {
+        match side {
+            util::Side::Buy => &mut self.open_buy_amount,
+            util::Side::Sell => &mut self.open_sell_amount
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    fn get_risk_manager() -> RiskManager {
+        RiskManager::new(RiskLimits {
+            max_order_notional: Some(rust_decimal::Decimal::from(1000)),
+            max_position: Some(rust_decimal::Decimal::from(5)),
+            price_collar_bps: Some(rust_decimal::Decimal::from(100)),
+            max_orders_per_second: Some(2)
+        })
+    }
+
+    fn check_order(risk_manager: &mut RiskManager, side: util::Side, amount: i64, limit_price: Option<i64>,
+                   now: time::Instant) -> Result<(), Rejection> {
+        risk_manager.check_order(constants::Feed::BinanceSpot, side, rust_decimal::Decimal::from(amount),
+                                 limit_price.map(rust_decimal::Decimal::from), Some(rust_decimal::Decimal::from(100)), now)
+    }
+
+    mod check_order {
+        use super::*;
+
+
+        #[test]
+        fn test_limits() {
+            let mut risk_manager = get_risk_manager();
+            let now = time::Instant::now();
+
+            assert!(matches!(check_order(&mut risk_manager, util::Side::Buy, 11, None, now),
+                Err(Rejection::OrderNotional{..})));
+            assert!(matches!(check_order(&mut risk_manager, util::Side::Buy, 1, Some(102), now),
+                Err(Rejection::PriceCollar{..})));
+            risk_manager.on_fill(util::Side::Sell, rust_decimal::Decimal::from(3));
+            assert!(matches!(check_order(&mut risk_manager, util::Side::Sell, 3, None, now),
+                Err(Rejection::Position{..})));
+            assert!(check_order(&mut risk_manager, util::Side::Buy, 8, Some(101), now).is_ok());
+        }
+
+        #[test]
+        fn test_position_includes_open_orders() {
+            let mut risk_manager = get_risk_manager();
+            let now = time::Instant::now();
+
+            assert!(check_order(&mut risk_manager, util::Side::Buy, 3, Some(100), now).is_ok());
+            assert!(matches!(check_order(&mut risk_manager, util::Side::Buy, 3, Some(100), now),
+                Err(Rejection::Position{..})));
+            // a fill only moves exposure to the position
+            risk_manager.on_fill(util::Side::Buy, rust_decimal::Decimal::ONE);
+            assert!(matches!(check_order(&mut risk_manager, util::Side::Buy, 3, Some(100), now + time::Duration::from_secs(1)),
+                Err(Rejection::Position{..})));
+            risk_manager.on_order_done(util::Side::Buy, rust_decimal::Decimal::TWO);
+            assert!(check_order(&mut risk_manager, util::Side::Buy, 3, Some(100), now + time::Duration::from_secs(1)).is_ok());
+        }
+
+        #[test]
+        fn test_rate_limit() {
+            let mut risk_manager = get_risk_manager();
+            let now = time::Instant::now();
+
+            assert!(check_order(&mut risk_manager, util::Side::Buy, 1, None, now).is_ok());
+            assert!(check_order(&mut risk_manager, util::Side::Buy, 1, None, now).is_ok());
+            assert!(matches!(check_order(&mut risk_manager, util::Side::Buy, 1, None, now),
+                Err(Rejection::RateLimit{..})));
+            // other venues have their own limit
+            assert!(risk_manager.check_order(constants::Feed::BitstampSpot, util::Side::Buy, rust_decimal::Decimal::ONE,
+                                             None, Some(rust_decimal::Decimal::from(100)), now).is_ok());
+            assert!(check_order(&mut risk_manager, util::Side::Buy, 1, None, now + time::Duration::from_secs(1)).is_ok());
+        }
+
+        #[test]
+        fn test_kill_switch_and_no_market_data() {
+            let mut risk_manager = get_risk_manager();
+            let now = time::Instant::now();
+
+            assert!(matches!(
+                risk_manager.check_order(constants::Feed::BinanceSpot, util::Side::Buy, rust_decimal::Decimal::ONE, None, None, now),
+                Err(Rejection::NoMarketData)));
+            risk_manager.set_kill_switch(true);
+            assert!(matches!(check_order(&mut risk_manager, util::Side::Buy, 1, None, now), Err(Rejection::KillSwitch)));
+        }
+    }
+}
\ No newline at end of file
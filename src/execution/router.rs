@@ -0,0 +1,248 @@
+//! Smart order router splitting parent orders across venues
+//!
+//! Each round we walk the aggregated book of feeds we have a venue for, allocate the remaining
+//! amount to the best priced levels and send one child order per feed, limited to the worst price
+//! we allocated on it. Whatever isn't filled is re-routed against the latest book in the next round.
+//! Child orders go through pre-trade risk checks before they're sent, rejected ones are not sent.
+//!
+//! Library only, no binary or service constructs a router until there are adapters for live venues.
+use std::time;
+
+use error_stack::{Report, Result};
+use rust_decimal;
+use strum::{EnumCount, IntoEnumIterator};
+use tokio::sync::watch;
+use tracing;
+
+use crate::constants;
+use crate::error;
+use crate::execution::order;
+use crate::execution::risk;
+use crate::execution::venue;
+use crate::types;
+use crate::util;
+
+
+pub struct Router {
+    orderbooks_rx: watch::Receiver<types::OrderBooksByFeed>,
+    venues: Vec<Box<dyn venue::VenueAdapter>>,
+    pub risk_manager: risk::RiskManager,
+    /// Maximum number of times we route the remaining amount of a parent order
+    max_rounds: usize,
+    next_child_id: order::OrderId
+}
+
+impl Router {
+    pub fn new(orderbooks_rx: watch::Receiver<types::OrderBooksByFeed>,
+               venues: Vec<Box<dyn venue::VenueAdapter>>,
+               risk_manager: risk::RiskManager,
+               max_rounds: usize) -> Self {
+        Self{orderbooks_rx, venues, risk_manager, max_rounds, next_child_id: 1}
+    }
+
+    /// Executes the parent order and returns it's final state
+    ///
+    /// We stop when the order is filled, when there's no liquidity within the limit price or when
+    /// none of the child orders of a round got filled.
+    pub async fn execute(&mut self, parent_order: order::ParentOrder)
+        -> Result<order::ParentOrderState, error::ExecutionError> {
+        if parent_order.amount <= rust_decimal::Decimal::ZERO {
+            return Err(Report::new(error::ExecutionError::InvalidOrder)
+                .attach_printable(format!("Invalid amount {}", parent_order.amount)))
+        }
+        if self.venues.is_empty() {
+            return Err(Report::new(error::ExecutionError::NoVenue))
+        }
+        let mut state = order::ParentOrderState::new(parent_order);
+
+        for _ in 0..self.max_rounds {
+            let child_orders = self.split(&parent_order, state.remaining_amount());
+            if child_orders.is_empty() {break}
+            let mid_price = util::get_mid_price(&self.orderbooks_rx.borrow());
+
+            let mut is_filled = false;
+            for child_order in child_orders {
+                if let Err(rejection) = self.risk_manager.check_order(
+                    child_order.feed, child_order.side, child_order.amount, Some(child_order.limit_price),
+                    mid_price, time::Instant::now()) {
+                    tracing::warn!("Child order rejected: {}", rejection);
+                    state.child_orders.push(child_order);
+                    state.reports.push(order::ExecutionReport{
+                        child_id: child_order.id, status: order::OrderStatus::Rejected, fills: vec![]});
+                    continue
+                }
+                let venue = self.venues.iter_mut()
+                    .find(|venue| venue.feed() as usize == child_order.feed as usize)
+                    .expect("Child orders are split only to feeds with a venue");
+                let report = match venue.send_order(&child_order).await {
+                    Ok(report) => report,
+                    Err(e) => {
+                        tracing::error!("Cannot send child order: {:?}", e);
+                        order::ExecutionReport{
+                            child_id: child_order.id, status: order::OrderStatus::Rejected, fills: vec![]}
+                    }
+                };
+                for fill in &report.fills {
+                    self.risk_manager.on_fill(child_order.side, fill.amount);
+                }
+                // child orders don't rest at the venue
+                self.risk_manager.on_order_done(child_order.side, child_order.amount - report.filled_amount());
+                is_filled |= !report.fills.is_empty();
+                state.child_orders.push(child_order);
+                state.reports.push(report);
+            }
+
+            if !is_filled || state.remaining_amount().is_zero() {break}
+        }
+
+        state.status = if state.remaining_amount().is_zero() {
+            order::OrderStatus::Filled
+        } else if state.filled_amount().is_zero() {
+            order::OrderStatus::Cancelled
+        } else {
+            order::OrderStatus::PartiallyFilled
+        };
+        Ok(state)
+    }
+
+    /// Allocates the amount to the best priced levels of feeds we have a venue for
+    fn split(&mut self, parent_order: &order::ParentOrder, amount: rust_decimal::Decimal) -> Vec<order::ChildOrder> {
+        let mut orderbooks = *self.orderbooks_rx.borrow();
+        for feed in constants::Feed::iter() {
+            if !self.venues.iter().any(|venue| venue.feed() as usize == feed as usize) {
+                orderbooks[feed as usize].set_unreachable_price();
+            }
+        }
+        let (asks, bids) = util::get_aggregated_orders(&orderbooks);
+        let (orders, is_marketable): (_, fn(rust_decimal::Decimal, rust_decimal::Decimal) -> bool) =
+            match parent_order.side {
+                util::Side::Buy => (asks, |price, limit_price| price <= limit_price),
+                util::Side::Sell => (bids, |price, limit_price| price >= limit_price)
+            };
+
+        // amount and worst price per feed
+        let mut allocations: [Option<(rust_decimal::Decimal, rust_decimal::Decimal)>; constants::Feed::COUNT] =
+            [None; constants::Feed::COUNT];
+        let mut amount_left = amount;
+        for book_order in orders.iter() {
+            if amount_left <= rust_decimal::Decimal::ZERO {break}
+            if let Some(limit_price) = parent_order.limit_price {
+                if !is_marketable(book_order.price, limit_price) {break}
+            }
+
+            let fill = std::cmp::min(book_order.amount, amount_left);
+            let allocation = allocations[book_order.feed as usize].get_or_insert((rust_decimal::Decimal::ZERO, book_order.price));
+            allocation.0 += fill;
+            allocation.1 = book_order.price;
+            amount_left -= fill;
+        }
+
+        constants::Feed::iter().zip(allocations)
+            .filter_map(|(feed, allocation)| {
+                let (amount, limit_price) = allocation?;
+                let id = self.next_child_id;
+                self.next_child_id += 1;
+                Some(order::ChildOrder{
+                    id, parent_id: parent_order.id, feed, side: parent_order.side, amount, limit_price})
+            })
+            .collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::execution::venue::simulated;
+
+
+    fn get_orderbooks() -> types::OrderBooksByFeed {
+        let mut orderbooks = util::get_initialized_orderbooks();
+        for (feed, asks) in [
+            (constants::Feed::BinanceSpot, [(101, 1), (103, 2)]),
+            (constants::Feed::BitstampSpot, [(102, 1), (104, 5)])] {
+            let orderbook = &mut orderbooks[feed as usize];
+            for (order, (price, amount)) in orderbook.asks.iter_mut().zip(asks) {
+                *order = util::Order{feed, price: rust_decimal::Decimal::from(price), amount: rust_decimal::Decimal::from(amount)};
+            }
+        }
+        orderbooks
+    }
+
+    fn get_router(feeds: &[constants::Feed]) -> (watch::Sender<types::OrderBooksByFeed>, Router) {
+        let (orderbooks_tx, orderbooks_rx) = watch::channel(get_orderbooks());
+        let venues = feeds.iter()
+            .map(|feed| Box::new(simulated::SimulatedVenue::new(*feed, orderbooks_rx.clone())) as Box<dyn venue::VenueAdapter>)
+            .collect();
+        (orderbooks_tx, Router::new(orderbooks_rx, venues, risk::RiskManager::new(risk::RiskLimits::default()), 3))
+    }
+
+    fn get_parent_order(amount: i64, limit_price: Option<i64>) -> order::ParentOrder {
+        order::ParentOrder {
+            id: 7,
+            side: util::Side::Buy,
+            amount: rust_decimal::Decimal::from(amount),
+            limit_price: limit_price.map(rust_decimal::Decimal::from)
+        }
+    }
+
+    mod execute {
+        use super::*;
+
+
+        #[tokio::test]
+        async fn test_split_across_venues() {
+            let (_orderbooks_tx, mut router) = get_router(&[constants::Feed::BinanceSpot, constants::Feed::BitstampSpot]);
+            let state = router.execute(get_parent_order(3, None)).await.expect("Expected parent order state");
+
+            // 1@101 and 1@103 binance, 1@102 bitstamp
+            assert_eq!(state.status, order::OrderStatus::Filled);
+            assert_eq!(state.child_orders.len(), 2);
+            assert_eq!(state.child_orders[0].amount, rust_decimal::Decimal::TWO);
+            assert_eq!(state.child_orders[0].limit_price, rust_decimal::Decimal::from(103));
+            assert_eq!(state.child_orders[1].amount, rust_decimal::Decimal::ONE);
+            assert!(state.child_orders.iter().all(|child_order| child_order.parent_id == 7));
+            assert_eq!(state.average_price(), Some(rust_decimal::Decimal::from(102)));
+        }
+
+        #[tokio::test]
+        async fn test_limit_price() {
+            let (_orderbooks_tx, mut router) = get_router(&[constants::Feed::BinanceSpot, constants::Feed::BitstampSpot]);
+            let state = router.execute(get_parent_order(5, Some(102))).await.expect("Expected parent order state");
+
+            assert_eq!(state.status, order::OrderStatus::PartiallyFilled);
+            assert_eq!(state.filled_amount(), rust_decimal::Decimal::TWO);
+            assert_eq!(state.remaining_amount(), rust_decimal::Decimal::from(3));
+        }
+
+        #[tokio::test]
+        async fn test_routes_only_to_venues() {
+            let (_orderbooks_tx, mut router) = get_router(&[constants::Feed::BinanceSpot]);
+            let state = router.execute(get_parent_order(2, None)).await.expect("Expected parent order state");
+
+            assert_eq!(state.status, order::OrderStatus::Filled);
+            assert!(state.fills().all(|fill| matches!(fill.feed, constants::Feed::BinanceSpot)));
+            assert_eq!(state.average_price(), Some(rust_decimal::Decimal::from(102)));
+        }
+
+        #[tokio::test]
+        async fn test_risk_rejection() {
+            let (_orderbooks_tx, mut router) = get_router(&[constants::Feed::BinanceSpot, constants::Feed::BitstampSpot]);
+            router.risk_manager = risk::RiskManager::new(risk::RiskLimits{
+                max_position: Some(rust_decimal::Decimal::TWO), ..Default::default()});
+            let state = router.execute(get_parent_order(3, None)).await.expect("Expected parent order state");
+
+            // bitstamp child order would exceed the position after binance is filled
+            assert_eq!(state.status, order::OrderStatus::PartiallyFilled);
+            assert_eq!(state.filled_amount(), rust_decimal::Decimal::TWO);
+            assert_eq!(state.reports[1].status, order::OrderStatus::Rejected);
+        }
+
+        #[tokio::test]
+        async fn test_invalid_amount() {
+            let (_orderbooks_tx, mut router) = get_router(&[constants::Feed::BinanceSpot]);
+
+            assert!(router.execute(get_parent_order(0, None)).await.is_err());
+        }
+    }
+}
\ No newline at end of file
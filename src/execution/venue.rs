@@ -0,0 +1,19 @@
+pub mod simulated;
+
+use async_trait;
+use error_stack::Result;
+
+use crate::constants;
+use crate::error;
+use crate::execution::order;
+
+
+/// Connection to a venue we can send child orders to
+#[async_trait::async_trait]
+pub trait VenueAdapter: Send {
+    fn feed(&self) -> constants::Feed;
+
+    /// Sends an immediate-or-cancel child order, the part that isn't filled is cancelled
+    async fn send_order(&mut self, child_order: &order::ChildOrder)
+        -> Result<order::ExecutionReport, error::VenueError>;
+}
\ No newline at end of file
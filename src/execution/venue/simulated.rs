@@ -0,0 +1,177 @@
+//! Simulated venue filling child orders against the order books coming from listeners
+//!
+//! Liquidity we take is removed from our copy of the feed's order book until the listener sends a
+//! new one, so consecutive child orders don't fill against the same levels twice. Works the same
+//! with live and recorded order books, which makes the execution layer testable offline.
+use std::time;
+
+use async_trait;
+use error_stack::{Report, Result};
+use rust_decimal;
+use tokio::sync::watch;
+
+use crate::constants;
+use crate::error;
+use crate::execution::order;
+use crate::execution::venue;
+use crate::types;
+use crate::util;
+
+
+pub struct SimulatedVenue {
+    feed: constants::Feed,
+    orderbooks_rx: watch::Receiver<types::OrderBooksByFeed>,
+    orderbook: util::OrderBookTopN
+}
+
+impl SimulatedVenue {
+    pub fn new(feed: constants::Feed, mut orderbooks_rx: watch::Receiver<types::OrderBooksByFeed>) -> Self {
+        let orderbook = orderbooks_rx.borrow_and_update()[feed as usize];
+        Self{feed, orderbooks_rx, orderbook}
+    }
+
+    /// Replaces our copy of the order book if the listener sent a new one
+    ///
+    /// If the sender is gone e.g. recording has ended, we keep filling against what's left.
+    fn refresh_orderbook(&mut self) {
+        if let Ok(true) = self.orderbooks_rx.has_changed() {
+            self.orderbook = self.orderbooks_rx.borrow_and_update()[self.feed as usize];
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl venue::VenueAdapter for SimulatedVenue {
+    fn feed(&self) -> constants::Feed {
+        self.feed
+    }
+
+    async fn send_order(&mut self, child_order: &order::ChildOrder)
+        -> Result<order::ExecutionReport, error::VenueError> {
+        if child_order.feed as usize != self.feed as usize {
+            return Err(Report::new(error::VenueError)
+                .attach_printable(format!("Child order for {:?} sent to {:?}", child_order.feed, self.feed)))
+        }
+        if child_order.amount <= rust_decimal::Decimal::ZERO {
+            return Ok(order::ExecutionReport{
+                child_id: child_order.id, status: order::OrderStatus::Rejected, fills: vec![]})
+        }
+        self.refresh_orderbook();
+
+        let timestamp = time::SystemTime::now();
+        let fills: Vec<order::Fill> = take_liquidity(
+            &mut self.orderbook, child_order.side, child_order.amount, Some(child_order.limit_price))
+            .into_iter()
+            .map(|(price, amount)| order::Fill{child_id: child_order.id, feed: self.feed, price, amount, timestamp})
+            .collect();
+        let amount_left = child_order.amount - fills.iter().map(|fill| fill.amount).sum::<rust_decimal::Decimal>();
+
+        let status = if amount_left.is_zero() {
+            order::OrderStatus::Filled
+        } else if fills.is_empty() {
+            order::OrderStatus::Cancelled
+        } else {
+            order::OrderStatus::PartiallyFilled
+        };
+        Ok(order::ExecutionReport{child_id: child_order.id, status, fills})
+    }
+}
+
+/// Takes liquidity from the opposite side of the order book, up to the limit price if given
+///
+/// Taken amounts are removed from the order book. Returns price and amount of each level we took
+/// from.
+pub fn take_liquidity(orderbook: &mut util::OrderBookTopN, side: util::Side, amount: rust_decimal::Decimal,
+                      limit_price: Option<rust_decimal::Decimal>) -> Vec<(rust_decimal::Decimal, rust_decimal::Decimal)> {
+    let inf = rust_decimal::Decimal::from(constants::ORDER_PRICE_INF);
+    let (orders, is_marketable): (_, fn(rust_decimal::Decimal, rust_decimal::Decimal) -> bool) = match side {
+        util::Side::Buy => (&mut orderbook.asks, |price, limit_price| price <= limit_price),
+        util::Side::Sell => (&mut orderbook.bids, |price, limit_price| price >= limit_price)
+    };
+    let mut taken = vec![];
+    let mut amount_left = amount;
+
+    for book_order in orders.iter_mut() {
+        if amount_left.is_zero() || book_order.price.abs() >= inf {break}
+        if let Some(limit_price) = limit_price {
+            if !is_marketable(book_order.price, limit_price) {break}
+        }
+        if book_order.amount.is_zero() {continue}
+
+        let amount = std::cmp::min(book_order.amount, amount_left);
+        book_order.amount -= amount;
+        amount_left -= amount;
+        taken.push((book_order.price, amount));
+    }
+    taken
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::execution::venue::VenueAdapter;
+
+
+    fn get_orderbooks() -> types::OrderBooksByFeed {
+        let mut orderbooks = util::get_initialized_orderbooks();
+        let feed = constants::Feed::BinanceSpot;
+        let orderbook = &mut orderbooks[feed as usize];
+        orderbook.asks[0] = util::Order{feed, price: rust_decimal::Decimal::from(101), amount: rust_decimal::Decimal::ONE};
+        orderbook.asks[1] = util::Order{feed, price: rust_decimal::Decimal::from(102), amount: rust_decimal::Decimal::TWO};
+        orderbooks
+    }
+
+    fn get_child_order(amount: i64, limit_price: i64) -> order::ChildOrder {
+        order::ChildOrder {
+            id: 1,
+            parent_id: 1,
+            feed: constants::Feed::BinanceSpot,
+            side: util::Side::Buy,
+            amount: rust_decimal::Decimal::from(amount),
+            limit_price: rust_decimal::Decimal::from(limit_price)
+        }
+    }
+
+    mod send_order {
+        use super::*;
+
+
+        #[tokio::test]
+        async fn test_fills_up_to_limit_price() {
+            let (_orderbooks_tx, orderbooks_rx) = watch::channel(get_orderbooks());
+            let mut venue = SimulatedVenue::new(constants::Feed::BinanceSpot, orderbooks_rx);
+            let report = venue.send_order(&get_child_order(5, 101)).await.expect("Expected report");
+
+            assert_eq!(report.status, order::OrderStatus::PartiallyFilled);
+            assert_eq!(report.fills.len(), 1);
+            assert_eq!(report.filled_amount(), rust_decimal::Decimal::ONE);
+        }
+
+        #[tokio::test]
+        async fn test_taken_liquidity_until_next_orderbook() {
+            let (orderbooks_tx, orderbooks_rx) = watch::channel(get_orderbooks());
+            let mut venue = SimulatedVenue::new(constants::Feed::BinanceSpot, orderbooks_rx);
+
+            let report = venue.send_order(&get_child_order(2, 102)).await.expect("Expected report");
+            assert_eq!(report.status, order::OrderStatus::Filled);
+            let report = venue.send_order(&get_child_order(2, 102)).await.expect("Expected report");
+            assert_eq!(report.status, order::OrderStatus::PartiallyFilled);
+            assert_eq!(report.filled_amount(), rust_decimal::Decimal::ONE);
+            let report = venue.send_order(&get_child_order(2, 102)).await.expect("Expected report");
+            assert_eq!(report.status, order::OrderStatus::Cancelled);
+
+            orderbooks_tx.send_replace(get_orderbooks());
+            let report = venue.send_order(&get_child_order(2, 102)).await.expect("Expected report");
+            assert_eq!(report.status, order::OrderStatus::Filled);
+        }
+
+        #[tokio::test]
+        async fn test_wrong_feed() {
+            let (_orderbooks_tx, orderbooks_rx) = watch::channel(get_orderbooks());
+            let mut venue = SimulatedVenue::new(constants::Feed::BitstampSpot, orderbooks_rx);
+
+            assert!(venue.send_order(&get_child_order(1, 101)).await.is_err());
+        }
+    }
+}
\ No newline at end of file
@@ -1,2 +1,24 @@
+pub mod fix;
+pub mod replay;
 pub mod ws;
-mod tls;
\ No newline at end of file
+mod tls;
+
+use async_trait;
+use error_stack::Result;
+use serde_json;
+
+use crate::error;
+
+
+/// Connection to a venue's msg stream, subscribers and listeners work with any transport
+///
+/// Besides the WebSockets client, a transport can replay recorded msgs or mock a venue in tests.
+#[async_trait::async_trait]
+pub trait Transport: Send {
+    /// Returns the next whole msg as received
+    async fn read_msg(&mut self) -> Result<String, error::ClientError>;
+    async fn send(&mut self, msg: &serde_json::Value);
+    async fn reconnect(&mut self) -> Result<(), error::ClientError>;
+    /// Starts closing the connection, reads fail once it's closed
+    async fn close(&mut self) -> Result<(), error::ClientError>;
+}
\ No newline at end of file
@@ -0,0 +1,318 @@
+//! FIX initiator client
+//!
+//! The session layer is handled here: logon, heartbeats, test requests and gap fills. Callers only
+//! see application messages. Sequence numbers are persisted, so a reconnect continues the session
+//! and the counterparty can resend what we missed.
+//!
+//! The connection is plain TCP, venues requiring TLS are expected to be reached through a TLS
+//! tunnel e.g. `stunnel`.
+use std::collections;
+use std::path;
+use std::time;
+
+use error_stack::{IntoReport, Report, Result, ResultExt};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing;
+
+use crate::constants;
+use crate::error;
+use crate::fix::{message, session, store};
+use crate::metrics;
+
+
+pub struct ClientManager<'a> {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+    session: session::Session,
+    /// Application messages received but not read yet
+    received: collections::VecDeque<message::Message>,
+    timer: tokio::time::Interval,
+    config: session::SessionConfig,
+    feed: constants::Feed,
+    feed_info: constants::FeedInfo<'a>
+}
+
+impl<'a> ClientManager<'a> {
+    /// Connects and logs on, reads are recorded as liveness of `feed`
+    pub async fn new(feed: constants::Feed, feed_info: constants::FeedInfo<'a>, config: session::SessionConfig,
+                     store_dir: path::PathBuf) -> Result<ClientManager<'a>, error::ClientError> {
+        let mut client = ClientManager {
+            stream: get_tcp_stream(feed_info.domain, feed_info.port).await?,
+            buffer: Vec::with_capacity(4096),
+            session: open_session(&config, &store_dir)?,
+            received: collections::VecDeque::new(),
+            timer: tokio::time::interval(time::Duration::from_secs(1)),
+            config,
+            feed,
+            feed_info
+        };
+        client.logon().await?;
+        Ok(client)
+    }
+
+    /// Returns the next application message
+    pub async fn read_msg(&mut self) -> Result<message::Message, error::ClientError> {
+        loop {
+            if let Some(msg) = self.received.pop_front() {
+                return Ok(msg)
+            }
+            self.poll().await?;
+        }
+    }
+
+    /// Reconnects and logs on, continuing the sequence numbers of the previous connection
+    pub async fn reconnect(&mut self) -> Result<(), error::ClientError> {
+        self.stream = get_tcp_stream(self.feed_info.domain, self.feed_info.port).await?;
+        self.buffer.clear();
+        self.received.clear();
+        self.session.restart(time::Instant::now());
+        self.logon().await
+    }
+
+    /// Sends Logout and waits for the counterparty's, at most `timeout`
+    ///
+    /// Application messages received in the meantime are discarded.
+    pub async fn logout(&mut self, timeout: time::Duration) -> Result<(), error::ClientError> {
+        let logout = self.session.logout("Logging out");
+        self.send(logout).await?;
+        tokio::time::timeout(timeout, async {
+            while self.session.state() != session::State::Disconnected {
+                self.received.clear();
+                if let Err(e) = self.poll().await {
+                    match e.current_context() {
+                        error::ClientError::EndpointClosedConnection => return Ok(()),
+                        _ => return Err(e)
+                    }
+                }
+            }
+            Ok(())
+        })
+            .await
+            .into_report()
+            .change_context(error::ClientError::Error)
+            .attach_printable("Logout timed out")?
+    }
+
+    pub async fn send(&mut self, msg: message::Message) -> Result<(), error::ClientError> {
+        let bytes = self.session.encode(msg, time::Instant::now(), time::SystemTime::now())
+            .change_context(error::ClientError::Error)?;
+        self.stream.write_all(&bytes)
+            .await
+            .into_report()
+            .change_context(error::ClientError::Error)
+            .attach_printable("Cannot send message")
+    }
+
+    /// Sends Logon and waits for the counterparty's, at most one heartbeat interval
+    async fn logon(&mut self) -> Result<(), error::ClientError> {
+        let logon = self.session.logon(false).change_context(error::ClientError::Error)?;
+        self.send(logon).await?;
+
+        let heartbeat_interval = self.config.heartbeat_interval;
+        tokio::time::timeout(heartbeat_interval, async {
+            while self.session.state() == session::State::AwaitingLogon {
+                self.poll().await?;
+            }
+            Ok::<(), Report<error::ClientError>>(())
+        })
+            .await
+            .into_report()
+            .change_context(error::ClientError::Error)
+            .attach_printable("Logon timed out")??;
+        tracing::info!("Logged on to {} as {}", self.config.target_comp_id, self.config.sender_comp_id);
+        Ok(())
+    }
+
+    /// Reads from the connection or runs the session timer, whichever comes first
+    async fn poll(&mut self) -> Result<(), error::ClientError> {
+        let actions = tokio::select! {
+            read = self.stream.read_buf(&mut self.buffer) => {
+                let length = read.into_report().change_context(error::ClientError::Error)?;
+                if length == 0 {
+                    return Err(Report::new(error::ClientError::EndpointClosedConnection))
+                }
+                // heartbeats keep a quiet market's feed live
+                metrics::get().record_feed_read(self.feed, time::SystemTime::now());
+                let mut actions = vec![];
+                while let Some((msg, msg_length)) = message::decode(&self.buffer)
+                    .change_context(error::ClientError::ParsingError)? {
+                    self.buffer.drain(..msg_length);
+                    actions.extend(self.session.on_message(msg, time::Instant::now())
+                        .change_context(error::ClientError::Error)?);
+                }
+                // the rest is an incomplete message, which can't be larger than a whole one
+                if self.buffer.len() > constants::fix::MAX_MESSAGE_SIZE {
+                    return Err(Report::new(error::ClientError::ParsingError).attach_printable("Message exceeds the maximum size"))
+                }
+                actions
+            }
+            _ = self.timer.tick() => self.session.on_timer(time::Instant::now())
+        };
+
+        for action in actions {
+            match action {
+                session::Action::Send(msg) => self.send(msg).await?,
+                session::Action::Deliver(msg) => self.received.push_back(msg),
+                session::Action::Disconnect => {
+                    let _ = self.stream.shutdown().await;
+                    return Err(Report::new(error::ClientError::EndpointClosedConnection))
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn open_session(config: &session::SessionConfig, store_dir: &path::Path) -> Result<session::Session, error::ClientError> {
+    let store = store::SequenceStore::open(store_dir, &config.sender_comp_id, &config.target_comp_id)
+        .change_context(error::ClientError::Error)?;
+    Ok(session::Session::new(config.clone(), store, time::Instant::now()))
+}
+
+async fn get_tcp_stream(domain: &str, port: u16) -> Result<TcpStream, error::ClientError> {
+    let stream = TcpStream::connect(format!("{}:{}", domain, port))
+        .await
+        .into_report()
+        .change_context(error::ClientError::Error)
+        .attach_printable("Establishing TCP stream failed")?;
+    // FIX messages are small and latency matters more than throughput
+    stream.set_nodelay(true).into_report().change_context(error::ClientError::Error)?;
+    Ok(stream)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fix::message::{msg_type, tag};
+    use crate::fix::test_util::{self, Acceptor};
+    use tokio::net::TcpListener;
+
+
+    async fn get_client(port: u16, store_dir: &path::Path) -> ClientManager<'static> {
+        let feed_info = constants::FeedInfo{domain: "127.0.0.1", path: "", port, protocol: constants::Protocol::FIX};
+        let config = session::SessionConfig {
+            role: session::Role::Initiator,
+            sender_comp_id: "CLIENT".to_owned(),
+            target_comp_id: "VENUE".to_owned(),
+            heartbeat_interval: time::Duration::from_secs(5)
+        };
+        ClientManager::new(constants::Feed::BitstampSpot, feed_info, config, store_dir.to_owned()).await
+            .expect("Expected logged on client")
+    }
+
+    async fn get_listener() -> (TcpListener, u16) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("Expected listener");
+        let port = listener.local_addr().expect("Expected address").port();
+        (listener, port)
+    }
+
+    async fn logon(acceptor: &mut Acceptor) -> message::Message {
+        let logon = acceptor.read().await;
+        acceptor.send(message::Message::new(msg_type::LOGON).with(tag::HEART_BT_INT, 5)).await;
+        logon
+    }
+
+    mod read_msg {
+        use super::*;
+
+
+        #[tokio::test]
+        async fn test_session_messages_are_handled() {
+            let store_dir = test_util::get_store_dir();
+            let (listener, port) = get_listener().await;
+            let acceptor = tokio::spawn(async move {
+                let mut acceptor = Acceptor::accept(&listener).await;
+                let logon = logon(&mut acceptor).await;
+                acceptor.send(message::Message::new(msg_type::TEST_REQUEST).with(tag::TEST_REQ_ID, "ping")).await;
+                acceptor.send(message::Message::new(msg_type::MARKET_DATA_SNAPSHOT_FULL_REFRESH)).await;
+                (logon, acceptor.read().await)
+            });
+            let mut client = get_client(port, store_dir.path()).await;
+
+            let msg = client.read_msg().await.expect("Expected application message");
+            let (logon, heartbeat) = acceptor.await.expect("Expected acceptor messages");
+            assert_eq!(msg.msg_type(), msg_type::MARKET_DATA_SNAPSHOT_FULL_REFRESH);
+            assert_eq!(logon.get(tag::MSG_SEQ_NUM), Some("1"));
+            assert_eq!(logon.get(tag::SENDER_COMP_ID), Some("CLIENT"));
+            assert_eq!(heartbeat.msg_type(), msg_type::HEARTBEAT);
+            assert_eq!(heartbeat.get(tag::TEST_REQ_ID), Some("ping"));
+        }
+
+        #[tokio::test]
+        async fn test_oversized_message_drops_connection() {
+            let store_dir = test_util::get_store_dir();
+            let (listener, port) = get_listener().await;
+            let acceptor = tokio::spawn(async move {
+                let mut acceptor = Acceptor::accept(&listener).await;
+                logon(&mut acceptor).await;
+                // never ends a field
+                let garbage = vec![b'1'; constants::fix::MAX_MESSAGE_SIZE + 1];
+                acceptor.stream.write_all(&garbage).await.expect("Expected sent bytes");
+                acceptor
+            });
+            let mut client = get_client(port, store_dir.path()).await;
+
+            let e = client.read_msg().await.expect_err("Expected dropped connection");
+            assert!(matches!(e.current_context(), error::ClientError::ParsingError));
+            drop(acceptor.await);
+        }
+
+        #[tokio::test]
+        async fn test_gap_is_resent() {
+            let store_dir = test_util::get_store_dir();
+            let (listener, port) = get_listener().await;
+            let acceptor = tokio::spawn(async move {
+                let mut acceptor = Acceptor::accept(&listener).await;
+                logon(&mut acceptor).await;
+                // skip seq num 2
+                acceptor.next_seq_num += 1;
+                acceptor.send(message::Message::new(msg_type::MARKET_DATA_INCREMENTAL_REFRESH)).await;
+                let resend_request = acceptor.read().await;
+                // gap fill seq num 2 and resend seq num 3
+                acceptor.next_seq_num = 2;
+                acceptor.send(message::Message::new(msg_type::SEQUENCE_RESET)
+                    .with(tag::GAP_FILL_FLAG, "Y").with(tag::NEW_SEQ_NO, 3)).await;
+                acceptor.send(message::Message::new(msg_type::MARKET_DATA_SNAPSHOT_FULL_REFRESH)).await;
+                resend_request
+            });
+            let mut client = get_client(port, store_dir.path()).await;
+
+            let msg = client.read_msg().await.expect("Expected application message");
+            let resend_request = acceptor.await.expect("Expected resend request");
+            assert_eq!(resend_request.msg_type(), msg_type::RESEND_REQUEST);
+            assert_eq!(resend_request.get(tag::BEGIN_SEQ_NO), Some("2"));
+            // the message after the gap is dropped, the next one is delivered
+            assert_eq!(msg.msg_type(), msg_type::MARKET_DATA_SNAPSHOT_FULL_REFRESH);
+        }
+    }
+
+    mod reconnect {
+        use super::*;
+
+
+        #[tokio::test]
+        async fn test_sequence_numbers_continue() {
+            let store_dir = test_util::get_store_dir();
+            let (listener, port) = get_listener().await;
+            let acceptor = tokio::spawn(async move {
+                let mut acceptor = Acceptor::accept(&listener).await;
+                logon(&mut acceptor).await;
+                drop(acceptor);
+
+                let mut acceptor = Acceptor::accept(&listener).await;
+                acceptor.next_seq_num = 2;
+                logon(&mut acceptor).await
+            });
+            let mut client = get_client(port, store_dir.path()).await;
+
+            let e = client.read_msg().await.expect_err("Expected closed connection");
+            assert!(matches!(e.current_context(), error::ClientError::EndpointClosedConnection));
+            client.reconnect().await.expect("Expected logged on client");
+            let logon = acceptor.await.expect("Expected logon");
+            assert_eq!(logon.get(tag::MSG_SEQ_NUM), Some("2"));
+            assert_eq!(logon.get(tag::RESET_SEQ_NUM_FLAG), None);
+        }
+    }
+}
\ No newline at end of file
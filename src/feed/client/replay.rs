@@ -0,0 +1,314 @@
+//! Replay of recorded raw feed messages
+//!
+//! A `ReplayClient` is a `client::Transport` like `ws::ClientManager`, but reads msgs from
+//! recordings of `listener::recorder` instead of a venue. `Replay` merges the recordings by receive
+//! timestamp and hands every msg to the client of its feed, either as fast as possible or at the
+//! recorded pace, optionally sped up.
+//!
+//! Msgs are handed over one at a time: clients request each msg and the next msg is handed over
+//! only once the client of the previous one requests again. Listeners read the next msg only after
+//! forwarding the previous one, so the order books reach listener aggregators' queues in the same
+//! order on every replay. There is one client per feed, msgs of feeds without a client are skipped.
+//! Recordings start after the subscription, so listeners reading a replay are already subscribed.
+use std::cmp;
+use std::collections;
+use std::fs;
+use std::path;
+use std::time;
+
+use async_trait;
+use error_stack::{IntoReport, Report, Result, ResultExt};
+use strum::EnumCount;
+use tokio::sync::{mpsc, oneshot};
+use tracing;
+
+use crate::constants;
+use crate::error;
+use crate::feed::client;
+use crate::feed::listener::recorder;
+
+
+#[derive(Clone, Copy, Debug)]
+pub enum Pace {
+    AsFastAsPossible,
+    /// Recorded pace, multiplied by the speed e.g. 2 replays twice as fast
+    RealTime(f64)
+}
+
+pub struct Replay {
+    paths: Vec<path::PathBuf>,
+    pace: Pace,
+    /// Msg requests of the clients, indexed by feed
+    requests_rx: [Option<mpsc::Receiver<oneshot::Sender<String>>>; constants::Feed::COUNT]
+}
+
+impl Replay {
+    /// Replays the given recordings, directories are replayed in the order of their file names
+    pub fn new(paths: Vec<path::PathBuf>, pace: Pace) -> Replay {
+        Replay {paths, pace, requests_rx: Default::default()}
+    }
+
+    /// Client reading the msgs of the feed, replaces the previous client of the feed
+    pub fn client(&mut self, feed: constants::Feed) -> ReplayClient {
+        // a client has at most one request pending
+        let (requests_tx, requests_rx) = mpsc::channel(1);
+        self.requests_rx[feed as usize] = Some(requests_rx);
+        ReplayClient {feed, requests_tx, pending_msg_rx: None, closed: false}
+    }
+
+    /// Entry point for the task - worker
+    ///
+    /// Returns when all msgs are read or all clients are dropped, clients then read the end of the
+    /// replay as a closed connection.
+    pub async fn run(mut self) -> Result<(), error::ClientError> {
+        let mut readers = vec![];
+        for path in get_recording_paths(&self.paths)? {
+            readers.push(recorder::RecordReader::open(&path).change_context(error::ClientError::Error)?);
+        }
+        let mut merger = Merger::new(readers)?;
+        let started_at = tokio::time::Instant::now();
+        let mut first_received_at: Option<time::SystemTime> = None;
+        let mut replayed: u64 = 0;
+        // pending request of each client, indexed by feed
+        let mut msgs_tx: [Option<oneshot::Sender<String>>; constants::Feed::COUNT] = Default::default();
+        let mut last_feed_id: Option<usize> = None;
+
+        while let Some(record) = merger.next_record()? {
+            let feed_id = record.feed as usize;
+            if self.requests_rx[feed_id].is_none() {continue}
+
+            if let Pace::RealTime(speed) = self.pace {
+                let first_received_at = *first_received_at.get_or_insert(record.received_at);
+                let offset = record.received_at.duration_since(first_received_at).unwrap_or_default();
+                tokio::time::sleep_until(started_at + offset.div_f64(speed)).await;
+            }
+            // the client of the previous msg requests again once it's done with it, only then the
+            // next msg can't overtake it
+            for client_feed_id in last_feed_id.into_iter().chain([feed_id]) {
+                if msgs_tx[client_feed_id].is_none() {
+                    msgs_tx[client_feed_id] = self.receive_request(client_feed_id).await;
+                }
+            }
+            let delivered = match msgs_tx[feed_id].take() {
+                Some(msg_tx) => msg_tx.send(record.msg).is_ok(),
+                None => false
+            };
+            if delivered {
+                replayed += 1;
+                last_feed_id = Some(feed_id);
+            } else {
+                tracing::warn!("Replay client of feed {} is gone", record.feed);
+                self.requests_rx[feed_id] = None;
+                last_feed_id = None;
+                if self.requests_rx.iter().all(Option::is_none) {
+                    break
+                }
+            }
+        }
+
+        tracing::info!("Replay finished after {} msgs", replayed);
+        Ok(())
+    }
+
+    /// Waits for the client's next request, `None` if the client is gone
+    async fn receive_request(&mut self, feed_id: usize) -> Option<oneshot::Sender<String>> {
+        match &mut self.requests_rx[feed_id] {
+            Some(requests_rx) => requests_rx.recv().await,
+            None => None
+        }
+    }
+}
+
+pub struct ReplayClient {
+    feed: constants::Feed,
+    requests_tx: mpsc::Sender<oneshot::Sender<String>>,
+    /// Request whose msg wasn't received yet, kept when a read is cancelled
+    pending_msg_rx: Option<oneshot::Receiver<String>>,
+    closed: bool
+}
+
+#[async_trait::async_trait]
+impl client::Transport for ReplayClient {
+    /// Returns the next recorded msg of the feed
+    async fn read_msg(&mut self) -> Result<String, error::ClientError> {
+        if self.closed {
+            return Err(Report::new(error::ClientError::EndpointClosedConnection))
+        }
+        if self.pending_msg_rx.is_none() {
+            let (msg_tx, msg_rx) = oneshot::channel();
+            if self.requests_tx.send(msg_tx).await.is_ok() {
+                self.pending_msg_rx = Some(msg_rx);
+            }
+        }
+        let msg = match &mut self.pending_msg_rx {
+            Some(msg_rx) => msg_rx.await.ok(),
+            None => None
+        };
+        self.pending_msg_rx = None;
+        msg.ok_or_else(|| Report::new(error::ClientError::EndpointClosedConnection)
+            .attach_printable(format!("Replay of feed {} finished", self.feed)))
+    }
+
+    /// A replay can't be reconnected to, the recorded msgs were already read
+    async fn reconnect(&mut self) -> Result<(), error::ClientError> {
+        Err(Report::new(error::ClientError::Error)
+            .attach_printable(format!("Cannot reconnect to replay of feed {}", self.feed)))
+    }
+
+    /// Following reads return a closed connection
+    async fn close(&mut self) -> Result<(), error::ClientError> {
+        self.closed = true;
+        Ok(())
+    }
+
+    /// Requests e.g. unsubscriptions are ignored, a recording can't respond to them
+    async fn send(&mut self, _msg: &serde_json::Value) {}
+}
+
+/// Recording files in replay order, files in directories are sorted by name
+fn get_recording_paths(paths: &[path::PathBuf]) -> Result<Vec<path::PathBuf>, error::ClientError> {
+    let mut recording_paths = vec![];
+    for path in paths {
+        if !path.is_dir() {
+            recording_paths.push(path.to_owned());
+            continue
+        }
+        let mut dir_paths = fs::read_dir(path)
+            .into_report()
+            .change_context(error::ClientError::Error)
+            .attach_printable_lazy(|| format!("Cannot read recording directory {}", path.display()))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_file())
+            .collect::<Vec<path::PathBuf>>();
+        dir_paths.sort();
+        recording_paths.extend(dir_paths);
+    }
+    Ok(recording_paths)
+}
+
+/// Merges recordings into one sequence ordered by receive timestamp
+///
+/// Records of a recording are in receive order, so only the next record of each is compared. Ties
+/// are broken by the order of the recordings, which keeps the merge deterministic.
+struct Merger {
+    readers: Vec<recorder::RecordReader>,
+    /// Next record of each reader, ordered by (receive timestamp, reader)
+    heads: collections::BinaryHeap<cmp::Reverse<(time::SystemTime, usize)>>,
+    records: Vec<Option<recorder::Record>>
+}
+
+impl Merger {
+    fn new(mut readers: Vec<recorder::RecordReader>) -> Result<Merger, error::ClientError> {
+        let mut heads = collections::BinaryHeap::with_capacity(readers.len());
+        let mut records = Vec::with_capacity(readers.len());
+        for (reader_id, reader) in readers.iter_mut().enumerate() {
+            let record = reader.read_record().change_context(error::ClientError::Error)?;
+            if let Some(record) = &record {
+                heads.push(cmp::Reverse((record.received_at, reader_id)));
+            }
+            records.push(record);
+        }
+        Ok(Merger {readers, heads, records})
+    }
+
+    fn next_record(&mut self) -> Result<Option<recorder::Record>, error::ClientError> {
+        let Some(cmp::Reverse((_, reader_id))) = self.heads.pop() else {return Ok(None)};
+        let record = self.records[reader_id].take();
+        let next_record = self.readers[reader_id].read_record().change_context(error::ClientError::Error)?;
+        if let Some(next_record) = &next_record {
+            self.heads.push(cmp::Reverse((next_record.received_at, reader_id)));
+        }
+        self.records[reader_id] = next_record;
+        Ok(record)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    mod run {
+        use super::*;
+        use client::Transport;
+
+        fn record(feed: constants::Feed, received_at_ms: u64, msg: &str) -> recorder::Record {
+            recorder::Record {
+                feed, received_at: time::UNIX_EPOCH + time::Duration::from_millis(received_at_ms), msg: msg.to_owned()
+            }
+        }
+
+        /// Writes each list of records to its own recording
+        fn write_recordings(dir: &path::Path, recordings: &[&[recorder::Record]]) -> Vec<path::PathBuf> {
+            recordings.iter().enumerate().map(|(i, records)| {
+                let recording_dir = dir.join(i.to_string());
+                let (records_tx, records_rx) = mpsc::channel(records.len());
+                let recorder = recorder::Recorder::new(records_tx);
+                for record in records.iter() {
+                    recorder.record(record.feed, record.received_at, &record.msg);
+                }
+                drop(recorder);
+                let mut writer = recorder::RecordWriter {
+                    records_rx,
+                    dir: recording_dir.clone(),
+                    compressed: false,
+                    max_file_size: u64::MAX,
+                    max_file_age: time::Duration::from_secs(3600)
+                };
+                writer.run().expect("Expected recording");
+                recording_dir
+            }).collect()
+        }
+
+        #[tokio::test]
+        async fn test_ordered_by_receive_timestamp() {
+            let dir = tempfile::tempdir().expect("Expected recording directory");
+            let paths = write_recordings(dir.path(), &[
+                &[record(constants::Feed::BinanceSpot, 1, "binance 1"), record(constants::Feed::BinanceSpot, 4, "binance 4")],
+                &[record(constants::Feed::BitstampSpot, 2, "bitstamp 2"), record(constants::Feed::BitstampSpot, 3, "bitstamp 3")]]);
+            let mut replay = Replay::new(paths, Pace::AsFastAsPossible);
+            let mut binance_client = replay.client(constants::Feed::BinanceSpot);
+            let mut bitstamp_client = replay.client(constants::Feed::BitstampSpot);
+            let replay_handle = tokio::spawn(replay.run());
+
+            // only one msg is handed over at a time, so the order doesn't depend on which client reads first
+            let mut msgs = vec![];
+            for _ in 0..4 {
+                let msg = tokio::select! {
+                    // the last msg is binance's, bitstamp's read may already see the end of the replay
+                    biased;
+                    msg = binance_client.read_msg() => msg,
+                    msg = bitstamp_client.read_msg() => msg
+                };
+                msgs.push(msg.expect("Expected msg"));
+            }
+
+            assert_eq!(msgs, ["binance 1", "bitstamp 2", "bitstamp 3", "binance 4"]);
+            replay_handle.await.expect("Replay doesn't panic").expect("Expected replay");
+            assert!(matches!(binance_client.read_msg().await.unwrap_err().current_context(),
+                             error::ClientError::EndpointClosedConnection));
+        }
+
+        #[tokio::test]
+        async fn test_next_msg_waits_for_previous_reader() {
+            let dir = tempfile::tempdir().expect("Expected recording directory");
+            let paths = write_recordings(dir.path(), &[
+                &[record(constants::Feed::BinanceSpot, 1, "binance 1"), record(constants::Feed::BitstampSpot, 2, "bitstamp 2")]]);
+            let mut replay = Replay::new(paths, Pace::AsFastAsPossible);
+            let mut binance_client = replay.client(constants::Feed::BinanceSpot);
+            let mut bitstamp_client = replay.client(constants::Feed::BitstampSpot);
+            let replay_handle = tokio::spawn(replay.run());
+
+            assert_eq!(binance_client.read_msg().await.expect("Expected msg"), "binance 1");
+            // binance's listener is still forwarding its msg
+            let read = tokio::time::timeout(time::Duration::from_millis(50), bitstamp_client.read_msg()).await;
+            assert!(read.is_err());
+
+            let (binance_read, bitstamp_read) = tokio::join!(binance_client.read_msg(), bitstamp_client.read_msg());
+            assert_eq!(bitstamp_read.expect("Expected msg"), "bitstamp 2");
+            assert!(binance_read.is_err());
+            replay_handle.await.expect("Replay doesn't panic").expect("Expected replay");
+        }
+    }
+}
\ No newline at end of file
@@ -1,8 +1,10 @@
+use async_trait;
 use error_stack::{IntoReport, Result, ResultExt, Report};
 use hyper::rt;
 
 use crate::constants;
 use crate::error;
+use crate::feed::client;
 use super::tls;
 
 
@@ -18,13 +20,16 @@ impl<'a> ClientManager<'a> {
             client: get_ws_client(feed_info.domain, feed_info.port, feed_info.path).await?,
             feed_info})
     }
+}
 
+#[async_trait::async_trait]
+impl<'a> client::Transport for ClientManager<'a> {
     /// Returns the whole message as received, as `String`
     ///
     /// In general we could work on single frames and thus avoid unneeded processing. However for some
     /// applications we just need the whole message. And that is what we return here - concatenated frames,
     /// from which we parse a string.
-    pub async fn read_msg(&mut self) -> Result<String, error::ClientError> {
+    async fn read_msg(&mut self) -> Result<String, error::ClientError> {
         match self.client.read_frame().await {
             Ok(frame) => {
                 match frame.opcode {
@@ -53,13 +58,22 @@ impl<'a> ClientManager<'a> {
         }
     }
 
-    pub async fn reconnect(&mut self) -> Result<(), error::ClientError> {
+    async fn reconnect(&mut self) -> Result<(), error::ClientError> {
         self.client = get_ws_client(self.feed_info.domain, self.feed_info.port, self.feed_info.path)
             .await?;
         Ok(())
     }
 
-    pub async fn send(&mut self, msg: &serde_json::Value) {
+    /// Starts the closing handshake with a normal closure, the venue then closes the connection
+    async fn close(&mut self) -> Result<(), error::ClientError> {
+        self.client.write_frame(fastwebsockets::Frame::close(1000, b""))
+            .await
+            .map_err(|e| Report::new(error::ClientError::Error)
+                .attach_printable("Cannot send close frame")
+                .attach_printable(e.to_string()))
+    }
+
+    async fn send(&mut self, msg: &serde_json::Value) {
         let _ = self.client.write_frame(
             fastwebsockets::Frame::text(msg.to_string().as_bytes().to_vec().into()))
             .await;
@@ -1 +1,3 @@
-pub mod orderbook_snap_change_forwarder;
\ No newline at end of file
+pub mod fix_market_data_forwarder;
+pub mod orderbook_snap_change_forwarder;
+pub mod recorder;
\ No newline at end of file
@@ -0,0 +1,205 @@
+//! FIX market data forwarder
+//!
+//! Works like `orderbook_snap_change_forwarder` for venues streaming market data over a FIX
+//! session: it forwards the venue's top N book to queue consumers only if it has changed, excludes
+//! the feed while the session is down and unsubscribes and logs out on shutdown. Msgs are not
+//! recorded, recordings hold WebSocket msgs only.
+use std::time;
+
+use error_stack::{Result, ResultExt};
+use tokio::sync::{mpsc, watch};
+use tracing;
+
+use crate::constants;
+use crate::error;
+use crate::feed::subscriber::fix;
+use crate::feed::subscriber::ws::Subscribe;
+use crate::metrics;
+use crate::types;
+use crate::util;
+
+
+pub struct Listener<'a> {
+    feed: constants::Feed,
+    instrument_name: String,
+    subscriber: fix::Subscriber<'a>,
+    queues_tx: Vec<mpsc::Sender<types::BoxedFeedOrderBook>>,
+    shutdown_rx: watch::Receiver<bool>
+}
+
+impl<'a> Listener<'a> {
+    /// Creates a listener of the logged on session forwarding order books to all the given queues
+    pub fn new(feed: constants::Feed, queues_tx: Vec<mpsc::Sender<types::BoxedFeedOrderBook>>, instrument_name: String,
+               subscriber: fix::Subscriber<'a>, shutdown_rx: watch::Receiver<bool>) -> Listener<'a> {
+        Listener{feed, instrument_name, subscriber, queues_tx, shutdown_rx}
+    }
+
+    /// Sends the order book to all listener aggregators
+    async fn forward(&mut self, feed_orderbook: util::FeedOrderBook) {
+        for queue_tx in &self.queues_tx {
+            if let Err(e) = queue_tx.send(Box::new(feed_orderbook)).await {
+                tracing::error!("Cannot send item to queue: {}", e);
+            }
+        }
+    }
+
+    /// Notifies downstream to exclude this feed, see `orderbook_snap_change_forwarder`
+    async fn exclude_listener_from_grpc_stream(&mut self) {
+        tracing::warn!("Excluding feed from gRPC stream: {}", self.feed);
+
+        let mut orderbook = util::OrderBookTopN::default();
+        orderbook.set_unreachable_price();
+        self.forward(util::FeedOrderBook::new(self.feed, orderbook, time::Instant::now())).await;
+    }
+
+    /// Unsubscribes and logs out, waiting a bounded time for the venue's Logout
+    async fn close(&mut self) {
+        self.subscriber.unsubscribe_from_l2_snap(&self.instrument_name).await;
+        let timeout = time::Duration::from_millis(constants::fix::LOGOUT_TIMEOUT_MS);
+        match self.subscriber.client.logout(timeout).await {
+            Ok(_) => tracing::info!("Logged out of feed {}", self.feed),
+            Err(e) => tracing::warn!("Feed {} didn't confirm the logout: {:?}", self.feed, e)
+        }
+    }
+
+    /// Entry point for the task - worker
+    ///
+    /// Returns when shutdown is signalled after logging out, or when the venue rejects the
+    /// subscription.
+    pub async fn run(&mut self) -> Result<(), error::ListenerError> {
+        self.subscriber.subscribe_to_l2_snap(&self.instrument_name).await
+            .change_context(error::ListenerError)?;
+        let mut old_orderbook: Option<util::OrderBookTopN> = None;
+
+        loop {
+            let read = tokio::select! {
+                biased;
+                _ = util::wait_for_shutdown(&mut self.shutdown_rx) => None,
+                read = self.subscriber.read_orderbook() => Some(read)
+            };
+            let Some(read) = read else {
+                self.close().await;
+                return Ok(())
+            };
+            match read {
+                Ok(orderbook) => {
+                    let read_at = time::Instant::now();
+                    metrics::get().feed_messages_received.with_label_values(&[self.feed.feed_name_for_grpc_service()]).inc();
+                    let changed = old_orderbook.is_none_or(|old_orderbook| has_orderbook_changed(&old_orderbook, &orderbook));
+                    metrics::get().feed_snapshots
+                        .with_label_values(&[self.feed.feed_name_for_grpc_service(), if changed {"true"} else {"false"}])
+                        .inc();
+                    if changed {
+                        self.forward(util::FeedOrderBook::new(self.feed, orderbook, read_at)).await;
+                        old_orderbook = Some(orderbook);
+                    }
+                }
+                Err(e) => {
+                    tracing::error!("Error reading from FIX session: {:?}", e);
+                    self.exclude_listener_from_grpc_stream().await;
+                    // a rejected subscription is not a session error, retrying won't help
+                    if e.downcast_ref::<error::ClientError>().is_none() {
+                        return Err(e.change_context(error::ListenerError))
+                    }
+
+                    tracing::info!("Reconnecting to feed: {}", self.feed);
+                    metrics::get().feed_reconnects.with_label_values(&[self.feed.feed_name_for_grpc_service()]).inc();
+                    old_orderbook = None;
+                    self.subscriber.client.reconnect().await
+                        .change_context(error::ListenerError)?;
+                    self.subscriber.subscribe_to_l2_snap(&self.instrument_name).await
+                        .change_context(error::ListenerError)?;
+                }
+            }
+        }
+    }
+}
+
+/// Whether any level of the top N has a different price or amount
+fn has_orderbook_changed(old_orderbook: &util::OrderBookTopN, new_orderbook: &util::OrderBookTopN) -> bool {
+    let levels = |orderbook: &util::OrderBookTopN| orderbook.asks.iter().chain(orderbook.bids.iter())
+        .map(|order| (order.price, order.amount))
+        .collect::<Vec<_>>();
+    levels(old_orderbook) != levels(new_orderbook)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path;
+
+    use tokio::net::TcpListener;
+
+    use crate::fix::message::{self, md_entry_type, msg_type, tag};
+    use crate::fix::session;
+    use crate::fix::test_util::{self, Acceptor};
+
+
+    fn get_snapshot(bid_price: i64) -> message::Message {
+        message::Message::new(msg_type::MARKET_DATA_SNAPSHOT_FULL_REFRESH)
+            .with(tag::NO_MD_ENTRIES, 2)
+            .with(tag::MD_ENTRY_TYPE, md_entry_type::BID)
+            .with(tag::MD_ENTRY_PX, bid_price)
+            .with(tag::MD_ENTRY_SIZE, 1)
+            .with(tag::MD_ENTRY_TYPE, md_entry_type::OFFER)
+            .with(tag::MD_ENTRY_PX, 101)
+            .with(tag::MD_ENTRY_SIZE, 1)
+    }
+
+    async fn get_subscriber(port: u16, store_dir: &path::Path) -> fix::Subscriber<'static> {
+        let feed_info = constants::FeedInfo{domain: "127.0.0.1", path: "", port, protocol: constants::Protocol::FIX};
+        let config = session::SessionConfig {
+            role: session::Role::Initiator,
+            sender_comp_id: "CLIENT".to_owned(),
+            target_comp_id: "VENUE".to_owned(),
+            heartbeat_interval: time::Duration::from_secs(5)
+        };
+        fix::Subscriber::new(constants::Feed::BitstampSpot, feed_info, config, store_dir.to_owned()).await
+            .expect("Expected logged on subscriber")
+    }
+
+    mod run {
+        use super::*;
+
+
+        #[tokio::test]
+        async fn test_forwards_changed_orderbooks() {
+            let store_dir = test_util::get_store_dir();
+            let listener = TcpListener::bind("127.0.0.1:0").await.expect("Expected listener");
+            let port = listener.local_addr().expect("Expected address").port();
+            let acceptor = tokio::spawn(async move {
+                let mut acceptor = Acceptor::accept(&listener).await;
+                acceptor.read().await;
+                acceptor.send(message::Message::new(msg_type::LOGON).with(tag::HEART_BT_INT, 5)).await;
+                acceptor.read().await;
+                acceptor.send(get_snapshot(100)).await;
+                acceptor.send(get_snapshot(100)).await;
+                acceptor.send(get_snapshot(99)).await;
+                // unsubscribe and logout
+                let msgs = [acceptor.read().await, acceptor.read().await];
+                acceptor.send(message::Message::new(msg_type::LOGOUT)).await;
+                msgs
+            });
+            let subscriber = get_subscriber(port, store_dir.path()).await;
+            let (queue_tx, mut queue_rx) = mpsc::channel(16);
+            let (shutdown_tx, shutdown_rx) = watch::channel(false);
+            let mut listener = Listener::new(
+                constants::Feed::BitstampSpot, vec![queue_tx], "ethbtc".to_owned(), subscriber, shutdown_rx);
+            let listener_handle = tokio::spawn(async move {listener.run().await});
+
+            // the subscription snapshot is forwarded, the repeated one is not
+            let feed_orderbook = queue_rx.recv().await.expect("Expected snapshot");
+            assert_eq!(feed_orderbook.orderbook.bids[0].price, rust_decimal::Decimal::from(100));
+            let feed_orderbook = queue_rx.recv().await.expect("Expected changed order book");
+            assert_eq!(feed_orderbook.orderbook.bids[0].price, rust_decimal::Decimal::from(99));
+
+            shutdown_tx.send_replace(true);
+            listener_handle.await.expect("Listener doesn't panic").expect("Expected listener stopped");
+            let [unsubscribe, logout] = acceptor.await.expect("Expected acceptor messages");
+            assert_eq!(unsubscribe.get(tag::SUBSCRIPTION_REQUEST_TYPE), Some("2"));
+            assert_eq!(logout.msg_type(), msg_type::LOGOUT);
+            assert!(queue_rx.try_recv().is_err());
+        }
+    }
+}
\ No newline at end of file
@@ -10,10 +10,10 @@ use std;
 use std::str::FromStr;
 
 use async_trait;
-use error_stack::{Result, ResultExt};
+use error_stack::{Report, Result, ResultExt};
 use rust_decimal;
 use tokio;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
 use tracing;
 
 use crate::constants;
@@ -21,8 +21,12 @@ use crate::constants::feed;
 use crate::constants::feed_aggregator;
 use constants::listener::orderbook_snap_change_forwarder;
 use crate::error;
+use crate::feed::client;
+use crate::feed::listener::recorder;
 use crate::feed::subscriber::ws;
 use crate::feed::subscriber::ws::Subscribe;
+use crate::metrics;
+use crate::metrics::latency;
 use crate::types;
 use crate::util;
 
@@ -48,6 +52,11 @@ pub trait ParseMsg: Send {
         return orders;
     }
 
+    /// Venue's timestamp of the msg, `None` if the venue doesn't send one
+    fn parse_exchange_timestamp(&self, _msg: &str) -> Option<std::time::SystemTime> {
+        None
+    }
+
     /// Parses a snap of the order book msg
     ///
     /// # Warning
@@ -65,27 +74,80 @@ pub trait ParseMsg: Send {
     fn parse_orderbook_snap(&self, feed: constants::Feed, msg: &str) -> util::OrderBookTopN {
         let asks = self.parse_json_array_slice(feed, msg, "data.asks");
         let bids = self.parse_json_array_slice(feed, msg, "data.bids");
-        util::OrderBookTopN {asks, bids}
+        util::OrderBookTopN {
+            asks,
+            bids,
+            exchange_timestamp: self.parse_exchange_timestamp(msg),
+            received_at: Some(std::time::SystemTime::now())
+        }
     }
 }
 
 
-pub struct Listener<'a, T: feed::Feed> {
+pub struct Listener<T: feed::Feed, C: client::Transport> {
     feed: constants::Feed,
     instrument_name: String,
     msg_offset_orderbook_start: usize,
-    subscriber: ws::Subscriber<'a, T>,
-    queue_tx: mpsc::Sender<types::BoxedFeedOrderBook>
+    subscriber: ws::Subscriber<T, C>,
+    /// Whether the transport is already subscribed when the listener starts
+    subscribed: bool,
+    queues_tx: Vec<mpsc::Sender<types::BoxedFeedOrderBook>>,
+    recorder: Option<recorder::Recorder>,
+    shutdown_rx: watch::Receiver<bool>
 }
 
-impl<'a, T: feed::Feed> Listener<'a, T>
-where ws::Subscriber<'a, T>: Subscribe, Self: ParseMsg {
-    pub async fn new(feed: constants::Feed, queue_tx: mpsc::Sender<types::BoxedFeedOrderBook>,
-                     msg_offset_orderbook_start: usize, instrument_name: String)
-        -> Result<Listener<'a, T>, error::ListenerError> {
-        let subscriber = ws::Subscriber::<'a, T>::new(feed.feed_info()).await
+impl<'a, T: feed::Feed> Listener<T, client::ws::ClientManager<'a>>
+where ws::Subscriber<T, client::ws::ClientManager<'a>>: Subscribe, Self: ParseMsg {
+    /// Creates a listener of the venue forwarding order books to all the given queues
+    ///
+    /// Each queue is consumed by a different listener aggregator. Every raw msg is handed to the
+    /// `recorder`, if any. The listener unsubscribes and closes the connection when `shutdown_rx`
+    /// becomes `true`.
+    pub async fn new(feed: constants::Feed, queues_tx: Vec<mpsc::Sender<types::BoxedFeedOrderBook>>,
+                     msg_offset_orderbook_start: usize, instrument_name: String, recorder: Option<recorder::Recorder>,
+                     shutdown_rx: watch::Receiver<bool>)
+        -> Result<Listener<T, client::ws::ClientManager<'a>>, error::ListenerError> {
+        let feed_info = feed.feed_info()
+            .ok_or(Report::new(error::ListenerError))
+            .attach_printable_lazy(|| format!("Feed {} has no venue to listen to", feed))?;
+        let subscriber = ws::Subscriber::<T, client::ws::ClientManager<'a>>::new(feed_info).await
                 .change_context(error::ListenerError)?;
-        Ok(Listener::<'a, T>{feed, msg_offset_orderbook_start, subscriber, queue_tx, instrument_name})
+        Ok(Listener{feed, msg_offset_orderbook_start, subscriber, subscribed: false, queues_tx, instrument_name, recorder,
+                    shutdown_rx})
+    }
+}
+
+impl<T: feed::Feed, C: client::Transport> Listener<T, C>
+where ws::Subscriber<T, C>: Subscribe, Self: ParseMsg {
+    /// Creates a listener reading from the given transport, it subscribes when it starts
+    pub fn with_client(feed: constants::Feed, queues_tx: Vec<mpsc::Sender<types::BoxedFeedOrderBook>>,
+                       msg_offset_orderbook_start: usize, instrument_name: String, client: C,
+                       recorder: Option<recorder::Recorder>, shutdown_rx: watch::Receiver<bool>) -> Listener<T, C> {
+        let subscriber = ws::Subscriber::with_client(client);
+        Listener{feed, msg_offset_orderbook_start, subscriber, subscribed: false, queues_tx, instrument_name, recorder,
+                 shutdown_rx}
+    }
+
+    /// Creates a listener reading from a transport that is already subscribed e.g. a replay
+    ///
+    /// The listener subscribes only after reconnecting, otherwise it works as with `with_client`.
+    pub fn with_subscribed_client(feed: constants::Feed, queues_tx: Vec<mpsc::Sender<types::BoxedFeedOrderBook>>,
+                                  msg_offset_orderbook_start: usize, instrument_name: String, client: C,
+                                  recorder: Option<recorder::Recorder>, shutdown_rx: watch::Receiver<bool>)
+                                  -> Listener<T, C> {
+        Listener{subscribed: true, ..Self::with_client(feed, queues_tx, msg_offset_orderbook_start, instrument_name,
+                                                        client, recorder, shutdown_rx)}
+    }
+
+    /// Sends the order book to all listener aggregators
+    async fn forward(&mut self, feed_orderbook: util::FeedOrderBook) {
+        //we might want to use a memory pool instead of `Box`ing `feed_orderbook`
+        for queue_tx in &self.queues_tx {
+            match queue_tx.send(Box::new(feed_orderbook)).await {
+                Ok(_) => {}
+                Err(e) => {tracing::error!("Cannot send item to queue: {}", e)}
+            }
+        }
     }
 
     /// Notifies downstream to exclude this feed from the gRPC stream.
@@ -102,12 +164,8 @@ where ws::Subscriber<'a, T>: Subscribe, Self: ParseMsg {
         let mut orderbook= util::OrderBookTopN::default();
         orderbook.set_unreachable_price();
 
-        let feed_orderbook = util::FeedOrderBook{feed: self.feed.to_owned(), orderbook};
-
-        match &self.queue_tx.send(Box::new(feed_orderbook)).await {
-            Ok(_) => {}
-            Err(e) => {tracing::error!("Cannot send item to queue: {}", e)}
-        }
+        let feed_orderbook = util::FeedOrderBook::new(self.feed.to_owned(), orderbook, std::time::Instant::now());
+        self.forward(feed_orderbook).await;
     }
 
     /// Detects if anything in the order book has changed
@@ -126,28 +184,64 @@ where ws::Subscriber<'a, T>: Subscribe, Self: ParseMsg {
     /// the whole message to the previous one but compare only the top of the message. If we decide
     /// to go that way, same invariants apply as mentioned in the #Details section.
     fn has_orderbook_changed(&self, old_msg: &str, new_msg: &str) -> bool {
-        if old_msg[self.msg_offset_orderbook_start..] == new_msg[self.msg_offset_orderbook_start..]
-            {false} else {true}
+        let changed = old_msg[self.msg_offset_orderbook_start..] != new_msg[self.msg_offset_orderbook_start..];
+        metrics::get().feed_snapshots
+            .with_label_values(&[self.feed.feed_name_for_grpc_service(), if changed {"true"} else {"false"}])
+            .inc();
+        changed
+    }
+
+    /// Unsubscribes and closes the connection, waiting a bounded time for the venue to confirm it
+    async fn close(&mut self) {
+        self.subscriber.unsubscribe_from_l2_snap(&self.instrument_name).await;
+        if let Err(e) = self.subscriber.client.close().await {
+            tracing::warn!("Cannot close connection to feed {}: {:?}", self.feed, e);
+            return
+        }
+        // msgs sent before the venue processed the close frame are discarded
+        let closed = tokio::time::timeout(
+            std::time::Duration::from_millis(orderbook_snap_change_forwarder::CLOSE_TIMEOUT_MS),
+            async {while self.subscriber.client.read_msg().await.is_ok() {}}).await;
+        match closed {
+            Ok(_) => tracing::info!("Closed connection to feed {}", self.feed),
+            Err(_) => tracing::warn!("Feed {} didn't confirm closing the connection", self.feed)
+        }
     }
 
     /// Entry point for the task - worker
+    ///
+    /// Returns when shutdown is signalled, after the connection is closed.
     pub async fn run(&mut self) -> Result<(), error::ListenerError> {
         let mut old_msg = orderbook_snap_change_forwarder::INIT_DUMMY_MSG.to_owned();
-        self.subscriber.subscribe_to_l2_snap(&self.instrument_name).await
-            .change_context(error::ListenerError)?;
+        if !self.subscribed {
+            self.subscriber.subscribe_to_l2_snap(&self.instrument_name).await
+                .change_context(error::ListenerError)?;
+        }
 
         loop {
-            match self.subscriber.client.read_msg().await {
+            let read = tokio::select! {
+                biased;
+                _ = util::wait_for_shutdown(&mut self.shutdown_rx) => None,
+                read = self.subscriber.client.read_msg() => Some(read)
+            };
+            let Some(read) = read else {
+                self.close().await;
+                return Ok(())
+            };
+            match read {
                 Ok(msg) => {
+                    let read_at = std::time::Instant::now();
+                    let received_at = std::time::SystemTime::now();
+                    metrics::get().record_feed_read(self.feed, received_at);
+                    if let Some(recorder) = &self.recorder {
+                        recorder.record(self.feed, received_at, &msg);
+                    }
+                    metrics::get().feed_messages_received.with_label_values(&[self.feed.feed_name_for_grpc_service()]).inc();
                     if self.has_orderbook_changed(&old_msg, &msg) {
                         let orderbook = self.parse_orderbook_snap(self.feed.to_owned(), &msg);
-                        let feed_orderbook = util::FeedOrderBook{feed: self.feed.to_owned(), orderbook};
-
-                        //we might want to use a memory pool instead of `Box`ing `feed_orderbook`
-                        match &self.queue_tx.send(Box::new(feed_orderbook)).await {
-                            Ok(_) => {}
-                            Err(e) => {tracing::error!("Cannot send item to queue: {}", e)}
-                        }
+                        let feed_orderbook = util::FeedOrderBook::new(self.feed.to_owned(), orderbook, read_at);
+                        metrics::get().latency.record(latency::Stage::Parse, feed_orderbook.forwarded_at - read_at);
+                        self.forward(feed_orderbook).await;
                         old_msg = msg;
                     }
                 },
@@ -158,6 +252,7 @@ where ws::Subscriber<'a, T>: Subscribe, Self: ParseMsg {
                     match e.current_context() {
                         error::ClientError::EndpointClosedConnection => {
                             tracing::info!("WebSocket endpoint has closed the connection, attempting to reconnect to feed: {}", self.feed);
+                            metrics::get().feed_reconnects.with_label_values(&[self.feed.feed_name_for_grpc_service()]).inc();
 
                             // try to reestablish previous state
                             self.subscriber.client.reconnect().await
@@ -166,7 +261,10 @@ where ws::Subscriber<'a, T>: Subscribe, Self: ParseMsg {
                                 .change_context(error::ListenerError)?;
                         }
                         error::ClientError::Error => {tracing::error!("Could not read from websockets: {}", e)}
-                        error::ClientError::ParsingError => {tracing::error!("Websockets msg could not be parsed: {}", e)}
+                        error::ClientError::ParsingError => {
+                            tracing::error!("Websockets msg could not be parsed: {}", e);
+                            metrics::get().feed_parse_failures.with_label_values(&[self.feed.feed_name_for_grpc_service()]).inc();
+                        }
                     }
                 }
             };
@@ -178,56 +276,71 @@ where ws::Subscriber<'a, T>: Subscribe, Self: ParseMsg {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections;
+
+    use error_stack::Report;
 
 
+    const BINANCE_MSG: &str = "{\"stream\":\"ethbtc@depth20@100ms\",\"data\":{\"lastUpdateId\":6829472812,\"bids\":[[\"0.05612000\",\"4.50000000\"]],\"asks\":[[\"0.05613000\",\"3.10000000\"]]}}";
+
+    /// Venue sending the given msgs, then closing the connection for good
+    struct MockTransport {
+        msgs: collections::VecDeque<String>
+    }
+
+    #[async_trait::async_trait]
+    impl client::Transport for MockTransport {
+        async fn read_msg(&mut self) -> Result<String, error::ClientError> {
+            self.msgs.pop_front().ok_or_else(|| Report::new(error::ClientError::EndpointClosedConnection))
+        }
+
+        async fn send(&mut self, _msg: &serde_json::Value) {}
+
+        async fn reconnect(&mut self) -> Result<(), error::ClientError> {
+            Err(Report::new(error::ClientError::Error))
+        }
+
+        async fn close(&mut self) -> Result<(), error::ClientError> {
+            Ok(())
+        }
+    }
+
+    fn get_listener<T: feed::Feed>(feed: constants::Feed, msg_offset_orderbook_start: usize, msgs: &[&str],
+                                   queue_tx: mpsc::Sender<types::BoxedFeedOrderBook>) -> Listener<T, MockTransport>
+    where ws::Subscriber<T, MockTransport>: Subscribe, Listener<T, MockTransport>: ParseMsg {
+        let client = MockTransport {msgs: msgs.iter().map(|msg| msg.to_string()).collect()};
+        Listener::with_subscribed_client(feed, vec![queue_tx], msg_offset_orderbook_start, "ethbtc".to_owned(), client,
+                                         None, watch::channel(false).1)
+    }
+
     //test different cases for this method
     mod has_orderbook_changed {
         use super::*;
 
-        #[tokio::test]
-        async fn test_orderbook_not_changed() {
+        #[test]
+        fn test_orderbook_not_changed() {
             let new_msg = orderbook_snap_change_forwarder::INIT_DUMMY_MSG;
             let old_msg = orderbook_snap_change_forwarder::INIT_DUMMY_MSG;
 
             let (tx_binance, _) = mpsc::channel::<types::BoxedFeedOrderBook>(constants::QUEUE_BUFFER_SIZE);
             let tx_bitstamp = tx_binance.clone();
-            let cloned_instrument_name1 = "btceth".to_owned();
-            let cloned_instrument_name2 = cloned_instrument_name1.to_owned();
-            let binance_spot = Listener::<feed::BinanceSpot>::new(
-                constants::Feed::BinanceSpot,
-                tx_binance,
-                orderbook_snap_change_forwarder::msg_offset_orderbook_start::BINANCE,
-                cloned_instrument_name1,
-            ).await.expect("Cannot create listener");
-            let bitstamp_spot = Listener::<feed::BitstampSpot>::new(
-                constants::Feed::BitstampSpot,
-                tx_bitstamp,
-                orderbook_snap_change_forwarder::msg_offset_orderbook_start::BITSTAMP,
-                cloned_instrument_name2
-            ).await.expect("Cannot create listener");
+            let binance_spot = get_listener::<feed::BinanceSpot>(
+                constants::Feed::BinanceSpot, orderbook_snap_change_forwarder::msg_offset_orderbook_start::BINANCE, &[], tx_binance);
+            let bitstamp_spot = get_listener::<feed::BitstampSpot>(
+                constants::Feed::BitstampSpot, orderbook_snap_change_forwarder::msg_offset_orderbook_start::BITSTAMP, &[], tx_bitstamp);
 
             assert_eq!(binance_spot.has_orderbook_changed(&new_msg, &old_msg), false);
             assert_eq!(bitstamp_spot.has_orderbook_changed(&new_msg, &old_msg), false);
         }
 
-        #[tokio::test]
-        async fn test_orderbook_has_changed() {
+        #[test]
+        fn test_orderbook_has_changed() {
             let (tx_binance, _) = mpsc::channel::<types::BoxedFeedOrderBook>(constants::QUEUE_BUFFER_SIZE);
             let tx_bitstamp = tx_binance.clone();
-            let cloned_instrument_name1 = "btceth".to_owned();
-            let cloned_instrument_name2 = cloned_instrument_name1.to_owned();
-            let binance_spot = Listener::<feed::BinanceSpot>::new(
-                constants::Feed::BinanceSpot,
-                tx_binance,
-                orderbook_snap_change_forwarder::msg_offset_orderbook_start::BINANCE,
-                cloned_instrument_name1,
-            ).await.expect("Cannot create listener");
-            let bitstamp_spot = Listener::<feed::BitstampSpot>::new(
-                constants::Feed::BitstampSpot,
-                tx_bitstamp,
-                orderbook_snap_change_forwarder::msg_offset_orderbook_start::BITSTAMP,
-                cloned_instrument_name2
-            ).await.expect("Cannot create listener");
+            let binance_spot = get_listener::<feed::BinanceSpot>(
+                constants::Feed::BinanceSpot, orderbook_snap_change_forwarder::msg_offset_orderbook_start::BINANCE, &[], tx_binance);
+            let bitstamp_spot = get_listener::<feed::BitstampSpot>(
+                constants::Feed::BitstampSpot, orderbook_snap_change_forwarder::msg_offset_orderbook_start::BITSTAMP, &[], tx_bitstamp);
 
             let old_msg = orderbook_snap_change_forwarder::INIT_DUMMY_MSG;
             let mut new_msg= old_msg.to_owned();
@@ -237,4 +350,32 @@ mod tests {
             assert_eq!(bitstamp_spot.has_orderbook_changed(&new_msg, &old_msg), true);
         }
     }
+
+    mod run {
+        use super::*;
+
+        #[tokio::test]
+        async fn test_forwards_changed_orderbooks() {
+            let changed_msg = BINANCE_MSG.replace("3.10000000", "2.00000000");
+            let (queue_tx, mut queue_rx) = mpsc::channel::<types::BoxedFeedOrderBook>(constants::QUEUE_BUFFER_SIZE);
+            let mut listener = get_listener::<feed::BinanceSpot>(
+                constants::Feed::BinanceSpot, orderbook_snap_change_forwarder::msg_offset_orderbook_start::BINANCE,
+                &[BINANCE_MSG, BINANCE_MSG, &changed_msg], queue_tx);
+
+            // the venue closes the connection after the msgs and reconnecting fails
+            assert!(listener.run().await.is_err());
+            drop(listener);
+            let mut orderbooks = vec![];
+            while let Some(feed_orderbook) = queue_rx.recv().await {
+                orderbooks.push(feed_orderbook.orderbook);
+            }
+
+            assert_eq!(orderbooks.len(), 3);
+            assert_eq!(orderbooks[0].asks[0].price, rust_decimal::Decimal::new(5613, 5));
+            assert_eq!(orderbooks[0].bids[0].amount, rust_decimal::Decimal::new(45, 1));
+            assert_eq!(orderbooks[1].asks[0].amount, rust_decimal::Decimal::new(2, 0));
+            // excluded from the stream when the connection was closed
+            assert_eq!(orderbooks[2].asks[0].price, rust_decimal::Decimal::from(constants::ORDER_PRICE_INF));
+        }
+    }
 }
\ No newline at end of file
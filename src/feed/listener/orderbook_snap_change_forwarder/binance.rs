@@ -1,8 +1,9 @@
 use async_trait;
 
 use crate::constants::feed;
+use crate::feed::client;
 use crate::feed::listener::orderbook_snap_change_forwarder;
 
 
 #[async_trait::async_trait]
-impl<'a> orderbook_snap_change_forwarder::ParseMsg for orderbook_snap_change_forwarder::Listener<'a, feed::BinanceSpot> {}
\ No newline at end of file
+impl<C: client::Transport> orderbook_snap_change_forwarder::ParseMsg for orderbook_snap_change_forwarder::Listener<feed::BinanceSpot, C> {}
\ No newline at end of file
@@ -1,8 +1,17 @@
+use std::time;
+
 use async_trait;
+use gjson;
 
 use crate::constants::feed;
+use crate::feed::client;
 use crate::feed::listener::orderbook_snap_change_forwarder;
 
 
 #[async_trait::async_trait]
-impl<'a> orderbook_snap_change_forwarder::ParseMsg for orderbook_snap_change_forwarder::Listener<'a, feed::BitstampSpot> {}
\ No newline at end of file
+impl<C: client::Transport> orderbook_snap_change_forwarder::ParseMsg for orderbook_snap_change_forwarder::Listener<feed::BitstampSpot, C> {
+    fn parse_exchange_timestamp(&self, msg: &str) -> Option<time::SystemTime> {
+        let microseconds: u64 = gjson::get(msg, "data.microtimestamp").str().parse().ok()?;
+        time::UNIX_EPOCH.checked_add(time::Duration::from_micros(microseconds))
+    }
+}
\ No newline at end of file
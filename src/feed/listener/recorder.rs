@@ -0,0 +1,363 @@
+//! Recorder of raw feed messages
+//!
+//! Listeners hand every msg read from the venue to a `Recorder`, and a `RecordWriter` appends them
+//! to files on a dedicated thread, so disk I/O never stalls a listener. A file starts with a header
+//! (magic, format version, flags) followed by records, all of them compressed as a single LZ4 frame
+//! if the compressed flag is set. A record is:
+//!     - length of the msg: u32, little endian
+//!     - feed: u8, the `constants::Feed` discriminant
+//!     - receive timestamp: u64 nanoseconds since the UNIX epoch, little endian
+//!     - msg: UTF-8 bytes as received
+//!
+//! The writer starts a new file once the current one exceeds a size or age. Files are named by the
+//! time the writer started and their sequence number, so they sort in recording order.
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::path;
+use std::time;
+
+use error_stack::{IntoReport, Report, Result, ResultExt};
+use lz4_flex;
+use strum::IntoEnumIterator;
+use tokio::sync::mpsc;
+use tracing;
+
+use crate::constants;
+use crate::error;
+use crate::metrics;
+
+
+const MAGIC: &[u8; 4] = b"DFBR";
+const VERSION: u8 = 1;
+const FLAG_COMPRESSED: u8 = 1;
+const HEADER_SIZE: usize = 6;
+const RECORD_HEADER_SIZE: usize = 13;
+const FILE_EXTENSION: &str = "rec";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    pub feed: constants::Feed,
+    pub received_at: time::SystemTime,
+    pub msg: String
+}
+
+/// Listener side of the recorder, cheap to clone for every listener
+#[derive(Clone)]
+pub struct Recorder {
+    records_tx: mpsc::Sender<Record>
+}
+
+impl Recorder {
+    pub fn new(records_tx: mpsc::Sender<Record>) -> Recorder {
+        Recorder {records_tx}
+    }
+
+    /// Never waits, if the writer falls behind the msg is dropped and counted
+    pub fn record(&self, feed: constants::Feed, received_at: time::SystemTime, msg: &str) {
+        let record = Record {feed, received_at, msg: msg.to_owned()};
+        if self.records_tx.try_send(record).is_err() {
+            metrics::get().feed_recorder_dropped.with_label_values(&[feed.feed_name_for_grpc_service()]).inc();
+        }
+    }
+}
+
+/// Appends records to files in `dir` until all `Recorder`s are dropped
+pub struct RecordWriter {
+    pub records_rx: mpsc::Receiver<Record>,
+    pub dir: path::PathBuf,
+    pub compressed: bool,
+    /// Uncompressed bytes after which the next record goes to a new file
+    pub max_file_size: u64,
+    pub max_file_age: time::Duration
+}
+
+impl RecordWriter {
+    /// Entry point for the thread - worker, blocks on the queue and on disk I/O
+    pub fn run(&mut self) -> Result<(), error::RecorderError> {
+        fs::create_dir_all(&self.dir)
+            .into_report()
+            .change_context(error::RecorderError)
+            .attach_printable_lazy(|| format!("Cannot create recording directory {}", self.dir.display()))?;
+        let started_at = time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap_or_default();
+        let mut file: Option<RecordFile> = None;
+        let mut file_sequence: u64 = 0;
+
+        loop {
+            let record = match self.records_rx.try_recv() {
+                Ok(record) => record,
+                Err(mpsc::error::TryRecvError::Empty) => {
+                    // the queue is drained, so persist what we have before waiting for more
+                    if let Some(file) = file.as_mut() {
+                        file.flush()?;
+                    }
+                    match self.records_rx.blocking_recv() {
+                        Some(record) => record,
+                        None => break
+                    }
+                }
+                Err(mpsc::error::TryRecvError::Disconnected) => break
+            };
+
+            let rotate = file.as_ref().is_none_or(|file|
+                file.size >= self.max_file_size || file.opened_at.elapsed() >= self.max_file_age);
+            if rotate {
+                if let Some(file) = file.take() {
+                    file.finish()?;
+                }
+                let path = self.dir.join(format!("feeds-{}-{:06}.{}", started_at.as_millis(), file_sequence, FILE_EXTENSION));
+                file = Some(RecordFile::create(path, self.compressed)?);
+                file_sequence += 1;
+            }
+            if let Some(file) = file.as_mut() {
+                file.write_record(&record)?;
+            }
+        }
+
+        if let Some(file) = file {
+            file.finish()?;
+        }
+        tracing::info!("Recorder stopped");
+        Ok(())
+    }
+}
+
+enum Sink {
+    Plain(io::BufWriter<fs::File>),
+    Compressed(lz4_flex::frame::FrameEncoder<io::BufWriter<fs::File>>)
+}
+
+struct RecordFile {
+    path: path::PathBuf,
+    sink: Sink,
+    size: u64,
+    opened_at: time::Instant
+}
+
+impl RecordFile {
+    fn create(path: path::PathBuf, compressed: bool) -> Result<RecordFile, error::RecorderError> {
+        let mut writer = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .map(io::BufWriter::new)
+            .into_report()
+            .change_context(error::RecorderError)
+            .attach_printable_lazy(|| format!("Cannot create recording {}", path.display()))?;
+        writer.write_all(MAGIC)
+            .and_then(|_| writer.write_all(&[VERSION, if compressed {FLAG_COMPRESSED} else {0}]))
+            .into_report()
+            .change_context(error::RecorderError)
+            .attach_printable_lazy(|| format!("Cannot write header of {}", path.display()))?;
+        let sink = if compressed {
+            Sink::Compressed(lz4_flex::frame::FrameEncoder::new(writer))
+        } else {
+            Sink::Plain(writer)
+        };
+        tracing::info!("Recording to {}", path.display());
+
+        Ok(RecordFile {path, sink, size: HEADER_SIZE as u64, opened_at: time::Instant::now()})
+    }
+
+    fn writer(&mut self) -> &mut dyn Write {
+        match &mut self.sink {
+            Sink::Plain(writer) => writer,
+            Sink::Compressed(writer) => writer
+        }
+    }
+
+    fn write_record(&mut self, record: &Record) -> Result<(), error::RecorderError> {
+        let msg = record.msg.as_bytes();
+        let length = u32::try_from(msg.len())
+            .into_report()
+            .change_context(error::RecorderError)
+            .attach_printable("Msg is too long to record")?;
+        let received_at = record.received_at.duration_since(time::UNIX_EPOCH).unwrap_or_default();
+        let received_at = u64::try_from(received_at.as_nanos()).unwrap_or(u64::MAX);
+
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+        header[0..4].copy_from_slice(&length.to_le_bytes());
+        header[4] = record.feed as u8;
+        header[5..13].copy_from_slice(&received_at.to_le_bytes());
+        let writer = self.writer();
+        writer.write_all(&header)
+            .and_then(|_| writer.write_all(msg))
+            .into_report()
+            .change_context(error::RecorderError)
+            .attach_printable_lazy(|| format!("Cannot write record to {}", self.path.display()))?;
+        self.size += (RECORD_HEADER_SIZE + msg.len()) as u64;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), error::RecorderError> {
+        self.writer().flush()
+            .into_report()
+            .change_context(error::RecorderError)
+            .attach_printable_lazy(|| format!("Cannot flush {}", self.path.display()))
+    }
+
+    /// Writes the end of the LZ4 frame, if compressed, and flushes
+    fn finish(self) -> Result<(), error::RecorderError> {
+        let path = self.path;
+        let writer = match self.sink {
+            Sink::Plain(writer) => writer,
+            Sink::Compressed(writer) => writer.finish()
+                .into_report()
+                .change_context(error::RecorderError)
+                .attach_printable_lazy(|| format!("Cannot finish compressed {}", path.display()))?
+        };
+        writer.into_inner()
+            .map_err(|e| Report::new(error::RecorderError)
+                .attach_printable(format!("Cannot flush {}: {}", path.display(), e.error())))?;
+        Ok(())
+    }
+}
+
+/// Reads records of a file written by `RecordWriter`
+pub struct RecordReader {
+    path: path::PathBuf,
+    reader: Box<dyn Read + Send>
+}
+
+impl RecordReader {
+    pub fn open(path: &path::Path) -> Result<RecordReader, error::RecorderError> {
+        let mut reader = fs::File::open(path)
+            .map(io::BufReader::new)
+            .into_report()
+            .change_context(error::RecorderError)
+            .attach_printable_lazy(|| format!("Cannot open recording {}", path.display()))?;
+        let mut header = [0u8; HEADER_SIZE];
+        reader.read_exact(&mut header)
+            .into_report()
+            .change_context(error::RecorderError)
+            .attach_printable_lazy(|| format!("Cannot read header of {}", path.display()))?;
+        if &header[0..4] != MAGIC || header[4] != VERSION {
+            return Err(Report::new(error::RecorderError)
+                .attach_printable(format!("{} is not a version {} recording", path.display(), VERSION)))
+        }
+        let reader: Box<dyn Read + Send> = if header[5] & FLAG_COMPRESSED != 0 {
+            Box::new(lz4_flex::frame::FrameDecoder::new(reader))
+        } else {
+            Box::new(reader)
+        };
+
+        Ok(RecordReader {path: path.to_owned(), reader})
+    }
+
+    /// Next record, `None` at the end of the file
+    pub fn read_record(&mut self) -> Result<Option<Record>, error::RecorderError> {
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+        match read_full(&mut self.reader, &mut header) {
+            Ok(0) => return Ok(None),
+            Ok(RECORD_HEADER_SIZE) => {}
+            Ok(_) => return Err(Report::new(error::RecorderError)
+                .attach_printable(format!("Truncated record in {}", self.path.display()))),
+            Err(e) => return Err(Report::new(e)
+                .change_context(error::RecorderError)
+                .attach_printable(format!("Cannot read record from {}", self.path.display())))
+        }
+        let length = u32::from_le_bytes(header[0..4].try_into().expect("Slice of 4 bytes")) as usize;
+        let feed = constants::Feed::iter().nth(header[4] as usize)
+            .ok_or_else(|| Report::new(error::RecorderError)
+                .attach_printable(format!("Unknown feed {} in {}", header[4], self.path.display())))?;
+        let received_at = u64::from_le_bytes(header[5..13].try_into().expect("Slice of 8 bytes"));
+
+        let mut msg = vec![0u8; length];
+        self.reader.read_exact(&mut msg)
+            .into_report()
+            .change_context(error::RecorderError)
+            .attach_printable_lazy(|| format!("Truncated record in {}", self.path.display()))?;
+        let msg = String::from_utf8(msg)
+            .into_report()
+            .change_context(error::RecorderError)
+            .attach_printable_lazy(|| format!("Msg is not UTF-8 in {}", self.path.display()))?;
+
+        Ok(Some(Record {feed, received_at: time::UNIX_EPOCH + time::Duration::from_nanos(received_at), msg}))
+    }
+}
+
+/// Reads until the buffer is full or the end of the stream, returns the number of bytes read
+fn read_full(reader: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buffer.len() {
+        match reader.read(&mut buffer[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(read)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    mod run {
+        use super::*;
+
+        fn record(feed: constants::Feed, msg: &str) -> Record {
+            Record {feed, received_at: time::UNIX_EPOCH + time::Duration::from_nanos(1_686_616_236_740_643_123), msg: msg.to_owned()}
+        }
+
+        fn write(dir: &path::Path, compressed: bool, max_file_size: u64, records: &[Record]) -> Vec<path::PathBuf> {
+            let (records_tx, records_rx) = mpsc::channel(records.len());
+            let recorder = Recorder::new(records_tx);
+            for record in records {
+                recorder.record(record.feed, record.received_at, &record.msg);
+            }
+            drop(recorder);
+            let mut writer = RecordWriter {
+                records_rx, dir: dir.to_owned(), compressed, max_file_size, max_file_age: time::Duration::from_secs(3600)
+            };
+            writer.run().expect("Expected records written");
+
+            let mut paths: Vec<path::PathBuf> = fs::read_dir(dir).expect("Expected recording directory")
+                .map(|entry| entry.expect("Expected directory entry").path())
+                .collect();
+            paths.sort();
+            paths
+        }
+
+        fn read(paths: &[path::PathBuf]) -> Vec<Record> {
+            let mut records = vec![];
+            for path in paths {
+                let mut reader = RecordReader::open(path).expect("Expected recording");
+                while let Some(record) = reader.read_record().expect("Expected record") {
+                    records.push(record);
+                }
+            }
+            records
+        }
+
+        #[test]
+        fn test_round_trip() {
+            let records = [
+                record(constants::Feed::BinanceSpot, "{\"stream\":\"ethbtc@depth20@100ms\"}"),
+                record(constants::Feed::BitstampSpot, "{\"event\":\"data\",\"channel\":\"order_book_ethbtc\"}")];
+            for compressed in [false, true] {
+                let dir = tempfile::tempdir().expect("Expected recording directory");
+                let paths = write(dir.path(), compressed, u64::MAX, &records);
+
+                assert_eq!(paths.len(), 1);
+                assert_eq!(read(&paths), records);
+            }
+        }
+
+        #[test]
+        fn test_rotation_by_size() {
+            let dir = tempfile::tempdir().expect("Expected recording directory");
+            let records = [
+                record(constants::Feed::BinanceSpot, "first"),
+                record(constants::Feed::BinanceSpot, "second")];
+            // each file is full after its first record
+            let paths = write(dir.path(), false, 1, &records);
+
+            assert_eq!(paths.len(), 2);
+            assert_eq!(read(&paths), records);
+        }
+    }
+}
\ No newline at end of file
@@ -1 +1,4 @@
+pub mod arbitrage;
+pub mod microstructure;
+pub mod synthetic;
 pub mod top_bbo;
\ No newline at end of file
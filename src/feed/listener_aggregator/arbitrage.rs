@@ -0,0 +1,447 @@
+//! Detects cross-venue arbitrage opportunities and publishes them to the gRPC service
+//!
+//! Consumes queue from `orderbook_snap_change_forwarder` listener. The aggregated book is crossed
+//! when the best bid on one feed is above the best ask on another feed (locked when they're equal).
+//! For each pair of feeds we walk both order books to find the amount that can be bought on one and
+//! sold on the other with a profit after taker fees.
+use std;
+use std::sync::Arc;
+use std::time;
+
+use rust_decimal;
+use strum::EnumCount;
+use tokio::sync::{broadcast, mpsc};
+
+use crate::constants;
+use crate::constants::feed_aggregator;
+use crate::types;
+use crate::util;
+
+
+pub struct Aggregator {
+    pub queue_rx: mpsc::Receiver<types::BoxedFeedOrderBook>,
+    pub queue_tx: Arc<broadcast::Sender<types::BoxedArbitrageOpportunity>>,
+    /// Maker/taker fees indexed by `constants::Feed`
+    pub fee_schedules: [util::FeeSchedule; constants::Feed::COUNT],
+    /// Minimum time between two reports of the same, still open, opportunity
+    pub debounce_interval: time::Duration
+}
+
+/// Opportunity we've last reported for a (buy feed, sell feed) pair
+#[derive(Clone, Copy)]
+struct ActiveOpportunity {
+    reported: util::ArbitrageOpportunity,
+    detected: time::Instant,
+    last_reported: time::Instant,
+    /// A change was suppressed by the debounce, it's published once the interval has passed
+    suppressed: bool
+}
+
+/// Result of walking the buy feed's asks against the sell feed's bids
+struct Crossing {
+    condition: util::ArbitrageCondition,
+    best_ask: rust_decimal::Decimal,
+    best_bid: rust_decimal::Decimal,
+    amount: rust_decimal::Decimal,
+    gross_profit: rust_decimal::Decimal,
+    net_profit: rust_decimal::Decimal
+}
+
+type ActiveOpportunities = [[Option<ActiveOpportunity>; constants::Feed::COUNT]; constants::Feed::COUNT];
+
+impl Aggregator {
+    /// Runs the aggregator task, should be run in it's own thread
+    ///
+    /// Unlike `top_bbo` we block while the queue is empty as opportunities are not on the latency
+    /// critical path of the BBO stream. When there's backlog in the queue, we catch up to the latest
+    /// market state before we run the detection. We also wake up when a suppressed change is due,
+    /// so it's published even if no other order book arrives.
+    pub fn run(&mut self) {
+        // the timer of suppressed changes needs a runtime, the aggregator keeps it's own thread
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .expect("Cannot build arbitrage detector runtime");
+        runtime.block_on(self.run_detection());
+    }
+
+    async fn run_detection(&mut self) {
+        let mut orderbooks = util::get_initialized_orderbooks();
+        let mut active_opportunities: ActiveOpportunities = Default::default();
+
+        loop {
+            let due_at = self.get_next_due_at(&active_opportunities);
+            // the sleep is created even when the branch is disabled
+            let sleep = tokio::time::sleep_until(tokio::time::Instant::from_std(due_at.unwrap_or_else(time::Instant::now)));
+            tokio::select! {
+                feed_orderbook = self.queue_rx.recv() => {
+                    let Some(feed_orderbook) = feed_orderbook else {break};
+                    orderbooks[feed_orderbook.feed as usize] = feed_orderbook.orderbook;
+
+                    // process backlog
+                    while let Ok(feed_orderbook) = self.queue_rx.try_recv() {
+                        orderbooks[feed_orderbook.feed as usize] = feed_orderbook.orderbook;
+                    }
+                }
+                _ = sleep, if due_at.is_some() => {}
+            }
+            self.detect(&orderbooks, &mut active_opportunities);
+        }
+    }
+
+    /// When the earliest suppressed change can be published
+    fn get_next_due_at(&self, active_opportunities: &ActiveOpportunities) -> Option<time::Instant> {
+        active_opportunities.iter()
+            .flatten()
+            .flatten()
+            .filter(|active| active.suppressed)
+            .map(|active| active.last_reported + self.debounce_interval)
+            .min()
+    }
+
+    /// Publishes opened, changed and closed opportunities for every pair of feeds
+    ///
+    /// Repeated reports of an unchanged opportunity are suppressed and a changed one is reported at
+    /// most once per `debounce_interval`, a suppressed change is marked and reported by the first
+    /// detection after the interval. Closing an opportunity is always reported.
+    fn detect(&self, orderbooks: &types::OrderBooksByFeed,
+              active_opportunities: &mut ActiveOpportunities) {
+        let now = time::Instant::now();
+        let timestamp = time::SystemTime::now();
+
+        // derived books can't be traded
+        for buy_feed in constants::Feed::venues() {
+            for sell_feed in constants::Feed::venues() {
+                if buy_feed as usize == sell_feed as usize {continue}
+
+                let active_opportunity = &mut active_opportunities[buy_feed as usize][sell_feed as usize];
+                let crossing = walk_orderbooks(
+                    &orderbooks[buy_feed as usize],
+                    &orderbooks[sell_feed as usize],
+                    &self.fee_schedules[buy_feed as usize],
+                    &self.fee_schedules[sell_feed as usize]);
+
+                match (crossing, active_opportunity.as_mut()) {
+                    (Some(crossing), None) => {
+                        let opportunity = util::ArbitrageOpportunity {
+                            state: util::ArbitrageState::Opened,
+                            condition: crossing.condition,
+                            buy_feed,
+                            sell_feed,
+                            best_ask: crossing.best_ask,
+                            best_bid: crossing.best_bid,
+                            amount: crossing.amount,
+                            gross_profit: crossing.gross_profit,
+                            net_profit: crossing.net_profit,
+                            detected_at: timestamp,
+                            timestamp,
+                            duration: time::Duration::ZERO
+                        };
+                        *active_opportunity = Some(
+                            ActiveOpportunity{reported: opportunity, detected: now, last_reported: now, suppressed: false});
+                        self.publish(opportunity);
+                    }
+                    (Some(crossing), Some(active)) => {
+                        let reported = &active.reported;
+                        let is_unchanged = reported.condition == crossing.condition
+                            && reported.best_ask == crossing.best_ask
+                            && reported.best_bid == crossing.best_bid
+                            && reported.amount == crossing.amount
+                            && reported.net_profit == crossing.net_profit;
+                        if is_unchanged {
+                            active.suppressed = false;
+                            continue
+                        }
+                        if now.duration_since(active.last_reported) < self.debounce_interval {
+                            active.suppressed = true;
+                            continue
+                        }
+                        let opportunity = util::ArbitrageOpportunity {
+                            state: util::ArbitrageState::Updated,
+                            condition: crossing.condition,
+                            best_ask: crossing.best_ask,
+                            best_bid: crossing.best_bid,
+                            amount: crossing.amount,
+                            gross_profit: crossing.gross_profit,
+                            net_profit: crossing.net_profit,
+                            timestamp,
+                            duration: now.duration_since(active.detected),
+                            ..*reported
+                        };
+                        active.reported = opportunity;
+                        active.last_reported = now;
+                        active.suppressed = false;
+                        self.publish(opportunity);
+                    }
+                    (None, Some(active)) => {
+                        let opportunity = util::ArbitrageOpportunity {
+                            state: util::ArbitrageState::Closed,
+                            timestamp,
+                            duration: now.duration_since(active.detected),
+                            ..active.reported
+                        };
+                        *active_opportunity = None;
+                        self.publish(opportunity);
+                    }
+                    (None, None) => {}
+                }
+            }
+        }
+    }
+
+    fn publish(&self, opportunity: util::ArbitrageOpportunity) {
+        match self.queue_tx.send(Box::new(opportunity)) {
+            Ok(_) => {
+                //msg is sent
+            }
+            Err(_) => {
+                //nobody subscribed to this broadcast yet
+            }
+        }
+    }
+}
+
+/// Walks asks of the buy order book against bids of the sell order book
+///
+/// Returns `None` if the books are neither crossed nor locked. Otherwise we accumulate levels while
+/// the next unit is still profitable after taker fees on both feeds. A crossing that is not
+/// profitable after fees is reported with zero amount.
+fn walk_orderbooks(buy_orderbook: &util::OrderBookTopN, sell_orderbook: &util::OrderBookTopN,
+                   buy_fees: &util::FeeSchedule, sell_fees: &util::FeeSchedule) -> Option<Crossing> {
+    let best_ask = buy_orderbook.asks[0].price;
+    let best_bid = sell_orderbook.bids[0].price;
+    let condition = if best_bid > best_ask {
+        util::ArbitrageCondition::Crossed
+    } else if best_bid == best_ask {
+        util::ArbitrageCondition::Locked
+    } else {
+        return None
+    };
+
+    let mut crossing = Crossing {
+        condition,
+        best_ask,
+        best_bid,
+        amount: rust_decimal::Decimal::ZERO,
+        gross_profit: rust_decimal::Decimal::ZERO,
+        net_profit: rust_decimal::Decimal::ZERO
+    };
+    let (mut ask_id, mut bid_id) = (0, 0);
+    let mut ask_amount_left = buy_orderbook.asks[0].amount;
+    let mut bid_amount_left = sell_orderbook.bids[0].amount;
+
+    while ask_id < feed_aggregator::TOP_N_BBO && bid_id < feed_aggregator::TOP_N_BBO {
+        let ask = &buy_orderbook.asks[ask_id];
+        let bid = &sell_orderbook.bids[bid_id];
+        let net_profit_per_unit = sell_fees.effective_bid_price(bid.price) - buy_fees.effective_ask_price(ask.price);
+        if net_profit_per_unit <= rust_decimal::Decimal::ZERO {break}
+
+        let amount = std::cmp::min(ask_amount_left, bid_amount_left);
+        crossing.amount += amount;
+        crossing.gross_profit += amount * (bid.price - ask.price);
+        crossing.net_profit += amount * net_profit_per_unit;
+        ask_amount_left -= amount;
+        bid_amount_left -= amount;
+
+        // at least one of the levels is exhausted, move to the next one
+        if ask_amount_left.is_zero() {
+            ask_id += 1;
+            if ask_id < feed_aggregator::TOP_N_BBO {ask_amount_left = buy_orderbook.asks[ask_id].amount}
+        }
+        if bid_amount_left.is_zero() {
+            bid_id += 1;
+            if bid_id < feed_aggregator::TOP_N_BBO {bid_amount_left = sell_orderbook.bids[bid_id].amount}
+        }
+    }
+    Some(crossing)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    fn set_level(orders: &mut [util::Order], feed: constants::Feed, levels: &[(i64, i64)]) {
+        for (order, (price, amount)) in orders.iter_mut().zip(levels) {
+            *order = util::Order{feed, price: rust_decimal::Decimal::from(*price), amount: rust_decimal::Decimal::from(*amount)};
+        }
+    }
+
+    /// Bitstamp bids are above Binance asks for the first two levels
+    fn get_crossed_orderbooks() -> types::OrderBooksByFeed {
+        let mut orderbooks = util::get_initialized_orderbooks();
+        let binance = &mut orderbooks[constants::Feed::BinanceSpot as usize];
+        set_level(&mut binance.asks, constants::Feed::BinanceSpot, &[(100, 1), (101, 2), (105, 5)]);
+        set_level(&mut binance.bids, constants::Feed::BinanceSpot, &[(99, 1)]);
+        let bitstamp = &mut orderbooks[constants::Feed::BitstampSpot as usize];
+        set_level(&mut bitstamp.asks, constants::Feed::BitstampSpot, &[(104, 1)]);
+        set_level(&mut bitstamp.bids, constants::Feed::BitstampSpot, &[(103, 2), (102, 3)]);
+        orderbooks
+    }
+
+    mod walk_orderbooks {
+        use super::*;
+
+        #[test]
+        fn test_not_crossed() {
+            let orderbooks = get_crossed_orderbooks();
+            let fees = util::FeeSchedule::default();
+
+            assert!(walk_orderbooks(
+                &orderbooks[constants::Feed::BitstampSpot as usize],
+                &orderbooks[constants::Feed::BinanceSpot as usize],
+                &fees, &fees).is_none());
+        }
+
+        #[test]
+        fn test_crossed_without_fees() {
+            let orderbooks = get_crossed_orderbooks();
+            let fees = util::FeeSchedule::default();
+            let crossing = walk_orderbooks(
+                &orderbooks[constants::Feed::BinanceSpot as usize],
+                &orderbooks[constants::Feed::BitstampSpot as usize],
+                &fees, &fees).expect("Expected crossed books");
+
+            // buy 1@100 + 2@101, sell 2@103 + 1@102
+            assert_eq!(crossing.condition, util::ArbitrageCondition::Crossed);
+            assert_eq!(crossing.amount, rust_decimal::Decimal::from(3));
+            assert_eq!(crossing.gross_profit, rust_decimal::Decimal::from(6));
+            assert_eq!(crossing.net_profit, crossing.gross_profit);
+        }
+
+        #[test]
+        fn test_crossed_with_fees() {
+            let orderbooks = get_crossed_orderbooks();
+            let fees = util::FeeSchedule{maker_bps: rust_decimal::Decimal::ZERO, taker_bps: rust_decimal::Decimal::from(50)};
+            let crossing = walk_orderbooks(
+                &orderbooks[constants::Feed::BinanceSpot as usize],
+                &orderbooks[constants::Feed::BitstampSpot as usize],
+                &fees, &fees).expect("Expected crossed books");
+
+            // after fees only buying 1@100.5 + 1@101.505 and selling 2@102.485 is profitable
+            assert_eq!(crossing.amount, rust_decimal::Decimal::from(2));
+            assert_eq!(crossing.gross_profit, rust_decimal::Decimal::from(5));
+            assert_eq!(crossing.net_profit, rust_decimal::Decimal::new(2965, 3));
+        }
+    }
+
+    mod detect {
+        use super::*;
+
+        fn get_aggregator(debounce_interval: time::Duration)
+            -> (Aggregator, broadcast::Receiver<types::BoxedArbitrageOpportunity>) {
+            let (_, queue_rx) = mpsc::channel::<types::BoxedFeedOrderBook>(1);
+            let (queue_tx, broadcast_rx) = broadcast::channel::<types::BoxedArbitrageOpportunity>(16);
+            let aggregator = Aggregator{
+                queue_rx,
+                queue_tx: Arc::new(queue_tx),
+                fee_schedules: [util::FeeSchedule::default(); constants::Feed::COUNT],
+                debounce_interval
+            };
+            (aggregator, broadcast_rx)
+        }
+
+        #[test]
+        fn test_opportunity_lifecycle() {
+            let (aggregator, mut broadcast_rx) = get_aggregator(time::Duration::ZERO);
+            let mut active_opportunities: ActiveOpportunities = Default::default();
+            let mut orderbooks = get_crossed_orderbooks();
+
+            aggregator.detect(&orderbooks, &mut active_opportunities);
+            let opportunity = broadcast_rx.try_recv().expect("Expected opened opportunity");
+            assert_eq!(opportunity.state, util::ArbitrageState::Opened);
+            assert_eq!(opportunity.buy_feed as usize, constants::Feed::BinanceSpot as usize);
+            assert_eq!(opportunity.sell_feed as usize, constants::Feed::BitstampSpot as usize);
+
+            // the same opportunity is not reported again
+            aggregator.detect(&orderbooks, &mut active_opportunities);
+            assert!(broadcast_rx.try_recv().is_err());
+
+            orderbooks[constants::Feed::BitstampSpot as usize].bids[0].price = rust_decimal::Decimal::from(104);
+            aggregator.detect(&orderbooks, &mut active_opportunities);
+            let opportunity = broadcast_rx.try_recv().expect("Expected updated opportunity");
+            assert_eq!(opportunity.state, util::ArbitrageState::Updated);
+            assert_eq!(opportunity.amount, rust_decimal::Decimal::from(3));
+
+            orderbooks[constants::Feed::BitstampSpot as usize].set_unreachable_price();
+            aggregator.detect(&orderbooks, &mut active_opportunities);
+            let opportunity = broadcast_rx.try_recv().expect("Expected closed opportunity");
+            assert_eq!(opportunity.state, util::ArbitrageState::Closed);
+            assert!(opportunity.detected_at <= opportunity.timestamp);
+        }
+
+        #[test]
+        fn test_debounced_change() {
+            let debounce_interval = time::Duration::from_millis(50);
+            let (aggregator, mut broadcast_rx) = get_aggregator(debounce_interval);
+            let mut active_opportunities: ActiveOpportunities = Default::default();
+            let mut orderbooks = get_crossed_orderbooks();
+
+            aggregator.detect(&orderbooks, &mut active_opportunities);
+            broadcast_rx.try_recv().expect("Expected opened opportunity");
+
+            // changes within the interval are suppressed, the latest one is due after the interval
+            orderbooks[constants::Feed::BitstampSpot as usize].bids[0].price = rust_decimal::Decimal::from(104);
+            aggregator.detect(&orderbooks, &mut active_opportunities);
+            orderbooks[constants::Feed::BitstampSpot as usize].bids[0].amount = rust_decimal::Decimal::from(1);
+            aggregator.detect(&orderbooks, &mut active_opportunities);
+            assert!(broadcast_rx.try_recv().is_err());
+            let due_at = aggregator.get_next_due_at(&active_opportunities).expect("Expected suppressed change");
+
+            std::thread::sleep(due_at.saturating_duration_since(time::Instant::now()));
+            aggregator.detect(&orderbooks, &mut active_opportunities);
+            let opportunity = broadcast_rx.try_recv().expect("Expected updated opportunity");
+            assert_eq!(opportunity.state, util::ArbitrageState::Updated);
+            assert_eq!(opportunity.best_bid, rust_decimal::Decimal::from(104));
+            // buy 1@100 + 2@101, sell 1@104 + 2@102
+            assert_eq!(opportunity.amount, rust_decimal::Decimal::from(3));
+            assert!(aggregator.get_next_due_at(&active_opportunities).is_none());
+        }
+    }
+
+    mod run {
+        use super::*;
+
+        #[test]
+        fn test_publishes_suppressed_change_without_new_orderbook() {
+            let (queue_tx, queue_rx) = mpsc::channel::<types::BoxedFeedOrderBook>(16);
+            let (broadcast_tx, mut broadcast_rx) = broadcast::channel::<types::BoxedArbitrageOpportunity>(16);
+            let mut aggregator = Aggregator{
+                queue_rx,
+                queue_tx: Arc::new(broadcast_tx),
+                fee_schedules: [util::FeeSchedule::default(); constants::Feed::COUNT],
+                debounce_interval: time::Duration::from_millis(50)
+            };
+            let aggregator_handle = std::thread::spawn(move || aggregator.run());
+            let send = move |orderbooks: &types::OrderBooksByFeed| {
+                for feed in [constants::Feed::BinanceSpot, constants::Feed::BitstampSpot] {
+                    let feed_orderbook = util::FeedOrderBook::new(feed, orderbooks[feed as usize], time::Instant::now());
+                    queue_tx.blocking_send(Box::new(feed_orderbook)).expect("Expected queued order book");
+                }
+            };
+            let recv = |broadcast_rx: &mut broadcast::Receiver<types::BoxedArbitrageOpportunity>| {
+                let deadline = time::Instant::now() + time::Duration::from_secs(5);
+                loop {
+                    match broadcast_rx.try_recv() {
+                        Ok(opportunity) => return opportunity,
+                        Err(_) if time::Instant::now() < deadline => std::thread::sleep(time::Duration::from_millis(1)),
+                        Err(e) => panic!("Expected opportunity: {}", e)
+                    }
+                }
+            };
+
+            let mut orderbooks = get_crossed_orderbooks();
+            send(&orderbooks);
+            assert_eq!(recv(&mut broadcast_rx).state, util::ArbitrageState::Opened);
+            orderbooks[constants::Feed::BitstampSpot as usize].bids[0].price = rust_decimal::Decimal::from(104);
+            send(&orderbooks);
+
+            let opportunity = recv(&mut broadcast_rx);
+            assert_eq!(opportunity.state, util::ArbitrageState::Updated);
+            assert_eq!(opportunity.best_bid, rust_decimal::Decimal::from(104));
+            drop(send);
+            aggregator_handle.join().expect("Aggregator stops when the queue is closed");
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,164 @@
+//! Calculates market microstructure signals of the aggregated book and publishes them to the gRPC
+//! service
+//!
+//! Consumes queue from `orderbook_snap_change_forwarder` listener. On each update we calculate mid
+//! price, microprice, top N order book imbalance, cumulative depth at offsets from mid price and each
+//! feed's contribution to the top of the book, so clients don't need to recalculate them from
+//! `Summary` levels.
+use std::sync::Arc;
+
+use rust_decimal;
+use strum::IntoEnumIterator;
+use tokio::sync::{broadcast, mpsc};
+
+use crate::constants;
+use crate::constants::feed_aggregator::microstructure;
+use crate::types;
+use crate::util;
+
+
+pub struct Aggregator {
+    pub queue_rx: mpsc::Receiver<types::BoxedFeedOrderBook>,
+    pub queue_tx: Arc<broadcast::Sender<types::BoxedMicrostructureSignals>>
+}
+
+impl Aggregator {
+    /// Runs the aggregator task, should be run in it's own thread
+    ///
+    /// We block while the queue is empty. When there's backlog in the queue, we catch up to the
+    /// latest market state before we run the calculations.
+    pub fn run(&mut self) {
+        let mut orderbooks = util::get_initialized_orderbooks();
+
+        while let Some(feed_orderbook) = self.queue_rx.blocking_recv() {
+            orderbooks[feed_orderbook.feed as usize] = feed_orderbook.orderbook;
+
+            // process backlog
+            while let Ok(feed_orderbook) = self.queue_rx.try_recv() {
+                orderbooks[feed_orderbook.feed as usize] = feed_orderbook.orderbook;
+            }
+
+            if let Some(signals) = get_signals(&orderbooks) {
+                match self.queue_tx.send(Box::new(signals)) {
+                    Ok(_) => {
+                        //msg is sent
+                    }
+                    Err(_) => {
+                        //nobody subscribed to this broadcast yet
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Calculates signals from the aggregated book
+///
+/// Returns `None` while the aggregated book has no bids or no asks e.g. all feeds are excluded.
+pub fn get_signals(orderbooks: &types::OrderBooksByFeed) -> Option<util::MicrostructureSignals> {
+    let (asks, bids) = util::get_aggregated_orders(orderbooks);
+    let (best_ask, best_bid) = (asks.first()?, bids.first()?);
+    let two = rust_decimal::Decimal::TWO;
+    let bps_per_unit = rust_decimal::Decimal::from(constants::BPS_PER_UNIT);
+    let mid_price = (best_ask.price + best_bid.price) / two;
+
+    // amounts at the best price, summed over feeds
+    let best_ask_amount: rust_decimal::Decimal = asks.iter()
+        .take_while(|order| order.price == best_ask.price).map(|order| order.amount).sum();
+    let best_bid_amount: rust_decimal::Decimal = bids.iter()
+        .take_while(|order| order.price == best_bid.price).map(|order| order.amount).sum();
+    let microprice = (best_ask.price * best_bid_amount + best_bid.price * best_ask_amount)
+        .checked_div(best_bid_amount + best_ask_amount)
+        .unwrap_or(mid_price);
+
+    let imbalances = microstructure::IMBALANCE_LEVELS.iter().map(|levels| {
+        let ask_amount: rust_decimal::Decimal = asks.iter().take(*levels).map(|order| order.amount).sum();
+        let bid_amount: rust_decimal::Decimal = bids.iter().take(*levels).map(|order| order.amount).sum();
+        util::OrderBookImbalance {
+            levels: *levels,
+            imbalance: (bid_amount - ask_amount).checked_div(bid_amount + ask_amount)
+                .unwrap_or(rust_decimal::Decimal::ZERO)
+        }
+    }).collect();
+
+    let depths = microstructure::DEPTH_OFFSETS_BPS.iter().map(|offset_bps| {
+        let offset_bps = rust_decimal::Decimal::from(*offset_bps);
+        let ask_limit = mid_price + mid_price * offset_bps / bps_per_unit;
+        let bid_limit = mid_price - mid_price * offset_bps / bps_per_unit;
+        util::DepthAtOffset {
+            offset_bps,
+            ask_amount: asks.iter().take_while(|order| order.price <= ask_limit).map(|order| order.amount).sum(),
+            bid_amount: bids.iter().take_while(|order| order.price >= bid_limit).map(|order| order.amount).sum()
+        }
+    }).collect();
+
+    let contributions = constants::Feed::iter().map(|feed| {
+        let orderbook = &orderbooks[feed as usize];
+        let ask_amount: rust_decimal::Decimal = orderbook.asks.iter()
+            .filter(|order| order.price == best_ask.price).map(|order| order.amount).sum();
+        let bid_amount: rust_decimal::Decimal = orderbook.bids.iter()
+            .filter(|order| order.price == best_bid.price).map(|order| order.amount).sum();
+        util::FeedContribution {
+            feed,
+            ask_amount,
+            bid_amount,
+            ask_share: ask_amount.checked_div(best_ask_amount).unwrap_or(rust_decimal::Decimal::ZERO),
+            bid_share: bid_amount.checked_div(best_bid_amount).unwrap_or(rust_decimal::Decimal::ZERO)
+        }
+    }).collect();
+
+    Some(util::MicrostructureSignals {mid_price, microprice, imbalances, depths, contributions})
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    fn set_levels(orders: &mut [util::Order], feed: constants::Feed, levels: &[(i64, i64)]) {
+        for (order, (price, amount)) in orders.iter_mut().zip(levels) {
+            *order = util::Order{feed, price: rust_decimal::Decimal::from(*price), amount: rust_decimal::Decimal::from(*amount)};
+        }
+    }
+
+    #[test]
+    fn test_no_signals_without_both_sides() {
+        let orderbooks = util::get_initialized_orderbooks();
+
+        assert!(get_signals(&orderbooks).is_none());
+    }
+
+    #[test]
+    fn test_signals() {
+        let mut orderbooks = util::get_initialized_orderbooks();
+        let binance = &mut orderbooks[constants::Feed::BinanceSpot as usize];
+        set_levels(&mut binance.asks, constants::Feed::BinanceSpot, &[(10010, 1), (10020, 2)]);
+        set_levels(&mut binance.bids, constants::Feed::BinanceSpot, &[(9990, 3), (9900, 4)]);
+        let bitstamp = &mut orderbooks[constants::Feed::BitstampSpot as usize];
+        set_levels(&mut bitstamp.asks, constants::Feed::BitstampSpot, &[(10010, 1), (10200, 5)]);
+        set_levels(&mut bitstamp.bids, constants::Feed::BitstampSpot, &[(9980, 1)]);
+        let signals = get_signals(&orderbooks).expect("Expected signals");
+
+        assert_eq!(signals.mid_price, rust_decimal::Decimal::from(10000));
+        // (10010 * 3 + 9990 * 2) / 5
+        assert_eq!(signals.microprice, rust_decimal::Decimal::from(10002));
+
+        assert_eq!(signals.imbalances[0].levels, 1);
+        assert_eq!(signals.imbalances[0].imbalance, rust_decimal::Decimal::new(5, 1));
+        // bids 3 + 1 + 4, asks 1 + 1 + 2 + 5
+        assert_eq!(signals.imbalances[1].imbalance, rust_decimal::Decimal::new(-1, 0) / rust_decimal::Decimal::from(17));
+
+        // 10 bps from mid is [9990, 10010], 25 bps is [9975, 10025]
+        assert_eq!(signals.depths[1].bid_amount, rust_decimal::Decimal::from(3));
+        assert_eq!(signals.depths[1].ask_amount, rust_decimal::Decimal::from(2));
+        assert_eq!(signals.depths[2].bid_amount, rust_decimal::Decimal::from(4));
+        assert_eq!(signals.depths[2].ask_amount, rust_decimal::Decimal::from(4));
+
+        let binance = &signals.contributions[constants::Feed::BinanceSpot as usize];
+        assert_eq!(binance.ask_share, rust_decimal::Decimal::new(5, 1));
+        assert_eq!(binance.bid_share, rust_decimal::Decimal::ONE);
+        let bitstamp = &signals.contributions[constants::Feed::BitstampSpot as usize];
+        assert_eq!(bitstamp.bid_share, rust_decimal::Decimal::ZERO);
+    }
+}
\ No newline at end of file
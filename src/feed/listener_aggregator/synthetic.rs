@@ -0,0 +1,202 @@
+//! Builds a synthetic cross-rate order book from order books of two instruments
+//!
+//! E.g. an ETH/BTC book from ETH/USDT (base leg) and BTC/USDT (quote leg). Consumes one queue per
+//! leg from `orderbook_snap_change_forwarder` listeners and forwards the synthetic book tagged with
+//! `constants::Feed::Synthetic` to other listener aggregators, so it's merged into `top_bbo`
+//! alongside native books.
+use std;
+
+use rust_decimal;
+use rust_decimal::RoundingStrategy;
+use tokio;
+use tokio::sync::mpsc;
+use tracing;
+
+use crate::constants;
+use crate::constants::feed_aggregator;
+use crate::types;
+use crate::util;
+
+
+pub struct Aggregator {
+    /// Order books of the base instrument in the common currency e.g. ETH/USDT
+    pub base_leg_rx: mpsc::Receiver<types::BoxedFeedOrderBook>,
+    /// Order books of the quote instrument in the common currency e.g. BTC/USDT
+    pub quote_leg_rx: mpsc::Receiver<types::BoxedFeedOrderBook>,
+    /// Each queue is consumed by a different listener aggregator
+    pub queues_tx: Vec<mpsc::Sender<types::BoxedFeedOrderBook>>
+}
+
+impl Aggregator {
+    /// Entry point for the task - worker
+    ///
+    /// A new synthetic book is forwarded on every update of either leg once both legs are known.
+    pub async fn run(&mut self) {
+        let mut base_orderbook: Option<util::OrderBookTopN> = None;
+        let mut quote_orderbook: Option<util::OrderBookTopN> = None;
+
+        loop {
+            // the synthetic book is traced from the read of the leg that updated it
+            let read_at = tokio::select! {
+                Some(feed_orderbook) = self.base_leg_rx.recv() => {
+                    base_orderbook = Some(feed_orderbook.orderbook);
+                    feed_orderbook.read_at
+                }
+                Some(feed_orderbook) = self.quote_leg_rx.recv() => {
+                    quote_orderbook = Some(feed_orderbook.orderbook);
+                    feed_orderbook.read_at
+                }
+                else => {
+                    tracing::info!("Both synthetic leg queues are closed");
+                    break
+                }
+            };
+
+            if let (Some(base_orderbook), Some(quote_orderbook)) = (&base_orderbook, &quote_orderbook) {
+                let orderbook = get_synthetic_orderbook(base_orderbook, quote_orderbook);
+                let feed_orderbook = util::FeedOrderBook::new(constants::Feed::Synthetic, orderbook, read_at);
+
+                for queue_tx in &self.queues_tx {
+                    match queue_tx.send(Box::new(feed_orderbook)).await {
+                        Ok(_) => {}
+                        Err(e) => {tracing::error!("Cannot send item to queue: {}", e)}
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Combines order books of both legs into the synthetic order book
+///
+/// Selling the base instrument synthetically means hitting base leg bids and lifting quote leg asks,
+/// buying it means lifting base leg asks and hitting quote leg bids. If either leg is excluded
+/// (unreachable prices), so are the levels of the synthetic book.
+pub fn get_synthetic_orderbook(base_orderbook: &util::OrderBookTopN, quote_orderbook: &util::OrderBookTopN)
+    -> util::OrderBookTopN {
+    let mut orderbook = util::OrderBookTopN::default();
+    orderbook.set_unreachable_price();
+
+    walk_legs(&mut orderbook.bids, &base_orderbook.bids, &quote_orderbook.asks, RoundingStrategy::ToZero);
+    walk_legs(&mut orderbook.asks, &base_orderbook.asks, &quote_orderbook.bids, RoundingStrategy::AwayFromZero);
+    // the synthetic book is as fresh as the latest leg update
+    orderbook.exchange_timestamp = std::cmp::max(base_orderbook.exchange_timestamp, quote_orderbook.exchange_timestamp);
+    orderbook.received_at = std::cmp::max(base_orderbook.received_at, quote_orderbook.received_at);
+    orderbook
+}
+
+/// Walks depth on both legs and fills synthetic levels
+///
+/// Both legs are priced in the common currency, so at each step we consume the notional in the
+/// common currency that both current levels can absorb. Each synthetic level is priced from the
+/// current level of each leg, so deeper levels get worse prices as both legs are walked. To stay
+/// conservative, prices are rounded against us and amounts are rounded down.
+fn walk_legs(synthetic_orders: &mut [util::Order; feed_aggregator::TOP_N_BBO],
+             base_orders: &[util::Order; feed_aggregator::TOP_N_BBO],
+             quote_orders: &[util::Order; feed_aggregator::TOP_N_BBO],
+             price_rounding: RoundingStrategy) {
+    let inf = rust_decimal::Decimal::from(constants::ORDER_PRICE_INF);
+    let is_reachable = |order: &util::Order| {
+        order.price > rust_decimal::Decimal::ZERO && order.price < inf
+    };
+    let (mut base_id, mut quote_id, mut synthetic_id) = (0, 0, 0);
+    let mut base_amount_left = base_orders[0].amount;
+    let mut quote_amount_left = quote_orders[0].amount;
+
+    while synthetic_id < feed_aggregator::TOP_N_BBO
+        && base_id < feed_aggregator::TOP_N_BBO
+        && quote_id < feed_aggregator::TOP_N_BBO {
+        let base_order = &base_orders[base_id];
+        let quote_order = &quote_orders[quote_id];
+        if !is_reachable(base_order) || !is_reachable(quote_order) {break}
+
+        let base_notional = base_amount_left * base_order.price;
+        let quote_notional = quote_amount_left * quote_order.price;
+        let amount = if base_notional <= quote_notional {
+            quote_amount_left -= base_notional / quote_order.price;
+            std::mem::take(&mut base_amount_left)
+        } else {
+            base_amount_left -= quote_notional / base_order.price;
+            quote_amount_left = rust_decimal::Decimal::ZERO;
+            quote_notional / base_order.price
+        };
+        let amount = amount.round_dp_with_strategy(
+            feed_aggregator::synthetic::DECIMAL_PLACES, RoundingStrategy::ToZero);
+
+        if !amount.is_zero() {
+            synthetic_orders[synthetic_id] = util::Order {
+                feed: constants::Feed::Synthetic,
+                price: (base_order.price / quote_order.price).round_dp_with_strategy(
+                    feed_aggregator::synthetic::DECIMAL_PLACES, price_rounding),
+                amount
+            };
+            synthetic_id += 1;
+        }
+
+        // at least one of the levels is exhausted, move to the next one
+        if base_amount_left.is_zero() {
+            base_id += 1;
+            if base_id < feed_aggregator::TOP_N_BBO {base_amount_left = base_orders[base_id].amount}
+        }
+        if quote_amount_left.is_zero() {
+            quote_id += 1;
+            if quote_id < feed_aggregator::TOP_N_BBO {quote_amount_left = quote_orders[quote_id].amount}
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    fn get_orderbook(asks: &[(i64, i64)], bids: &[(i64, i64)]) -> util::OrderBookTopN {
+        let mut orderbook = util::OrderBookTopN::default();
+        orderbook.set_unreachable_price();
+        for (order, (price, amount)) in orderbook.asks.iter_mut().zip(asks) {
+            order.price = rust_decimal::Decimal::from(*price);
+            order.amount = rust_decimal::Decimal::from(*amount);
+        }
+        for (order, (price, amount)) in orderbook.bids.iter_mut().zip(bids) {
+            order.price = rust_decimal::Decimal::from(*price);
+            order.amount = rust_decimal::Decimal::from(*amount);
+        }
+        orderbook
+    }
+
+    #[test]
+    fn test_synthetic_levels() {
+        // ETH/USDT and BTC/USDT
+        let base_orderbook = get_orderbook(&[(2000, 10), (2010, 10)], &[(1990, 10), (1980, 10)]);
+        let quote_orderbook = get_orderbook(&[(40000, 1), (40100, 1)], &[(39900, 1), (39800, 1)]);
+        let orderbook = get_synthetic_orderbook(&base_orderbook, &quote_orderbook);
+
+        // the first BTC ask (40000 USDT) absorbs both ETH bid levels (19900 + 19800 USDT)
+        assert_eq!(orderbook.bids[0].price, rust_decimal::Decimal::new(4975, 5));
+        assert_eq!(orderbook.bids[0].amount, rust_decimal::Decimal::from(10));
+        assert_eq!(orderbook.bids[1].price, rust_decimal::Decimal::new(495, 4));
+        assert_eq!(orderbook.bids[1].amount, rust_decimal::Decimal::from(10));
+        assert!(matches!(orderbook.bids[1].feed, constants::Feed::Synthetic));
+        assert_eq!(orderbook.bids[2].price, rust_decimal::Decimal::from(-constants::ORDER_PRICE_INF));
+
+        // selling the first BTC (39900 USDT) buys 10 ETH at 2000 and the rest at 2010, then we
+        // continue on the next BTC bid
+        assert_eq!(orderbook.asks[0].price, rust_decimal::Decimal::new(5012532, 8));
+        assert_eq!(orderbook.asks[0].amount, rust_decimal::Decimal::from(10));
+        assert_eq!(orderbook.asks[1].price, rust_decimal::Decimal::new(5037594, 8));
+        assert_eq!(orderbook.asks[1].amount, rust_decimal::Decimal::new(990049751, 8));
+        assert_eq!(orderbook.asks[2].price, rust_decimal::Decimal::new(5050252, 8));
+    }
+
+    #[test]
+    fn test_excluded_leg() {
+        let base_orderbook = get_orderbook(&[(2000, 10)], &[(1990, 10)]);
+        let mut quote_orderbook = util::OrderBookTopN::default();
+        quote_orderbook.set_unreachable_price();
+        let orderbook = get_synthetic_orderbook(&base_orderbook, &quote_orderbook);
+
+        assert_eq!(orderbook.asks[0].price, rust_decimal::Decimal::from(constants::ORDER_PRICE_INF));
+        assert_eq!(orderbook.bids[0].price, rust_decimal::Decimal::from(-constants::ORDER_PRICE_INF));
+    }
+}
\ No newline at end of file
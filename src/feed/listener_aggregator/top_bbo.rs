@@ -1,43 +1,70 @@
 //! Aggregates top N BBO from multiple feeds and publishes to the gRPC service
 //!
-//! Consumes queue from `orderbook_snap_change_forwarder` listener.
+//! Consumes queue from `orderbook_snap_change_forwarder` listener. Optionally ranks the book by
+//! fee-inclusive effective prices, configured per feed with `util::FeeSchedule`.
 use std;
 use std::sync::Arc;
+use std::time;
 
 use rust_decimal;
-use rust_decimal::prelude::ToPrimitive;
 use strum::EnumCount;
-use tokio::sync::{broadcast, mpsc};
+use tokio::sync::{broadcast, mpsc, watch};
 use tracing;
 
 use crate::constants;
 use crate::constants::feed_aggregator;
-use crate::service::grpc::server::orderbook;
+use crate::metrics;
+use crate::metrics::latency;
 use crate::types;
 use crate::util;
 
 
 pub struct Aggregator {
     pub queue_rx: mpsc::Receiver<types::BoxedFeedOrderBook>,
-    pub queue_tx: Arc<broadcast::Sender<types::BoxedOrderbookSummary>>
+    pub queue_tx: Arc<broadcast::Sender<types::SharedAggregatedBook>>,
+    /// Latest order book of each feed, for consumers that need more than top N of the aggregated book
+    pub orderbooks_tx: watch::Sender<types::OrderBooksByFeed>,
+    /// Latest aggregated book, so new consumers don't wait for the next update
+    pub aggregated_book_tx: watch::Sender<Option<types::SharedAggregatedBook>>,
+    /// Maker/taker fees indexed by `constants::Feed`
+    pub fee_schedules: [util::FeeSchedule; constants::Feed::COUNT],
+    /// Additionally rank the book by fee-inclusive effective prices
+    pub fee_adjusted: bool,
+    /// Becomes `true` when the server shuts down
+    pub shutdown_rx: watch::Receiver<bool>
 }
 
 impl Aggregator {
     /// Runs the aggregator task, should be run in it's own thread
     ///
     /// When there's backlog in the queue, we try to catch up to the latest market state before we
-    /// run the calculations.
+    /// run the calculations. Returns when shutdown is signalled.
     pub fn run(&mut self) {
-        const RESERVED_SIZE:usize = constants::Feed::COUNT * feed_aggregator::TOP_N_BBO;
-        let mut orderbooks = get_initialized_orderbooks();
+        let mut orderbooks = util::get_initialized_orderbooks();
         let mut new_update_available = false;
+        let mut sequence: u64 = 0;
+        let mut backlog: i64 = 0;
+        // latest socket read and dequeue among the order books of the next calculation
+        let mut read_at = time::Instant::now();
+        let mut dequeued_at = time::Instant::now();
+        let mut queue_closed = false;
 
         loop {
+            // cheap enough to check on every iteration, it's an uncontended read lock
+            if *self.shutdown_rx.borrow() {
+                tracing::info!("Aggregator stopped");
+                break
+            }
+
             // process backlog
             loop {
                 match self.queue_rx.try_recv() {
                     Ok(feed_orderbook) => {
                         let feed_id = feed_orderbook.feed as usize;
+                        backlog += 1;
+                        dequeued_at = time::Instant::now();
+                        metrics::get().latency.record(latency::Stage::QueueWait, dequeued_at - feed_orderbook.forwarded_at);
+                        read_at = if new_update_available {read_at.max(feed_orderbook.read_at)} else {feed_orderbook.read_at};
 
                         // replace old order book reference with an updated one
                         orderbooks[feed_id] = feed_orderbook.orderbook;
@@ -48,81 +75,32 @@ impl Aggregator {
                         // market state, continue with calculations
                         break
                     }
-                    Err(e) => {tracing::error!("Cannot receive from queue: {}", e)}
+                    Err(mpsc::error::TryRecvError::Disconnected) => {
+                        // all listeners are gone, publish what we have and stop
+                        queue_closed = true;
+                        break
+                    }
                 }
             }
 
             if new_update_available {
-                // We could allocate the vector before the loop but since we're storing references,
-                // we'd have lifetime problems (the borrow checker doesn't recognize `.clear()`
-                // dropping the refs). Another approach is using `std::mem::transmute` but that's
-                // in the domain of unsafe code. Perhaps use a small memory pool.
-                let mut asks: Vec<&util::Order> = vec![];
-                let mut bids: Vec<&util::Order> = vec![];
-                let mut asks_grpc: Vec<orderbook::Level> = vec![];
-                let mut bids_grpc: Vec<orderbook::Level> = vec![];
-                asks.reserve(RESERVED_SIZE);
-                bids.reserve(RESERVED_SIZE);
-                asks_grpc.reserve(feed_aggregator::TOP_N_BBO);
-                bids_grpc.reserve(feed_aggregator::TOP_N_BBO);
-
-                // Get top of the book from all books.
-                // We concatenate only top N asks/bids from all order books to get sorted top N.
-                // For that to be true, asks/bids need to be ordered (which we observe in the data we
-                // receive).
-                for orderbook in orderbooks.iter() {
-                    let sliced_asks = &orderbook.asks[0..feed_aggregator::TOP_N_BBO];
-                    for order in sliced_asks.iter() { asks.push(order); }
-
-                    let sliced_bids = &orderbook.bids[0..feed_aggregator::TOP_N_BBO];
-                    for order in sliced_bids.iter() { bids.push(order); }
-                }
+                sequence += 1;
+                metrics::get().aggregator_queue_depth.set(backlog);
+                backlog = 0;
+                let started_at = time::Instant::now();
+                let aggregated_book = self.aggregate(&orderbooks, sequence, read_at);
+                metrics::get().aggregator_compute_seconds.observe(started_at.elapsed().as_secs_f64());
+                metrics::get().latency.record(latency::Stage::Aggregation, aggregated_book.aggregated_at - dequeued_at);
+                let aggregated_at = aggregated_book.aggregated_at;
+                // readers get the latest state without subscribing, so store it even if there's none
+                self.orderbooks_tx.send_replace(orderbooks);
+                // stored before broadcasting, so it's never older than what subscribers already received
+                let aggregated_book = Arc::new(aggregated_book);
+                self.aggregated_book_tx.send_replace(Some(aggregated_book.clone()));
 
-                // sort asks by (price increasing, amount decreasing)
-                asks.sort_unstable_by(|a, b| {
-                    if a.price == b.price {
-                        b.amount.cmp(&a.amount)
-                    } else {
-                        a.price.cmp(&b.price)
-                    }
-                });
-                // sort bids by (price decreasing, amount decreasing)
-                bids.sort_unstable_by(|a, b| {
-                    if a.price == b.price {
-                        b.amount.cmp(&a.amount)
-                    } else {
-                        b.price.cmp(&a.price)
-                    }
-                });
-
-                for ask in &asks[0..feed_aggregator::TOP_N_BBO] {
-                    asks_grpc.push(
-                        orderbook::Level {
-                            exchange: ask.feed.feed_name_for_grpc_service().to_owned(),
-                            price: ask.price.to_f64().unwrap(),
-                            amount: ask.amount.to_f64().unwrap()
-                        });
-                }
-                for bid in &bids[0..feed_aggregator::TOP_N_BBO] {
-                    bids_grpc.push(
-                        orderbook::Level {
-                            exchange: bid.feed.feed_name_for_grpc_service().to_owned(),
-                            price: bid.price.to_f64().unwrap(),
-                            amount: bid.amount.to_f64().unwrap()
-                        });
-                }
-
-                let spread = asks[0].price - bids[0].price;
-                let orderbook_summary = orderbook::Summary {
-                    spread: spread.to_f64().unwrap(),
-                    asks: asks_grpc,
-                    bids: bids_grpc
-                };
-
-                //The top_bbo aggregator could send a more general message suitable for multiple consumers.
-                //If that would be needed, we could introduce a transformer for the stream e.g. each
-                //stream consumer would have it's own (async) transformer (method).
-                match self.queue_tx.send(Box::new(orderbook_summary)) {
+                //We send a general message suitable for multiple consumers. Each stream consumer has
+                //it's own transformer to the message format it serves e.g. gRPC `Summary`.
+                match self.queue_tx.send(aggregated_book) {
                     Ok(_) => {
                         //msg is sent
                     }
@@ -130,20 +108,182 @@ impl Aggregator {
                         //nobody subscribed to this broadcast yet
                     }
                 }
+                metrics::get().latency.record(latency::Stage::Broadcast, aggregated_at.elapsed());
                 new_update_available = false;
             }
+
+            if queue_closed {
+                tracing::info!("Aggregator stopped, all listeners closed the queue");
+                break
+            }
+        }
+    }
+
+    /// Merges top N of all order books and ranks them
+    ///
+    /// We concatenate only top N asks/bids from all order books to get sorted top N. For that to be
+    /// true, asks/bids need to be ordered (which we observe in the data we receive). Effective
+    /// prices are calculated with `rust_decimal` so ranking by them is exact.
+    fn aggregate(&self, orderbooks: &types::OrderBooksByFeed, sequence: u64, read_at: time::Instant)
+        -> util::AggregatedBook {
+        const RESERVED_SIZE:usize = constants::Feed::COUNT * feed_aggregator::TOP_N_BBO;
+        let mut asks: Vec<util::RankedOrder> = Vec::with_capacity(RESERVED_SIZE);
+        let mut bids: Vec<util::RankedOrder> = Vec::with_capacity(RESERVED_SIZE);
+
+        // order books are indexed by feed, so are the fee schedules
+        for (orderbook, fees) in orderbooks.iter().zip(self.fee_schedules.iter()) {
+            let to_ranked_order = |order: &util::Order, effective_price| util::RankedOrder {
+                order: *order,
+                effective_price,
+                exchange_timestamp: orderbook.exchange_timestamp,
+                received_at: orderbook.received_at
+            };
+            for order in &orderbook.asks[0..feed_aggregator::TOP_N_BBO] {
+                asks.push(to_ranked_order(order, fees.effective_ask_price(order.price)));
+            }
+            for order in &orderbook.bids[0..feed_aggregator::TOP_N_BBO] {
+                bids.push(to_ranked_order(order, fees.effective_bid_price(order.price)));
+            }
+        }
+
+        let fee_adjusted = if self.fee_adjusted {
+            Some(rank(asks.clone(), bids.clone(), |ranked_order| ranked_order.effective_price))
+        } else {
+            None
+        };
+        util::AggregatedBook {
+            sequence,
+            published_at: time::SystemTime::now(),
+            read_at,
+            aggregated_at: time::Instant::now(),
+            raw: rank(asks, bids, |ranked_order| ranked_order.order.price),
+            fee_adjusted
         }
     }
 }
 
-/// Initializes order books to highest asks and lowest bids
+/// Sorts asks and bids by the given price and keeps top N of each, besides all of them
 ///
-/// Since when the program starts, not all order books have the representable value of the market,
-/// the ordered results would include default values i.e. price = 0 for e.g. asks. To prevent that,
-/// we initialize the values to practically positive and negative infinities.
-fn get_initialized_orderbooks() -> [util::OrderBookTopN; constants::Feed::COUNT]{
-    let mut orderbooks = [util::OrderBookTopN::default(); constants::Feed::COUNT];
-
-    for orderbook in &mut orderbooks {orderbook.set_unreachable_price();}
-    return orderbooks;
+/// Asks are sorted by (price increasing, amount decreasing) and bids by (price decreasing, amount
+/// decreasing).
+fn rank(mut asks: Vec<util::RankedOrder>, mut bids: Vec<util::RankedOrder>,
+        price: fn(&util::RankedOrder) -> rust_decimal::Decimal) -> util::RankedBook {
+    asks.sort_unstable_by(|a, b| {
+        if price(a) == price(b) {
+            b.order.amount.cmp(&a.order.amount)
+        } else {
+            price(a).cmp(&price(b))
+        }
+    });
+    bids.sort_unstable_by(|a, b| {
+        if price(a) == price(b) {
+            b.order.amount.cmp(&a.order.amount)
+        } else {
+            price(b).cmp(&price(a))
+        }
+    });
+    let top_asks = asks[..feed_aggregator::TOP_N_BBO].to_vec();
+    let top_bids = bids[..feed_aggregator::TOP_N_BBO].to_vec();
+
+    util::RankedBook {spread: price(&asks[0]) - price(&bids[0]), asks: top_asks, bids: top_bids, all_asks: asks, all_bids: bids}
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    mod aggregate {
+        use super::*;
+
+        fn get_aggregator(fee_adjusted: bool) -> Aggregator {
+            let (_, queue_rx) = mpsc::channel::<types::BoxedFeedOrderBook>(1);
+            let (queue_tx, _) = broadcast::channel::<types::SharedAggregatedBook>(1);
+            let (orderbooks_tx, _) = watch::channel(util::get_initialized_orderbooks());
+            let (aggregated_book_tx, _) = watch::channel(None);
+            let (_, shutdown_rx) = watch::channel(false);
+            let mut fee_schedules = [util::FeeSchedule::default(); constants::Feed::COUNT];
+            fee_schedules[constants::Feed::BinanceSpot as usize].taker_bps = rust_decimal::Decimal::from(10);
+
+            Aggregator {
+                queue_rx, queue_tx: Arc::new(queue_tx), orderbooks_tx, aggregated_book_tx, fee_schedules, fee_adjusted, shutdown_rx
+            }
+        }
+
+        /// Binance quotes a 1 bp better top of the book but charges 10 bps taker fee
+        fn get_orderbooks() -> types::OrderBooksByFeed {
+            let mut orderbooks = util::get_initialized_orderbooks();
+            for (feed, ask, bid) in [
+                (constants::Feed::BinanceSpot, 9999, 10001),
+                (constants::Feed::BitstampSpot, 10000, 10000)] {
+                let orderbook = &mut orderbooks[feed as usize];
+                orderbook.asks[0] = util::Order{feed, price: rust_decimal::Decimal::new(ask, 2), amount: rust_decimal::Decimal::ONE};
+                orderbook.bids[0] = util::Order{feed, price: rust_decimal::Decimal::new(bid, 2), amount: rust_decimal::Decimal::ONE};
+            }
+            orderbooks
+        }
+
+        #[test]
+        fn test_raw_ranking() {
+            let aggregated_book = get_aggregator(false).aggregate(&get_orderbooks(), 1, time::Instant::now());
+
+            assert!(aggregated_book.fee_adjusted.is_none());
+            assert_eq!(aggregated_book.raw.asks.len(), feed_aggregator::TOP_N_BBO);
+            assert_eq!(aggregated_book.raw.asks[0].order.feed as usize, constants::Feed::BinanceSpot as usize);
+            assert_eq!(aggregated_book.raw.bids[0].order.feed as usize, constants::Feed::BinanceSpot as usize);
+            assert_eq!(aggregated_book.raw.spread, rust_decimal::Decimal::new(-2, 2));
+        }
+
+        #[test]
+        fn test_fee_adjusted_ranking() {
+            let aggregated_book = get_aggregator(true).aggregate(&get_orderbooks(), 1, time::Instant::now());
+            let ranked_book = aggregated_book.fee_adjusted.expect("Expected fee-adjusted view");
+
+            assert_eq!(ranked_book.asks[0].order.feed as usize, constants::Feed::BitstampSpot as usize);
+            assert_eq!(ranked_book.bids[0].order.feed as usize, constants::Feed::BitstampSpot as usize);
+            assert_eq!(ranked_book.spread, rust_decimal::Decimal::ZERO);
+            // raw prices are still exposed next to effective prices
+            assert_eq!(ranked_book.asks[1].order.price, rust_decimal::Decimal::new(9999, 2));
+            assert_eq!(ranked_book.asks[1].effective_price, rust_decimal::Decimal::new(10008999, 5));
+        }
+    }
+
+
+    mod run {
+        use super::*;
+
+        fn get_aggregator(queue_rx: mpsc::Receiver<types::BoxedFeedOrderBook>, shutdown_rx: watch::Receiver<bool>)
+            -> Aggregator {
+            let (queue_tx, _) = broadcast::channel::<types::SharedAggregatedBook>(1);
+            let (orderbooks_tx, _) = watch::channel(util::get_initialized_orderbooks());
+            let (aggregated_book_tx, _) = watch::channel(None);
+            let fee_schedules = [util::FeeSchedule::default(); constants::Feed::COUNT];
+
+            Aggregator {
+                queue_rx, queue_tx: Arc::new(queue_tx), orderbooks_tx, aggregated_book_tx, fee_schedules, fee_adjusted: false, shutdown_rx
+            }
+        }
+
+        #[test]
+        fn test_shutdown() {
+            let (_queue_tx, queue_rx) = mpsc::channel::<types::BoxedFeedOrderBook>(1);
+            let (shutdown_tx, shutdown_rx) = watch::channel(false);
+            let aggregator = std::thread::spawn(move || get_aggregator(queue_rx, shutdown_rx).run());
+
+            std::thread::sleep(time::Duration::from_millis(10));
+            shutdown_tx.send_replace(true);
+            aggregator.join().expect("Aggregator stops");
+        }
+
+        #[test]
+        fn test_queue_closed() {
+            let (queue_tx, queue_rx) = mpsc::channel::<types::BoxedFeedOrderBook>(1);
+            let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+            let aggregator = std::thread::spawn(move || get_aggregator(queue_rx, shutdown_rx).run());
+
+            drop(queue_tx);
+            aggregator.join().expect("Aggregator stops");
+        }
+    }
 }
\ No newline at end of file
@@ -1 +1,2 @@
+pub mod fix;
 pub mod ws;
\ No newline at end of file
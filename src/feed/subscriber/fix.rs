@@ -0,0 +1,264 @@
+//! Market data subscriber on a FIX session
+//!
+//! Subscribes with a MarketDataRequest and keeps the top N levels of the venue's book from full
+//! and incremental refreshes, so listeners get the same `util::OrderBookTopN` as from WebSocket
+//! subscribers.
+use std::path;
+use std::str::FromStr;
+use std::time;
+
+use async_trait;
+use error_stack::{Report, Result, ResultExt};
+use rust_decimal;
+use tracing;
+
+use crate::constants;
+use crate::constants::feed_aggregator;
+use crate::error;
+use crate::feed::client;
+use crate::feed::subscriber::ws;
+use crate::fix::message::{self, md_entry_type, md_update_action, msg_type, tag};
+use crate::fix::session;
+use crate::util;
+
+
+pub struct Subscriber<'a> {
+    pub client: client::fix::ClientManager<'a>,
+    feed: constants::Feed,
+    orderbook: util::OrderBookTopN,
+    /// The snapshot read when subscribing wasn't returned yet
+    snapshot_pending: bool
+}
+
+impl<'a> Subscriber<'a> {
+    /// Logs on, orders of the book are tagged with `feed`
+    pub async fn new(feed: constants::Feed, feed_info: constants::FeedInfo<'a>, config: session::SessionConfig,
+                     store_dir: path::PathBuf) -> Result<Subscriber<'a>, error::SubscriberError> {
+        let client = client::fix::ClientManager::new(feed, feed_info, config, store_dir).await
+            .change_context(error::SubscriberError)?;
+        let mut orderbook = util::OrderBookTopN::default();
+        orderbook.set_unreachable_price();
+        Ok(Self{client, feed, orderbook, snapshot_pending: false})
+    }
+
+    /// Reads market data and returns the book after the update
+    ///
+    /// The first read after subscribing returns the snapshot the subscription was confirmed with.
+    pub async fn read_orderbook(&mut self) -> Result<util::OrderBookTopN, error::SubscriberError> {
+        if std::mem::take(&mut self.snapshot_pending) {
+            return Ok(self.orderbook)
+        }
+        loop {
+            let msg = self.client.read_msg().await.change_context(error::SubscriberError)?;
+            match msg.msg_type() {
+                msg_type::MARKET_DATA_SNAPSHOT_FULL_REFRESH | msg_type::MARKET_DATA_INCREMENTAL_REFRESH => {
+                    apply_market_data(&mut self.orderbook, self.feed, &msg);
+                    self.orderbook.exchange_timestamp = msg.get(tag::SENDING_TIME).and_then(message::parse_timestamp);
+                    self.orderbook.received_at = Some(time::SystemTime::now());
+                    return Ok(self.orderbook)
+                }
+                msg_type::MARKET_DATA_REQUEST_REJECT => {
+                    return Err(Report::new(error::SubscriberError)
+                        .attach_printable("Subscription rejected")
+                        .attach_printable(msg.get(tag::TEXT).unwrap_or_default().to_owned()))
+                }
+                _ => tracing::debug!("Ignoring message {}", msg.msg_type())
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<'a> ws::Subscribe for Subscriber<'a> {
+    async fn subscribe_to_l2_snap(&mut self, instrument_name: &str) -> Result<(), error::SubscriberError> {
+        self.client.send(get_market_data_request(instrument_name, 1)).await.change_context(error::SubscriberError)?;
+
+        // verify subscription succeeded, the first response is the snapshot or a reject
+        self.snapshot_pending = false;
+        self.read_orderbook().await?;
+        self.snapshot_pending = true;
+        tracing::info!("Subscribed to {}", instrument_name);
+        Ok(())
+    }
+
+    async fn unsubscribe_from_l2_snap(&mut self, instrument_name: &str) {
+        match self.client.send(get_market_data_request(instrument_name, 2)).await {
+            Ok(_) => tracing::info!("Unsubscribed from {}", instrument_name),
+            Err(e) => tracing::warn!("Cannot unsubscribe from {}: {:?}", instrument_name, e)
+        }
+    }
+}
+
+/// MarketDataRequest (V) for the top N of the book, `subscription_request_type` is 1 to subscribe
+/// and 2 to unsubscribe
+fn get_market_data_request(instrument_name: &str, subscription_request_type: u8) -> message::Message {
+    message::Message::new(msg_type::MARKET_DATA_REQUEST)
+        .with(tag::MD_REQ_ID, instrument_name)
+        .with(tag::SUBSCRIPTION_REQUEST_TYPE, subscription_request_type)
+        .with(tag::MARKET_DEPTH, feed_aggregator::TOP_N_BBO)
+        .with(tag::MD_UPDATE_TYPE, 1)
+        .with(tag::NO_MD_ENTRY_TYPES, 2)
+        .with(tag::MD_ENTRY_TYPE, md_entry_type::BID)
+        .with(tag::MD_ENTRY_TYPE, md_entry_type::OFFER)
+        .with(tag::NO_RELATED_SYM, 1)
+        .with(tag::SYMBOL, instrument_name)
+}
+
+#[derive(Default)]
+struct Entry<'a> {
+    update_action: &'a str,
+    entry_type: &'a str,
+    price: Option<rust_decimal::Decimal>,
+    amount: Option<rust_decimal::Decimal>,
+    position: Option<usize>
+}
+
+/// Applies a full (W) or incremental (X) refresh to the book
+///
+/// Entries are placed by `MDEntryPositionNo`, or by price if the venue doesn't send it. New entries
+/// shift the levels below down and deletes shift them up, levels beyond top N are dropped.
+pub fn apply_market_data(orderbook: &mut util::OrderBookTopN, feed: constants::Feed, msg: &message::Message) {
+    let is_full_refresh = msg.msg_type() == msg_type::MARKET_DATA_SNAPSHOT_FULL_REFRESH;
+    if is_full_refresh {
+        *orderbook = util::OrderBookTopN::default();
+        orderbook.set_unreachable_price();
+    }
+
+    for entry in parse_entries(msg) {
+        let is_bid = match entry.entry_type {
+            md_entry_type::BID => true,
+            md_entry_type::OFFER => false,
+            _ => continue
+        };
+        let levels = if is_bid {&mut orderbook.bids} else {&mut orderbook.asks};
+        let update_action = if is_full_refresh {md_update_action::NEW} else {entry.update_action};
+        let price = entry.price.unwrap_or_default();
+        let index = match (entry.position, update_action) {
+            (Some(position), _) => position.checked_sub(1),
+            (None, md_update_action::NEW) if is_full_refresh => levels.iter().position(|order| order.amount.is_zero()),
+            (None, md_update_action::NEW) => levels.iter()
+                .position(|order| if is_bid {order.price < price} else {order.price > price}),
+            (None, _) => levels.iter().position(|order| order.price == price)
+        };
+        let Some(index) = index.filter(|index| *index < feed_aggregator::TOP_N_BBO) else {continue};
+        let order = util::Order{feed, price, amount: entry.amount.unwrap_or_default()};
+
+        match update_action {
+            md_update_action::NEW if is_full_refresh => levels[index] = order,
+            md_update_action::NEW => {
+                levels[index..].rotate_right(1);
+                levels[index] = order;
+            }
+            md_update_action::CHANGE => levels[index] = order,
+            md_update_action::DELETE => {
+                levels[index..].rotate_left(1);
+                levels[feed_aggregator::TOP_N_BBO - 1] = get_empty_level(feed, is_bid);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Walks the `NoMDEntries` group, each entry starts with the first tag of the group
+fn parse_entries(msg: &message::Message) -> Vec<Entry<'_>> {
+    let fields = msg.fields();
+    let Some(start) = fields.iter().position(|(field_tag, _)| *field_tag == tag::NO_MD_ENTRIES) else {
+        return vec![]
+    };
+    let group = &fields[start + 1..];
+    let Some(delimiter) = group.first().map(|(field_tag, _)| *field_tag) else {
+        return vec![]
+    };
+
+    let mut entries: Vec<Entry> = vec![];
+    for (field_tag, value) in group {
+        if *field_tag == delimiter {entries.push(Entry::default())}
+        let entry = entries.last_mut().expect("The group starts with the delimiter");
+        match *field_tag {
+            tag::MD_UPDATE_ACTION => entry.update_action = value,
+            tag::MD_ENTRY_TYPE => entry.entry_type = value,
+            tag::MD_ENTRY_PX => entry.price = rust_decimal::Decimal::from_str(value).ok(),
+            tag::MD_ENTRY_SIZE => entry.amount = rust_decimal::Decimal::from_str(value).ok(),
+            tag::MD_ENTRY_POSITION_NO => entry.position = value.parse().ok(),
+            _ => {}
+        }
+    }
+    entries
+}
+
+fn get_empty_level(feed: constants::Feed, is_bid: bool) -> util::Order {
+    let price = if is_bid {-constants::ORDER_PRICE_INF} else {constants::ORDER_PRICE_INF};
+    util::Order{feed, price: rust_decimal::Decimal::from(price), amount: rust_decimal::Decimal::ZERO}
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    fn get_entry(msg: message::Message, update_action: Option<&str>, entry_type: &str, price: i64, amount: i64,
+                 position: Option<usize>) -> message::Message {
+        let mut msg = msg;
+        if let Some(update_action) = update_action {msg.push(tag::MD_UPDATE_ACTION, update_action)}
+        msg.push(tag::MD_ENTRY_TYPE, entry_type);
+        msg.push(tag::MD_ENTRY_PX, price);
+        msg.push(tag::MD_ENTRY_SIZE, amount);
+        if let Some(position) = position {msg.push(tag::MD_ENTRY_POSITION_NO, position)}
+        msg
+    }
+
+    fn get_snapshot() -> message::Message {
+        let msg = message::Message::new(msg_type::MARKET_DATA_SNAPSHOT_FULL_REFRESH)
+            .with(tag::SYMBOL, "ethbtc")
+            .with(tag::NO_MD_ENTRIES, 3);
+        let msg = get_entry(msg, None, md_entry_type::BID, 100, 1, Some(1));
+        let msg = get_entry(msg, None, md_entry_type::BID, 99, 2, Some(2));
+        get_entry(msg, None, md_entry_type::OFFER, 101, 3, Some(1))
+    }
+
+    mod apply_market_data {
+        use super::*;
+
+
+        #[test]
+        fn test_snapshot() {
+            let mut orderbook = util::OrderBookTopN::default();
+            apply_market_data(&mut orderbook, constants::Feed::BinanceSpot, &get_snapshot());
+
+            assert_eq!(orderbook.bids[1].price, rust_decimal::Decimal::from(99));
+            assert_eq!(orderbook.asks[0].amount, rust_decimal::Decimal::from(3));
+            assert_eq!(orderbook.asks[1].price, rust_decimal::Decimal::from(constants::ORDER_PRICE_INF));
+        }
+
+        #[test]
+        fn test_incremental_by_position() {
+            let mut orderbook = util::OrderBookTopN::default();
+            apply_market_data(&mut orderbook, constants::Feed::BinanceSpot, &get_snapshot());
+            let msg = message::Message::new(msg_type::MARKET_DATA_INCREMENTAL_REFRESH).with(tag::NO_MD_ENTRIES, 3);
+            let msg = get_entry(msg, Some(md_update_action::NEW), md_entry_type::BID, 100, 5, Some(1));
+            let msg = get_entry(msg, Some(md_update_action::DELETE), md_entry_type::BID, 99, 2, Some(3));
+            let msg = get_entry(msg, Some(md_update_action::CHANGE), md_entry_type::OFFER, 101, 1, Some(1));
+            apply_market_data(&mut orderbook, constants::Feed::BinanceSpot, &msg);
+
+            let bids: Vec<(i64, i64)> = orderbook.bids[..3].iter()
+                .map(|order| (order.price.try_into().unwrap(), order.amount.try_into().unwrap()))
+                .collect();
+            assert_eq!(bids, vec![(100, 5), (100, 1), (-constants::ORDER_PRICE_INF as i64, 0)]);
+            assert_eq!(orderbook.asks[0].amount, rust_decimal::Decimal::ONE);
+        }
+
+        #[test]
+        fn test_incremental_by_price() {
+            let mut orderbook = util::OrderBookTopN::default();
+            apply_market_data(&mut orderbook, constants::Feed::BinanceSpot, &get_snapshot());
+            let msg = message::Message::new(msg_type::MARKET_DATA_INCREMENTAL_REFRESH).with(tag::NO_MD_ENTRIES, 2);
+            let msg = get_entry(msg, Some(md_update_action::NEW), md_entry_type::OFFER, 102, 4, None);
+            let msg = get_entry(msg, Some(md_update_action::DELETE), md_entry_type::BID, 100, 0, None);
+            apply_market_data(&mut orderbook, constants::Feed::BinanceSpot, &msg);
+
+            assert_eq!(orderbook.asks[1].price, rust_decimal::Decimal::from(102));
+            assert_eq!(orderbook.bids[0].price, rust_decimal::Decimal::from(99));
+        }
+    }
+}
\ No newline at end of file
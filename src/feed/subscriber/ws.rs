@@ -15,17 +15,27 @@ use crate::feed::client;
 #[async_trait::async_trait]
 pub trait Subscribe: Send {
     async fn subscribe_to_l2_snap(&mut self, instrument_name: &str) -> Result<(), error::SubscriberError>;
+    /// Sends the unsubscribe request without waiting for the venue to confirm it
+    async fn unsubscribe_from_l2_snap(&mut self, instrument_name: &str);
 }
 
-pub struct Subscriber<'a, T: feed::Feed> {
-    pub client: client::ws::ClientManager<'a>,
+pub struct Subscriber<T: feed::Feed, C: client::Transport> {
+    pub client: C,
     marker: std::marker::PhantomData<T>
 }
 
-impl<'a, T: feed::Feed> Subscriber<'a, T> {
-    pub async fn new(feed_info: constants::FeedInfo<'a>) -> Result<Subscriber<'a, T>, error::SubscriberError> {
+impl<'a, T: feed::Feed> Subscriber<T, client::ws::ClientManager<'a>> {
+    /// Connects to the venue over WebSockets
+    pub async fn new(feed_info: constants::FeedInfo<'a>)
+        -> Result<Subscriber<T, client::ws::ClientManager<'a>>, error::SubscriberError> {
         let client = client::ws::ClientManager::new(feed_info).await
             .change_context(error::SubscriberError)?;
-        Ok(Self{client, marker: std::marker::PhantomData})
+        Ok(Self::with_client(client))
+    }
+}
+
+impl<T: feed::Feed, C: client::Transport> Subscriber<T, C> {
+    pub fn with_client(client: C) -> Subscriber<T, C> {
+        Self{client, marker: std::marker::PhantomData}
     }
 }
\ No newline at end of file
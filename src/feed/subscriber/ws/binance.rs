@@ -5,11 +5,12 @@ use tracing;
 
 use crate::constants::feed;
 use crate::error;
+use crate::feed::client;
 use crate::feed::subscriber::ws;
 
 
 #[async_trait::async_trait]
-impl<'a> ws::Subscribe for ws::Subscriber<'a, feed::BinanceSpot> {
+impl<C: client::Transport> ws::Subscribe for ws::Subscriber<feed::BinanceSpot, C> {
     async fn subscribe_to_l2_snap(&mut self, instrument_name: &str) -> Result<(), error::SubscriberError> {
         let rq = serde_json::json!({
             "method": "SUBSCRIBE",
@@ -36,4 +37,16 @@ impl<'a> ws::Subscribe for ws::Subscriber<'a, feed::BinanceSpot> {
         }
         Ok(())
     }
+
+    async fn unsubscribe_from_l2_snap(&mut self, instrument_name: &str) {
+        let rq = serde_json::json!({
+            "method": "UNSUBSCRIBE",
+            "params": [
+                format!("{}@depth20@100ms", instrument_name)
+            ],
+            "id": 2
+        });
+        self.client.send(&rq).await;
+        tracing::info!("Unsubscribed from {}", instrument_name);
+    }
 }
\ No newline at end of file
@@ -5,11 +5,12 @@ use tracing;
 
 use crate::constants::feed;
 use crate::error;
+use crate::feed::client;
 use crate::feed::subscriber::ws;
 
 
 #[async_trait::async_trait]
-impl<'a> ws::Subscribe for ws::Subscriber<'a, feed::BitstampSpot> {
+impl<C: client::Transport> ws::Subscribe for ws::Subscriber<feed::BitstampSpot, C> {
     async fn subscribe_to_l2_snap(&mut self, instrument_name: &str) -> Result<(), error::SubscriberError> {
         let rq = serde_json::json!({
             "event": "bts:subscribe",
@@ -40,4 +41,15 @@ impl<'a> ws::Subscribe for ws::Subscriber<'a, feed::BitstampSpot> {
         }
         Ok(())
     }
+
+    async fn unsubscribe_from_l2_snap(&mut self, instrument_name: &str) {
+        let rq = serde_json::json!({
+            "event": "bts:unsubscribe",
+            "data": {
+                "channel": format!("order_book_{}", instrument_name)
+            }
+        });
+        self.client.send(&rq).await;
+        tracing::info!("Unsubscribed from {}", instrument_name);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,5 @@
+pub mod message;
+pub mod session;
+pub mod store;
+#[cfg(test)]
+pub mod test_util;
\ No newline at end of file
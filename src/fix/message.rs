@@ -0,0 +1,339 @@
+//! FIX tag=value message encoding and decoding
+//!
+//! A message keeps its fields in order, without `BeginString`, `BodyLength` and `CheckSum` which are
+//! calculated when the message is encoded. Repeating groups are kept as consecutive fields, it's up
+//! to the application to walk them.
+use std::time;
+
+use error_stack::{Report, Result};
+
+use crate::constants;
+use crate::error;
+
+
+pub const SOH: u8 = 0x01;
+
+pub mod tag {
+    pub const BEGIN_SEQ_NO: u32 = 7;
+    pub const BEGIN_STRING: u32 = 8;
+    pub const BODY_LENGTH: u32 = 9;
+    pub const CHECK_SUM: u32 = 10;
+    pub const END_SEQ_NO: u32 = 16;
+    pub const MSG_SEQ_NUM: u32 = 34;
+    pub const MSG_TYPE: u32 = 35;
+    pub const NEW_SEQ_NO: u32 = 36;
+    pub const POSS_DUP_FLAG: u32 = 43;
+    pub const REF_SEQ_NUM: u32 = 45;
+    pub const SENDER_COMP_ID: u32 = 49;
+    pub const SENDING_TIME: u32 = 52;
+    pub const SYMBOL: u32 = 55;
+    pub const TARGET_COMP_ID: u32 = 56;
+    pub const TEXT: u32 = 58;
+    pub const ENCRYPT_METHOD: u32 = 98;
+    pub const HEART_BT_INT: u32 = 108;
+    pub const TEST_REQ_ID: u32 = 112;
+    pub const GAP_FILL_FLAG: u32 = 123;
+    pub const RESET_SEQ_NUM_FLAG: u32 = 141;
+    pub const NO_RELATED_SYM: u32 = 146;
+    pub const MD_REQ_ID: u32 = 262;
+    pub const SUBSCRIPTION_REQUEST_TYPE: u32 = 263;
+    pub const MARKET_DEPTH: u32 = 264;
+    pub const MD_UPDATE_TYPE: u32 = 265;
+    pub const NO_MD_ENTRY_TYPES: u32 = 267;
+    pub const NO_MD_ENTRIES: u32 = 268;
+    pub const MD_ENTRY_TYPE: u32 = 269;
+    pub const MD_ENTRY_PX: u32 = 270;
+    pub const MD_ENTRY_SIZE: u32 = 271;
+    pub const MD_MKT: u32 = 275;
+    pub const MD_UPDATE_ACTION: u32 = 279;
+    pub const MD_REQ_REJ_REASON: u32 = 281;
+    pub const MD_ENTRY_POSITION_NO: u32 = 290;
+    pub const SESSION_REJECT_REASON: u32 = 373;
+}
+
+pub mod msg_type {
+    pub const HEARTBEAT: &str = "0";
+    pub const TEST_REQUEST: &str = "1";
+    pub const RESEND_REQUEST: &str = "2";
+    pub const REJECT: &str = "3";
+    pub const SEQUENCE_RESET: &str = "4";
+    pub const LOGOUT: &str = "5";
+    pub const LOGON: &str = "A";
+    pub const MARKET_DATA_REQUEST: &str = "V";
+    pub const MARKET_DATA_SNAPSHOT_FULL_REFRESH: &str = "W";
+    pub const MARKET_DATA_INCREMENTAL_REFRESH: &str = "X";
+    pub const MARKET_DATA_REQUEST_REJECT: &str = "Y";
+}
+
+pub mod md_entry_type {
+    pub const BID: &str = "0";
+    pub const OFFER: &str = "1";
+}
+
+pub mod md_update_action {
+    pub const NEW: &str = "0";
+    pub const CHANGE: &str = "1";
+    pub const DELETE: &str = "2";
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Message {
+    fields: Vec<(u32, String)>
+}
+
+impl Message {
+    pub fn new(msg_type: &str) -> Self {
+        Self{fields: vec![(tag::MSG_TYPE, msg_type.to_owned())]}
+    }
+
+    /// Appends the field
+    pub fn with(mut self, tag: u32, value: impl ToString) -> Self {
+        self.push(tag, value);
+        self
+    }
+
+    pub fn push(&mut self, tag: u32, value: impl ToString) {
+        self.fields.push((tag, value.to_string()));
+    }
+
+    /// Sets the value of the first occurrence of the tag, or appends the field
+    pub fn set(&mut self, tag: u32, value: impl ToString) {
+        match self.fields.iter_mut().find(|(field_tag, _)| *field_tag == tag) {
+            Some(field) => field.1 = value.to_string(),
+            None => self.push(tag, value)
+        }
+    }
+
+    /// Value of the first occurrence of the tag
+    pub fn get(&self, tag: u32) -> Option<&str> {
+        self.fields.iter().find(|(field_tag, _)| *field_tag == tag).map(|(_, value)| value.as_str())
+    }
+
+    pub fn get_u64(&self, tag: u32) -> Option<u64> {
+        self.get(tag)?.parse().ok()
+    }
+
+    pub fn get_flag(&self, tag: u32) -> bool {
+        self.get(tag) == Some("Y")
+    }
+
+    pub fn msg_type(&self) -> &str {
+        self.get(tag::MSG_TYPE).unwrap_or_default()
+    }
+
+    pub fn fields(&self) -> &[(u32, String)] {
+        &self.fields
+    }
+
+    /// Encodes the message, `MsgType` first
+    pub fn encode(&self, begin_string: &str) -> Vec<u8> {
+        let mut body = Vec::new();
+        let msg_type = self.fields.iter().filter(|(tag, _)| *tag == tag::MSG_TYPE);
+        let other_fields = self.fields.iter().filter(|(tag, _)| *tag != tag::MSG_TYPE);
+        for (tag, value) in msg_type.chain(other_fields) {
+            body.extend_from_slice(format!("{}={}", tag, value).as_bytes());
+            body.push(SOH);
+        }
+
+        let mut msg = format!("{}={}\x01{}={}\x01", tag::BEGIN_STRING, begin_string, tag::BODY_LENGTH, body.len())
+            .into_bytes();
+        msg.extend_from_slice(&body);
+        let check_sum = get_check_sum(&msg);
+        msg.extend_from_slice(format!("{}={:03}\x01", tag::CHECK_SUM, check_sum).as_bytes());
+        msg
+    }
+}
+
+/// Decodes the first message in the buffer
+///
+/// Returns the message and the number of bytes it takes, or `None` if the buffer doesn't hold a
+/// whole message yet. Messages larger than `MAX_MESSAGE_SIZE` are rejected.
+pub fn decode(buffer: &[u8]) -> Result<Option<(Message, usize)>, error::FixError> {
+    // 8=FIX.4.4|9=
+    let Some(body_length_end) = buffer.iter().enumerate()
+        .filter(|(_, byte)| **byte == SOH)
+        .nth(1)
+        .map(|(i, _)| i) else {
+        return Ok(None)
+    };
+    let header = parse_fields(&buffer[..body_length_end + 1])?;
+    if header.len() != 2 || header[0].0 != tag::BEGIN_STRING || header[1].0 != tag::BODY_LENGTH {
+        return Err(Report::new(error::FixError::ParsingError).attach_printable("Message doesn't start with BeginString and BodyLength"))
+    }
+    let body_length: usize = header[1].1.parse()
+        .map_err(|_| Report::new(error::FixError::ParsingError).attach_printable("Invalid BodyLength"))?;
+
+    // 10=xxx| is always 7 bytes
+    let Some((body_end, msg_end)) = (body_length_end + 1).checked_add(body_length)
+        .and_then(|body_end| Some((body_end, body_end.checked_add(7)?)))
+        .filter(|(_, msg_end)| *msg_end <= constants::fix::MAX_MESSAGE_SIZE) else {
+        return Err(Report::new(error::FixError::ParsingError).attach_printable(format!("BodyLength {} too large", body_length)))
+    };
+    if buffer.len() < msg_end {
+        return Ok(None)
+    }
+    let trailer = parse_fields(&buffer[body_end..msg_end])?;
+    let check_sum = trailer.first()
+        .filter(|(tag, _)| *tag == tag::CHECK_SUM)
+        .and_then(|(_, value)| value.parse::<u8>().ok())
+        .ok_or_else(|| Report::new(error::FixError::ParsingError).attach_printable("Invalid CheckSum"))?;
+    if check_sum != get_check_sum(&buffer[..body_end]) {
+        return Err(Report::new(error::FixError::ParsingError).attach_printable("CheckSum mismatch"))
+    }
+
+    let fields = parse_fields(&buffer[body_length_end + 1..body_end])?;
+    if fields.first().map(|(tag, _)| *tag) != Some(tag::MSG_TYPE) {
+        return Err(Report::new(error::FixError::ParsingError).attach_printable("MsgType is not the first field"))
+    }
+    Ok(Some((Message{fields}, msg_end)))
+}
+
+/// SendingTime format `YYYYMMDD-HH:MM:SS.sss` in UTC
+pub fn format_timestamp(timestamp: time::SystemTime) -> String {
+    let since_epoch = timestamp.duration_since(time::UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (year, month, day) = get_civil_date((seconds / 86_400) as i64);
+    let seconds_of_day = seconds % 86_400;
+
+    format!("{:04}{:02}{:02}-{:02}:{:02}:{:02}.{:03}", year, month, day,
+            seconds_of_day / 3600, seconds_of_day % 3600 / 60, seconds_of_day % 60, since_epoch.subsec_millis())
+}
+
+/// Parses `UTCTimestamp` i.e. `YYYYMMDD-HH:MM:SS` with optional fractional seconds
+pub fn parse_timestamp(value: &str) -> Option<time::SystemTime> {
+    let (date, time_of_day) = value.split_once('-')?;
+    let (time_of_day, fraction) = time_of_day.split_once('.').unwrap_or((time_of_day, ""));
+    if date.len() != 8 || time_of_day.len() != 8 || fraction.len() > 9 {
+        return None
+    }
+    let year: i64 = date[..4].parse().ok()?;
+    let month: u32 = date[4..6].parse().ok()?;
+    let day: u32 = date[6..].parse().ok()?;
+    let mut parts = time_of_day.split(':').map(|part| part.parse::<u64>().ok());
+    let (hours, minutes, seconds) = (parts.next()??, parts.next()??, parts.next()??);
+    let nanoseconds: u64 = if fraction.is_empty() {0} else {
+        fraction.parse::<u64>().ok()? * 10u64.pow(9 - fraction.len() as u32)
+    };
+    let days = u64::try_from(get_days_from_civil(year, month, day)).ok()?;
+
+    time::UNIX_EPOCH.checked_add(time::Duration::new(
+        days * 86_400 + hours * 3600 + minutes * 60 + seconds, nanoseconds as u32))
+}
+
+/// Converts days since Unix epoch to a proleptic Gregorian calendar date
+fn get_civil_date(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 {month_index + 3} else {month_index - 9} as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Converts a proleptic Gregorian calendar date to days since Unix epoch, inverse of `get_civil_date`
+fn get_days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month_index = i64::from(if month > 2 {month - 3} else {month + 9});
+    let day_of_year = (153 * month_index + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn get_check_sum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn parse_fields(bytes: &[u8]) -> Result<Vec<(u32, String)>, error::FixError> {
+    bytes.split(|byte| *byte == SOH)
+        .filter(|field| !field.is_empty())
+        .map(|field| {
+            let field = std::str::from_utf8(field)
+                .map_err(|_| Report::new(error::FixError::ParsingError).attach_printable("Field is not UTF-8"))?;
+            let (tag, value) = field.split_once('=')
+                .ok_or_else(|| Report::new(error::FixError::ParsingError).attach_printable(format!("Invalid field {}", field)))?;
+            let tag = tag.parse()
+                .map_err(|_| Report::new(error::FixError::ParsingError).attach_printable(format!("Invalid tag {}", tag)))?;
+            Ok((tag, value.to_owned()))
+        })
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    mod decode {
+        use super::*;
+
+
+        #[test]
+        fn test_encode_decode() {
+            let msg = Message::new(msg_type::LOGON)
+                .with(tag::SENDER_COMP_ID, "CLIENT")
+                .with(tag::HEART_BT_INT, 30);
+            let mut bytes = msg.encode("FIX.4.4");
+            let msg_length = bytes.len();
+            bytes.extend_from_slice(b"8=FIX");
+
+            assert!(bytes.starts_with(b"8=FIX.4.4\x019=22\x0135=A\x01"));
+            let (decoded, length) = decode(&bytes).expect("Expected valid message").expect("Expected whole message");
+            assert_eq!(decoded, msg);
+            assert_eq!(length, msg_length);
+            assert!(decode(&bytes[..msg_length - 1]).expect("Expected valid message").is_none());
+        }
+
+        #[test]
+        fn test_known_check_sum() {
+            let bytes = b"8=FIX.4.4\x019=5\x0135=0\x0110=163\x01";
+            let (msg, _) = decode(bytes).expect("Expected valid message").expect("Expected whole message");
+
+            assert_eq!(msg.msg_type(), msg_type::HEARTBEAT);
+            assert_eq!(Message::new(msg_type::HEARTBEAT).encode("FIX.4.4"), bytes.to_vec());
+        }
+
+        #[test]
+        fn test_invalid_check_sum() {
+            assert!(decode(b"8=FIX.4.4\x019=5\x0135=0\x0110=000\x01").is_err());
+        }
+
+        #[test]
+        fn test_body_length_too_large() {
+            assert!(decode(b"8=FIX.4.4\x019=18446744073709551615\x0135=0\x01").is_err());
+            assert!(decode(format!("8=FIX.4.4\x019={}\x01", constants::fix::MAX_MESSAGE_SIZE).as_bytes()).is_err());
+        }
+    }
+
+    mod format_timestamp {
+        use super::*;
+
+
+        #[test]
+        fn test_format() {
+            let timestamp = time::UNIX_EPOCH + time::Duration::from_millis(1_686_616_236_740);
+
+            assert_eq!(format_timestamp(timestamp), "20230613-00:30:36.740");
+        }
+    }
+
+    mod parse_timestamp {
+        use super::*;
+
+
+        #[test]
+        fn test_parse() {
+            let timestamp = time::UNIX_EPOCH + time::Duration::from_millis(1_686_616_236_740);
+
+            assert_eq!(parse_timestamp("20230613-00:30:36.740"), Some(timestamp));
+            assert_eq!(parse_timestamp("20240229-23:59:59"), Some(time::UNIX_EPOCH + time::Duration::from_secs(1_709_251_199)));
+            assert_eq!(parse_timestamp("20230613-00:30"), None);
+        }
+    }
+}
\ No newline at end of file
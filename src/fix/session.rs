@@ -0,0 +1,425 @@
+//! FIX session layer, independent of the transport
+//!
+//! The session consumes received messages and timer ticks and returns actions for the connection
+//! to carry out: messages to send, application messages to deliver and disconnects. Outgoing
+//! messages get their header stamped and sequence number assigned in `Session::encode`.
+//!
+//! We don't store sent messages. Market data is stale by the time it would be resent, so resend
+//! requests are answered with a gap fill and the counterparty should re-request what it needs.
+use std::time;
+
+use error_stack::Result;
+use tracing;
+
+use crate::constants;
+use crate::error;
+use crate::fix::message::{self, msg_type, tag};
+use crate::fix::store;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Acceptor,
+    Initiator
+}
+
+#[derive(Clone, Debug)]
+pub struct SessionConfig {
+    pub role: Role,
+    pub sender_comp_id: String,
+    pub target_comp_id: String,
+    /// Acceptor takes the interval from the counterparty's Logon
+    pub heartbeat_interval: time::Duration
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    AwaitingLogon,
+    LoggedOn,
+    /// We sent Logout and wait for the counterparty's
+    LoggingOut,
+    Disconnected
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Message to send, encode it with `Session::encode`
+    Send(message::Message),
+    /// Application message
+    Deliver(message::Message),
+    Disconnect
+}
+
+pub struct Session {
+    config: SessionConfig,
+    store: store::SequenceStore,
+    state: State,
+    last_received_at: time::Instant,
+    last_sent_at: time::Instant,
+    test_request_sent_at: Option<time::Instant>,
+    next_test_req_id: u64,
+    /// Highest sequence number received after a gap we requested a resend for
+    resend_requested_until: Option<u64>
+}
+
+impl Session {
+    pub fn new(config: SessionConfig, store: store::SequenceStore, now: time::Instant) -> Self {
+        Self {
+            config,
+            store,
+            state: State::AwaitingLogon,
+            last_received_at: now,
+            last_sent_at: now,
+            test_request_sent_at: None,
+            next_test_req_id: 1,
+            resend_requested_until: None
+        }
+    }
+
+    /// Starts over on a new connection, sequence numbers continue from the store
+    pub fn restart(&mut self, now: time::Instant) {
+        self.state = State::AwaitingLogon;
+        self.last_received_at = now;
+        self.last_sent_at = now;
+        self.test_request_sent_at = None;
+        self.resend_requested_until = None;
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn next_target_seq_num(&self) -> u64 {
+        self.store.next_target_seq_num()
+    }
+
+    /// Logon sent by the initiator, with `reset_seq_num` both sides start over from 1
+    pub fn logon(&mut self, reset_seq_num: bool) -> Result<message::Message, error::FixError> {
+        let mut logon = message::Message::new(msg_type::LOGON)
+            .with(tag::ENCRYPT_METHOD, 0)
+            .with(tag::HEART_BT_INT, self.config.heartbeat_interval.as_secs());
+        if reset_seq_num {
+            self.store.reset()?;
+            self.resend_requested_until = None;
+            logon.push(tag::RESET_SEQ_NUM_FLAG, "Y");
+        }
+        Ok(logon)
+    }
+
+    pub fn logout(&mut self, text: &str) -> message::Message {
+        self.state = State::LoggingOut;
+        message::Message::new(msg_type::LOGOUT).with(tag::TEXT, text)
+    }
+
+    /// Processes a received message
+    pub fn on_message(&mut self, msg: message::Message, now: time::Instant) -> Result<Vec<Action>, error::FixError> {
+        self.last_received_at = now;
+        self.test_request_sent_at = None;
+        let mut actions = vec![];
+
+        if msg.get(tag::SENDER_COMP_ID) != Some(self.config.target_comp_id.as_str())
+            || msg.get(tag::TARGET_COMP_ID) != Some(self.config.sender_comp_id.as_str()) {
+            return Ok(self.disconnect(actions, "Invalid CompID"))
+        }
+        match (self.state, msg.msg_type()) {
+            (State::AwaitingLogon, msg_type::LOGON) => {
+                if msg.get_flag(tag::RESET_SEQ_NUM_FLAG) && self.config.role == Role::Acceptor {
+                    self.store.reset()?;
+                    self.resend_requested_until = None;
+                }
+                if self.config.role == Role::Acceptor {
+                    if let Some(heartbeat_interval) = msg.get_u64(tag::HEART_BT_INT).filter(|interval| *interval > 0) {
+                        self.config.heartbeat_interval = time::Duration::from_secs(heartbeat_interval);
+                    }
+                    let mut logon = message::Message::new(msg_type::LOGON)
+                        .with(tag::ENCRYPT_METHOD, 0)
+                        .with(tag::HEART_BT_INT, self.config.heartbeat_interval.as_secs());
+                    if msg.get_flag(tag::RESET_SEQ_NUM_FLAG) {logon.push(tag::RESET_SEQ_NUM_FLAG, "Y")}
+                    actions.push(Action::Send(logon));
+                }
+                self.state = State::LoggedOn;
+            }
+            (State::AwaitingLogon, _) => {
+                return Ok(self.disconnect(actions, "First message is not Logon"))
+            }
+            (_, msg_type::SEQUENCE_RESET) => {
+                // both gap fill and reset mode move the expected sequence number forward
+                if let Some(new_seq_no) = msg.get_u64(tag::NEW_SEQ_NO) {
+                    if new_seq_no > self.store.next_target_seq_num() {
+                        self.store.set_next_target_seq_num(new_seq_no)?;
+                    }
+                }
+                return Ok(actions)
+            }
+            _ => {}
+        }
+
+        let Some(seq_num) = msg.get_u64(tag::MSG_SEQ_NUM) else {
+            return Ok(self.disconnect(actions, "MsgSeqNum is missing"))
+        };
+        let expected_seq_num = self.store.next_target_seq_num();
+        if seq_num > expected_seq_num {
+            // the counterparty's resend request is answered right away, otherwise both sides wait for each other
+            if msg.msg_type() == msg_type::RESEND_REQUEST {
+                actions.push(Action::Send(self.get_gap_fill(&msg)));
+            }
+            // messages after the gap are dropped, the counterparty resends them, we request them once
+            // until the gap is filled
+            let is_resend_pending = self.resend_requested_until.is_some_and(|until| until >= expected_seq_num);
+            if !is_resend_pending {
+                actions.push(Action::Send(message::Message::new(msg_type::RESEND_REQUEST)
+                    .with(tag::BEGIN_SEQ_NO, expected_seq_num)
+                    .with(tag::END_SEQ_NO, 0)));
+            }
+            self.resend_requested_until = Some(std::cmp::max(seq_num, self.resend_requested_until.unwrap_or_default()));
+            return Ok(actions)
+        }
+        if seq_num < expected_seq_num {
+            if msg.get_flag(tag::POSS_DUP_FLAG) {
+                return Ok(actions)
+            }
+            let text = format!("MsgSeqNum too low, expecting {} but received {}", expected_seq_num, seq_num);
+            return Ok(self.disconnect(actions, &text))
+        }
+        self.store.set_next_target_seq_num(expected_seq_num + 1)?;
+
+        match msg.msg_type() {
+            msg_type::LOGON | msg_type::HEARTBEAT => {}
+            msg_type::TEST_REQUEST => {
+                let mut heartbeat = message::Message::new(msg_type::HEARTBEAT);
+                if let Some(test_req_id) = msg.get(tag::TEST_REQ_ID) {heartbeat.push(tag::TEST_REQ_ID, test_req_id)}
+                actions.push(Action::Send(heartbeat));
+            }
+            msg_type::RESEND_REQUEST => actions.push(Action::Send(self.get_gap_fill(&msg))),
+            msg_type::REJECT => {
+                tracing::warn!("Session level reject of message {}: {}",
+                    msg.get(tag::REF_SEQ_NUM).unwrap_or_default(), msg.get(tag::TEXT).unwrap_or_default());
+            }
+            msg_type::LOGOUT => {
+                if self.state != State::LoggingOut {
+                    actions.push(Action::Send(message::Message::new(msg_type::LOGOUT)));
+                }
+                self.state = State::Disconnected;
+                actions.push(Action::Disconnect);
+            }
+            _ => actions.push(Action::Deliver(msg))
+        }
+        Ok(actions)
+    }
+
+    /// Sends heartbeats and test requests, disconnects if the counterparty doesn't respond
+    pub fn on_timer(&mut self, now: time::Instant) -> Vec<Action> {
+        if self.state != State::LoggedOn {return vec![]}
+        let heartbeat_interval = self.config.heartbeat_interval;
+        let mut actions = vec![];
+
+        // give the counterparty's heartbeat a bit of slack for transmission time
+        if now.duration_since(self.last_received_at) > heartbeat_interval + heartbeat_interval / 5 {
+            match self.test_request_sent_at {
+                Some(sent_at) if now.duration_since(sent_at) > heartbeat_interval => {
+                    tracing::warn!("Counterparty {} is not responding", self.config.target_comp_id);
+                    self.state = State::Disconnected;
+                    return vec![Action::Disconnect]
+                }
+                Some(_) => {}
+                None => {
+                    self.test_request_sent_at = Some(now);
+                    actions.push(Action::Send(
+                        message::Message::new(msg_type::TEST_REQUEST).with(tag::TEST_REQ_ID, self.next_test_req_id)));
+                    self.next_test_req_id += 1;
+                }
+            }
+        }
+        if actions.is_empty() && now.duration_since(self.last_sent_at) >= heartbeat_interval {
+            actions.push(Action::Send(message::Message::new(msg_type::HEARTBEAT)));
+        }
+        actions
+    }
+
+    /// Stamps the header and encodes the message
+    ///
+    /// A message which already has `MsgSeqNum` e.g. a gap fill, doesn't take a new sequence number.
+    pub fn encode(&mut self, msg: message::Message, now: time::Instant, timestamp: time::SystemTime)
+        -> Result<Vec<u8>, error::FixError> {
+        let seq_num = match msg.get_u64(tag::MSG_SEQ_NUM) {
+            Some(seq_num) => seq_num,
+            None => {
+                let seq_num = self.store.next_sender_seq_num();
+                self.store.set_next_sender_seq_num(seq_num + 1)?;
+                seq_num
+            }
+        };
+        let mut stamped_msg = message::Message::new(msg.msg_type())
+            .with(tag::SENDER_COMP_ID, &self.config.sender_comp_id)
+            .with(tag::TARGET_COMP_ID, &self.config.target_comp_id)
+            .with(tag::MSG_SEQ_NUM, seq_num)
+            .with(tag::SENDING_TIME, message::format_timestamp(timestamp));
+        let header_tags = [tag::MSG_TYPE, tag::SENDER_COMP_ID, tag::TARGET_COMP_ID, tag::MSG_SEQ_NUM, tag::SENDING_TIME];
+        for (tag, value) in msg.fields().iter().filter(|(tag, _)| !header_tags.contains(tag)) {
+            stamped_msg.push(*tag, value);
+        }
+
+        self.last_sent_at = now;
+        Ok(stamped_msg.encode(constants::fix::BEGIN_STRING))
+    }
+
+    /// Answers the resend request, sent messages are not stored so all of them are gap filled
+    fn get_gap_fill(&self, resend_request: &message::Message) -> message::Message {
+        let begin_seq_no = resend_request.get_u64(tag::BEGIN_SEQ_NO).unwrap_or(1);
+        message::Message::new(msg_type::SEQUENCE_RESET)
+            .with(tag::MSG_SEQ_NUM, begin_seq_no)
+            .with(tag::POSS_DUP_FLAG, "Y")
+            .with(tag::GAP_FILL_FLAG, "Y")
+            .with(tag::NEW_SEQ_NO, self.store.next_sender_seq_num())
+    }
+
+    fn disconnect(&mut self, mut actions: Vec<Action>, text: &str) -> Vec<Action> {
+        tracing::warn!("Disconnecting {}: {}", self.config.target_comp_id, text);
+        actions.push(Action::Send(message::Message::new(msg_type::LOGOUT).with(tag::TEXT, text)));
+        actions.push(Action::Disconnect);
+        self.state = State::Disconnected;
+        actions
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fix::test_util;
+
+
+    fn get_session() -> (test_util::StoreDir, Session) {
+        let store_dir = test_util::get_store_dir();
+        let store = store::SequenceStore::open(store_dir.path(), "SERVER", "CLIENT").expect("Expected store");
+        let config = SessionConfig {
+            role: Role::Acceptor,
+            sender_comp_id: "SERVER".to_owned(),
+            target_comp_id: "CLIENT".to_owned(),
+            heartbeat_interval: time::Duration::from_secs(30)
+        };
+        (store_dir, Session::new(config, store, time::Instant::now()))
+    }
+
+    fn get_message(msg_type: &str, seq_num: u64) -> message::Message {
+        message::Message::new(msg_type)
+            .with(tag::SENDER_COMP_ID, "CLIENT")
+            .with(tag::TARGET_COMP_ID, "SERVER")
+            .with(tag::MSG_SEQ_NUM, seq_num)
+    }
+
+    mod on_message {
+        use super::*;
+
+
+        #[test]
+        fn test_logon_and_test_request() {
+            let (_store_dir, mut session) = get_session();
+            let now = time::Instant::now();
+
+            let actions = session.on_message(get_message(msg_type::LOGON, 1).with(tag::HEART_BT_INT, 10), now)
+                .expect("Expected actions");
+            assert!(matches!(&actions[..], [Action::Send(logon)] if logon.get(tag::HEART_BT_INT) == Some("10")));
+            assert_eq!(session.state(), State::LoggedOn);
+
+            let actions = session.on_message(get_message(msg_type::TEST_REQUEST, 2).with(tag::TEST_REQ_ID, "abc"), now)
+                .expect("Expected actions");
+            assert!(matches!(&actions[..], [Action::Send(heartbeat)]
+                if heartbeat.msg_type() == msg_type::HEARTBEAT && heartbeat.get(tag::TEST_REQ_ID) == Some("abc")));
+            assert_eq!(session.next_target_seq_num(), 3);
+        }
+
+        #[test]
+        fn test_sequence_gap() {
+            let (_store_dir, mut session) = get_session();
+            let now = time::Instant::now();
+            session.on_message(get_message(msg_type::LOGON, 1), now).expect("Expected actions");
+
+            let actions = session.on_message(get_message(msg_type::MARKET_DATA_REQUEST, 4), now).expect("Expected actions");
+            assert!(matches!(&actions[..], [Action::Send(resend_request)]
+                if resend_request.msg_type() == msg_type::RESEND_REQUEST && resend_request.get(tag::BEGIN_SEQ_NO) == Some("2")));
+
+            let gap_fill = get_message(msg_type::SEQUENCE_RESET, 2).with(tag::GAP_FILL_FLAG, "Y").with(tag::NEW_SEQ_NO, 4);
+            session.on_message(gap_fill, now).expect("Expected actions");
+            let actions = session.on_message(get_message(msg_type::MARKET_DATA_REQUEST, 4), now).expect("Expected actions");
+            assert!(matches!(&actions[..], [Action::Deliver(_)]));
+
+            let actions = session.on_message(get_message(msg_type::HEARTBEAT, 2), now).expect("Expected actions");
+            assert!(matches!(&actions[..], [Action::Send(_), Action::Disconnect]));
+        }
+
+        #[test]
+        fn test_resend_request_gap_fill() {
+            let (_store_dir, mut session) = get_session();
+            let now = time::Instant::now();
+            session.on_message(get_message(msg_type::LOGON, 1), now).expect("Expected actions");
+            for _ in 0..3 {
+                session.encode(message::Message::new(msg_type::HEARTBEAT), now, time::SystemTime::now())
+                    .expect("Expected encoded message");
+            }
+
+            let actions = session.on_message(get_message(msg_type::RESEND_REQUEST, 2).with(tag::BEGIN_SEQ_NO, 2), now)
+                .expect("Expected actions");
+            let [Action::Send(gap_fill)] = &actions[..] else {panic!("Expected gap fill")};
+            assert_eq!(gap_fill.get(tag::MSG_SEQ_NUM), Some("2"));
+            assert_eq!(gap_fill.get(tag::NEW_SEQ_NO), Some("4"));
+            let bytes = session.encode(gap_fill.clone(), now, time::SystemTime::now()).expect("Expected encoded message");
+            let (decoded, _) = message::decode(&bytes).expect("Expected valid message").expect("Expected whole message");
+            assert_eq!(decoded.get(tag::MSG_SEQ_NUM), Some("2"));
+            assert_eq!(decoded.fields()[1], (tag::SENDER_COMP_ID, "SERVER".to_owned()));
+        }
+
+        #[test]
+        fn test_single_resend_request_per_gap() {
+            let (_store_dir, mut session) = get_session();
+            let now = time::Instant::now();
+            session.on_message(get_message(msg_type::LOGON, 1), now).expect("Expected actions");
+
+            let actions = session.on_message(get_message(msg_type::MARKET_DATA_REQUEST, 4), now).expect("Expected actions");
+            assert!(matches!(&actions[..], [Action::Send(resend_request)] if resend_request.msg_type() == msg_type::RESEND_REQUEST));
+            let actions = session.on_message(get_message(msg_type::HEARTBEAT, 5), now).expect("Expected actions");
+            assert!(actions.is_empty());
+
+            // the counterparty's resend request after the gap is answered, ours is still pending
+            let actions = session.on_message(get_message(msg_type::RESEND_REQUEST, 6).with(tag::BEGIN_SEQ_NO, 1), now)
+                .expect("Expected actions");
+            assert!(matches!(&actions[..], [Action::Send(gap_fill)] if gap_fill.msg_type() == msg_type::SEQUENCE_RESET));
+
+            // a new gap after the previous one was filled is requested again
+            let gap_fill = get_message(msg_type::SEQUENCE_RESET, 2).with(tag::GAP_FILL_FLAG, "Y").with(tag::NEW_SEQ_NO, 7);
+            session.on_message(gap_fill, now).expect("Expected actions");
+            let actions = session.on_message(get_message(msg_type::HEARTBEAT, 9), now).expect("Expected actions");
+            assert!(matches!(&actions[..], [Action::Send(resend_request)]
+                if resend_request.msg_type() == msg_type::RESEND_REQUEST && resend_request.get(tag::BEGIN_SEQ_NO) == Some("7")));
+        }
+
+        #[test]
+        fn test_first_message_not_logon() {
+            let (_store_dir, mut session) = get_session();
+
+            let actions = session.on_message(get_message(msg_type::HEARTBEAT, 1), time::Instant::now())
+                .expect("Expected actions");
+            assert!(matches!(&actions[..], [Action::Send(_), Action::Disconnect]));
+            assert_eq!(session.state(), State::Disconnected);
+        }
+    }
+
+    mod on_timer {
+        use super::*;
+
+
+        #[test]
+        fn test_heartbeat_and_test_request() {
+            let (_store_dir, mut session) = get_session();
+            let now = time::Instant::now();
+            session.on_message(get_message(msg_type::LOGON, 1), now).expect("Expected actions");
+
+            let actions = session.on_timer(now + time::Duration::from_secs(30));
+            assert!(matches!(&actions[..], [Action::Send(heartbeat)] if heartbeat.msg_type() == msg_type::HEARTBEAT));
+            let actions = session.on_timer(now + time::Duration::from_secs(37));
+            assert!(matches!(&actions[..], [Action::Send(test_request)] if test_request.msg_type() == msg_type::TEST_REQUEST));
+            let actions = session.on_timer(now + time::Duration::from_secs(68));
+            assert_eq!(actions, vec![Action::Disconnect]);
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,237 @@
+//! Persistent sequence numbers of a FIX session
+//!
+//! Each session (pair of CompIDs) has its own file holding the next sequence number we send and
+//! the next one we expect, so a session survives restarts and reconnections. Changes are written by
+//! one writer thread shared by all stores, so sessions running on the async runtime don't block on
+//! file I/O, not even when they end. Changes made while a write is in progress are conflated into
+//! the next one. A file is replaced by renaming a fully written temporary file and the directory is
+//! synced after, so a crash can't leave it truncated or lose the rename.
+use std::collections;
+use std::fs;
+use std::io::Write;
+use std::path;
+use std::sync;
+use std::thread;
+
+use error_stack::{IntoReport, Report, Result, ResultExt};
+use tracing;
+
+use crate::error;
+
+
+pub struct SequenceStore {
+    next_sender_seq_num: u64,
+    next_target_seq_num: u64,
+    path: path::PathBuf
+}
+
+/// State shared with the writer thread
+struct Writer {
+    state: sync::Mutex<WriterState>,
+    changed: sync::Condvar,
+    written: sync::Condvar
+}
+
+#[derive(Default)]
+struct WriterState {
+    /// Changes not written yet by store file, a change stays until it's written
+    changes: collections::HashMap<path::PathBuf, Change>,
+    /// Error of the last failed write by store file, returned by the next change
+    errors: collections::HashMap<path::PathBuf, String>
+}
+
+struct Change {
+    seq_nums: (u64, u64),
+    version: u64,
+    written_version: u64
+}
+
+impl SequenceStore {
+    /// Opens the store of the session, a new session starts at 1
+    ///
+    /// A session has one store at a time, the previous store must be dropped first. Changes of the
+    /// previous store not written yet are taken over.
+    pub fn open(dir: &path::Path, sender_comp_id: &str, target_comp_id: &str) -> Result<Self, error::FixError> {
+        fs::create_dir_all(dir)
+            .into_report()
+            .change_context(error::FixError::StoreError)
+            .attach_printable_lazy(|| format!("Cannot create store directory {}", dir.display()))?;
+        let path = dir.join(format!("{}-{}.seqnums", sender_comp_id, target_comp_id));
+
+        let change = get_writer().state.lock().expect("Store writer doesn't panic")
+            .changes.get(&path).map(|change| change.seq_nums);
+        let (next_sender_seq_num, next_target_seq_num) = match (change, fs::read_to_string(&path)) {
+            (Some(seq_nums), _) => seq_nums,
+            (None, Ok(content)) => {
+                let seq_nums: Vec<u64> = content.split_whitespace().filter_map(|seq_num| seq_num.parse().ok()).collect();
+                match seq_nums[..] {
+                    [next_sender_seq_num, next_target_seq_num] => (next_sender_seq_num, next_target_seq_num),
+                    _ => return Err(Report::new(error::FixError::StoreError)
+                        .attach_printable(format!("Invalid store file {}", path.display())))
+                }
+            }
+            (None, Err(_)) => (1, 1)
+        };
+        let store = Self{next_sender_seq_num, next_target_seq_num, path};
+        store.persist()?;
+        Ok(store)
+    }
+
+    pub fn next_sender_seq_num(&self) -> u64 {
+        self.next_sender_seq_num
+    }
+
+    pub fn next_target_seq_num(&self) -> u64 {
+        self.next_target_seq_num
+    }
+
+    pub fn set_next_sender_seq_num(&mut self, seq_num: u64) -> Result<(), error::FixError> {
+        self.next_sender_seq_num = seq_num;
+        self.persist()
+    }
+
+    pub fn set_next_target_seq_num(&mut self, seq_num: u64) -> Result<(), error::FixError> {
+        self.next_target_seq_num = seq_num;
+        self.persist()
+    }
+
+    pub fn reset(&mut self) -> Result<(), error::FixError> {
+        self.next_sender_seq_num = 1;
+        self.next_target_seq_num = 1;
+        self.persist()
+    }
+
+    /// Hands the sequence numbers to the writer, fails if the previous write failed
+    fn persist(&self) -> Result<(), error::FixError> {
+        let writer = get_writer();
+        let mut state = writer.state.lock().expect("Store writer doesn't panic");
+        let seq_nums = (self.next_sender_seq_num, self.next_target_seq_num);
+        state.changes.entry(self.path.to_owned())
+            .and_modify(|change| {
+                change.seq_nums = seq_nums;
+                change.version += 1;
+            })
+            .or_insert(Change{seq_nums, version: 1, written_version: 0});
+        writer.changed.notify_one();
+        match state.errors.remove(&self.path) {
+            Some(error) => Err(Report::new(error::FixError::StoreError).attach_printable(error)),
+            None => Ok(())
+        }
+    }
+}
+
+/// Waits until all changes of all stores are written, e.g. before the process exits
+pub fn flush() {
+    let writer = get_writer();
+    let state = writer.state.lock().expect("Store writer doesn't panic");
+    let _state = writer.written
+        .wait_while(state, |state| !state.changes.is_empty())
+        .expect("Store writer doesn't panic");
+}
+
+/// The writer of all stores, started on first use
+fn get_writer() -> &'static Writer {
+    static WRITER: sync::OnceLock<&'static Writer> = sync::OnceLock::new();
+    WRITER.get_or_init(|| {
+        let writer: &'static Writer = Box::leak(Box::new(Writer {
+            state: sync::Mutex::new(WriterState::default()),
+            changed: sync::Condvar::new(),
+            written: sync::Condvar::new()
+        }));
+        thread::Builder::new()
+            .name("fix-store".to_owned())
+            .spawn(|| writer.run())
+            .expect("Cannot start FIX store writer");
+        writer
+    })
+}
+
+impl Writer {
+    fn run(&self) {
+        let mut state = self.state.lock().expect("Store writer doesn't panic");
+        loop {
+            state = self.changed
+                .wait_while(state, |state| state.changes.values().all(|change| change.version == change.written_version))
+                .expect("Store writer doesn't panic");
+            let Some((path, seq_nums, version)) = state.changes.iter()
+                .find(|(_, change)| change.version != change.written_version)
+                .map(|(path, change)| (path.to_owned(), change.seq_nums, change.version)) else {continue};
+
+            drop(state);
+            let result = write_atomically(&path, seq_nums);
+            state = self.state.lock().expect("Store writer doesn't panic");
+            if let Some(change) = state.changes.get_mut(&path) {
+                change.written_version = version;
+                if change.version == version {
+                    state.changes.remove(&path);
+                }
+            }
+            if let Err(e) = result {
+                tracing::error!("Cannot persist FIX sequence numbers: {:?}", e);
+                state.errors.insert(path, format!("{:?}", e));
+            }
+            self.written.notify_all();
+        }
+    }
+}
+
+/// Writes a temporary file next to the store file, renames it over the store file and syncs the
+/// directory
+fn write_atomically(path: &path::Path, (next_sender_seq_num, next_target_seq_num): (u64, u64))
+    -> Result<(), error::FixError> {
+    let tmp_path = path.with_extension("seqnums.tmp");
+    let mut file = fs::File::create(&tmp_path)
+        .into_report()
+        .change_context(error::FixError::StoreError)
+        .attach_printable_lazy(|| format!("Cannot create store file {}", tmp_path.display()))?;
+    write!(file, "{} {}", next_sender_seq_num, next_target_seq_num)
+        .and_then(|_| file.sync_all())
+        .into_report()
+        .change_context(error::FixError::StoreError)
+        .attach_printable_lazy(|| format!("Cannot write store file {}", tmp_path.display()))?;
+    fs::rename(&tmp_path, path)
+        .into_report()
+        .change_context(error::FixError::StoreError)
+        .attach_printable_lazy(|| format!("Cannot replace store file {}", path.display()))?;
+    let dir = path.parent().unwrap_or(path::Path::new("."));
+    fs::File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .into_report()
+        .change_context(error::FixError::StoreError)
+        .attach_printable_lazy(|| format!("Cannot sync store directory {}", dir.display()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    mod open {
+        use super::*;
+        use crate::fix::test_util;
+
+
+        #[test]
+        fn test_persisted_seq_nums() {
+            let store_dir = test_util::get_store_dir();
+            let dir = store_dir.path();
+            let mut store = SequenceStore::open(dir, "SERVER", "CLIENT").expect("Expected store");
+            assert_eq!(store.next_sender_seq_num(), 1);
+            store.set_next_sender_seq_num(5).expect("Expected persisted seq num");
+            store.set_next_target_seq_num(7).expect("Expected persisted seq num");
+            drop(store);
+
+            // changes not written yet are taken over
+            let store = SequenceStore::open(dir, "SERVER", "CLIENT").expect("Expected store");
+            assert_eq!(store.next_sender_seq_num(), 5);
+            assert_eq!(store.next_target_seq_num(), 7);
+            let other_store = SequenceStore::open(dir, "SERVER", "OTHER").expect("Expected store");
+            assert_eq!(other_store.next_target_seq_num(), 1);
+
+            flush();
+            assert_eq!(fs::read_to_string(dir.join("SERVER-CLIENT.seqnums")).expect("Expected store file"), "5 7");
+            assert!(!dir.join("SERVER-CLIENT.seqnums.tmp").exists());
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,62 @@
+//! Stand-ins shared by tests of FIX sessions and of the clients and listeners built on them
+use std::path;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::constants;
+use crate::fix::message::{self, tag};
+use crate::fix::store;
+
+
+/// Local venue stand-in, messages are built by hand to check what the client sends
+pub struct Acceptor {
+    pub stream: TcpStream,
+    buffer: Vec<u8>,
+    pub next_seq_num: u64
+}
+
+impl Acceptor {
+    pub async fn accept(listener: &TcpListener) -> Self {
+        let (stream, _) = listener.accept().await.expect("Expected connection");
+        Self{stream, buffer: vec![], next_seq_num: 1}
+    }
+
+    pub async fn read(&mut self) -> message::Message {
+        loop {
+            if let Some((msg, length)) = message::decode(&self.buffer).expect("Expected valid message") {
+                self.buffer.drain(..length);
+                return msg
+            }
+            self.stream.read_buf(&mut self.buffer).await.expect("Expected bytes");
+        }
+    }
+
+    pub async fn send(&mut self, msg: message::Message) {
+        let msg = msg
+            .with(tag::SENDER_COMP_ID, "VENUE")
+            .with(tag::TARGET_COMP_ID, "CLIENT")
+            .with(tag::MSG_SEQ_NUM, self.next_seq_num);
+        self.next_seq_num += 1;
+        self.stream.write_all(&msg.encode(constants::fix::BEGIN_STRING)).await.expect("Expected sent message");
+    }
+}
+
+/// Temporary directory of sequence stores, removed once the store writer wrote all changes
+pub struct StoreDir(tempfile::TempDir);
+
+impl StoreDir {
+    pub fn path(&self) -> &path::Path {
+        self.0.path()
+    }
+}
+
+impl Drop for StoreDir {
+    fn drop(&mut self) {
+        store::flush();
+    }
+}
+
+pub fn get_store_dir() -> StoreDir {
+    StoreDir(tempfile::tempdir().expect("Expected store directory"))
+}
\ No newline at end of file
@@ -1,6 +1,9 @@
 pub mod constants;
 pub mod error;
+pub mod execution;
 pub mod feed;
+pub mod fix;
+pub mod metrics;
 pub mod types;
 pub mod util;
 pub mod service;
\ No newline at end of file
@@ -0,0 +1,149 @@
+//! Prometheus metrics of the whole pipeline
+//!
+//! Metrics live in a process-wide registry and are updated where things happen, so listeners,
+//! aggregators and services don't pass a handle around. `service::metrics` serves them in the
+//! text exposition format, together with the stage latencies of `latency`.
+pub mod latency;
+
+use std::sync::OnceLock;
+use std::time;
+
+use prometheus;
+use prometheus::{Encoder, Histogram, HistogramOpts, IntCounterVec, IntGauge, IntGaugeVec, Opts};
+
+use crate::constants;
+
+
+pub struct Metrics {
+    registry: prometheus::Registry,
+    /// Messages read from the venue, by `feed`
+    pub feed_messages_received: IntCounterVec,
+    /// Messages the venue client couldn't parse, by `feed`
+    pub feed_parse_failures: IntCounterVec,
+    /// Reconnects after the venue closed the connection, by `feed`
+    pub feed_reconnects: IntCounterVec,
+    /// Time of the last read from the venue's connection in ms since the epoch, by `feed`
+    pub feed_last_read_timestamp: IntGaugeVec,
+    /// Raw msgs the recorder dropped because it fell behind, by `feed`
+    pub feed_recorder_dropped: IntCounterVec,
+    /// Snapshots by `feed` and whether the order book `changed`, only changed ones are forwarded
+    pub feed_snapshots: IntCounterVec,
+    /// Order books waiting in the `top_bbo` aggregator's queue when it started the last calculation
+    pub aggregator_queue_depth: IntGauge,
+    /// Time the `top_bbo` aggregator takes to aggregate and rank the book
+    pub aggregator_compute_seconds: Histogram,
+    /// Times a consumer fell behind a broadcast and skipped items, by `consumer`
+    pub broadcast_lag_events: IntCounterVec,
+    /// Streaming gRPC clients, by `stream`
+    pub grpc_connected_clients: IntGaugeVec,
+    /// Stage latencies of order book updates, from the socket read to gRPC clients
+    pub latency: latency::LatencyHistograms
+}
+
+impl Metrics {
+    fn new() -> prometheus::Result<Metrics> {
+        let registry = prometheus::Registry::new_custom(Some("dragonflybot".to_owned()), None)?;
+        let metrics = Metrics {
+            feed_messages_received: IntCounterVec::new(
+                Opts::new("feed_messages_received_total", "Messages read from the venue"), &["feed"])?,
+            feed_parse_failures: IntCounterVec::new(
+                Opts::new("feed_parse_failures_total", "Messages that couldn't be parsed"), &["feed"])?,
+            feed_reconnects: IntCounterVec::new(
+                Opts::new("feed_reconnects_total", "Reconnects to the venue"), &["feed"])?,
+            feed_last_read_timestamp: IntGaugeVec::new(
+                Opts::new("feed_last_read_timestamp_ms", "Time of the last read from the venue"), &["feed"])?,
+            feed_recorder_dropped: IntCounterVec::new(
+                Opts::new("feed_recorder_dropped_total", "Raw messages the recorder dropped"), &["feed"])?,
+            feed_snapshots: IntCounterVec::new(
+                Opts::new("feed_snapshots_total", "Order book snapshots by whether they changed"), &["feed", "changed"])?,
+            aggregator_queue_depth: IntGauge::new(
+                "aggregator_queue_depth", "Order books in the aggregator queue at the last calculation")?,
+            aggregator_compute_seconds: Histogram::with_opts(
+                HistogramOpts::new("aggregator_compute_seconds", "Time to aggregate and rank the book")
+                    // from 1 µs to ~0.5 s
+                    .buckets(prometheus::exponential_buckets(0.000_001, 2.0, 20)?))?,
+            broadcast_lag_events: IntCounterVec::new(
+                Opts::new("broadcast_lag_events_total", "Times a consumer lagged behind a broadcast"), &["consumer"])?,
+            grpc_connected_clients: IntGaugeVec::new(
+                Opts::new("grpc_connected_clients", "Streaming gRPC clients"), &["stream"])?,
+            latency: latency::LatencyHistograms::new(),
+            registry
+        };
+
+        metrics.registry.register(Box::new(metrics.feed_messages_received.clone()))?;
+        metrics.registry.register(Box::new(metrics.feed_parse_failures.clone()))?;
+        metrics.registry.register(Box::new(metrics.feed_reconnects.clone()))?;
+        metrics.registry.register(Box::new(metrics.feed_last_read_timestamp.clone()))?;
+        metrics.registry.register(Box::new(metrics.feed_recorder_dropped.clone()))?;
+        metrics.registry.register(Box::new(metrics.feed_snapshots.clone()))?;
+        metrics.registry.register(Box::new(metrics.aggregator_queue_depth.clone()))?;
+        metrics.registry.register(Box::new(metrics.aggregator_compute_seconds.clone()))?;
+        metrics.registry.register(Box::new(metrics.broadcast_lag_events.clone()))?;
+        metrics.registry.register(Box::new(metrics.grpc_connected_clients.clone()))?;
+        Ok(metrics)
+    }
+
+    /// Marks the feed's connection as live, called on every read whether the book changed or not
+    pub fn record_feed_read(&self, feed: constants::Feed, read_at: time::SystemTime) {
+        let timestamp = read_at.duration_since(time::UNIX_EPOCH).unwrap_or_default().as_millis();
+        self.feed_last_read_timestamp.with_label_values(&[feed.feed_name_for_grpc_service()]).set(timestamp as i64);
+    }
+
+    /// Time of the feed's last read, `None` if nothing was read yet
+    pub fn get_feed_last_read(&self, feed: constants::Feed) -> Option<time::SystemTime> {
+        let timestamp = self.feed_last_read_timestamp.with_label_values(&[feed.feed_name_for_grpc_service()]).get();
+        (timestamp > 0).then(|| time::UNIX_EPOCH + time::Duration::from_millis(timestamp as u64))
+    }
+
+    /// All metrics in the Prometheus text exposition format
+    pub fn encode(&self) -> String {
+        let mut buffer = vec![];
+        prometheus::TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("Metrics are encodable");
+        String::from_utf8(buffer).expect("Metrics are UTF-8") + &self.latency.encode()
+    }
+}
+
+/// Process-wide metrics, registered on first use
+pub fn get() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(|| Metrics::new().expect("Metric definitions are valid"))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    mod encode {
+        use super::*;
+
+
+        #[test]
+        fn test_labeled_metrics() {
+            get().feed_reconnects.with_label_values(&["test_feed"]).inc();
+            let encoded = get().encode();
+
+            assert!(encoded.contains("# TYPE dragonflybot_feed_reconnects_total counter"));
+            assert!(encoded.contains("dragonflybot_feed_reconnects_total{feed=\"test_feed\"} 1"));
+            assert!(encoded.contains("dragonflybot_aggregator_compute_seconds_bucket"));
+        }
+    }
+
+    mod get_feed_last_read {
+        use super::*;
+
+
+        #[test]
+        fn test_recorded_read() {
+            let read_at = time::UNIX_EPOCH + time::Duration::from_millis(1_700_000_000_123);
+            // no listener reads the synthetic feed
+            assert_eq!(get().get_feed_last_read(constants::Feed::Synthetic), None);
+
+            get().record_feed_read(constants::Feed::Synthetic, read_at);
+            assert_eq!(get().get_feed_last_read(constants::Feed::Synthetic), Some(read_at));
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,182 @@
+//! HDR histograms of the latencies of pipeline stages
+//!
+//! Every `FeedOrderBook` carries the monotonic time it was read from the socket, so updates are
+//! traced from the socket read until they are handed to a gRPC client:
+//!     - parse: read from the socket → forwarded by the listener
+//!     - queue wait: forwarded → taken from the queue by the `top_bbo` aggregator
+//!     - aggregation: taken from the queue → aggregated book ready
+//!     - broadcast: aggregated book ready → published to all consumers
+//!     - gRPC send: aggregated book ready → handed to a client's `BookSummary` stream
+//!     - end to end: read from the socket → handed to a client's `BookSummary` stream
+//!
+//! Latencies are recorded in nanoseconds with 3 significant digits, up to a minute. Each thread
+//! records into its own histograms, so the hot path never waits for another thread, and they are
+//! merged when encoded.
+use std::cell::RefCell;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time;
+
+use hdrhistogram;
+use strum::{EnumCount, IntoEnumIterator};
+
+
+const QUANTILES: [f64; 5] = [0.5, 0.9, 0.99, 0.999, 1.0];
+
+#[derive(strum::EnumCount, strum::EnumIter, strum::IntoStaticStr, Clone, Copy, Debug)]
+#[strum(serialize_all = "snake_case")]
+pub enum Stage {
+    Parse,
+    QueueWait,
+    Aggregation,
+    Broadcast,
+    GrpcSend,
+    EndToEnd
+}
+
+/// Histograms of one thread indexed by `Stage`, created on the stage's first record
+///
+/// Only locked by its thread and while encoding, so the lock is uncontended when recording.
+type Shard = Mutex<[Option<hdrhistogram::Histogram<u64>>; Stage::COUNT]>;
+
+thread_local! {
+    /// The shard of this thread by id of the `LatencyHistograms` it belongs to
+    static SHARDS: RefCell<Vec<(usize, Arc<Shard>)>> = const { RefCell::new(Vec::new()) };
+}
+
+pub struct LatencyHistograms {
+    id: usize,
+    /// Shards of all threads that recorded, shards of exited threads are kept
+    shards: Mutex<Vec<Arc<Shard>>>
+}
+
+impl LatencyHistograms {
+    pub fn new() -> LatencyHistograms {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+        LatencyHistograms {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            shards: Mutex::new(Vec::new())
+        }
+    }
+
+    /// Latencies above the highest trackable value are recorded as the highest one
+    pub fn record(&self, stage: Stage, latency: time::Duration) {
+        let nanos = u64::try_from(latency.as_nanos()).unwrap_or(u64::MAX).max(1);
+        SHARDS.with(|shards| {
+            let mut shards = shards.borrow_mut();
+            let index = shards.iter().position(|(id, _)| *id == self.id).unwrap_or_else(|| {
+                let shard = Arc::new(Shard::default());
+                self.shards.lock().unwrap_or_else(PoisonError::into_inner).push(Arc::clone(&shard));
+                shards.push((self.id, shard));
+                shards.len() - 1
+            });
+            shards[index].1.lock().unwrap_or_else(PoisonError::into_inner)[stage as usize]
+                .get_or_insert_with(new_histogram)
+                .saturating_record(nanos);
+        });
+    }
+
+    /// The histogram of the stage over all threads
+    fn get_merged(&self, stage: Stage) -> hdrhistogram::Histogram<u64> {
+        let mut merged = new_histogram();
+        for shard in self.shards.lock().unwrap_or_else(PoisonError::into_inner).iter() {
+            if let Some(histogram) = &shard.lock().unwrap_or_else(PoisonError::into_inner)[stage as usize] {
+                merged.add(histogram).expect("Histograms have the same bounds");
+            }
+        }
+        merged
+    }
+
+    /// Quantiles of each stage as a Prometheus summary
+    pub fn encode(&self) -> String {
+        let mut encoded = String::new();
+        let _ = writeln!(encoded, "# HELP dragonflybot_stage_latency_seconds Latency of a pipeline stage");
+        let _ = writeln!(encoded, "# TYPE dragonflybot_stage_latency_seconds summary");
+        for stage in Stage::iter() {
+            let histogram = self.get_merged(stage);
+            let name: &str = stage.into();
+            for quantile in QUANTILES {
+                let _ = writeln!(encoded, "dragonflybot_stage_latency_seconds{{stage=\"{}\",quantile=\"{}\"}} {}",
+                                 name, quantile, to_seconds(histogram.value_at_quantile(quantile)));
+            }
+            let _ = writeln!(encoded, "dragonflybot_stage_latency_seconds_sum{{stage=\"{}\"}} {}",
+                             name, histogram.mean() * histogram.len() as f64 / 1e9);
+            let _ = writeln!(encoded, "dragonflybot_stage_latency_seconds_count{{stage=\"{}\"}} {}", name, histogram.len());
+        }
+        encoded
+    }
+
+    /// Human readable quantiles of each stage, in microseconds
+    pub fn dump(&self) -> String {
+        let mut dump = String::from("stage          count      p50      p90      p99    p99.9      max (µs)");
+        for stage in Stage::iter() {
+            let histogram = self.get_merged(stage);
+            let name: &str = stage.into();
+            let _ = write!(dump, "\n{:<12} {:>7}", name, histogram.len());
+            for quantile in QUANTILES {
+                let _ = write!(dump, " {:>8.1}", histogram.value_at_quantile(quantile) as f64 / 1e3);
+            }
+        }
+        dump
+    }
+}
+
+impl Default for LatencyHistograms {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn new_histogram() -> hdrhistogram::Histogram<u64> {
+    hdrhistogram::Histogram::new_with_bounds(1, 60_000_000_000, 3).expect("Histogram bounds are valid")
+}
+
+fn to_seconds(nanos: u64) -> f64 {
+    nanos as f64 / 1e9
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    mod encode {
+        use super::*;
+
+
+        #[test]
+        fn test_quantiles() {
+            let histograms = LatencyHistograms::new();
+            for micros in 1..=100 {
+                histograms.record(Stage::QueueWait, time::Duration::from_micros(micros));
+            }
+            let encoded = histograms.encode();
+
+            assert!(encoded.contains("dragonflybot_stage_latency_seconds{stage=\"queue_wait\",quantile=\"0.5\"} 0.00005"));
+            assert!(encoded.contains("dragonflybot_stage_latency_seconds_count{stage=\"queue_wait\"} 100"));
+            assert!(encoded.contains("dragonflybot_stage_latency_seconds_count{stage=\"parse\"} 0"));
+            assert!(histograms.dump().contains("queue_wait       100"));
+        }
+
+        #[test]
+        fn test_merged_threads() {
+            let histograms = LatencyHistograms::new();
+            std::thread::scope(|scope| {
+                for _ in 0..4 {
+                    scope.spawn(|| {
+                        for micros in 1..=25 {
+                            histograms.record(Stage::Parse, time::Duration::from_micros(micros));
+                        }
+                    });
+                }
+            });
+            histograms.record(Stage::Parse, time::Duration::from_micros(100));
+
+            let encoded = histograms.encode();
+            assert!(encoded.contains("dragonflybot_stage_latency_seconds_count{stage=\"parse\"} 101"));
+            assert!(encoded.contains("dragonflybot_stage_latency_seconds{stage=\"parse\",quantile=\"1\"} 0.0001"));
+        }
+    }
+}
\ No newline at end of file
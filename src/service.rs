@@ -1 +1,5 @@
-pub mod grpc;
\ No newline at end of file
+pub mod fix;
+pub mod grpc;
+pub mod metrics;
+pub mod rest;
+pub mod ws;
\ No newline at end of file
@@ -0,0 +1,240 @@
+//! FIX 4.4 acceptor serving the aggregated book
+//!
+//! Each connection gets it's own task running the session and the market data subscriptions of the
+//! counterparty. The counterparty's CompID is taken from it's Logon, sequence numbers of each
+//! counterparty are persisted in the store directory. On shutdown the server stops accepting
+//! connections and logs out of each session, waiting a bounded time for the counterparty's Logout.
+pub mod market_data;
+
+use std::net;
+use std::path;
+use std::sync::Arc;
+use std::time;
+
+use error_stack::{IntoReport, Report, Result, ResultExt};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, watch};
+use tokio::task;
+use tracing;
+
+use crate::constants;
+use crate::error;
+use crate::fix::message::{self, msg_type, tag};
+use crate::fix::{session, store};
+use crate::metrics;
+use crate::types;
+use crate::util;
+
+
+pub struct FixServer {
+    pub sender_comp_id: String,
+    pub store_dir: path::PathBuf,
+    pub instrument_name: String,
+    pub broadcast_aggregator_tx: Arc<broadcast::Sender<types::SharedAggregatedBook>>,
+    /// Latest order book of each feed as seen by the aggregator, for snapshots
+    pub orderbooks_rx: watch::Receiver<types::OrderBooksByFeed>,
+    /// Becomes `true` when the server shuts down
+    pub shutdown_rx: watch::Receiver<bool>
+}
+
+impl FixServer {
+    /// Serves until shutdown, then returns once all sessions are logged out
+    pub async fn run(self, addr: net::SocketAddr) -> Result<(), error::FixError> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .into_report()
+            .change_context(error::FixError::Error)
+            .attach_printable_lazy(|| format!("Cannot bind FIX server to {}", addr))?;
+        let mut shutdown_rx = self.shutdown_rx.clone();
+        let server = Arc::new(self);
+        let mut connections = task::JoinSet::new();
+
+        loop {
+            let accepted = tokio::select! {
+                _ = util::wait_for_shutdown(&mut shutdown_rx) => break,
+                // reap finished connections
+                Some(_) = connections.join_next() => continue,
+                accepted = listener.accept() => accepted
+            };
+            let (stream, peer_addr) = match accepted {
+                Ok(connection) => connection,
+                Err(e) => {
+                    tracing::error!("Cannot accept FIX connection: {}", e);
+                    continue
+                }
+            };
+            tracing::info!("New FIX connection from {}", peer_addr);
+            let server = Arc::clone(&server);
+            connections.spawn(
+                async move {
+                    if let Err(e) = server.handle_connection(stream).await {
+                        tracing::error!("FIX connection from {}: {:?}", peer_addr, e);
+                    }
+                    tracing::info!("FIX connection from {} closed", peer_addr);
+                });
+        }
+
+        drop(listener);
+        while connections.join_next().await.is_some() {}
+        Ok(())
+    }
+
+    async fn handle_connection(&self, mut stream: TcpStream) -> Result<(), error::FixError> {
+        let mut buffer = Vec::with_capacity(4096);
+        let mut broadcast_rx = self.broadcast_aggregator_tx.subscribe();
+        let mut timer = tokio::time::interval(time::Duration::from_secs(1));
+        let mut session: Option<session::Session> = None;
+        let mut subscriptions: Vec<market_data::Subscription> = vec![];
+        let mut shutdown_rx = self.shutdown_rx.clone();
+        // set once we sent Logout on shutdown
+        let mut logout_deadline: Option<tokio::time::Instant> = None;
+
+        loop {
+            // the sleep is created even when the branch is disabled
+            let logout_timeout = tokio::time::sleep_until(logout_deadline.unwrap_or_else(tokio::time::Instant::now));
+            let actions = tokio::select! {
+                _ = util::wait_for_shutdown(&mut shutdown_rx), if logout_deadline.is_none() => {
+                    let Some(session) = session.as_mut().filter(|session| session.state() == session::State::LoggedOn) else {
+                        let _ = stream.shutdown().await;
+                        return Ok(())
+                    };
+                    let timeout = time::Duration::from_millis(constants::fix::LOGOUT_TIMEOUT_MS);
+                    logout_deadline = Some(tokio::time::Instant::now() + timeout);
+                    vec![session::Action::Send(session.logout("Server shutting down"))]
+                }
+                _ = logout_timeout, if logout_deadline.is_some() => {
+                    tracing::warn!("FIX counterparty didn't confirm the logout");
+                    let _ = stream.shutdown().await;
+                    return Ok(())
+                }
+                read = stream.read_buf(&mut buffer) => {
+                    let length = read.into_report().change_context(error::FixError::Error)?;
+                    if length == 0 {
+                        return Err(Report::new(error::FixError::EndpointClosedConnection))
+                    }
+                    let mut actions = vec![];
+                    while let Some((msg, msg_length)) = message::decode(&buffer)? {
+                        buffer.drain(..msg_length);
+                        let session = match session.as_mut() {
+                            Some(session) => session,
+                            None => session.insert(self.open_session(&msg)?)
+                        };
+                        actions.extend(session.on_message(msg, time::Instant::now())?);
+                    }
+                    // the rest is an incomplete message, which can't be larger than a whole one
+                    if buffer.len() > constants::fix::MAX_MESSAGE_SIZE {
+                        return Err(Report::new(error::FixError::ParsingError).attach_printable("Message exceeds the maximum size"))
+                    }
+                    actions
+                }
+                _ = timer.tick() => match session.as_mut() {
+                    Some(session) => session.on_timer(time::Instant::now()),
+                    None => vec![]
+                },
+                update = broadcast_rx.recv(), if logout_deadline.is_none() => match update {
+                    Ok(aggregated_book) => self.on_aggregated_book(&aggregated_book, &mut subscriptions),
+                    //If we lag behind, the next update is diffed against the last book we sent.
+                    Err(broadcast::error::RecvError::Lagged(_)) => {
+                        metrics::get().broadcast_lag_events.with_label_values(&["fix"]).inc();
+                        continue
+                    }
+                    Err(e) => {
+                        return Err(Report::new(error::FixError::Error).attach_printable(format!("Receiving from queue: {}", e)))
+                    }
+                }
+            };
+            let Some(session) = session.as_mut() else {continue};
+
+            for action in actions {
+                let msgs = match action {
+                    session::Action::Send(msg) => vec![msg],
+                    session::Action::Deliver(msg) => self.on_application_message(&msg, &mut subscriptions),
+                    session::Action::Disconnect => {
+                        let _ = stream.shutdown().await;
+                        return Ok(())
+                    }
+                };
+                for msg in msgs {
+                    let bytes = session.encode(msg, time::Instant::now(), time::SystemTime::now())?;
+                    stream.write_all(&bytes).await.into_report().change_context(error::FixError::Error)?;
+                }
+            }
+        }
+    }
+
+    /// Opens the session of the counterparty identified by it's Logon
+    fn open_session(&self, logon: &message::Message) -> Result<session::Session, error::FixError> {
+        if logon.msg_type() != msg_type::LOGON {
+            return Err(Report::new(error::FixError::Error).attach_printable("First message is not Logon"))
+        }
+        let target_comp_id = logon.get(tag::SENDER_COMP_ID)
+            .filter(|comp_id| !comp_id.is_empty() && comp_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+            .ok_or_else(|| Report::new(error::FixError::Error).attach_printable("Invalid SenderCompID"))?;
+        let store = store::SequenceStore::open(&self.store_dir, &self.sender_comp_id, target_comp_id)?;
+        tracing::info!("FIX session {}->{} starts at {}", target_comp_id, self.sender_comp_id, store.next_target_seq_num());
+
+        Ok(session::Session::new(
+            session::SessionConfig {
+                role: session::Role::Acceptor,
+                sender_comp_id: self.sender_comp_id.to_owned(),
+                target_comp_id: target_comp_id.to_owned(),
+                heartbeat_interval: time::Duration::from_secs(constants::fix::HEARTBEAT_INTERVAL_S)
+            },
+            store,
+            time::Instant::now()))
+    }
+
+    /// Handles MarketDataRequest, returns the messages to send
+    fn on_application_message(&self, msg: &message::Message, subscriptions: &mut Vec<market_data::Subscription>)
+        -> Vec<message::Message> {
+        if msg.msg_type() != msg_type::MARKET_DATA_REQUEST {
+            return vec![message::Message::new(msg_type::REJECT)
+                .with(tag::REF_SEQ_NUM, msg.get(tag::MSG_SEQ_NUM).unwrap_or_default())
+                .with(tag::SESSION_REJECT_REASON, 11)
+                .with(tag::TEXT, "Unsupported MsgType")]
+        }
+        let request = match market_data::parse_request(msg, &self.instrument_name) {
+            Ok(request) => request,
+            Err(reject) => return vec![reject]
+        };
+
+        match request.subscription_request_type {
+            market_data::SubscriptionRequestType::Unsubscribe => {
+                subscriptions.retain(|subscription| subscription.request.md_req_id != request.md_req_id);
+                vec![]
+            }
+            market_data::SubscriptionRequestType::Subscribe
+                if subscriptions.iter().any(|subscription| subscription.request.md_req_id == request.md_req_id) => {
+                vec![message::Message::new(msg_type::MARKET_DATA_REQUEST_REJECT)
+                    .with(tag::MD_REQ_ID, &request.md_req_id)
+                    .with(tag::MD_REQ_REJ_REASON, market_data::reject_reason::DUPLICATE_MD_REQ_ID)]
+            }
+            subscription_request_type => {
+                let book = market_data::Book::from_orderbooks(&self.orderbooks_rx.borrow(), request.depth);
+                let snapshot = market_data::get_snapshot(&request, &self.instrument_name, &book);
+                if subscription_request_type == market_data::SubscriptionRequestType::Subscribe {
+                    subscriptions.push(market_data::Subscription{request, book});
+                }
+                vec![snapshot]
+            }
+        }
+    }
+
+    fn on_aggregated_book(&self, aggregated_book: &types::SharedAggregatedBook,
+                          subscriptions: &mut [market_data::Subscription]) -> Vec<session::Action> {
+        let mut actions = vec![];
+        for subscription in subscriptions.iter_mut() {
+            let book = market_data::Book::from_aggregated_book(aggregated_book, subscription.request.depth);
+            let msg = match subscription.request.update_type {
+                market_data::UpdateType::Incremental => market_data::get_incremental_refresh(
+                    &subscription.request, &self.instrument_name, &subscription.book, &book),
+                market_data::UpdateType::FullRefresh =>
+                    Some(market_data::get_snapshot(&subscription.request, &self.instrument_name, &book))
+            };
+            if let Some(msg) = msg {actions.push(session::Action::Send(msg))}
+            subscription.book = book;
+        }
+        actions
+    }
+}
\ No newline at end of file
@@ -0,0 +1,294 @@
+//! MarketDataRequest handling of the FIX acceptor
+//!
+//! Subscriptions stream either full refreshes (W) whenever the book changes or incremental refreshes
+//! (X) with entries diffed by position in the book, `MDEntryPositionNo` is 1 for the best level.
+use crate::constants;
+use crate::fix::message::{self, md_entry_type, md_update_action, msg_type, tag};
+use crate::types;
+use crate::util;
+
+
+pub mod reject_reason {
+    pub const UNKNOWN_SYMBOL: &str = "0";
+    pub const DUPLICATE_MD_REQ_ID: &str = "1";
+    pub const UNSUPPORTED_SUBSCRIPTION_REQUEST_TYPE: &str = "4";
+    pub const UNSUPPORTED_MARKET_DEPTH: &str = "5";
+    pub const UNSUPPORTED_MD_UPDATE_TYPE: &str = "6";
+    pub const UNSUPPORTED_MD_ENTRY_TYPE: &str = "8";
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SubscriptionRequestType {
+    Snapshot,
+    Subscribe,
+    Unsubscribe
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpdateType {
+    FullRefresh,
+    Incremental
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Request {
+    pub md_req_id: String,
+    pub subscription_request_type: SubscriptionRequestType,
+    /// Number of levels on each side
+    pub depth: usize,
+    pub update_type: UpdateType,
+    pub include_bids: bool,
+    pub include_offers: bool
+}
+
+/// Top levels of the aggregated book
+#[derive(Clone, Debug, Default)]
+pub struct Book {
+    pub bids: Vec<util::Order>,
+    pub asks: Vec<util::Order>
+}
+impl Book {
+    pub fn from_orderbooks(orderbooks: &types::OrderBooksByFeed, depth: usize) -> Self {
+        let (mut asks, mut bids) = util::get_aggregated_orders(orderbooks);
+        asks.truncate(depth);
+        bids.truncate(depth);
+        Self{bids, asks}
+    }
+
+    pub fn from_aggregated_book(aggregated_book: &util::AggregatedBook, depth: usize) -> Self {
+        Self {
+            bids: aggregated_book.raw.bids.iter().take(depth).map(|ranked_order| ranked_order.order).collect(),
+            asks: aggregated_book.raw.asks.iter().take(depth).map(|ranked_order| ranked_order.order).collect()
+        }
+    }
+}
+
+pub struct Subscription {
+    pub request: Request,
+    /// Book last sent to the counterparty
+    pub book: Book
+}
+
+/// Parses the request, or returns the MarketDataRequestReject to send
+pub fn parse_request(msg: &message::Message, instrument_name: &str) -> Result<Request, message::Message> {
+    let md_req_id = msg.get(tag::MD_REQ_ID).unwrap_or_default().to_owned();
+    let reject = |reason: &str, text: &str| message::Message::new(msg_type::MARKET_DATA_REQUEST_REJECT)
+        .with(tag::MD_REQ_ID, &md_req_id)
+        .with(tag::MD_REQ_REJ_REASON, reason)
+        .with(tag::TEXT, text);
+
+    let subscription_request_type = match msg.get(tag::SUBSCRIPTION_REQUEST_TYPE) {
+        Some("0") => SubscriptionRequestType::Snapshot,
+        Some("1") => SubscriptionRequestType::Subscribe,
+        Some("2") => SubscriptionRequestType::Unsubscribe,
+        _ => return Err(reject(reject_reason::UNSUPPORTED_SUBSCRIPTION_REQUEST_TYPE, "Unsupported SubscriptionRequestType"))
+    };
+    // 0 is the full book, which is the top N levels we aggregate
+    let depth = match msg.get_u64(tag::MARKET_DEPTH) {
+        Some(0) => constants::feed_aggregator::TOP_N_BBO,
+        Some(depth) => std::cmp::min(depth as usize, constants::feed_aggregator::TOP_N_BBO),
+        None => return Err(reject(reject_reason::UNSUPPORTED_MARKET_DEPTH, "Invalid MarketDepth"))
+    };
+    let update_type = match msg.get(tag::MD_UPDATE_TYPE) {
+        Some("0") => UpdateType::FullRefresh,
+        Some("1") | None => UpdateType::Incremental,
+        _ => return Err(reject(reject_reason::UNSUPPORTED_MD_UPDATE_TYPE, "Unsupported MDUpdateType"))
+    };
+
+    let entry_types: Vec<&str> = msg.fields().iter()
+        .filter(|(field_tag, _)| *field_tag == tag::MD_ENTRY_TYPE)
+        .map(|(_, value)| value.as_str())
+        .collect();
+    if entry_types.iter().any(|entry_type| *entry_type != md_entry_type::BID && *entry_type != md_entry_type::OFFER) {
+        return Err(reject(reject_reason::UNSUPPORTED_MD_ENTRY_TYPE, "Only bids and offers are supported"))
+    }
+    let symbols: Vec<&str> = msg.fields().iter()
+        .filter(|(field_tag, _)| *field_tag == tag::SYMBOL)
+        .map(|(_, value)| value.as_str())
+        .collect();
+    if symbols.is_empty() || symbols.iter().any(|symbol| !symbol.eq_ignore_ascii_case(instrument_name)) {
+        return Err(reject(reject_reason::UNKNOWN_SYMBOL, &format!("Only {} is available", instrument_name)))
+    }
+
+    Ok(Request {
+        md_req_id: md_req_id.clone(),
+        subscription_request_type,
+        depth,
+        update_type,
+        include_bids: entry_types.contains(&md_entry_type::BID),
+        include_offers: entry_types.contains(&md_entry_type::OFFER)
+    })
+}
+
+/// MarketDataSnapshotFullRefresh of the book
+pub fn get_snapshot(request: &Request, instrument_name: &str, book: &Book) -> message::Message {
+    let mut entries = vec![];
+    if request.include_bids {
+        entries.extend(book.bids.iter().enumerate().map(|(i, order)| (md_entry_type::BID, i + 1, order)));
+    }
+    if request.include_offers {
+        entries.extend(book.asks.iter().enumerate().map(|(i, order)| (md_entry_type::OFFER, i + 1, order)));
+    }
+
+    let mut snapshot = message::Message::new(msg_type::MARKET_DATA_SNAPSHOT_FULL_REFRESH)
+        .with(tag::MD_REQ_ID, &request.md_req_id)
+        .with(tag::SYMBOL, instrument_name)
+        .with(tag::NO_MD_ENTRIES, entries.len());
+    for (entry_type, position, order) in entries {
+        push_entry(&mut snapshot, entry_type, position, order);
+    }
+    snapshot
+}
+
+/// MarketDataIncrementalRefresh from the last sent book to the new one, `None` if nothing changed
+pub fn get_incremental_refresh(request: &Request, instrument_name: &str, last_book: &Book, book: &Book)
+    -> Option<message::Message> {
+    let mut entries = vec![];
+    if request.include_bids {
+        entries.extend(diff_levels(&last_book.bids, &book.bids).into_iter().map(|entry| (md_entry_type::BID, entry)));
+    }
+    if request.include_offers {
+        entries.extend(diff_levels(&last_book.asks, &book.asks).into_iter().map(|entry| (md_entry_type::OFFER, entry)));
+    }
+    if entries.is_empty() {return None}
+
+    let mut refresh = message::Message::new(msg_type::MARKET_DATA_INCREMENTAL_REFRESH)
+        .with(tag::MD_REQ_ID, &request.md_req_id)
+        .with(tag::NO_MD_ENTRIES, entries.len());
+    for (entry_type, (update_action, position, order)) in entries {
+        refresh.push(tag::MD_UPDATE_ACTION, update_action);
+        refresh.push(tag::SYMBOL, instrument_name);
+        push_entry(&mut refresh, entry_type, position, order);
+    }
+    Some(refresh)
+}
+
+/// Update action, position and level of changed positions
+///
+/// Deletes are ordered from the bottom of the book, so a counterparty shifting levels up on each
+/// delete ends up with the same book.
+fn diff_levels<'a>(last_levels: &'a [util::Order], levels: &'a [util::Order]) -> Vec<(&'static str, usize, &'a util::Order)> {
+    let mut entries = vec![];
+    let mut deletes = vec![];
+    for i in 0..std::cmp::max(last_levels.len(), levels.len()) {
+        match (last_levels.get(i), levels.get(i)) {
+            (Some(last_order), Some(order)) if !is_same_level(last_order, order) => entries.push((md_update_action::CHANGE, i + 1, order)),
+            (None, Some(order)) => entries.push((md_update_action::NEW, i + 1, order)),
+            (Some(last_order), None) => deletes.push((md_update_action::DELETE, i + 1, last_order)),
+            _ => {}
+        }
+    }
+    entries.extend(deletes.into_iter().rev());
+    entries
+}
+
+fn is_same_level(order: &util::Order, other_order: &util::Order) -> bool {
+    order.feed as usize == other_order.feed as usize && order.price == other_order.price && order.amount == other_order.amount
+}
+
+fn push_entry(msg: &mut message::Message, entry_type: &str, position: usize, order: &util::Order) {
+    msg.push(tag::MD_ENTRY_TYPE, entry_type);
+    msg.push(tag::MD_ENTRY_PX, order.price.normalize());
+    msg.push(tag::MD_ENTRY_SIZE, order.amount.normalize());
+    msg.push(tag::MD_MKT, order.feed.feed_name_for_grpc_service());
+    msg.push(tag::MD_ENTRY_POSITION_NO, position);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    fn get_request_message() -> message::Message {
+        message::Message::new(msg_type::MARKET_DATA_REQUEST)
+            .with(tag::MD_REQ_ID, "md-1")
+            .with(tag::SUBSCRIPTION_REQUEST_TYPE, 1)
+            .with(tag::MARKET_DEPTH, 0)
+            .with(tag::MD_UPDATE_TYPE, 1)
+            .with(tag::NO_MD_ENTRY_TYPES, 2)
+            .with(tag::MD_ENTRY_TYPE, md_entry_type::BID)
+            .with(tag::MD_ENTRY_TYPE, md_entry_type::OFFER)
+            .with(tag::NO_RELATED_SYM, 1)
+            .with(tag::SYMBOL, "ETHBTC")
+    }
+
+    fn get_order(feed: constants::Feed, price: i64, amount: i64) -> util::Order {
+        util::Order{feed, price: rust_decimal::Decimal::from(price), amount: rust_decimal::Decimal::from(amount)}
+    }
+
+    mod parse_request {
+        use super::*;
+
+
+        #[test]
+        fn test_subscribe() {
+            let request = parse_request(&get_request_message(), "ethbtc").expect("Expected request");
+
+            assert_eq!(request.subscription_request_type, SubscriptionRequestType::Subscribe);
+            assert_eq!(request.depth, constants::feed_aggregator::TOP_N_BBO);
+            assert_eq!(request.update_type, UpdateType::Incremental);
+            assert!(request.include_bids && request.include_offers);
+        }
+
+        #[test]
+        fn test_reject() {
+            let reject = parse_request(&get_request_message(), "btcusdt").expect_err("Expected reject");
+            assert_eq!(reject.get(tag::MD_REQ_REJ_REASON), Some(reject_reason::UNKNOWN_SYMBOL));
+
+            let msg = get_request_message().with(tag::MD_ENTRY_TYPE, "2");
+            let reject = parse_request(&msg, "ethbtc").expect_err("Expected reject");
+            assert_eq!(reject.get(tag::MD_REQ_REJ_REASON), Some(reject_reason::UNSUPPORTED_MD_ENTRY_TYPE));
+            assert_eq!(reject.get(tag::MD_REQ_ID), Some("md-1"));
+        }
+    }
+
+    mod get_incremental_refresh {
+        use super::*;
+
+
+        #[test]
+        fn test_diff_by_position() {
+            let request = parse_request(&get_request_message(), "ethbtc").expect("Expected request");
+            let last_book = Book {
+                bids: vec![get_order(constants::Feed::BinanceSpot, 100, 1), get_order(constants::Feed::BitstampSpot, 99, 2)],
+                asks: vec![get_order(constants::Feed::BinanceSpot, 101, 1)]
+            };
+            let book = Book {
+                bids: vec![get_order(constants::Feed::BinanceSpot, 100, 1)],
+                asks: vec![get_order(constants::Feed::BinanceSpot, 101, 3), get_order(constants::Feed::BitstampSpot, 102, 1)]
+            };
+            let refresh = get_incremental_refresh(&request, "ethbtc", &last_book, &book).expect("Expected refresh");
+
+            let actions: Vec<(&str, &str)> = refresh.fields().iter()
+                .filter(|(field_tag, _)| *field_tag == tag::MD_UPDATE_ACTION || *field_tag == tag::MD_ENTRY_POSITION_NO)
+                .map(|(_, value)| value.as_str())
+                .collect::<Vec<_>>()
+                .chunks(2)
+                .map(|chunk| (chunk[0], chunk[1]))
+                .collect();
+            assert_eq!(refresh.get(tag::NO_MD_ENTRIES), Some("3"));
+            assert_eq!(actions, vec![("2", "2"), ("1", "1"), ("0", "2")]);
+            assert!(get_incremental_refresh(&request, "ethbtc", &book, &book).is_none());
+        }
+    }
+
+    mod get_snapshot {
+        use super::*;
+
+
+        #[test]
+        fn test_entries() {
+            let request = Request{include_offers: false, ..parse_request(&get_request_message(), "ethbtc").expect("Expected request")};
+            let book = Book {
+                bids: vec![get_order(constants::Feed::BitstampSpot, 100, 2)],
+                asks: vec![get_order(constants::Feed::BinanceSpot, 101, 1)]
+            };
+            let snapshot = get_snapshot(&request, "ethbtc", &book);
+
+            assert_eq!(snapshot.get(tag::NO_MD_ENTRIES), Some("1"));
+            assert_eq!(snapshot.get(tag::MD_ENTRY_PX), Some("100"));
+            assert_eq!(snapshot.get(tag::MD_MKT), Some("bitstamp"));
+        }
+    }
+}
\ No newline at end of file
@@ -1,5 +1,207 @@
+pub mod arbitrage_detector;
+pub mod auth;
+pub mod health;
+pub mod microstructure_analytics;
 pub mod orderbook_aggregator;
+pub mod paper_trading;
+pub mod tls;
+
+use std::collections;
+use std::time;
+
+use rust_decimal;
+use rust_decimal::prelude::ToPrimitive;
+use tokio::sync::{broadcast, mpsc, watch};
+use tonic;
+use tracing;
+
+use crate::metrics;
+use crate::util;
 
 pub mod server {
+    pub mod arbitrage {tonic::include_proto!("arbitrage");}
+    pub mod microstructure {tonic::include_proto!("microstructure");}
     pub mod orderbook {tonic::include_proto!("orderbook");}
+    pub mod paper_trading {tonic::include_proto!("paper_trading");}
+
+    /// Encoded descriptors of all the services above, for server reflection
+    pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("dragonflybot_descriptor");
+}
+
+
+/// What to do when a client reads slower than items are published
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SlowConsumerPolicy {
+    /// Keep only the latest item, for streams of state where only the freshest matters
+    Conflate,
+    /// Buffer up to `buffer_size` items, then end the stream with `RESOURCE_EXHAUSTED`
+    Disconnect{buffer_size: usize},
+    /// Buffer up to `buffer_size` items, dropping the oldest when full
+    DropOldest{buffer_size: usize}
+}
+impl SlowConsumerPolicy {
+    fn buffer_size(&self) -> usize {
+        match self {
+            SlowConsumerPolicy::Conflate => 1,
+            SlowConsumerPolicy::Disconnect{buffer_size} | SlowConsumerPolicy::DropOldest{buffer_size} => *buffer_size
+        }
+    }
+}
+
+/// Forwards items from an aggregator broadcast to a client's gRPC stream
+///
+/// Each client gets it's own task and bounded buffer, `policy` decides what happens when the buffer
+/// is full, so a slow client can't exhaust server memory. Items are transformed to the client's
+/// message with `transform` when they are sent, together with the number of items the client
+/// didn't get so far (dropped by the policy or skipped when the broadcast lagged). Items it returns
+/// `None` for are not sent to the client. `stream_name` labels the stream's metrics e.g.
+/// `orderbook.BookSummary`. On shutdown, pending items are discarded and the stream ends with
+/// `UNAVAILABLE`.
+pub fn spawn_stream_forwarder<T, M, F>(stream_name: &'static str, mut broadcast_rx: broadcast::Receiver<T>,
+                                       mut shutdown_rx: watch::Receiver<bool>, policy: SlowConsumerPolicy,
+                                       transform: F) -> mpsc::Receiver<Result<M, tonic::Status>>
+where
+    T: Clone + Send + 'static,
+    M: Send + 'static,
+    F: Fn(&T, u64) -> Option<M> + Send + 'static {
+    // the stream holds one message, the rest waits in the buffer where the policy applies
+    let (queue_grpc_tx, queue_grpc_rx) = mpsc::channel::<Result::<M, tonic::Status>>(1);
+
+    tokio::spawn(
+        async move {
+            let mut buffer: collections::VecDeque<T> = collections::VecDeque::with_capacity(policy.buffer_size());
+            let mut dropped: u64 = 0;
+            // the stream ends with this status once the buffer is sent
+            let mut end_status: Option<tonic::Status> = None;
+            let connected_clients = metrics::get().grpc_connected_clients.with_label_values(&[stream_name]);
+            connected_clients.inc();
+
+            loop {
+                if buffer.is_empty() {
+                    if let Some(status) = end_status {
+                        let _ = queue_grpc_tx.send(Err(status)).await;
+                        break
+                    }
+                }
+                tokio::select! {
+                    // send before receiving, so the buffer fills only when the client is slow
+                    biased;
+                    _ = queue_grpc_tx.closed() => break,
+                    permit = queue_grpc_tx.reserve(), if !buffer.is_empty() => {
+                        let Ok(permit) = permit else {break};
+                        let item = buffer.pop_front().expect("Buffer is not empty");
+                        if let Some(msg) = transform(&item, dropped) {permit.send(Ok(msg))}
+                    }
+                    _ = util::wait_for_shutdown(&mut shutdown_rx), if end_status.is_none() => {
+                        buffer.clear();
+                        end_status = Some(tonic::Status::unavailable("Server is shutting down"));
+                    }
+                    received = broadcast_rx.recv(), if end_status.is_none() => match received {
+                        Ok(item) => {
+                            if buffer.len() == policy.buffer_size() {
+                                if let SlowConsumerPolicy::Disconnect{buffer_size} = policy {
+                                    tracing::warn!("Disconnecting slow client");
+                                    buffer.clear();
+                                    end_status = Some(tonic::Status::resource_exhausted(
+                                        format!("Client is too slow, more than {} messages are pending", buffer_size)));
+                                    continue
+                                }
+                                buffer.pop_front();
+                                dropped += 1;
+                            }
+                            buffer.push_back(item);
+                        }
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            metrics::get().broadcast_lag_events.with_label_values(&[stream_name]).inc();
+                            dropped += skipped
+                        }
+                        Err(e) => {
+                            tracing::error!("Receiving from queue: {}", e);
+                            end_status = Some(tonic::Status::new(tonic::Code::Internal, "Streaming error"));
+                        }
+                    }
+                }
+            }
+            //client disconnected or the stream ended
+            tracing::info!("Client disconnected");
+            connected_clients.dec();
+        }
+    );
+    queue_grpc_rx
+}
+
+/// Converts at the protocol boundary, all calculations are done on `rust_decimal::Decimal`
+pub fn decimal_to_f64(value: rust_decimal::Decimal) -> f64 {
+    value.to_f64().unwrap_or(f64::NAN)
+}
+
+/// Nanoseconds since Unix epoch
+pub fn to_unix_nanos(timestamp: time::SystemTime) -> u64 {
+    timestamp.duration_since(time::UNIX_EPOCH).map_or(0, |duration| duration.as_nanos() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    mod spawn_stream_forwarder {
+        use super::*;
+
+
+        /// Publishes 1..=5 before the client reads, returns what the client gets as (item, dropped)
+        async fn get_received(policy: SlowConsumerPolicy) -> Vec<Result<(u64, u64), tonic::Code>> {
+            let (broadcast_tx, broadcast_rx) = broadcast::channel::<u64>(16);
+            let (_, shutdown_rx) = watch::channel(false);
+            let mut queue_grpc_rx = spawn_stream_forwarder(
+                "test", broadcast_rx, shutdown_rx, policy, |item, dropped| Some((*item, dropped)));
+            for item in 1..=5 {
+                broadcast_tx.send(item).expect("Expected subscriber");
+            }
+            drop(broadcast_tx);
+
+            let mut received = vec![];
+            while let Some(msg) = queue_grpc_rx.recv().await {
+                received.push(msg.map_err(|status| status.code()));
+            }
+            received
+        }
+
+        #[tokio::test]
+        async fn test_conflate() {
+            let received = get_received(SlowConsumerPolicy::Conflate).await;
+
+            // the first item is in the stream already, the rest is conflated to the latest
+            assert_eq!(received, vec![Ok((1, 0)), Ok((5, 3)), Err(tonic::Code::Internal)]);
+        }
+
+        #[tokio::test]
+        async fn test_drop_oldest() {
+            let received = get_received(SlowConsumerPolicy::DropOldest{buffer_size: 2}).await;
+
+            assert_eq!(received, vec![Ok((1, 0)), Ok((4, 2)), Ok((5, 2)), Err(tonic::Code::Internal)]);
+        }
+
+        #[tokio::test]
+        async fn test_disconnect() {
+            let received = get_received(SlowConsumerPolicy::Disconnect{buffer_size: 2}).await;
+
+            assert_eq!(received, vec![Ok((1, 0)), Err(tonic::Code::ResourceExhausted)]);
+        }
+
+        #[tokio::test]
+        async fn test_shutdown() {
+            let (broadcast_tx, broadcast_rx) = broadcast::channel::<u64>(16);
+            let (shutdown_tx, shutdown_rx) = watch::channel(false);
+            let mut queue_grpc_rx = spawn_stream_forwarder(
+                "test", broadcast_rx, shutdown_rx, SlowConsumerPolicy::Conflate, |item, _| Some(*item));
+            broadcast_tx.send(1).expect("Expected subscriber");
+            assert_eq!(queue_grpc_rx.recv().await.map(|msg| msg.ok()), Some(Some(1)));
+
+            shutdown_tx.send_replace(true);
+            let status = queue_grpc_rx.recv().await.and_then(|msg| msg.err()).expect("Expected final status");
+            assert_eq!(status.code(), tonic::Code::Unavailable);
+            assert!(queue_grpc_rx.recv().await.is_none());
+        }
+    }
 }
\ No newline at end of file
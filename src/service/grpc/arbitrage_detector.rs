@@ -0,0 +1,80 @@
+use std::pin;
+use std::sync::Arc;
+
+use tokio::sync::{broadcast, watch};
+use tokio_stream;
+use tokio_stream::wrappers;
+use tonic;
+use tracing;
+
+use super::server::arbitrage;
+use super::server::arbitrage::arbitrage_detector_server;
+use crate::constants;
+use crate::service::grpc;
+use crate::service::grpc::auth;
+use crate::types;
+use crate::util;
+
+
+pub struct ArbitrageDetectorService {
+    pub instrument_name: String,
+    pub broadcast_opportunity_tx: Arc<broadcast::Sender<types::BoxedArbitrageOpportunity>>,
+    /// Becomes `true` when the server shuts down
+    pub shutdown_rx: watch::Receiver<bool>
+}
+
+#[tonic::async_trait]
+impl arbitrage_detector_server::ArbitrageDetector for ArbitrageDetectorService {
+    type OpportunitiesStream =
+        pin::Pin<Box<dyn tokio_stream::Stream<Item = Result<arbitrage::Opportunity, tonic::Status>> + Send + 'static>>;
+
+    /// Streams only opportunities between venues the client is entitled to
+    async fn opportunities(&self, request: tonic::Request<arbitrage::Empty>)
+                           -> Result<tonic::Response<Self::OpportunitiesStream>, tonic::Status> {
+        let entitlements = auth::get_instrument_entitlements(&request, &self.instrument_name)
+            .ok_or_else(|| auth::instrument_not_entitled(&self.instrument_name))?;
+        tracing::info!("New arbitrage client connected");
+        let queue_grpc_rx = grpc::spawn_stream_forwarder(
+            "arbitrage.Opportunities",
+            self.broadcast_opportunity_tx.subscribe(),
+            self.shutdown_rx.clone(),
+            grpc::SlowConsumerPolicy::DropOldest{buffer_size: constants::service::grpc::STREAM_BUFFER_SIZE},
+            move |opportunity: &types::BoxedArbitrageOpportunity, _| is_opportunity_entitled(opportunity, entitlements.as_ref())
+                .then(|| to_opportunity(opportunity)));
+
+        let stream = wrappers::ReceiverStream::new(queue_grpc_rx);
+        Ok(tonic::Response::new(Box::pin(stream) as Self::OpportunitiesStream))
+    }
+}
+
+/// Whether the client is entitled to both venues, always if authentication is disabled
+fn is_opportunity_entitled(opportunity: &util::ArbitrageOpportunity, entitlements: Option<&auth::Entitlements>) -> bool {
+    entitlements.is_none_or(|entitlements|
+        entitlements.is_feed_entitled(opportunity.buy_feed) && entitlements.is_feed_entitled(opportunity.sell_feed))
+}
+
+fn to_opportunity(opportunity: &util::ArbitrageOpportunity) -> arbitrage::Opportunity {
+    let state = match opportunity.state {
+        util::ArbitrageState::Opened => arbitrage::State::Opened,
+        util::ArbitrageState::Updated => arbitrage::State::Updated,
+        util::ArbitrageState::Closed => arbitrage::State::Closed
+    };
+    let condition = match opportunity.condition {
+        util::ArbitrageCondition::Crossed => arbitrage::Condition::Crossed,
+        util::ArbitrageCondition::Locked => arbitrage::Condition::Locked
+    };
+    arbitrage::Opportunity {
+        state: state.into(),
+        condition: condition.into(),
+        buy_exchange: opportunity.buy_feed.feed_name_for_grpc_service().to_owned(),
+        sell_exchange: opportunity.sell_feed.feed_name_for_grpc_service().to_owned(),
+        best_ask: grpc::decimal_to_f64(opportunity.best_ask),
+        best_bid: grpc::decimal_to_f64(opportunity.best_bid),
+        amount: grpc::decimal_to_f64(opportunity.amount),
+        gross_profit: grpc::decimal_to_f64(opportunity.gross_profit),
+        net_profit: grpc::decimal_to_f64(opportunity.net_profit),
+        detected_at: grpc::to_unix_nanos(opportunity.detected_at),
+        timestamp: grpc::to_unix_nanos(opportunity.timestamp),
+        duration: opportunity.duration.as_nanos() as u64
+    }
+}
\ No newline at end of file
@@ -0,0 +1,269 @@
+//! Bearer token authentication and per-client entitlements of gRPC services
+//!
+//! Tokens are either listed in a local JSON file, mapping each token to the client's entitlements:
+//!
+//! `{"<token>": {"client": "desk-a", "instruments": ["ethbtc"], "exchanges": ["binance"], "depth": 5}}`
+//!
+//! or are HS256 JWTs signed with a static key, carrying the same fields as claims (`sub` is the
+//! client) and an optional `exp`. Missing `instruments` or `exchanges` mean all of them, missing
+//! `depth` means top N. `"admin": true` entitles to operator actions like the kill switch.
+use std::fs;
+use std::path;
+use std::sync::Arc;
+use std::time;
+
+use base64::Engine;
+use error_stack::{IntoReport, Report, Result, ResultExt};
+use ring::{constant_time, hmac};
+use serde_json;
+use tonic;
+
+use crate::constants;
+use crate::error;
+
+
+/// What a client may receive
+#[derive(Clone, Debug)]
+pub struct Entitlements {
+    pub client: String,
+    /// `None` if entitled to all instruments
+    pub instruments: Option<Vec<String>>,
+    /// `None` if entitled to all venues
+    pub feeds: Option<Vec<constants::Feed>>,
+    pub max_depth: usize,
+    /// Whether the client may take operator actions like the kill switch
+    pub admin: bool
+}
+impl Entitlements {
+    pub fn is_instrument_entitled(&self, instrument_name: &str) -> bool {
+        self.instruments.as_ref()
+            .is_none_or(|instruments| instruments.iter().any(|instrument| instrument.eq_ignore_ascii_case(instrument_name)))
+    }
+
+    pub fn is_feed_entitled(&self, feed: constants::Feed) -> bool {
+        self.feeds.as_ref().is_none_or(|feeds| feeds.iter().any(|other| *other as usize == feed as usize))
+    }
+
+    /// Whether the client may receive what's derived from all venues
+    pub fn is_every_venue_entitled(&self) -> bool {
+        constants::Feed::venues().all(|feed| self.is_feed_entitled(feed))
+    }
+}
+
+/// Returns the entitlements the interceptor attached, `None` if the client isn't entitled to the
+/// instrument
+///
+/// Requests have no entitlements attached when authentication is disabled, which is `Some(None)`.
+pub fn get_instrument_entitlements<T>(request: &tonic::Request<T>, instrument_name: &str) -> Option<Option<Entitlements>> {
+    match request.extensions().get::<Entitlements>() {
+        Some(entitlements) => entitlements.is_instrument_entitled(instrument_name)
+            .then(|| Some(entitlements.clone())),
+        None => Some(None)
+    }
+}
+
+pub fn instrument_not_entitled(instrument_name: &str) -> tonic::Status {
+    tonic::Status::permission_denied(format!("Not entitled to {}", instrument_name))
+}
+
+pub enum Authenticator {
+    Tokens(Vec<(String, Entitlements)>),
+    Jwt(hmac::Key)
+}
+
+impl Authenticator {
+    pub fn from_tokens_file(path: &path::Path) -> Result<Authenticator, error::ServiceError> {
+        let tokens: serde_json::Value = serde_json::from_slice(&read_file(path)?)
+            .into_report()
+            .change_context(error::ServiceError)
+            .attach_printable("Tokens file is not JSON")?;
+        let tokens = tokens.as_object()
+            .ok_or_else(|| Report::new(error::ServiceError).attach_printable("Tokens file is not a JSON object"))?
+            .iter()
+            .map(|(token, claims)| parse_entitlements(claims, "client")
+                .map(|entitlements| (token.to_owned(), entitlements))
+                .map_err(|message| Report::new(error::ServiceError).attach_printable(message)))
+            .collect::<Result<_, _>>()?;
+        Ok(Authenticator::Tokens(tokens))
+    }
+
+    /// The key is the whole content of the file, without trailing line breaks
+    pub fn from_jwt_key_file(path: &path::Path) -> Result<Authenticator, error::ServiceError> {
+        let key = read_file(path)?;
+        let key = key.strip_suffix(b"\n").unwrap_or(&key);
+        Ok(Authenticator::Jwt(hmac::Key::new(hmac::HMAC_SHA256, key.strip_suffix(b"\r").unwrap_or(key))))
+    }
+
+    /// Returns the client's entitlements, or the reason the token is rejected
+    pub fn authenticate(&self, token: &str, now: time::SystemTime) -> std::result::Result<Entitlements, &'static str> {
+        match self {
+            Authenticator::Tokens(tokens) => {
+                // compare all tokens in constant time, so the timing doesn't tell how much of a token matched
+                let mut found = None;
+                for (other, entitlements) in tokens {
+                    if constant_time::verify_slices_are_equal(other.as_bytes(), token.as_bytes()).is_ok() {
+                        found = Some(entitlements.clone());
+                    }
+                }
+                found.ok_or("Unknown token")
+            }
+            Authenticator::Jwt(key) => verify_jwt(key, token, now)
+        }
+    }
+}
+
+/// Authenticates requests and attaches the client's `Entitlements` to them
+#[derive(Clone)]
+pub struct Interceptor {
+    /// `None` if authentication is disabled, requests then have no entitlements attached
+    pub authenticator: Option<Arc<Authenticator>>
+}
+
+impl tonic::service::Interceptor for Interceptor {
+    fn call(&mut self, mut request: tonic::Request<()>) -> std::result::Result<tonic::Request<()>, tonic::Status> {
+        let Some(authenticator) = &self.authenticator else {
+            return Ok(request)
+        };
+        let token = request.metadata().get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| tonic::Status::unauthenticated("Missing bearer token"))?;
+        let entitlements = authenticator.authenticate(token, time::SystemTime::now())
+            .map_err(tonic::Status::unauthenticated)?;
+
+        request.extensions_mut().insert(entitlements);
+        Ok(request)
+    }
+}
+
+fn verify_jwt(key: &hmac::Key, token: &str, now: time::SystemTime) -> std::result::Result<Entitlements, &'static str> {
+    let mut parts = token.split('.');
+    let (Some(header), Some(payload), Some(signature), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+        return Err("Malformed token")
+    };
+    let decode = |part: &str| base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(part).map_err(|_| "Malformed token");
+    let signing_input_length = header.len() + 1 + payload.len();
+    hmac::verify(key, &token.as_bytes()[..signing_input_length], &decode(signature)?).map_err(|_| "Invalid signature")?;
+
+    let header: serde_json::Value = serde_json::from_slice(&decode(header)?).map_err(|_| "Malformed token")?;
+    if header["alg"] != "HS256" {
+        return Err("Unsupported algorithm, expected HS256")
+    }
+    let claims: serde_json::Value = serde_json::from_slice(&decode(payload)?).map_err(|_| "Malformed token")?;
+    if let Some(expires_at) = claims.get("exp") {
+        let expires_at = expires_at.as_u64().ok_or("Malformed token")?;
+        if time::UNIX_EPOCH + time::Duration::from_secs(expires_at) <= now {
+            return Err("Token expired")
+        }
+    }
+    parse_entitlements(&claims, "sub").map_err(|_| "Malformed token")
+}
+
+fn parse_entitlements(claims: &serde_json::Value, client_field: &str) -> std::result::Result<Entitlements, String> {
+    let client = claims[client_field].as_str().ok_or(format!("Missing {}", client_field))?.to_owned();
+    let get_names = |field: &str| match &claims[field] {
+        serde_json::Value::Null => Ok(None),
+        serde_json::Value::Array(names) => names.iter()
+            .map(|name| name.as_str().map(str::to_owned).ok_or(format!("{} must be a list of names", field)))
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map(Some),
+        _ => Err(format!("{} must be a list of names", field))
+    };
+    let instruments = get_names("instruments")?;
+    let feeds = get_names("exchanges")?
+        .map(|names| names.iter()
+            .map(|name| constants::Feed::from_feed_name(name).ok_or(format!("Unknown exchange {}", name)))
+            .collect::<std::result::Result<Vec<_>, _>>())
+        .transpose()?;
+    let max_depth = match &claims["depth"] {
+        serde_json::Value::Null => constants::feed_aggregator::TOP_N_BBO,
+        depth => depth.as_u64()
+            .map(|depth| depth as usize)
+            .filter(|depth| (1..=constants::feed_aggregator::TOP_N_BBO).contains(depth))
+            .ok_or(format!("Depth must be from 1 to {}", constants::feed_aggregator::TOP_N_BBO))?
+    };
+    let admin = match &claims["admin"] {
+        serde_json::Value::Null => false,
+        admin => admin.as_bool().ok_or("admin must be a boolean")?
+    };
+    Ok(Entitlements{client, instruments, feeds, max_depth, admin})
+}
+
+fn read_file(path: &path::Path) -> Result<Vec<u8>, error::ServiceError> {
+    fs::read(path)
+        .into_report()
+        .change_context(error::ServiceError)
+        .attach_printable_lazy(|| format!("Cannot read {}", path.display()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    fn get_jwt(key: &[u8], header: &str, claims: &str) -> String {
+        let encode = |part: &str| base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(part);
+        let signing_input = format!("{}.{}", encode(header), encode(claims));
+        let signature = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key), signing_input.as_bytes());
+        format!("{}.{}", signing_input, base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(signature.as_ref()))
+    }
+
+    mod authenticate {
+        use super::*;
+
+
+        #[test]
+        fn test_tokens() {
+            let claims = serde_json::json!({"client": "desk-a", "exchanges": ["binance"], "depth": 3});
+            let entitlements = parse_entitlements(&claims, "client").expect("Expected entitlements");
+            let authenticator = Authenticator::Tokens(vec![("secret".to_owned(), entitlements)]);
+
+            let entitlements = authenticator.authenticate("secret", time::SystemTime::now()).expect("Expected known token");
+            assert_eq!(entitlements.client, "desk-a");
+            assert_eq!(entitlements.max_depth, 3);
+            assert!(entitlements.is_feed_entitled(constants::Feed::BinanceSpot));
+            assert!(!entitlements.is_feed_entitled(constants::Feed::BitstampSpot));
+            assert!(entitlements.is_instrument_entitled("ETHBTC"));
+            assert!(!entitlements.is_every_venue_entitled());
+            assert!(!entitlements.admin);
+            assert!(authenticator.authenticate("secre", time::SystemTime::now()).is_err());
+        }
+
+        #[test]
+        fn test_jwt() {
+            let authenticator = Authenticator::Jwt(hmac::Key::new(hmac::HMAC_SHA256, b"key"));
+            let header = r#"{"alg":"HS256","typ":"JWT"}"#;
+            let token = get_jwt(b"key", header, r#"{"sub":"desk-b","instruments":["ethbtc"],"admin":true,"exp":2000000000}"#);
+
+            let entitlements = authenticator.authenticate(&token, time::SystemTime::now()).expect("Expected valid token");
+            assert_eq!(entitlements.client, "desk-b");
+            assert!(!entitlements.is_instrument_entitled("btcusdt"));
+            assert_eq!(entitlements.max_depth, constants::feed_aggregator::TOP_N_BBO);
+            assert!(entitlements.admin);
+
+            let expired_at = time::UNIX_EPOCH + time::Duration::from_secs(2_000_000_000);
+            assert_eq!(authenticator.authenticate(&token, expired_at).err(), Some("Token expired"));
+            let forged = get_jwt(b"other key", header, r#"{"sub":"desk-b"}"#);
+            assert_eq!(authenticator.authenticate(&forged, time::SystemTime::now()).err(), Some("Invalid signature"));
+            let unsigned = get_jwt(b"key", r#"{"alg":"none"}"#, r#"{"sub":"desk-b"}"#);
+            assert!(authenticator.authenticate(&unsigned, time::SystemTime::now()).is_err());
+        }
+    }
+
+    mod get_instrument_entitlements {
+        use super::*;
+
+
+        #[test]
+        fn test_instrument_not_entitled() {
+            let mut request = tonic::Request::new(());
+            assert!(matches!(get_instrument_entitlements(&request, "ethbtc"), Some(None)));
+
+            let claims = serde_json::json!({"client": "desk-a", "instruments": ["btcusdt"]});
+            request.extensions_mut().insert(parse_entitlements(&claims, "client").expect("Expected entitlements"));
+            assert!(get_instrument_entitlements(&request, "ethbtc").is_none());
+            assert!(get_instrument_entitlements(&request, "btcusdt").expect("Expected entitled instrument").is_some());
+        }
+    }
+}
\ No newline at end of file
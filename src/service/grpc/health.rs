@@ -0,0 +1,97 @@
+//! Readiness of the order book service, served by the standard `grpc.health.v1.Health` service
+//!
+//! The order book service (and the server as a whole, the empty service name) is `SERVING` only
+//! while at least one feed is live, i.e. something was read from it's connection within
+//! `HEALTH_MAX_AGE_S`, and the aggregator has published, not as soon as the process is up. Books
+//! are forwarded only when they change, so a quiet market is still live. Both are `NOT_SERVING`
+//! once the server shuts down.
+use std::time;
+
+use tokio::sync::watch;
+use tonic_health::{server::HealthReporter, ServingStatus};
+use tracing;
+
+use crate::constants;
+use crate::metrics;
+use crate::types;
+use crate::util;
+
+
+pub struct HealthMonitor {
+    pub reporter: HealthReporter,
+    /// Name of the service whose status follows readiness, e.g. `orderbook.OrderbookAggregator`
+    pub service_name: &'static str,
+    pub aggregated_book_rx: watch::Receiver<Option<types::SharedAggregatedBook>>,
+    pub max_age: time::Duration,
+    pub check_interval: time::Duration,
+    /// Becomes `true` when the server shuts down
+    pub shutdown_rx: watch::Receiver<bool>
+}
+
+impl HealthMonitor {
+    /// Periodically checks readiness and updates the statuses when it changes, until shutdown
+    pub async fn run(mut self) {
+        let mut interval = tokio::time::interval(self.check_interval);
+        let mut was_ready = None;
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = util::wait_for_shutdown(&mut self.shutdown_rx) => {
+                    self.reporter.set_service_status("", ServingStatus::NotServing).await;
+                    self.reporter.set_service_status(self.service_name, ServingStatus::NotServing).await;
+                    break
+                }
+            }
+            let last_reads: Vec<_> = constants::Feed::venues().map(|feed| metrics::get().get_feed_last_read(feed)).collect();
+            let ready = is_ready(&last_reads, self.aggregated_book_rx.borrow().is_some(), time::SystemTime::now(), self.max_age);
+            if was_ready == Some(ready) {
+                continue
+            }
+
+            let status = if ready {ServingStatus::Serving} else {ServingStatus::NotServing};
+            tracing::info!("{} is {:?}", self.service_name, status);
+            self.reporter.set_service_status("", status).await;
+            self.reporter.set_service_status(self.service_name, status).await;
+            was_ready = Some(ready);
+        }
+    }
+}
+
+/// Whether at least one feed was read within `max_age` and the aggregator has published
+fn is_ready(last_reads: &[Option<time::SystemTime>], published: bool, now: time::SystemTime, max_age: time::Duration)
+    -> bool {
+    // timestamps ahead of `now` are fresh
+    let is_fresh = |timestamp: &time::SystemTime| now.duration_since(*timestamp).unwrap_or_default() <= max_age;
+
+    last_reads.iter().flatten().any(is_fresh) && published
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    mod is_ready {
+        use super::*;
+
+
+        #[test]
+        fn test_stale_feeds_or_aggregator() {
+            let now = time::UNIX_EPOCH + time::Duration::from_secs(100);
+            let max_age = time::Duration::from_secs(30);
+            let mut last_reads = vec![None, None];
+
+            // nothing read yet
+            assert!(!is_ready(&last_reads, true, now, max_age));
+
+            last_reads[1] = Some(now - time::Duration::from_secs(5));
+            assert!(is_ready(&last_reads, true, now, max_age));
+            assert!(!is_ready(&last_reads, false, now, max_age));
+
+            last_reads[1] = Some(now - time::Duration::from_secs(31));
+            assert!(!is_ready(&last_reads, true, now, max_age));
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,72 @@
+use std::pin;
+use std::sync::Arc;
+
+use tokio::sync::{broadcast, watch};
+use tokio_stream;
+use tokio_stream::wrappers;
+use tonic;
+use tracing;
+
+use super::server::microstructure;
+use super::server::microstructure::microstructure_analytics_server;
+use crate::service::grpc;
+use crate::service::grpc::auth;
+use crate::types;
+use crate::util;
+
+
+pub struct MicrostructureAnalyticsService {
+    pub instrument_name: String,
+    pub broadcast_signals_tx: Arc<broadcast::Sender<types::BoxedMicrostructureSignals>>,
+    /// Becomes `true` when the server shuts down
+    pub shutdown_rx: watch::Receiver<bool>
+}
+
+#[tonic::async_trait]
+impl microstructure_analytics_server::MicrostructureAnalytics for MicrostructureAnalyticsService {
+    type SignalsStream =
+        pin::Pin<Box<dyn tokio_stream::Stream<Item = Result<microstructure::MicrostructureSignals, tonic::Status>> + Send + 'static>>;
+
+    /// Signals are derived from all venues, only clients entitled to all of them receive them
+    async fn signals(&self, request: tonic::Request<microstructure::Empty>)
+                     -> Result<tonic::Response<Self::SignalsStream>, tonic::Status> {
+        let entitlements = auth::get_instrument_entitlements(&request, &self.instrument_name)
+            .ok_or_else(|| auth::instrument_not_entitled(&self.instrument_name))?;
+        if !entitlements.is_none_or(|entitlements| entitlements.is_every_venue_entitled()) {
+            return Err(tonic::Status::permission_denied("Signals require entitlements to all exchanges"))
+        }
+        tracing::info!("New microstructure client connected");
+        let queue_grpc_rx = grpc::spawn_stream_forwarder(
+            "microstructure.Signals",
+            self.broadcast_signals_tx.subscribe(),
+            self.shutdown_rx.clone(),
+            grpc::SlowConsumerPolicy::Conflate,
+            |signals: &types::BoxedMicrostructureSignals, _| Some(to_signals(signals)));
+
+        let stream = wrappers::ReceiverStream::new(queue_grpc_rx);
+        Ok(tonic::Response::new(Box::pin(stream) as Self::SignalsStream))
+    }
+}
+
+fn to_signals(signals: &util::MicrostructureSignals) -> microstructure::MicrostructureSignals {
+    microstructure::MicrostructureSignals {
+        mid_price: grpc::decimal_to_f64(signals.mid_price),
+        microprice: grpc::decimal_to_f64(signals.microprice),
+        imbalances: signals.imbalances.iter().map(|imbalance| microstructure::Imbalance {
+            levels: imbalance.levels as u32,
+            imbalance: grpc::decimal_to_f64(imbalance.imbalance)
+        }).collect(),
+        depths: signals.depths.iter().map(|depth| microstructure::DepthAtOffset {
+            offset_bps: grpc::decimal_to_f64(depth.offset_bps),
+            bid_amount: grpc::decimal_to_f64(depth.bid_amount),
+            ask_amount: grpc::decimal_to_f64(depth.ask_amount)
+        }).collect(),
+        contributions: signals.contributions.iter().map(|contribution| microstructure::ExchangeContribution {
+            exchange: contribution.feed.feed_name_for_grpc_service().to_owned(),
+            bid_amount: grpc::decimal_to_f64(contribution.bid_amount),
+            ask_amount: grpc::decimal_to_f64(contribution.ask_amount),
+            bid_share: grpc::decimal_to_f64(contribution.bid_share),
+            ask_share: grpc::decimal_to_f64(contribution.ask_share)
+        }).collect()
+    }
+}
\ No newline at end of file
@@ -1,15 +1,25 @@
 use std;
 use std::pin;
 
-use tokio::sync::{broadcast, mpsc};
+use rust_decimal;
+use rust_decimal::prelude::FromPrimitive;
+use strum::IntoEnumIterator;
+use tokio::sync::mpsc;
 use tokio_stream;
 use tokio_stream::wrappers;
+use tokio_stream::StreamExt;
 use tonic;
 use tracing;
 
 use super::server::orderbook;
 use super::server::orderbook::orderbook_aggregator_server;
 use crate::constants;
+use crate::execution::cost;
+use crate::metrics;
+use crate::metrics::latency;
+use crate::service::grpc;
+use crate::service::grpc::auth;
+use crate::types;
 use crate::util;
 
 
@@ -19,68 +29,465 @@ pub struct OrderbookAggregatorService {pub context: util::GrpcClientContext}
 impl orderbook_aggregator_server::OrderbookAggregator for OrderbookAggregatorService {
     type BookSummaryStream =
         pin::Pin<Box<dyn tokio_stream::Stream<Item = Result<orderbook::Summary, tonic::Status>> + Send + 'static>>;
+    type ExecutionCostStreamStream =
+        pin::Pin<Box<dyn tokio_stream::Stream<Item = Result<orderbook::ExecutionCostReply, tonic::Status>> + Send + 'static>>;
 
-    async fn book_summary(&self, _: tonic::Request<orderbook::Empty>)
+    /// Starts the stream with the latest book, so clients don't wait for the next update
+    ///
+    /// We subscribe before reading the latest book, an update in between may be sent twice (with the
+    /// same sequence number) but none is missed.
+    async fn book_summary(&self, request: tonic::Request<orderbook::BookSummaryRequest>)
                           -> Result<tonic::Response<Self::BookSummaryStream>, tonic::Status> {
-        tracing::info!("New client connected");
-        let mut broadcast_rx = self.context.broadcast_aggregator_tx.subscribe();
+        let entitlements = auth::get_instrument_entitlements(&request, &self.context.instrument_name)
+            .ok_or_else(|| auth::instrument_not_entitled(&self.context.instrument_name))?;
+        tracing::info!("New client connected: {}", entitlements.as_ref().map_or("anonymous", |entitlements| &entitlements.client));
+        let view = self.parse_book_summary_request(request.get_ref(), entitlements).ok_or_else(fee_adjusted_not_enabled)?;
+        let broadcast_rx = self.context.broadcast_aggregator_tx.subscribe();
+        let snapshot = self.get_latest_summary(&view);
+        let queue_grpc_rx = grpc::spawn_stream_forwarder(
+            "orderbook.BookSummary",
+            broadcast_rx,
+            self.context.shutdown_rx.clone(),
+            to_slow_consumer_policy(request.get_ref().slow_consumer_policy()),
+            move |aggregated_book: &types::SharedAggregatedBook, dropped| {
+                // the book is handed to the client's stream right after it's transformed
+                let summary = to_summary(aggregated_book, &view).map(|summary| orderbook::Summary{dropped, ..summary});
+                metrics::get().latency.record(latency::Stage::GrpcSend, aggregated_book.aggregated_at.elapsed());
+                metrics::get().latency.record(latency::Stage::EndToEnd, aggregated_book.read_at.elapsed());
+                summary
+            });
+
+        let stream = tokio_stream::iter(snapshot.map(Ok)).chain(wrappers::ReceiverStream::new(queue_grpc_rx));
+        Ok(tonic::Response::new(Box::pin(stream) as Self::BookSummaryStream))
+    }
+
+    async fn get_snapshot(&self, request: tonic::Request<orderbook::BookSummaryRequest>)
+                          -> Result<tonic::Response<orderbook::Summary>, tonic::Status> {
+        let entitlements = auth::get_instrument_entitlements(&request, &self.context.instrument_name)
+            .ok_or_else(|| auth::instrument_not_entitled(&self.context.instrument_name))?;
+        let view = self.parse_book_summary_request(request.get_ref(), entitlements).ok_or_else(fee_adjusted_not_enabled)?;
+        self.get_latest_summary(&view)
+            .map(tonic::Response::new)
+            .ok_or_else(|| tonic::Status::unavailable("No market data available"))
+    }
+
+    async fn execution_cost(&self, request: tonic::Request<orderbook::ExecutionCostRequest>)
+                            -> Result<tonic::Response<orderbook::ExecutionCostReply>, tonic::Status> {
+        let entitlements = auth::get_instrument_entitlements(&request, &self.context.instrument_name)
+            .ok_or_else(|| auth::instrument_not_entitled(&self.context.instrument_name))?;
+        let (side, amount) = parse_execution_cost_request(request.get_ref()).ok_or_else(invalid_amount)?;
+        let orderbooks = get_entitled_orderbooks(&self.context.orderbooks_rx.borrow(), entitlements.as_ref());
+
+        match cost::get_execution_cost(&orderbooks, side, amount) {
+            Some(execution_cost) => Ok(tonic::Response::new(to_execution_cost_reply(&execution_cost))),
+            None => Err(tonic::Status::unavailable("No market data available"))
+        }
+    }
+
+    /// Streams the cost on every update of the aggregated book
+    async fn execution_cost_stream(&self, request: tonic::Request<orderbook::ExecutionCostRequest>)
+                                   -> Result<tonic::Response<Self::ExecutionCostStreamStream>, tonic::Status> {
+        tracing::info!("New execution cost client connected");
+        let entitlements = auth::get_instrument_entitlements(&request, &self.context.instrument_name)
+            .ok_or_else(|| auth::instrument_not_entitled(&self.context.instrument_name))?;
+        let (side, amount) = parse_execution_cost_request(request.get_ref()).ok_or_else(invalid_amount)?;
+        let mut orderbooks_rx = self.context.orderbooks_rx.clone();
+        let mut shutdown_rx = self.context.shutdown_rx.clone();
+        // the watch channel already keeps only the latest state, so there's no need to buffer
         let (queue_grpc_tx, queue_grpc_rx) =
-            mpsc::channel::<Result::<orderbook::Summary, tonic::Status>>(constants::QUEUE_BUFFER_SIZE);
+            mpsc::channel::<Result::<orderbook::ExecutionCostReply, tonic::Status>>(1);
 
         tokio::spawn(
             async move {
+                let connected_clients = metrics::get().grpc_connected_clients.with_label_values(&["orderbook.ExecutionCostStream"]);
+                connected_clients.inc();
                 loop {
-                    match broadcast_rx.recv().await {
-                        Ok(orderbook_summary) => {
-                            match queue_grpc_tx.send(Result::<_, tonic::Status>::Ok(*orderbook_summary)).await {
-                                Ok(_) => {}
-                                Err(_) => {
-                                    //client disconnected
-                                    tracing::info!("Client disconnected");
-                                    break
-                                }
-                            }
-                        }
-                        Err(broadcast::error::RecvError::Lagged(_)) => {
-                            //If we lag behind, keep retrying until we get to the most recent data.
-                            loop {
-                                match broadcast_rx.recv().await {
-                                    Ok(orderbook_summary) => {
-                                        break match queue_grpc_tx.send(Result::<_, tonic::Status>::Ok(*orderbook_summary)).await {
-                                            Ok(_) => {}
-                                            Err(e) => {
-                                                //client disconnected
-                                                tracing::info!("Client disconnected: {}", e);
-                                                break
-                                            }
-                                        }
-                                    }
-                                    Err(_) => {
-                                        // we're lagging behind so continue with the loop until we
-                                        // get to the current item on the queue
-                                    }
-                                }
-                            }
+                    tokio::select! {
+                        biased;
+                        _ = util::wait_for_shutdown(&mut shutdown_rx) => {
+                            let _ = queue_grpc_tx.send(Err(tonic::Status::unavailable("Server is shutting down"))).await;
+                            break
                         }
-                        Err(e) => {
-                            tracing::error!("Receiving from queue: {}", e);
-                            match queue_grpc_tx.send(Result::<_, tonic::Status>::Err(
-                                tonic::Status::new(tonic::Code::Internal, "Streaming error"))).await {
-                                Ok(_) => {}
-                                Err(_) => {
-                                    //client disconnected
-                                    tracing::info!("Client disconnected");
-                                    break
-                                }
-                            }
+                        changed = orderbooks_rx.changed() => if changed.is_err() {break}
+                    }
+                    let orderbooks = get_entitled_orderbooks(&orderbooks_rx.borrow_and_update(), entitlements.as_ref());
+                    if let Some(execution_cost) = cost::get_execution_cost(&orderbooks, side, amount) {
+                        if queue_grpc_tx.send(Ok(to_execution_cost_reply(&execution_cost))).await.is_err() {
+                            //client disconnected
+                            tracing::info!("Client disconnected");
+                            break
                         }
                     }
                 }
+                connected_clients.dec();
             }
         );
         let stream = wrappers::ReceiverStream::new(queue_grpc_rx);
-        Ok(tonic::Response::new(Box::pin(stream) as Self::BookSummaryStream))
+        Ok(tonic::Response::new(Box::pin(stream) as Self::ExecutionCostStreamStream))
+    }
+}
+
+/// The book as a client requested it and may receive it
+#[derive(Clone)]
+struct SummaryView {
+    fee_adjusted: bool,
+    price_format: orderbook::PriceFormat,
+    /// `None` if authentication is disabled
+    entitlements: Option<auth::Entitlements>
+}
+
+impl OrderbookAggregatorService {
+    /// Returns `None` if the client requested the fee-adjusted view and it's not enabled
+    fn parse_book_summary_request(&self, request: &orderbook::BookSummaryRequest,
+                                  entitlements: Option<auth::Entitlements>) -> Option<SummaryView> {
+        (!request.fee_adjusted || self.context.fee_adjusted)
+            .then_some(SummaryView{fee_adjusted: request.fee_adjusted, price_format: request.price_format(), entitlements})
+    }
+
+    /// Returns `None` until the aggregator publishes the first book
+    fn get_latest_summary(&self, view: &SummaryView) -> Option<orderbook::Summary> {
+        self.context.aggregated_book_rx.borrow()
+            .as_ref()
+            .and_then(|aggregated_book| to_summary(aggregated_book, view))
+    }
+}
+
+/// Excludes venues the client isn't entitled to, like the listener excludes a disconnected feed
+fn get_entitled_orderbooks(orderbooks: &types::OrderBooksByFeed, entitlements: Option<&auth::Entitlements>)
+    -> types::OrderBooksByFeed {
+    let mut orderbooks = *orderbooks;
+    if let Some(entitlements) = entitlements {
+        for feed in constants::Feed::iter().filter(|feed| !entitlements.is_feed_entitled(*feed)) {
+            orderbooks[feed as usize].set_unreachable_price();
+        }
+    }
+    orderbooks
+}
+
+fn to_slow_consumer_policy(policy: orderbook::SlowConsumerPolicy) -> grpc::SlowConsumerPolicy {
+    let buffer_size = constants::service::grpc::STREAM_BUFFER_SIZE;
+    match policy {
+        orderbook::SlowConsumerPolicy::Conflate => grpc::SlowConsumerPolicy::Conflate,
+        orderbook::SlowConsumerPolicy::Disconnect => grpc::SlowConsumerPolicy::Disconnect{buffer_size},
+        orderbook::SlowConsumerPolicy::DropOldest => grpc::SlowConsumerPolicy::DropOldest{buffer_size}
+    }
+}
+
+/// Returns `None` if the amount is not a positive number
+fn parse_execution_cost_request(request: &orderbook::ExecutionCostRequest) -> Option<(util::Side, rust_decimal::Decimal)> {
+    let side = match request.side() {
+        orderbook::Side::Buy => util::Side::Buy,
+        orderbook::Side::Sell => util::Side::Sell
+    };
+    rust_decimal::Decimal::from_f64(request.amount)
+        .filter(|amount| *amount > rust_decimal::Decimal::ZERO)
+        .map(|amount| (side, amount))
+}
+
+fn invalid_amount() -> tonic::Status {
+    tonic::Status::invalid_argument("Amount must be a positive number")
+}
+
+fn fee_adjusted_not_enabled() -> tonic::Status {
+    tonic::Status::failed_precondition("Fee-adjusted ranking is not enabled on the server")
+}
+
+fn to_execution_cost_reply(execution_cost: &util::ExecutionCost) -> orderbook::ExecutionCostReply {
+    let side = match execution_cost.side {
+        util::Side::Buy => orderbook::Side::Buy,
+        util::Side::Sell => orderbook::Side::Sell
+    };
+    orderbook::ExecutionCostReply {
+        side: side.into(),
+        requested_amount: grpc::decimal_to_f64(execution_cost.requested_amount),
+        filled_amount: grpc::decimal_to_f64(execution_cost.filled_amount),
+        notional: grpc::decimal_to_f64(execution_cost.notional),
+        vwap: grpc::decimal_to_f64(execution_cost.vwap),
+        worst_price: grpc::decimal_to_f64(execution_cost.worst_price),
+        mid_price: grpc::decimal_to_f64(execution_cost.mid_price),
+        slippage_bps: grpc::decimal_to_f64(execution_cost.slippage_bps),
+        allocations: execution_cost.allocations.iter().map(|allocation| orderbook::Allocation {
+            exchange: allocation.feed.feed_name_for_grpc_service().to_owned(),
+            amount: grpc::decimal_to_f64(allocation.amount),
+            notional: grpc::decimal_to_f64(allocation.notional)
+        }).collect()
+    }
+}
+
+/// Transforms the aggregator output to the gRPC message for the view the client requested
+///
+/// Returns `None` if the client requested a view the aggregator doesn't calculate.
+fn to_summary(aggregated_book: &util::AggregatedBook, view: &SummaryView) -> Option<orderbook::Summary> {
+    let ranked_book = if view.fee_adjusted {
+        aggregated_book.fee_adjusted.as_ref()?
+    } else {
+        &aggregated_book.raw
+    };
+    match &view.entitlements {
+        Some(entitlements) => {
+            let entitled_book = get_entitled_book(ranked_book, entitlements, view.fee_adjusted);
+            Some(to_summary_view(aggregated_book, &entitled_book, view.price_format))
+        }
+        None => Some(to_summary_view(aggregated_book, ranked_book, view.price_format))
+    }
+}
+
+/// Leaves out levels of venues the client isn't entitled to and levels beyond it's depth
+///
+/// Levels are taken from all ranked levels, so venues ranked below the top N still fill the depth
+/// once the others are left out. The spread is recalculated from the remaining top of the book, by
+/// the price the book is ranked by.
+fn get_entitled_book(ranked_book: &util::RankedBook, entitlements: &auth::Entitlements, fee_adjusted: bool)
+    -> util::RankedBook {
+    let get_levels = |levels: &[util::RankedOrder]| -> Vec<util::RankedOrder> {
+        levels.iter()
+            .filter(|ranked_order| entitlements.is_feed_entitled(ranked_order.order.feed))
+            .take(entitlements.max_depth)
+            .copied()
+            .collect()
+    };
+    let asks = get_levels(&ranked_book.all_asks);
+    let bids = get_levels(&ranked_book.all_bids);
+    let price = |ranked_order: &util::RankedOrder| if fee_adjusted {ranked_order.effective_price} else {ranked_order.order.price};
+    let spread = match (asks.first(), bids.first()) {
+        (Some(ask), Some(bid)) => price(ask) - price(bid),
+        _ => rust_decimal::Decimal::ZERO
+    };
+    util::RankedBook{spread, asks, bids, ..Default::default()}
+}
+
+fn to_summary_view(aggregated_book: &util::AggregatedBook, ranked_book: &util::RankedBook,
+                   price_format: orderbook::PriceFormat) -> orderbook::Summary {
+    let to_level = |ranked_order: &util::RankedOrder| orderbook::Level {
+        exchange: ranked_order.order.feed.feed_name_for_grpc_service().to_owned(),
+        price: grpc::decimal_to_f64(ranked_order.order.price),
+        amount: grpc::decimal_to_f64(ranked_order.order.amount),
+        effective_price: grpc::decimal_to_f64(ranked_order.effective_price),
+        exchange_timestamp: ranked_order.exchange_timestamp.map_or(0, grpc::to_unix_nanos),
+        received_at: ranked_order.received_at.map_or(0, grpc::to_unix_nanos),
+        exact_price: to_exact_decimal(ranked_order.order.price, price_format),
+        exact_amount: to_exact_decimal(ranked_order.order.amount, price_format),
+        exact_effective_price: to_exact_decimal(ranked_order.effective_price, price_format)
+    };
+    orderbook::Summary {
+        spread: grpc::decimal_to_f64(ranked_book.spread),
+        bids: ranked_book.bids.iter().map(to_level).collect(),
+        asks: ranked_book.asks.iter().map(to_level).collect(),
+        sequence: aggregated_book.sequence,
+        published_at: grpc::to_unix_nanos(aggregated_book.published_at),
+        exact_spread: to_exact_decimal(ranked_book.spread, price_format),
+        dropped: 0
     }
 }
 
+/// Returns `None` for `PriceFormat::Double`, the client reads the doubles only
+///
+/// A mantissa that doesn't fit `int64` is rounded to fewer fractional digits, which doesn't happen
+/// with venue prices and amounts but can with calculated effective prices. Integers that don't fit
+/// are left out.
+fn to_exact_decimal(value: rust_decimal::Decimal, price_format: orderbook::PriceFormat) -> Option<orderbook::Decimal> {
+    let value = match price_format {
+        orderbook::PriceFormat::Double => return None,
+        orderbook::PriceFormat::String => orderbook::decimal::Value::Text(value.to_string()),
+        orderbook::PriceFormat::Scaled => {
+            let mut value = value.normalize();
+            while i64::try_from(value.mantissa()).is_err() && value.scale() > 0 {
+                value = value.round_dp(value.scale() - 1);
+            }
+            orderbook::decimal::Value::Scaled(orderbook::ScaledInteger {
+                mantissa: i64::try_from(value.mantissa()).ok()?,
+                exponent: -(value.scale() as i32)
+            })
+        }
+    };
+    Some(orderbook::Decimal{value: Some(value)})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use orderbook_aggregator_server::OrderbookAggregator;
+    use std::sync::Arc;
+    use tokio::sync::{broadcast, watch};
+
+
+    fn get_service(aggregated_book: Option<types::SharedAggregatedBook>)
+        -> (OrderbookAggregatorService, broadcast::Sender<types::SharedAggregatedBook>) {
+        let (broadcast_tx, _) = broadcast::channel(1);
+        let (_, orderbooks_rx) = watch::channel(util::get_initialized_orderbooks());
+        let (_, aggregated_book_rx) = watch::channel(aggregated_book);
+        let (_, shutdown_rx) = watch::channel(false);
+        let context = util::GrpcClientContext {
+            instrument_name: "ethbtc".to_owned(),
+            broadcast_aggregator_tx: Arc::new(broadcast_tx.clone()),
+            orderbooks_rx,
+            aggregated_book_rx,
+            fee_adjusted: false,
+            shutdown_rx
+        };
+        (OrderbookAggregatorService{context}, broadcast_tx)
+    }
+
+    fn get_aggregated_book(sequence: u64) -> types::SharedAggregatedBook {
+        Arc::new(util::AggregatedBook {
+            sequence,
+            published_at: std::time::SystemTime::now(),
+            read_at: std::time::Instant::now(),
+            aggregated_at: std::time::Instant::now(),
+            raw: util::RankedBook::default(),
+            fee_adjusted: None
+        })
+    }
+
+    mod get_snapshot {
+        use super::*;
+
+
+        #[tokio::test]
+        async fn test_no_market_data() {
+            let (service, _) = get_service(None);
+            let request = tonic::Request::new(orderbook::BookSummaryRequest{fee_adjusted: false, ..Default::default()});
+
+            let status = service.get_snapshot(request).await.expect_err("Expected unavailable");
+            assert_eq!(status.code(), tonic::Code::Unavailable);
+        }
+
+        #[tokio::test]
+        async fn test_fee_adjusted_not_enabled() {
+            let (service, _) = get_service(Some(get_aggregated_book(1)));
+            let request = tonic::Request::new(orderbook::BookSummaryRequest{fee_adjusted: true, ..Default::default()});
+
+            let status = service.get_snapshot(request).await.expect_err("Expected failed precondition");
+            assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+        }
+
+        #[tokio::test]
+        async fn test_instrument_not_entitled() {
+            let (service, _) = get_service(Some(get_aggregated_book(1)));
+            let mut request = tonic::Request::new(orderbook::BookSummaryRequest::default());
+            request.extensions_mut().insert(auth::Entitlements {
+                client: "desk-a".to_owned(),
+                instruments: Some(vec!["btcusdt".to_owned()]),
+                feeds: None,
+                max_depth: 1,
+                admin: false
+            });
+
+            let status = service.get_snapshot(request).await.expect_err("Expected permission denied");
+            assert_eq!(status.code(), tonic::Code::PermissionDenied);
+        }
+    }
 
+    mod to_exact_decimal {
+        use super::*;
+
+
+        #[test]
+        fn test_formats() {
+            let value = rust_decimal::Decimal::new(6_812_345_678, 11);
+            let scaled = orderbook::decimal::Value::Scaled(orderbook::ScaledInteger{mantissa: 6_812_345_678, exponent: -11});
+
+            assert_eq!(to_exact_decimal(value, orderbook::PriceFormat::Double), None);
+            assert_eq!(to_exact_decimal(value, orderbook::PriceFormat::String).and_then(|decimal| decimal.value),
+                       Some(orderbook::decimal::Value::Text("0.06812345678".to_owned())));
+            assert_eq!(to_exact_decimal(value, orderbook::PriceFormat::Scaled).and_then(|decimal| decimal.value), Some(scaled));
+        }
+
+        #[test]
+        fn test_scaled_mantissa_overflow() {
+            // 28 fractional digits, the mantissa doesn't fit int64
+            let value = rust_decimal::Decimal::ONE / rust_decimal::Decimal::from(3);
+            let Some(orderbook::decimal::Value::Scaled(scaled)) = to_exact_decimal(value, orderbook::PriceFormat::Scaled)
+                .and_then(|decimal| decimal.value) else {panic!("Expected scaled decimal")};
+
+            assert_eq!(scaled.mantissa, 3_333_333_333_333_333_333);
+            assert_eq!(scaled.exponent, -19);
+            assert_eq!(to_exact_decimal(rust_decimal::Decimal::MAX, orderbook::PriceFormat::Scaled), None);
+        }
+    }
+
+    mod get_entitled_book {
+        use super::*;
+
+
+        #[test]
+        fn test_feeds_and_depth() {
+            let get_ranked_order = |feed, price| {
+                let order = util::Order{feed, price: rust_decimal::Decimal::from(price), amount: rust_decimal::Decimal::ONE};
+                util::RankedOrder{order, effective_price: order.price, exchange_timestamp: None, received_at: None}
+            };
+            let asks = vec![get_ranked_order(constants::Feed::BinanceSpot, 101), get_ranked_order(constants::Feed::BitstampSpot, 102),
+                            get_ranked_order(constants::Feed::BitstampSpot, 103)];
+            let bids = vec![get_ranked_order(constants::Feed::BinanceSpot, 100), get_ranked_order(constants::Feed::BitstampSpot, 99)];
+            let ranked_book = util::RankedBook {
+                spread: rust_decimal::Decimal::ONE, asks: asks.clone(), bids: bids.clone(), all_asks: asks, all_bids: bids};
+            let entitlements = auth::Entitlements {
+                client: "desk-a".to_owned(),
+                instruments: None,
+                feeds: Some(vec![constants::Feed::BitstampSpot]),
+                max_depth: 1,
+                admin: false
+            };
+            let entitled_book = get_entitled_book(&ranked_book, &entitlements, false);
+
+            assert_eq!(entitled_book.asks.len(), 1);
+            assert_eq!(entitled_book.asks[0].order.price, rust_decimal::Decimal::from(102));
+            assert_eq!(entitled_book.bids[0].order.price, rust_decimal::Decimal::from(99));
+            assert_eq!(entitled_book.spread, rust_decimal::Decimal::from(3));
+        }
+
+        #[test]
+        fn test_feed_ranked_below_top_n() {
+            let get_ranked_order = |feed, price| {
+                let order = util::Order{feed, price: rust_decimal::Decimal::from(price), amount: rust_decimal::Decimal::ONE};
+                util::RankedOrder{order, effective_price: order.price, exchange_timestamp: None, received_at: None}
+            };
+            // binance fills the top N, bitstamp is ranked below it
+            let top_n = constants::feed_aggregator::TOP_N_BBO as i64;
+            let mut all_asks: Vec<_> = (0..top_n).map(|i| get_ranked_order(constants::Feed::BinanceSpot, 101 + i)).collect();
+            let mut all_bids: Vec<_> = (0..top_n).map(|i| get_ranked_order(constants::Feed::BinanceSpot, 100 - i)).collect();
+            all_asks.push(get_ranked_order(constants::Feed::BitstampSpot, 200));
+            all_bids.push(get_ranked_order(constants::Feed::BitstampSpot, 50));
+            let ranked_book = util::RankedBook {
+                spread: rust_decimal::Decimal::ONE,
+                asks: all_asks[..constants::feed_aggregator::TOP_N_BBO].to_vec(),
+                bids: all_bids[..constants::feed_aggregator::TOP_N_BBO].to_vec(),
+                all_asks,
+                all_bids
+            };
+            let entitlements = auth::Entitlements {
+                client: "desk-a".to_owned(),
+                instruments: None,
+                feeds: Some(vec![constants::Feed::BitstampSpot]),
+                max_depth: constants::feed_aggregator::TOP_N_BBO,
+                admin: false
+            };
+            let entitled_book = get_entitled_book(&ranked_book, &entitlements, false);
+
+            assert_eq!(entitled_book.asks.len(), 1);
+            assert_eq!(entitled_book.asks[0].order.price, rust_decimal::Decimal::from(200));
+            assert_eq!(entitled_book.bids[0].order.price, rust_decimal::Decimal::from(50));
+            assert_eq!(entitled_book.spread, rust_decimal::Decimal::from(150));
+        }
+    }
+
+
+    mod book_summary {
+        use super::*;
+
+
+        #[tokio::test]
+        async fn test_starts_with_latest_book() {
+            let (service, broadcast_tx) = get_service(Some(get_aggregated_book(1)));
+            let request = tonic::Request::new(orderbook::BookSummaryRequest{fee_adjusted: false, ..Default::default()});
+            let mut stream = service.book_summary(request).await.expect("Expected stream").into_inner();
+
+            let snapshot = stream.next().await.expect("Expected snapshot").expect("Expected summary");
+            broadcast_tx.send(get_aggregated_book(2)).expect("Expected subscriber");
+            let update = stream.next().await.expect("Expected update").expect("Expected summary");
+            assert_eq!(snapshot.sequence, 1);
+            assert_eq!(update.sequence, 2);
+            assert!(update.published_at > 0);
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,189 @@
+use std::pin;
+use std::sync::Arc;
+
+use rust_decimal;
+use rust_decimal::prelude::FromPrimitive;
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+use tokio_stream;
+use tokio_stream::wrappers;
+use tonic;
+use tracing;
+
+use super::server::paper_trading;
+use super::server::paper_trading::paper_trading_server;
+use crate::constants;
+use crate::execution::{order, paper};
+use crate::service::grpc;
+use crate::service::grpc::auth;
+use crate::types;
+use crate::util;
+
+
+pub struct PaperTradingService {
+    pub instrument_name: String,
+    pub command_tx: mpsc::Sender<paper::Command>,
+    pub broadcast_order_tx: Arc<broadcast::Sender<types::BoxedPaperOrder>>,
+    /// Becomes `true` when the server shuts down
+    pub shutdown_rx: watch::Receiver<bool>
+}
+
+impl PaperTradingService {
+    /// Returns `None` if the engine is not running
+    async fn send_command<T>(&self, command: paper::Command, reply_rx: oneshot::Receiver<T>) -> Option<T> {
+        self.command_tx.send(command).await.ok()?;
+        reply_rx.await.ok()
+    }
+}
+
+#[tonic::async_trait]
+impl paper_trading_server::PaperTrading for PaperTradingService {
+    type OrderUpdatesStream =
+        pin::Pin<Box<dyn tokio_stream::Stream<Item = Result<paper_trading::Order, tonic::Status>> + Send + 'static>>;
+
+    async fn submit_order(&self, request: tonic::Request<paper_trading::OrderRequest>)
+                          -> Result<tonic::Response<paper_trading::Order>, tonic::Status> {
+        let entitlements = auth::get_instrument_entitlements(&request, &self.instrument_name)
+            .ok_or_else(|| auth::instrument_not_entitled(&self.instrument_name))?;
+        let request = parse_order_request(request.get_ref())
+            .ok_or_else(|| tonic::Status::invalid_argument("Unknown exchange or invalid amount"))?;
+        if !entitlements.is_none_or(|entitlements| entitlements.is_feed_entitled(request.feed)) {
+            return Err(tonic::Status::permission_denied(format!("Not entitled to {}", request.feed.feed_name_for_grpc_service())))
+        }
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let paper_order = self.send_command(paper::Command::SubmitOrder{request, reply_tx}, reply_rx).await
+            .ok_or_else(engine_unavailable)?;
+
+        Ok(tonic::Response::new(to_order(&paper_order)))
+    }
+
+    async fn cancel_order(&self, request: tonic::Request<paper_trading::CancelOrderRequest>)
+                          -> Result<tonic::Response<paper_trading::Order>, tonic::Status> {
+        auth::get_instrument_entitlements(&request, &self.instrument_name)
+            .ok_or_else(|| auth::instrument_not_entitled(&self.instrument_name))?;
+        let id = request.get_ref().id;
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let paper_order = self.send_command(paper::Command::CancelOrder{id, reply_tx}, reply_rx).await
+            .ok_or_else(engine_unavailable)?
+            .ok_or_else(|| tonic::Status::not_found("No working order with this id"))?;
+
+        Ok(tonic::Response::new(to_order(&paper_order)))
+    }
+
+    async fn get_account(&self, request: tonic::Request<paper_trading::Empty>)
+                         -> Result<tonic::Response<paper_trading::Account>, tonic::Status> {
+        auth::get_instrument_entitlements(&request, &self.instrument_name)
+            .ok_or_else(|| auth::instrument_not_entitled(&self.instrument_name))?;
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let account = self.send_command(paper::Command::GetAccount{reply_tx}, reply_rx).await
+            .ok_or_else(engine_unavailable)?;
+
+        Ok(tonic::Response::new(paper_trading::Account {
+            position: grpc::decimal_to_f64(account.position),
+            average_price: grpc::decimal_to_f64(account.average_price),
+            mark_price: account.mark_price.map_or(f64::NAN, grpc::decimal_to_f64),
+            realized_pnl: grpc::decimal_to_f64(account.realized_pnl),
+            unrealized_pnl: grpc::decimal_to_f64(account.unrealized_pnl),
+            fees: grpc::decimal_to_f64(account.fees)
+        }))
+    }
+
+    /// Halts or resumes trading of all clients, only admins may flip it
+    async fn set_kill_switch(&self, request: tonic::Request<paper_trading::KillSwitchRequest>)
+                             -> Result<tonic::Response<paper_trading::Empty>, tonic::Status> {
+        let entitlements = auth::get_instrument_entitlements(&request, &self.instrument_name)
+            .ok_or_else(|| auth::instrument_not_entitled(&self.instrument_name))?;
+        if !entitlements.is_none_or(|entitlements| entitlements.admin) {
+            return Err(tonic::Status::permission_denied("Kill switch requires the admin entitlement"))
+        }
+        let is_on = request.get_ref().is_on;
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.send_command(paper::Command::SetKillSwitch{is_on, reply_tx}, reply_rx).await
+            .ok_or_else(engine_unavailable)?;
+
+        Ok(tonic::Response::new(paper_trading::Empty{}))
+    }
+
+    /// Streams only updates of orders on venues the client is entitled to
+    async fn order_updates(&self, request: tonic::Request<paper_trading::Empty>)
+                           -> Result<tonic::Response<Self::OrderUpdatesStream>, tonic::Status> {
+        let entitlements = auth::get_instrument_entitlements(&request, &self.instrument_name)
+            .ok_or_else(|| auth::instrument_not_entitled(&self.instrument_name))?;
+        tracing::info!("New paper-trading client connected");
+        let queue_grpc_rx = grpc::spawn_stream_forwarder(
+            "paper_trading.OrderUpdates",
+            self.broadcast_order_tx.subscribe(),
+            self.shutdown_rx.clone(),
+            // order updates can't be skipped, the client would miss fills
+            grpc::SlowConsumerPolicy::Disconnect{buffer_size: constants::service::grpc::STREAM_BUFFER_SIZE},
+            move |paper_order: &types::BoxedPaperOrder, _| entitlements.as_ref()
+                .is_none_or(|entitlements| entitlements.is_feed_entitled(paper_order.request.feed))
+                .then(|| to_order(paper_order)));
+
+        let stream = wrappers::ReceiverStream::new(queue_grpc_rx);
+        Ok(tonic::Response::new(Box::pin(stream) as Self::OrderUpdatesStream))
+    }
+}
+
+/// Returns `None` for an unknown exchange or an amount which is not a number
+///
+/// Everything else is validated by the engine, which rejects invalid orders.
+fn parse_order_request(request: &paper_trading::OrderRequest) -> Option<paper::OrderRequest> {
+    let side = match request.side() {
+        paper_trading::Side::Buy => util::Side::Buy,
+        paper_trading::Side::Sell => util::Side::Sell
+    };
+    let order_type = match request.order_type() {
+        paper_trading::OrderType::Limit => paper::OrderType::Limit,
+        paper_trading::OrderType::Market => paper::OrderType::Market
+    };
+    Some(paper::OrderRequest {
+        feed: constants::Feed::from_venue_name(&request.exchange)?,
+        side,
+        order_type,
+        amount: rust_decimal::Decimal::from_f64(request.amount)?,
+        limit_price: rust_decimal::Decimal::from_f64(request.limit_price)
+    })
+}
+
+fn engine_unavailable() -> tonic::Status {
+    tonic::Status::unavailable("Paper-trading engine is not running")
+}
+
+fn to_order(paper_order: &paper::PaperOrder) -> paper_trading::Order {
+    let request = &paper_order.request;
+    let side = match request.side {
+        util::Side::Buy => paper_trading::Side::Buy,
+        util::Side::Sell => paper_trading::Side::Sell
+    };
+    let order_type = match request.order_type {
+        paper::OrderType::Limit => paper_trading::OrderType::Limit,
+        paper::OrderType::Market => paper_trading::OrderType::Market
+    };
+    let status = match paper_order.status {
+        order::OrderStatus::PendingNew => paper_trading::OrderStatus::PendingNew,
+        order::OrderStatus::New => paper_trading::OrderStatus::New,
+        order::OrderStatus::PartiallyFilled => paper_trading::OrderStatus::PartiallyFilled,
+        order::OrderStatus::Filled => paper_trading::OrderStatus::Filled,
+        order::OrderStatus::Cancelled => paper_trading::OrderStatus::Cancelled,
+        order::OrderStatus::Rejected => paper_trading::OrderStatus::Rejected
+    };
+    paper_trading::Order {
+        id: paper_order.id,
+        exchange: request.feed.feed_name_for_grpc_service().to_owned(),
+        side: side.into(),
+        order_type: order_type.into(),
+        amount: grpc::decimal_to_f64(request.amount),
+        limit_price: request.limit_price.map_or(f64::NAN, grpc::decimal_to_f64),
+        status: status.into(),
+        filled_amount: grpc::decimal_to_f64(paper_order.filled_amount()),
+        average_price: paper_order.average_price().map_or(f64::NAN, grpc::decimal_to_f64),
+        fills: paper_order.fills.iter().map(|fill| paper_trading::Fill {
+            price: grpc::decimal_to_f64(fill.price),
+            amount: grpc::decimal_to_f64(fill.amount),
+            fee: grpc::decimal_to_f64(fill.fee),
+            is_maker: fill.is_maker,
+            timestamp: grpc::to_unix_nanos(fill.timestamp)
+        }).collect(),
+        reject_reason: paper_order.reject_reason.clone().unwrap_or_default()
+    }
+}
\ No newline at end of file
@@ -0,0 +1,65 @@
+//! TLS configuration of the gRPC server and clients
+//!
+//! Certificates and keys are read from PEM files. The server optionally verifies client
+//! certificates against a CA bundle (mutual TLS).
+use std::fs;
+use std::path;
+
+use error_stack::{IntoReport, Result, ResultExt};
+use tonic::transport;
+
+use crate::error;
+
+
+/// Server presenting the certificate of `cert`, clients must present a certificate signed by
+/// `client_ca` if given
+pub fn get_server_tls_config(cert: &path::Path, key: &path::Path, client_ca: Option<&path::Path>)
+    -> Result<transport::ServerTlsConfig, error::ServiceError> {
+    let identity = transport::Identity::from_pem(read_pem(cert)?, read_pem(key)?);
+    let tls_config = transport::ServerTlsConfig::new().identity(identity);
+
+    match client_ca {
+        Some(client_ca) => Ok(tls_config.client_ca_root(transport::Certificate::from_pem(read_pem(client_ca)?))),
+        None => Ok(tls_config)
+    }
+}
+
+/// Client verifying the server's certificate for `domain` against `ca` and presenting `identity`
+/// (certificate and key) if the server requires it
+pub fn get_client_tls_config(domain: &str, ca: &path::Path, identity: Option<(&path::Path, &path::Path)>)
+    -> Result<transport::ClientTlsConfig, error::ServiceError> {
+    let mut tls_config = transport::ClientTlsConfig::new()
+        .domain_name(domain)
+        .ca_certificate(transport::Certificate::from_pem(read_pem(ca)?));
+    if let Some((cert, key)) = identity {
+        tls_config = tls_config.identity(transport::Identity::from_pem(read_pem(cert)?, read_pem(key)?));
+    }
+    Ok(tls_config)
+}
+
+fn read_pem(path: &path::Path) -> Result<Vec<u8>, error::ServiceError> {
+    fs::read(path)
+        .into_report()
+        .change_context(error::ServiceError)
+        .attach_printable_lazy(|| format!("Cannot read {}", path.display()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    mod get_server_tls_config {
+        use super::*;
+
+
+        #[test]
+        fn test_missing_file() {
+            let path = path::Path::new("does-not-exist.pem");
+
+            let e = get_server_tls_config(path, path, None).expect_err("Expected missing certificate");
+            assert!(format!("{:?}", e).contains("does-not-exist.pem"));
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,54 @@
+//! HTTP endpoint Prometheus scrapes the pipeline's metrics from
+//!
+//! `GET /metrics` returns `metrics::get()` in the text exposition format.
+use std::convert;
+use std::net;
+
+use error_stack::{IntoReport, Result, ResultExt};
+use hyper;
+use tokio::sync::watch;
+
+use crate::error;
+use crate::metrics;
+use crate::util;
+
+
+pub struct MetricsServer {
+    /// Becomes `true` when the server shuts down
+    pub shutdown_rx: watch::Receiver<bool>
+}
+
+impl MetricsServer {
+    /// Serves until shutdown, then stops accepting connections and waits for scrapes in flight
+    pub async fn run(mut self, addr: net::SocketAddr) -> Result<(), error::ServiceError> {
+        let make_service = hyper::service::make_service_fn(|_| async {
+            Ok::<_, convert::Infallible>(hyper::service::service_fn(|request| {
+                let response = handle_request(&request);
+                async move {Ok::<_, convert::Infallible>(response)}
+            }))
+        });
+
+        hyper::Server::try_bind(&addr)
+            .into_report()
+            .change_context(error::ServiceError)
+            .attach_printable_lazy(|| format!("Cannot bind metrics server to {}", addr))?
+            .serve(make_service)
+            .with_graceful_shutdown(async move {util::wait_for_shutdown(&mut self.shutdown_rx).await})
+            .await
+            .into_report()
+            .change_context(error::ServiceError)
+    }
+}
+
+fn handle_request(request: &hyper::Request<hyper::Body>) -> hyper::Response<hyper::Body> {
+    let (status, body) = match (request.method(), request.uri().path()) {
+        (&hyper::Method::GET, "/metrics") => (hyper::StatusCode::OK, metrics::get().encode()),
+        (_, "/metrics") => (hyper::StatusCode::METHOD_NOT_ALLOWED, "Only GET is allowed\n".to_owned()),
+        _ => (hyper::StatusCode::NOT_FOUND, "Not found\n".to_owned())
+    };
+    hyper::Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(hyper::Body::from(body))
+        .expect("Response is valid")
+}
\ No newline at end of file
@@ -0,0 +1,208 @@
+//! HTTP/JSON snapshot API of the consolidated book
+//!
+//! `GET /book/{instrument}?depth=N&exchanges=binance,bitstamp` returns the aggregated book and the
+//! book of each venue, read from the latest state the aggregator stored, so requests don't
+//! subscribe to the broadcast stream. Prices and amounts are decimal strings, timestamps are
+//! nanoseconds since Unix epoch.
+use std::convert;
+use std::net;
+use std::sync::Arc;
+use std::time;
+
+use error_stack::{IntoReport, Result, ResultExt};
+use hyper;
+use serde_json;
+use strum::IntoEnumIterator;
+use tokio::sync::watch;
+
+use crate::constants;
+use crate::error;
+use crate::service::grpc;
+use crate::types;
+use crate::util;
+
+
+pub struct RestServer {
+    pub instrument_name: String,
+    /// Latest order book of each feed as seen by the aggregator
+    pub orderbooks_rx: watch::Receiver<types::OrderBooksByFeed>,
+    /// Becomes `true` when the server shuts down
+    pub shutdown_rx: watch::Receiver<bool>
+}
+
+impl RestServer {
+    /// Serves until shutdown, then stops accepting connections and waits for requests in flight
+    pub async fn run(self, addr: net::SocketAddr) -> Result<(), error::ServiceError> {
+        let mut shutdown_rx = self.shutdown_rx.clone();
+        let server = Arc::new(self);
+        let make_service = hyper::service::make_service_fn(move |_| {
+            let server = Arc::clone(&server);
+            async move {
+                Ok::<_, convert::Infallible>(hyper::service::service_fn(move |request| {
+                    let response = server.handle_request(&request);
+                    async move {Ok::<_, convert::Infallible>(response)}
+                }))
+            }
+        });
+
+        hyper::Server::try_bind(&addr)
+            .into_report()
+            .change_context(error::ServiceError)
+            .attach_printable_lazy(|| format!("Cannot bind REST server to {}", addr))?
+            .serve(make_service)
+            .with_graceful_shutdown(async move {util::wait_for_shutdown(&mut shutdown_rx).await})
+            .await
+            .into_report()
+            .change_context(error::ServiceError)
+    }
+
+    fn handle_request(&self, request: &hyper::Request<hyper::Body>) -> hyper::Response<hyper::Body> {
+        let Some(instrument) = request.uri().path().strip_prefix("/book/") else {
+            return get_response(hyper::StatusCode::NOT_FOUND, serde_json::json!({"error": "Not found"}))
+        };
+        if request.method() != hyper::Method::GET {
+            return get_response(hyper::StatusCode::METHOD_NOT_ALLOWED, serde_json::json!({"error": "Only GET is allowed"}))
+        }
+        if !instrument.eq_ignore_ascii_case(&self.instrument_name) {
+            return get_response(hyper::StatusCode::NOT_FOUND, serde_json::json!({
+                "error": format!("Unknown instrument {}, only {} is available", instrument, self.instrument_name)}))
+        }
+
+        match parse_query(request.uri().query()) {
+            Ok((depth, feeds)) => {
+                let orderbooks = *self.orderbooks_rx.borrow();
+                get_response(hyper::StatusCode::OK,
+                             get_book(&self.instrument_name, &orderbooks, depth, &feeds, time::SystemTime::now()))
+            }
+            Err(message) => get_response(hyper::StatusCode::BAD_REQUEST, serde_json::json!({"error": message}))
+        }
+    }
+}
+
+/// Returns depth and feeds to include, or the error message for the client
+fn parse_query(query: Option<&str>) -> std::result::Result<(usize, Vec<constants::Feed>), String> {
+    let mut depth = constants::feed_aggregator::TOP_N_BBO;
+    let mut feeds: Vec<constants::Feed> = constants::Feed::iter().collect();
+
+    for (key, value) in query.unwrap_or_default().split('&').filter_map(|pair| pair.split_once('=')) {
+        match key {
+            "depth" => {
+                depth = value.parse().ok()
+                    .filter(|depth| (1..=constants::feed_aggregator::TOP_N_BBO).contains(depth))
+                    .ok_or(format!("Depth must be from 1 to {}", constants::feed_aggregator::TOP_N_BBO))?;
+            }
+            "exchanges" => {
+                feeds = value.replace("%2C", ",").replace("%2c", ",").split(',')
+                    .map(|name| constants::Feed::from_feed_name(name).ok_or(format!("Unknown exchange {}", name)))
+                    .collect::<std::result::Result<_, _>>()?;
+            }
+            _ => return Err(format!("Unknown parameter {}", key))
+        }
+    }
+    Ok((depth, feeds))
+}
+
+/// Aggregated book of the feeds and the book of each feed
+fn get_book(instrument_name: &str, orderbooks: &types::OrderBooksByFeed, depth: usize, feeds: &[constants::Feed],
+            now: time::SystemTime) -> serde_json::Value {
+    let mut included_orderbooks = *orderbooks;
+    for feed in constants::Feed::iter().filter(|feed| !feeds.iter().any(|other| *other as usize == *feed as usize)) {
+        included_orderbooks[feed as usize].set_unreachable_price();
+    }
+    let (asks, bids) = util::get_aggregated_orders(&included_orderbooks);
+    let spread = match (asks.first(), bids.first()) {
+        (Some(ask), Some(bid)) => serde_json::json!((ask.price - bid.price).to_string()),
+        _ => serde_json::Value::Null
+    };
+    let to_level = |order: &util::Order| serde_json::json!({
+        "exchange": order.feed.feed_name_for_grpc_service(),
+        "price": order.price.to_string(),
+        "amount": order.amount.to_string()
+    });
+
+    let exchanges: Vec<serde_json::Value> = feeds.iter().map(|feed| {
+        let orderbook = &orderbooks[*feed as usize];
+        let mut single_orderbook = util::get_initialized_orderbooks();
+        single_orderbook[*feed as usize] = *orderbook;
+        let (asks, bids) = util::get_aggregated_orders(&single_orderbook);
+        serde_json::json!({
+            "exchange": feed.feed_name_for_grpc_service(),
+            "exchange_timestamp": orderbook.exchange_timestamp.map(grpc::to_unix_nanos),
+            "received_at": orderbook.received_at.map(grpc::to_unix_nanos),
+            "bids": bids.iter().take(depth).map(to_level).collect::<Vec<_>>(),
+            "asks": asks.iter().take(depth).map(to_level).collect::<Vec<_>>()
+        })
+    }).collect();
+
+    serde_json::json!({
+        "instrument": instrument_name,
+        "timestamp": grpc::to_unix_nanos(now),
+        "aggregated": {
+            "spread": spread,
+            "bids": bids.iter().take(depth).map(to_level).collect::<Vec<_>>(),
+            "asks": asks.iter().take(depth).map(to_level).collect::<Vec<_>>()
+        },
+        "exchanges": exchanges
+    })
+}
+
+fn get_response(status: hyper::StatusCode, body: serde_json::Value) -> hyper::Response<hyper::Body> {
+    hyper::Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(hyper::Body::from(body.to_string()))
+        .expect("Response is valid")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    mod parse_query {
+        use super::*;
+
+
+        #[test]
+        fn test_depth_and_exchanges() {
+            let (depth, feeds) = parse_query(Some("depth=3&exchanges=bitstamp%2Cbinance")).expect("Expected valid query");
+
+            assert_eq!(depth, 3);
+            assert_eq!(feeds.len(), 2);
+            assert!(matches!(feeds[0], constants::Feed::BitstampSpot));
+            assert_eq!(parse_query(None).expect("Expected valid query").1.len(), constants::Feed::iter().count());
+        }
+
+        #[test]
+        fn test_invalid_query() {
+            assert!(parse_query(Some("depth=0")).is_err());
+            assert!(parse_query(Some("exchanges=kraken")).is_err());
+            assert!(parse_query(Some("size=1")).is_err());
+        }
+    }
+
+    mod get_book {
+        use super::*;
+
+
+        #[test]
+        fn test_excluded_exchanges() {
+            let mut orderbooks = util::get_initialized_orderbooks();
+            let received_at = time::UNIX_EPOCH + time::Duration::from_secs(1);
+            for (feed, price) in [(constants::Feed::BinanceSpot, 101), (constants::Feed::BitstampSpot, 100)] {
+                let orderbook = &mut orderbooks[feed as usize];
+                orderbook.asks[0] = util::Order{feed, price: rust_decimal::Decimal::from(price), amount: rust_decimal::Decimal::ONE};
+                orderbook.bids[0] = util::Order{feed, price: rust_decimal::Decimal::from(price - 2), amount: rust_decimal::Decimal::ONE};
+                orderbook.received_at = Some(received_at);
+            }
+            let book = get_book("ethbtc", &orderbooks, 5, &[constants::Feed::BinanceSpot], time::SystemTime::now());
+
+            assert_eq!(book["aggregated"]["asks"][0]["price"], "101");
+            assert_eq!(book["aggregated"]["spread"], "2");
+            assert_eq!(book["exchanges"].as_array().map(Vec::len), Some(1));
+            assert_eq!(book["exchanges"][0]["received_at"], 1_000_000_000u64);
+            assert_eq!(book["exchanges"][0]["bids"][0]["price"], "99");
+        }
+    }
+}
\ No newline at end of file
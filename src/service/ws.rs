@@ -0,0 +1,371 @@
+//! WebSocket server publishing the aggregated book as JSON
+//!
+//! Clients send `{"method": "subscribe", "instrument": "ethbtc", "depth": 5}` to start receiving
+//! the top `depth` levels on every update of the aggregated book, optionally with
+//! `"fee_adjusted": true` for the fee-inclusive ranking, and `{"method": "unsubscribe", "instrument":
+//! "ethbtc"}` to stop. Prices and amounts are decimal strings, so they're exactly what venues sent.
+//!
+//! Same as with gRPC streams, a client lagging behind skips to the most recent book. On shutdown
+//! clients get a close frame with code 1001 (going away).
+use std::convert;
+use std::io;
+use std::net;
+use std::pin;
+use std::sync::Arc;
+use std::task;
+
+use error_stack::{IntoReport, Result, ResultExt};
+use fastwebsockets;
+use hyper;
+use serde_json;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{broadcast, mpsc, watch};
+use tracing;
+
+use crate::constants;
+use crate::error;
+use crate::metrics;
+use crate::types;
+use crate::util;
+
+
+pub struct WsServer {
+    pub instrument_name: String,
+    pub broadcast_aggregator_tx: Arc<broadcast::Sender<types::SharedAggregatedBook>>,
+    /// Whether the aggregator ranks the book by fee-inclusive effective prices
+    pub fee_adjusted: bool,
+    /// Becomes `true` when the server shuts down
+    pub shutdown_rx: watch::Receiver<bool>
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Request {
+    Subscribe(Subscription),
+    Unsubscribe{instrument: String}
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Subscription {
+    instrument: String,
+    depth: usize,
+    fee_adjusted: bool
+}
+
+/// Frames from the client, copied out of the reader's buffer
+enum ClientFrame {
+    Text(String),
+    Ping(Vec<u8>),
+    Close
+}
+
+/// Joins a read half and a write half into one stream
+///
+/// `fastwebsockets` needs a stream that's both readable and writable, while reading a frame is not
+/// cancel safe. So the connection is split and each direction gets it's own `WebSocket`, the read
+/// one paired with a sink and the write one with an empty reader.
+struct Duplex<R, W> {
+    reader: R,
+    writer: W
+}
+
+impl<R: AsyncRead + Unpin, W: Unpin> AsyncRead for Duplex<R, W> {
+    fn poll_read(self: pin::Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut tokio::io::ReadBuf<'_>)
+        -> task::Poll<io::Result<()>> {
+        pin::Pin::new(&mut self.get_mut().reader).poll_read(cx, buf)
+    }
+}
+
+impl<R: Unpin, W: AsyncWrite + Unpin> AsyncWrite for Duplex<R, W> {
+    fn poll_write(self: pin::Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> task::Poll<io::Result<usize>> {
+        pin::Pin::new(&mut self.get_mut().writer).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: pin::Pin<&mut Self>, cx: &mut task::Context<'_>) -> task::Poll<io::Result<()>> {
+        pin::Pin::new(&mut self.get_mut().writer).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: pin::Pin<&mut Self>, cx: &mut task::Context<'_>) -> task::Poll<io::Result<()>> {
+        pin::Pin::new(&mut self.get_mut().writer).poll_shutdown(cx)
+    }
+}
+
+impl WsServer {
+    /// Serves until shutdown, then stops accepting connections and waits for clients to be closed
+    pub async fn run(self, addr: net::SocketAddr) -> Result<(), error::ServiceError> {
+        let mut shutdown_rx = self.shutdown_rx.clone();
+        let server = Arc::new(self);
+        // each connection's task holds a sender, the channel closes once all of them finished
+        let (connection_tx, mut connection_rx) = mpsc::channel::<()>(1);
+        let make_service = hyper::service::make_service_fn({
+            let connection_tx = connection_tx.clone();
+            move |_| {
+                let server = Arc::clone(&server);
+                let connection_tx = connection_tx.clone();
+                async move {
+                    Ok::<_, convert::Infallible>(hyper::service::service_fn(move |request| {
+                        Arc::clone(&server).handle_request(request, connection_tx.clone())
+                    }))
+                }
+            }
+        });
+
+        let result = hyper::Server::try_bind(&addr)
+            .into_report()
+            .change_context(error::ServiceError)
+            .attach_printable_lazy(|| format!("Cannot bind WebSocket server to {}", addr))?
+            .serve(make_service)
+            .with_graceful_shutdown(async move {util::wait_for_shutdown(&mut shutdown_rx).await})
+            .await
+            .into_report()
+            .change_context(error::ServiceError);
+        drop(connection_tx);
+        // upgraded connections are not hyper's anymore
+        let _ = connection_rx.recv().await;
+        result
+    }
+
+    async fn handle_request(self: Arc<Self>, mut request: hyper::Request<hyper::Body>, connection_tx: mpsc::Sender<()>)
+        -> std::result::Result<hyper::Response<hyper::Body>, convert::Infallible> {
+        if !fastwebsockets::upgrade::is_upgrade_request(&request) {
+            return Ok(get_bad_request("Expected a WebSocket upgrade request"))
+        }
+        let (response, upgrade_fut) = match fastwebsockets::upgrade::upgrade(&mut request) {
+            Ok(upgrade) => upgrade,
+            Err(e) => return Ok(get_bad_request(&e.to_string()))
+        };
+
+        tokio::spawn(
+            async move {
+                match upgrade_fut.await {
+                    Ok(ws) => {
+                        tracing::info!("New WebSocket client connected");
+                        self.handle_connection(ws).await;
+                    }
+                    Err(e) => tracing::error!("WebSocket upgrade failed: {}", e)
+                }
+                drop(connection_tx);
+            });
+        Ok(response)
+    }
+
+    async fn handle_connection(&self, ws: fastwebsockets::WebSocket<hyper::upgrade::Upgraded>) {
+        let (read_half, write_half) = tokio::io::split(ws.into_inner());
+        let mut reader = fastwebsockets::WebSocket::after_handshake(
+            Duplex{reader: read_half, writer: tokio::io::sink()}, fastwebsockets::Role::Server);
+        // the reader can't write, pongs and close are sent by the writer
+        reader.set_auto_pong(false);
+        reader.set_auto_close(false);
+        let mut reader = fastwebsockets::FragmentCollector::new(reader);
+        let mut writer = fastwebsockets::WebSocket::after_handshake(
+            Duplex{reader: tokio::io::empty(), writer: write_half}, fastwebsockets::Role::Server);
+
+        let (frame_tx, mut frame_rx) = mpsc::channel::<ClientFrame>(constants::service::ws::FRAME_BUFFER_SIZE);
+        let reader_handle = tokio::spawn(
+            async move {
+                loop {
+                    let client_frame = match reader.read_frame().await {
+                        Ok(frame) => match frame.opcode {
+                            fastwebsockets::OpCode::Text =>
+                                ClientFrame::Text(String::from_utf8_lossy(&frame.payload).into_owned()),
+                            fastwebsockets::OpCode::Ping => ClientFrame::Ping(frame.payload.to_vec()),
+                            fastwebsockets::OpCode::Close => ClientFrame::Close,
+                            _ => continue
+                        },
+                        Err(_) => ClientFrame::Close
+                    };
+                    let is_close = matches!(client_frame, ClientFrame::Close);
+                    if frame_tx.send(client_frame).await.is_err() || is_close {break}
+                }
+            });
+
+        let mut broadcast_rx = self.broadcast_aggregator_tx.subscribe();
+        let mut shutdown_rx = self.shutdown_rx.clone();
+        let mut subscriptions: Vec<Subscription> = vec![];
+        'connection: loop {
+            let msgs = tokio::select! {
+                _ = util::wait_for_shutdown(&mut shutdown_rx) => {
+                    let _ = writer.write_frame(fastwebsockets::Frame::close(1001, b"Server shutting down")).await;
+                    break
+                }
+                client_frame = frame_rx.recv() => match client_frame {
+                    Some(ClientFrame::Text(text)) => vec![self.on_request(&text, &mut subscriptions)],
+                    Some(ClientFrame::Ping(payload)) => {
+                        let _ = writer.write_frame(fastwebsockets::Frame::pong(payload.into())).await;
+                        continue
+                    }
+                    Some(ClientFrame::Close) | None => {
+                        let _ = writer.write_frame(fastwebsockets::Frame::close(1000, b"")).await;
+                        break
+                    }
+                },
+                update = broadcast_rx.recv() => match update {
+                    Ok(aggregated_book) => subscriptions.iter()
+                        .filter_map(|subscription| to_book_msg(&aggregated_book, subscription))
+                        .collect(),
+                    Err(broadcast::error::RecvError::Lagged(_)) => {
+                        //If we lag behind, keep retrying until we get to the most recent data.
+                        metrics::get().broadcast_lag_events.with_label_values(&["ws"]).inc();
+                        continue
+                    }
+                    Err(e) => {
+                        tracing::error!("Receiving from queue: {}", e);
+                        let _ = writer.write_frame(fastwebsockets::Frame::close(1011, b"Streaming error")).await;
+                        break
+                    }
+                }
+            };
+
+            for msg in msgs {
+                let frame = fastwebsockets::Frame::text(msg.to_string().into_bytes().into());
+                if writer.write_frame(frame).await.is_err() {
+                    //client disconnected
+                    break 'connection
+                }
+            }
+        }
+        //otherwise the reader waits for the client's next frame and keeps its half of the socket open
+        reader_handle.abort();
+        tracing::info!("WebSocket client disconnected");
+    }
+
+    /// Applies the request and returns the reply
+    fn on_request(&self, text: &str, subscriptions: &mut Vec<Subscription>) -> serde_json::Value {
+        let request = match parse_request(text, &self.instrument_name, self.fee_adjusted) {
+            Ok(request) => request,
+            Err(message) => return serde_json::json!({"event": "error", "message": message})
+        };
+        match request {
+            Request::Subscribe(subscription) => {
+                let reply = serde_json::json!({
+                    "event": "subscribed",
+                    "instrument": subscription.instrument,
+                    "depth": subscription.depth,
+                    "fee_adjusted": subscription.fee_adjusted
+                });
+                subscriptions.retain(|other| other.instrument != subscription.instrument);
+                subscriptions.push(subscription);
+                reply
+            }
+            Request::Unsubscribe{instrument} => {
+                subscriptions.retain(|subscription| subscription.instrument != instrument);
+                serde_json::json!({"event": "unsubscribed", "instrument": instrument})
+            }
+        }
+    }
+}
+
+/// Returns the error message to send to the client if the request is invalid
+fn parse_request(text: &str, instrument_name: &str, fee_adjusted: bool) -> std::result::Result<Request, String> {
+    let request: serde_json::Value = serde_json::from_str(text).map_err(|_| "Request is not JSON".to_owned())?;
+    let instrument = request["instrument"].as_str()
+        .ok_or("Missing instrument")?
+        .to_lowercase();
+    if instrument != instrument_name.to_lowercase() {
+        return Err(format!("Unknown instrument {}, only {} is available", instrument, instrument_name))
+    }
+
+    match request["method"].as_str() {
+        Some("subscribe") => {
+            let depth = match &request["depth"] {
+                serde_json::Value::Null => constants::feed_aggregator::TOP_N_BBO,
+                depth => depth.as_u64()
+                    .map(|depth| depth as usize)
+                    .filter(|depth| (1..=constants::feed_aggregator::TOP_N_BBO).contains(depth))
+                    .ok_or(format!("Depth must be from 1 to {}", constants::feed_aggregator::TOP_N_BBO))?
+            };
+            let is_fee_adjusted = request["fee_adjusted"].as_bool().unwrap_or(false);
+            if is_fee_adjusted && !fee_adjusted {
+                return Err("Fee-adjusted ranking is not enabled on the server".to_owned())
+            }
+            Ok(Request::Subscribe(Subscription{instrument, depth, fee_adjusted: is_fee_adjusted}))
+        }
+        Some("unsubscribe") => Ok(Request::Unsubscribe{instrument}),
+        _ => Err("Unknown method, expected subscribe or unsubscribe".to_owned())
+    }
+}
+
+/// Returns `None` if the subscription is for a view the aggregator doesn't calculate
+fn to_book_msg(aggregated_book: &util::AggregatedBook, subscription: &Subscription) -> Option<serde_json::Value> {
+    let ranked_book = if subscription.fee_adjusted {
+        aggregated_book.fee_adjusted.as_ref()?
+    } else {
+        &aggregated_book.raw
+    };
+    let to_level = |ranked_order: &util::RankedOrder| serde_json::json!({
+        "exchange": ranked_order.order.feed.feed_name_for_grpc_service(),
+        "price": ranked_order.order.price.to_string(),
+        "amount": ranked_order.order.amount.to_string(),
+        "effective_price": ranked_order.effective_price.to_string()
+    });
+    Some(serde_json::json!({
+        "event": "book",
+        "instrument": subscription.instrument,
+        "spread": ranked_book.spread.to_string(),
+        "bids": ranked_book.bids.iter().take(subscription.depth).map(to_level).collect::<Vec<_>>(),
+        "asks": ranked_book.asks.iter().take(subscription.depth).map(to_level).collect::<Vec<_>>()
+    }))
+}
+
+fn get_bad_request(message: &str) -> hyper::Response<hyper::Body> {
+    hyper::Response::builder()
+        .status(hyper::StatusCode::BAD_REQUEST)
+        .body(hyper::Body::from(message.to_owned()))
+        .expect("Response is valid")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    mod parse_request {
+        use super::*;
+
+
+        #[test]
+        fn test_subscribe() {
+            let request = parse_request(r#"{"method": "subscribe", "instrument": "ETHBTC", "depth": 5}"#, "ethbtc", false);
+
+            assert_eq!(request, Ok(Request::Subscribe(Subscription{instrument: "ethbtc".to_owned(), depth: 5, fee_adjusted: false})));
+        }
+
+        #[test]
+        fn test_invalid_requests() {
+            assert!(parse_request(r#"{"method": "subscribe", "instrument": "btcusdt"}"#, "ethbtc", false).is_err());
+            assert!(parse_request(r#"{"method": "subscribe", "instrument": "ethbtc", "depth": 0}"#, "ethbtc", false).is_err());
+            assert!(parse_request(r#"{"method": "subscribe", "instrument": "ethbtc", "fee_adjusted": true}"#, "ethbtc", false).is_err());
+            assert!(parse_request("subscribe", "ethbtc", false).is_err());
+        }
+    }
+
+    mod to_book_msg {
+        use super::*;
+
+
+        #[test]
+        fn test_depth_and_exact_prices() {
+            let order = util::Order {
+                feed: constants::Feed::BitstampSpot,
+                price: rust_decimal::Decimal::new(6_812_345_678, 11),
+                amount: rust_decimal::Decimal::ONE
+            };
+            let ranked_order = util::RankedOrder{order, effective_price: order.price, exchange_timestamp: None, received_at: None};
+            let aggregated_book = util::AggregatedBook {
+                sequence: 1,
+                published_at: std::time::SystemTime::now(),
+                read_at: std::time::Instant::now(),
+                aggregated_at: std::time::Instant::now(),
+                raw: util::RankedBook{bids: vec![ranked_order; 3], asks: vec![ranked_order; 3], ..Default::default()},
+                fee_adjusted: None
+            };
+            let subscription = Subscription{instrument: "ethbtc".to_owned(), depth: 2, fee_adjusted: false};
+            let msg = to_book_msg(&aggregated_book, &subscription).expect("Expected book message");
+
+            assert_eq!(msg["bids"].as_array().map(Vec::len), Some(2));
+            assert_eq!(msg["asks"][0]["price"], "0.06812345678");
+            assert_eq!(msg["asks"][0]["exchange"], "bitstamp");
+            assert!(to_book_msg(&aggregated_book, &Subscription{fee_adjusted: true, ..subscription}).is_none());
+        }
+    }
+}
\ No newline at end of file
@@ -1,6 +1,16 @@
-use crate::service::grpc::server::orderbook;
+use std::sync::Arc;
+
+use strum::EnumCount;
+
+use crate::constants;
+use crate::execution::paper;
 use crate::util;
 
 
+pub type BoxedArbitrageOpportunity = Box<util::ArbitrageOpportunity>;
 pub type BoxedFeedOrderBook = Box<util::FeedOrderBook>;
-pub type BoxedOrderbookSummary = Box<orderbook::Summary>;
\ No newline at end of file
+pub type BoxedMicrostructureSignals = Box<util::MicrostructureSignals>;
+pub type BoxedPaperOrder = Box<paper::PaperOrder>;
+pub type OrderBooksByFeed = [util::OrderBookTopN; constants::Feed::COUNT];
+/// Published once and shared by the watch and broadcast channels of `top_bbo`
+pub type SharedAggregatedBook = Arc<util::AggregatedBook>;
\ No newline at end of file
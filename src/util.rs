@@ -1,16 +1,28 @@
 use std::sync::Arc;
+use std::time;
 
 use rust_decimal;
-use tokio::sync::broadcast;
+use strum::EnumCount;
+use tokio::sync::{broadcast, watch};
 
 use crate::constants;
 use crate::types;
 
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub struct FeedOrderBook {
     pub feed: constants::Feed,
-    pub orderbook: OrderBookTopN
+    pub orderbook: OrderBookTopN,
+    /// Monotonic time the msg was read from the socket, for latency tracing
+    pub read_at: time::Instant,
+    /// Monotonic time the order book was forwarded to listener aggregators
+    pub forwarded_at: time::Instant
+}
+impl FeedOrderBook {
+    /// Order book forwarded now
+    pub fn new(feed: constants::Feed, orderbook: OrderBookTopN, read_at: time::Instant) -> FeedOrderBook {
+        FeedOrderBook{feed, orderbook, read_at, forwarded_at: time::Instant::now()}
+    }
 }
 #[derive(Clone, Copy, Debug)]
 pub struct Order {
@@ -31,13 +43,19 @@ impl Default for Order {
 #[derive(Clone, Copy, Debug)]
 pub struct OrderBookTopN {
     pub asks: [Order; constants::feed_aggregator::TOP_N_BBO],
-    pub bids: [Order; constants::feed_aggregator::TOP_N_BBO]
+    pub bids: [Order; constants::feed_aggregator::TOP_N_BBO],
+    /// Venue's timestamp of the book, `None` if the venue doesn't send one
+    pub exchange_timestamp: Option<time::SystemTime>,
+    /// When the listener received the venue's message, `None` if there's no data from the venue
+    pub received_at: Option<time::SystemTime>
 }
 impl Default for OrderBookTopN {
     fn default() -> Self {
         Self {
             asks: [Order::default(); constants::feed_aggregator::TOP_N_BBO],
-            bids: [Order::default(); constants::feed_aggregator::TOP_N_BBO]
+            bids: [Order::default(); constants::feed_aggregator::TOP_N_BBO],
+            exchange_timestamp: None,
+            received_at: None
         }
     }
 }
@@ -52,7 +70,216 @@ impl OrderBookTopN {
     }
 }
 
+/// Initializes order books to highest asks and lowest bids
+///
+/// Since when the program starts, not all order books have the representable value of the market,
+/// the ordered results would include default values i.e. price = 0 for e.g. asks. To prevent that,
+/// we initialize the values to practically positive and negative infinities.
+pub fn get_initialized_orderbooks() -> types::OrderBooksByFeed {
+    let mut orderbooks = [OrderBookTopN::default(); constants::Feed::COUNT];
+
+    for orderbook in &mut orderbooks {orderbook.set_unreachable_price();}
+    orderbooks
+}
+
+/// Merges top N of all order books into price ordered asks and bids
+///
+/// Orders of excluded feeds (unreachable prices) and empty levels are left out.
+pub fn get_aggregated_orders(orderbooks: &types::OrderBooksByFeed) -> (Vec<Order>, Vec<Order>) {
+    const RESERVED_SIZE:usize = constants::Feed::COUNT * constants::feed_aggregator::TOP_N_BBO;
+    let inf = rust_decimal::Decimal::from(constants::ORDER_PRICE_INF);
+    let mut asks: Vec<Order> = Vec::with_capacity(RESERVED_SIZE);
+    let mut bids: Vec<Order> = Vec::with_capacity(RESERVED_SIZE);
+
+    for orderbook in orderbooks.iter() {
+        asks.extend(orderbook.asks.iter().filter(|order| order.price < inf && !order.amount.is_zero()));
+        bids.extend(orderbook.bids.iter().filter(|order| order.price > -inf && !order.amount.is_zero()));
+    }
+    asks.sort_unstable_by_key(|order| order.price);
+    bids.sort_unstable_by_key(|order| std::cmp::Reverse(order.price));
+    (asks, bids)
+}
+
+/// Mid price of the aggregated book, `None` if there are no bids or no asks
+pub fn get_mid_price(orderbooks: &types::OrderBooksByFeed) -> Option<rust_decimal::Decimal> {
+    let (asks, bids) = get_aggregated_orders(orderbooks);
+    Some((asks.first()?.price + bids.first()?.price) / rust_decimal::Decimal::TWO)
+}
+
+/// Maker and taker fees of a feed, in basis points
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FeeSchedule {
+    pub maker_bps: rust_decimal::Decimal,
+    pub taker_bps: rust_decimal::Decimal
+}
+impl FeeSchedule {
+    /// Price paid per unit when lifting an ask, fees included
+    pub fn effective_ask_price(&self, price: rust_decimal::Decimal) -> rust_decimal::Decimal {
+        price + price * self.taker_bps / rust_decimal::Decimal::from(constants::BPS_PER_UNIT)
+    }
+
+    /// Price received per unit when hitting a bid, fees deducted
+    pub fn effective_bid_price(&self, price: rust_decimal::Decimal) -> rust_decimal::Decimal {
+        price - price * self.taker_bps / rust_decimal::Decimal::from(constants::BPS_PER_UNIT)
+    }
+}
+
+/// An order from the aggregated book together with its fee-inclusive price
+#[derive(Clone, Copy, Debug)]
+pub struct RankedOrder {
+    pub order: Order,
+    pub effective_price: rust_decimal::Decimal,
+    /// Timestamps of the venue's book the order comes from
+    pub exchange_timestamp: Option<time::SystemTime>,
+    pub received_at: Option<time::SystemTime>
+}
+
+/// Top N bids and asks of the aggregated book in ranking order
+#[derive(Clone, Debug, Default)]
+pub struct RankedBook {
+    pub spread: rust_decimal::Decimal,
+    pub asks: Vec<RankedOrder>,
+    pub bids: Vec<RankedOrder>,
+    /// Levels of all feeds in ranking order, views leaving out feeds are cut to depth from these
+    pub all_asks: Vec<RankedOrder>,
+    pub all_bids: Vec<RankedOrder>
+}
+
+/// Output of the `top_bbo` aggregator
+///
+/// Consumers pick the view they need and transform it to their own message format.
+#[derive(Clone, Debug)]
+pub struct AggregatedBook {
+    /// Increases by one with every published book, so consumers can detect missed updates
+    pub sequence: u64,
+    pub published_at: time::SystemTime,
+    /// Monotonic time the latest order book it includes was read from the socket
+    pub read_at: time::Instant,
+    /// Monotonic time the book was ready to publish
+    pub aggregated_at: time::Instant,
+    /// Ranked by raw venue prices
+    pub raw: RankedBook,
+    /// Ranked by fee-inclusive effective prices, only when enabled on the aggregator
+    pub fee_adjusted: Option<RankedBook>
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+    Buy,
+    Sell
+}
+
+/// Part of an order filled on one feed
+#[derive(Clone, Copy, Debug)]
+pub struct FeedAllocation {
+    pub feed: constants::Feed,
+    pub amount: rust_decimal::Decimal,
+    pub notional: rust_decimal::Decimal
+}
+
+/// Cost of executing an order against the aggregated book
+#[derive(Clone, Debug)]
+pub struct ExecutionCost {
+    pub side: Side,
+    pub requested_amount: rust_decimal::Decimal,
+    /// Less than requested if there's not enough liquidity in top N of all order books
+    pub filled_amount: rust_decimal::Decimal,
+    pub notional: rust_decimal::Decimal,
+    pub vwap: rust_decimal::Decimal,
+    pub worst_price: rust_decimal::Decimal,
+    pub mid_price: rust_decimal::Decimal,
+    /// VWAP's distance from mid price against us, in basis points
+    pub slippage_bps: rust_decimal::Decimal,
+    pub allocations: Vec<FeedAllocation>
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArbitrageCondition {
+    /// Best bid on the sell venue is above the best ask on the buy venue
+    Crossed,
+    /// Best bid on the sell venue equals the best ask on the buy venue
+    Locked
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArbitrageState {
+    Opened,
+    Updated,
+    Closed
+}
+
+/// Output of the `arbitrage` aggregator
+#[derive(Clone, Copy, Debug)]
+pub struct ArbitrageOpportunity {
+    pub state: ArbitrageState,
+    pub condition: ArbitrageCondition,
+    pub buy_feed: constants::Feed,
+    pub sell_feed: constants::Feed,
+    pub best_ask: rust_decimal::Decimal,
+    pub best_bid: rust_decimal::Decimal,
+    /// Amount that can be bought and sold with a profit after taker fees
+    pub amount: rust_decimal::Decimal,
+    pub gross_profit: rust_decimal::Decimal,
+    /// Profit after taker fees on both venues
+    pub net_profit: rust_decimal::Decimal,
+    pub detected_at: time::SystemTime,
+    pub timestamp: time::SystemTime,
+    /// How long the opportunity has been open
+    pub duration: time::Duration
+}
+
+/// Imbalance of the top levels of the aggregated book, from -1 (only asks) to 1 (only bids)
+#[derive(Clone, Copy, Debug)]
+pub struct OrderBookImbalance {
+    pub levels: usize,
+    pub imbalance: rust_decimal::Decimal
+}
+
+/// Cumulative amount within the offset from mid price
+#[derive(Clone, Copy, Debug)]
+pub struct DepthAtOffset {
+    pub offset_bps: rust_decimal::Decimal,
+    pub bid_amount: rust_decimal::Decimal,
+    pub ask_amount: rust_decimal::Decimal
+}
+
+/// Feed's share of the amount at the best bid and best ask of the aggregated book
+#[derive(Clone, Copy, Debug)]
+pub struct FeedContribution {
+    pub feed: constants::Feed,
+    pub bid_amount: rust_decimal::Decimal,
+    pub ask_amount: rust_decimal::Decimal,
+    pub bid_share: rust_decimal::Decimal,
+    pub ask_share: rust_decimal::Decimal
+}
+
+/// Output of the `microstructure` aggregator
+#[derive(Clone, Debug, Default)]
+pub struct MicrostructureSignals {
+    pub mid_price: rust_decimal::Decimal,
+    /// Mid price weighted by the amount on the opposite side of the top of the book
+    pub microprice: rust_decimal::Decimal,
+    pub imbalances: Vec<OrderBookImbalance>,
+    pub depths: Vec<DepthAtOffset>,
+    pub contributions: Vec<FeedContribution>
+}
+
 pub struct GrpcClientContext {
     pub instrument_name: String,
-    pub broadcast_aggregator_tx: Arc<broadcast::Sender<types::BoxedOrderbookSummary>>
+    pub broadcast_aggregator_tx: Arc<broadcast::Sender<types::SharedAggregatedBook>>,
+    /// Latest order book of each feed as seen by the aggregator
+    pub orderbooks_rx: watch::Receiver<types::OrderBooksByFeed>,
+    /// Latest aggregated book, `None` until the aggregator publishes the first one
+    pub aggregated_book_rx: watch::Receiver<Option<types::SharedAggregatedBook>>,
+    /// Whether the aggregator ranks the book by fee-inclusive effective prices
+    pub fee_adjusted: bool,
+    /// Becomes `true` when the server shuts down
+    pub shutdown_rx: watch::Receiver<bool>
+}
+
+/// Waits until shutdown is signalled, forever if the sender is gone without signalling it
+pub async fn wait_for_shutdown(shutdown_rx: &mut watch::Receiver<bool>) {
+    if shutdown_rx.wait_for(|shutdown| *shutdown).await.is_err() {
+        std::future::pending::<()>().await;
+    }
 }
\ No newline at end of file